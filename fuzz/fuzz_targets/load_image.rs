@@ -0,0 +1,13 @@
+#![no_main]
+
+//! Feeds the input as the outer `docker save` tarball.
+
+use docker_duplicate_files::Analyzer;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(analyzer) = Analyzer::builder().min_size(0).load(data) else {
+        return;
+    };
+    let _ = analyzer.find_duplicates();
+});
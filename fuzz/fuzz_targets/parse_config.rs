@@ -0,0 +1,10 @@
+#![no_main]
+
+use docker_duplicate_files::schemas::DockerConfig;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(json) = std::str::from_utf8(data) {
+        let _ = json.parse::<DockerConfig>();
+    }
+});
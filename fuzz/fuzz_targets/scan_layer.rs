@@ -0,0 +1,16 @@
+#![no_main]
+
+//! Wraps the input as the only layer of an otherwise valid image.
+
+use docker_duplicate_files::Analyzer;
+use docker_duplicate_files::testing::ImageBuilder;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let image = ImageBuilder::new().raw_layer(data).build();
+    let Ok(analyzer) = Analyzer::builder().min_size(0).load(image.as_slice()) else {
+        return;
+    };
+    let _ = analyzer.find_duplicates();
+    let _ = analyzer.entries().collect::<Vec<_>>();
+});
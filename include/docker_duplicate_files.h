@@ -0,0 +1,35 @@
+/*
+ * C API of docker_duplicate_files, built with
+ *
+ *     cargo rustc --release --lib --features capi --crate-type cdylib
+ *
+ * Functions return NULL or -1 on failure; ddf_last_error() then describes
+ * what went wrong on the calling thread.
+ */
+#ifndef DOCKER_DUPLICATE_FILES_H
+#define DOCKER_DUPLICATE_FILES_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* JSON report of duplicate files in a `docker save` tarball. Free the result
+ * with ddf_string_free(). min_size 0 keeps the default threshold. */
+char *ddf_analyze(const char *image_path, uint64_t min_size);
+
+/* Writes a deduplicated copy of the image to output_path. Returns 0 on
+ * success, -1 on failure. */
+int ddf_dedupe(const char *image_path, const char *output_path, uint64_t min_size);
+
+/* Last error on this thread, or NULL. Owned by the library. */
+const char *ddf_last_error(void);
+
+void ddf_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* DOCKER_DUPLICATE_FILES_H */
@@ -0,0 +1,92 @@
+//! The deduplication plan as a shell script, for keeping the build the
+//! source of truth instead of rewriting its output: each layer's `ln`
+//! commands belong at the end of the Dockerfile step that created that
+//! layer, since a later step can't take bytes out of an earlier layer.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::analyzer::DeDupTransaction;
+use crate::paths;
+use crate::policy::Action;
+use crate::schemas::{HistoryEntry, layer_history};
+
+/// `path` as one single-quoted shell word.
+pub fn shell_quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
+
+/// A `sh` script of the changes in `plan`, by layer in ascending order,
+/// each layer's block headed by the history entry that created it.
+pub fn link_script(
+    plan: &HashMap<usize, Vec<DeDupTransaction>>,
+    history: &[HistoryEntry],
+) -> String {
+    let mut script = String::from(
+        "#!/bin/sh\n\
+         # Deduplication as build steps. Run each block at the end of the\n\
+         # Dockerfile step it names, in the same RUN, so the duplicate never\n\
+         # lands in a layer.\n\
+         set -e\n",
+    );
+    let mut layers: Vec<&usize> = plan.keys().collect();
+    layers.sort();
+    for layer_index in layers {
+        let transactions = &plan[layer_index];
+        let saved: u64 = transactions.iter().map(|t| t.size).sum();
+        let _ = write!(script, "\n# Layer {}, saves {} bytes", layer_index, saved);
+        if let Some(step) = layer_history(history, *layer_index) {
+            let _ = write!(script, ": {}", step.created_by.replace('\n', " "));
+        }
+        script.push('\n');
+        for transaction in transactions {
+            let original = shell_quote(&format!(
+                "/{}",
+                paths::normalize(&transaction.original_path)
+            ));
+            let target = shell_quote(&format!("/{}", paths::normalize(&transaction.target_path)));
+            let _ = match transaction.action {
+                Action::Symlink => writeln!(script, "ln -sf {} {}", original, target),
+                Action::Hardlink => writeln!(script, "ln -f {} {}", original, target),
+                Action::Omit => writeln!(script, "rm -f {}", target),
+                Action::Skip => Ok(()),
+            };
+        }
+    }
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_script() {
+        let transaction = |original: &str, target: &str, action| DeDupTransaction {
+            original_path: original.to_string(),
+            original_layer: 0,
+            target_path: target.to_string(),
+            action,
+            size: 10,
+            hash: "ab".to_string(),
+        };
+        let plan = HashMap::from([
+            (
+                1,
+                vec![transaction(
+                    "usr/lib/a.so",
+                    "./opt/it's.so",
+                    Action::Symlink,
+                )],
+            ),
+            (0, vec![transaction("a", "b", Action::Hardlink)]),
+        ]);
+        let script = link_script(&plan, &[]);
+        let hardlink = script.find("ln -f '/a' '/b'").unwrap();
+        let symlink = script
+            .find(r"ln -sf '/usr/lib/a.so' '/opt/it'\''s.so'")
+            .unwrap();
+        assert!(hardlink < symlink);
+        assert!(script.contains("# Layer 1, saves 10 bytes\n"));
+    }
+}
@@ -1,52 +1,100 @@
 use std::cmp::Reverse;
-use std::collections::HashMap;
-use std::fs;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
+#[cfg(feature = "parallel")]
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result, anyhow};
-use flate2::Compression;
 use flate2::read::GzDecoder;
-use flate2::write::GzEncoder;
-use humansize::{BINARY, format_size};
-use itertools::Itertools;
-use log::{debug, info};
-use rapidhash::v3::{RapidSecrets, rapidhash_v3_file_seeded};
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use tar::{Archive, Builder};
-use tempfile::{TempDir, tempdir};
+#[cfg(feature = "parallel")]
+use rayon::iter::{ParallelBridge, ParallelIterator};
+#[cfg(feature = "parallel")]
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+use tar::{Archive, Builder, Header};
+use tempfile::tempdir_in;
+use tracing::{Span, debug, field, info, info_span, warn};
 
+use crate::attribution::{self, StepWaste};
+#[cfg(feature = "reports")]
+use crate::cancel;
+use crate::cancel::CancellableReader;
+use crate::checkpoint::{Checkpoint, WorkDir};
+#[cfg(feature = "reports")]
+use crate::compressibility::{self, LayerCompressionEstimate};
+#[cfg(feature = "sha256")]
+use crate::digest_writer::DigestReader;
+use crate::disk_space;
+use crate::elf::{self, ElfNearDuplicate};
+use crate::entries::{Entries, EntryInfo, EntryKind, LargeFile};
+use crate::error::{DedupeError, IoResultExt, Result};
+use crate::junk::{self, JunkFile};
+use crate::options::{AnalyzerBuilder, AnalyzerOptions};
+use crate::packages::{self, DuplicatePackage};
+use crate::paths::{self, PathMatcher};
+use crate::policy::{Action, PolicyContext};
+use crate::provenance::{self, LayerProvenance};
+use crate::scan;
 use crate::schemas::*;
-use crate::sha_writer::Sha256Writer;
-use crate::tee_writer::TeeWriter;
+use crate::timings::{Phase, Timings};
+use crate::union::UnionView;
+use crate::unpack;
 
-#[derive(Debug, Clone)]
+mod flatten;
+mod plan;
+#[cfg(feature = "rewrite")]
+pub(crate) mod rewrite;
+#[cfg(feature = "rewrite")]
+mod shared;
+#[cfg(feature = "rewrite")]
+mod split;
+#[cfg(feature = "rewrite")]
+mod squash;
+#[cfg(feature = "sha256")]
+mod verify;
+
+pub use plan::{ModificationPlan, PlannedLayer};
+#[cfg(feature = "rewrite")]
+pub use shared::SHARED_DIR;
+#[cfg(feature = "rewrite")]
+pub use split::SplitBy;
+#[cfg(feature = "sha256")]
+pub use verify::{Problem, Verification};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileInfo {
     pub path: String,
     pub size: u64,
     pub hash: String,
     pub layer_index: usize,
+    /// Digests and history of the layer, set on the files of duplicate
+    /// groups
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<LayerProvenance>,
 }
 
-#[derive(Debug, Clone)]
+/// A set of files with identical contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DuplicateInfo {
     pub original: FileInfo,
     pub duplicates: Vec<FileInfo>,
     pub total_savings: u64,
 }
 
-#[derive(Debug)]
-pub enum LinkType {
-    Sym,
-    Hard,
-}
-
-#[derive(Debug)]
+/// One planned change: replace `target_path` according to `action`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeDupTransaction {
-    original_path: String,
-    target_path: String,
-    link_type: LinkType,
+    pub original_path: String,
+    /// Layer of the original. Symlinks may point up, to a later layer's.
+    pub original_layer: usize,
+    pub target_path: String,
+    pub action: Action,
+    /// Bytes saved by the change
+    pub size: u64,
+    /// Content hash of both files
+    pub hash: String,
 }
 
 #[derive(Debug, Clone)]
@@ -54,65 +102,539 @@ pub struct Layer {
     pub path: PathBuf,
     pub layer_index: usize,
     pub hash: String,
+    /// Layers other than tars are passed through without being read
+    pub format: LayerFormat,
+    /// sha256 of the blob as stored, when known without reading it, as for
+    /// the layers a rewrite writes
+    pub blob_digest: Option<String>,
+}
+
+/// What a layer blob holds, by its media type or, without one, its first
+/// bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LayerFormat {
+    /// A tar, gzipped or not
+    #[default]
+    Tar,
+    /// Encrypted with ocicrypt
+    Encrypted,
+    /// Anything else, e.g. an artifact or a squashfs image
+    Unsupported,
+    /// No entries at all: a zero-byte blob, or a tar, gzipped or not, of
+    /// nothing but its end-of-archive blocks
+    Empty,
+}
+
+/// How a layer blob is compressed, by its magic bytes. Only gzip can be
+/// decompressed; zstd and xz are recognized to say so.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerCompression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
 }
+
+impl LayerCompression {
+    const MAGIC: [(&[u8], LayerCompression); 3] = [
+        (&[0x1f, 0x8b], LayerCompression::Gzip),
+        (&[0x28, 0xb5, 0x2f, 0xfd], LayerCompression::Zstd),
+        (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], LayerCompression::Xz),
+    ];
+
+    /// By the start of a blob. Anything without a known magic, including
+    /// input too short for one, is taken as uncompressed.
+    pub fn detect(head: &[u8]) -> Self {
+        Self::MAGIC
+            .iter()
+            .find(|(magic, _)| head.starts_with(magic))
+            .map_or(LayerCompression::None, |&(_, compression)| compression)
+    }
+
+    /// Of the file at `path`, which may be empty.
+    pub fn of_file(path: &Path) -> Result<Self> {
+        let mut head = Vec::with_capacity(6);
+        File::open(path)?.take(6).read_to_end(&mut head)?;
+        Ok(Self::detect(&head))
+    }
+}
+
 const BUFFER_SIZE: usize = 4 * 1024 * 1024; // 4MB buffer for better I/O performance
 
+/// File at the root of an image listing, one glob pattern per line, paths
+/// its authors want no deduplication to touch
+pub const IGNORE_FILE: &str = ".dedupeignore";
+/// Larger ignore files are refused rather than read into memory
+const MAX_IGNORE_FILE_SIZE: u64 = 1024 * 1024;
+
 impl Layer {
     pub fn open_reader(&self) -> Result<Box<dyn Read>> {
-        let file = File::open(&self.path)?;
-        if is_gzipped(&self.path)? {
-            Ok(Box::new(GzDecoder::new(file)))
-        } else {
-            Ok(Box::new(BufReader::with_capacity(BUFFER_SIZE, file)))
+        match self.format {
+            LayerFormat::Tar => {}
+            LayerFormat::Empty => return Ok(Box::new(std::io::empty())),
+            LayerFormat::Encrypted => {
+                return Err(DedupeError::Encrypted(format!(
+                    "layer {} can't be read",
+                    self.layer_index
+                )));
+            }
+            LayerFormat::Unsupported => {
+                return Err(DedupeError::UnsupportedLayer(format!(
+                    "layer {} isn't a tar",
+                    self.layer_index
+                )));
+            }
+        }
+        let file = CancellableReader::new(File::open(&self.path)?);
+        match LayerCompression::of_file(&self.path)? {
+            LayerCompression::Gzip => Ok(Box::new(GzDecoder::new(file))),
+            LayerCompression::None => Ok(Box::new(BufReader::with_capacity(BUFFER_SIZE, file))),
+            compression => Err(DedupeError::UnsupportedCompression(format!(
+                "layer {} is {:?}-compressed",
+                self.layer_index, compression
+            ))),
         }
     }
 }
 
 pub struct Analyzer {
-    pub tmp_dir: TempDir,
+    pub work_dir: WorkDir,
     pub layers: Vec<Layer>,
-    pub min_size: u64,
-    no_compression: bool,
+    options: AnalyzerOptions,
+    excludes: PathMatcher,
     original_manifest: Manifest,
     original_config: DockerConfig,
+    timings: Timings,
+    #[cfg(feature = "parallel")]
+    thread_pool: Option<Arc<ThreadPool>>,
+    #[cfg(feature = "parallel")]
+    pipelines: Option<Permits>,
+}
+
+/// Drops the changes of `plan` whose file or original `matcher` matches,
+/// logging `why` for each. Returns how many were dropped.
+fn drop_matching(
+    plan: &mut HashMap<usize, Vec<DeDupTransaction>>,
+    matcher: &PathMatcher,
+    why: &str,
+) -> usize {
+    let mut dropped = 0;
+    for transactions in plan.values_mut() {
+        transactions.retain(|t| {
+            let matched = matcher.is_match(&t.target_path) || matcher.is_match(&t.original_path);
+            if matched {
+                debug!("Not replacing {}, {}", t.target_path, why);
+                dropped += 1;
+            }
+            !matched
+        });
+    }
+    plan.retain(|_, transactions| !transactions.is_empty());
+    dropped
+}
+
+fn layer_error(layer: &Layer, source: DedupeError) -> DedupeError {
+    DedupeError::Layer {
+        index: layer.layer_index,
+        path: layer.path.clone(),
+        source: Box::new(source),
+    }
+}
+
+/// The normalized paths the symlinks among `entries` point to.
+fn link_targets(entries: &[EntryInfo]) -> HashSet<String> {
+    entries
+        .iter()
+        .filter(|e| e.kind == EntryKind::Symlink)
+        .filter_map(|e| paths::link_target(&e.path, e.link_target.as_deref()?))
+        .collect()
+}
+
+/// A counting semaphore, held by every layer being decompressed or
+/// rewritten so at most so many are at once.
+#[cfg(feature = "parallel")]
+struct Permits {
+    available: Mutex<usize>,
+    released: Condvar,
 }
 
-const GZIP_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
+#[cfg(feature = "parallel")]
+struct Permit<'a>(&'a Permits);
 
-fn is_gzipped(file_path: &Path) -> Result<bool> {
-    let mut file = File::open(file_path)?;
-    let mut magic_bytes = [0u8; 2];
-    file.read_exact(&mut magic_bytes)?;
-    Ok(magic_bytes == GZIP_MAGIC_BYTES)
+#[cfg(feature = "parallel")]
+impl Permits {
+    fn new(count: usize) -> Result<Self> {
+        if count == 0 {
+            return Err(DedupeError::InvalidOption(
+                "At least one layer pipeline is needed".to_string(),
+            ));
+        }
+        Ok(Self {
+            available: Mutex::new(count),
+            released: Condvar::new(),
+        })
+    }
+
+    /// Blocks until a permit is free, returning it on drop.
+    fn acquire(&self) -> Permit<'_> {
+        let available = self.available.lock().unwrap_or_else(|e| e.into_inner());
+        let mut available = self
+            .released
+            .wait_while(available, |available| *available == 0)
+            .unwrap_or_else(|e| e.into_inner());
+        *available -= 1;
+        Permit(self)
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.0.released.notify_one();
+    }
+}
+
+#[cfg(feature = "parallel")]
+fn build_thread_pool(threads: usize) -> Result<ThreadPool> {
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("dedupe-{}", i))
+        .build()
+        .map_err(|e| DedupeError::InvalidOption(format!("Can't start thread pool: {}", e)))
+}
+
+/// Hard links `src` to `dst`, copying when they live on different
+/// filesystems.
+pub(crate) fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    if fs::hard_link(src, dst).is_err() {
+        fs::copy(src, dst)
+            .with_context(|| format!("Failed to copy {} to {}", src.display(), dst.display()))?;
+    }
+    Ok(())
+}
+
+/// Longest path or link target the name fields of a tar header hold.
+const TAR_NAME_LEN: usize = 100;
+
+/// Largest size the octal size field of a ustar header holds, 8 GiB - 1.
+/// GNU headers store larger ones in base-256.
+const TAR_MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+/// A GNU header with the fields of `ustar`, all of which it shares up to
+/// the magic and after it until the ustar prefix.
+fn gnu_header(ustar: &Header) -> Header {
+    let mut header = Header::new_gnu();
+    let (from, to) = (ustar.as_bytes(), header.as_mut_bytes());
+    to[..257].copy_from_slice(&from[..257]);
+    to[265..345].copy_from_slice(&from[265..345]);
+    header
+}
+
+/// Appends a copy of `entry` at `path`, with its full link target and
+/// size. Paths and targets too long for the header go in GNU long name
+/// entries, which a ustar header can't be paired with, and sizes from
+/// 8 GiB on in base-256, which only GNU headers define, so their copy gets
+/// a GNU header instead. PAX records aren't copied, a size past the
+/// header's among them, so the size is the entry's rather than the
+/// header's.
+fn append_entry<W: Write, R: Read>(
+    builder: &mut Builder<W>,
+    entry: &mut tar::Entry<R>,
+    path: &Path,
+) -> std::io::Result<()> {
+    let target = entry.link_name()?.map(|target| target.into_owned());
+    let size = entry.size();
+    let mut header = entry.header().clone();
+    let too_long = |p: &Path| p.as_os_str().len() > TAR_NAME_LEN;
+    let long_names = too_long(path) || target.as_deref().is_some_and(too_long);
+    if (header.as_ustar().is_some() && long_names)
+        || (header.as_gnu().is_none() && size > TAR_MAX_OCTAL_SIZE)
+    {
+        header = gnu_header(&header);
+    }
+    let entry_type = header.entry_type();
+    match target {
+        Some(target) if entry_type.is_symlink() || entry_type.is_hard_link() => {
+            header.set_size(0);
+            builder.append_link(&mut header, path, target)
+        }
+        _ => {
+            header.set_size(size);
+            builder.append_data(&mut header, path, entry)
+        }
+    }
+}
+
+/// Largest blob, and contents, [`is_empty_layer`] reads through. Empty tars
+/// are padded to at most 20 blocks of 512 bytes.
+const EMPTY_LAYER_LIMIT: u64 = 64 * 1024;
+
+/// Whether the file holds no entries: zero bytes, or only zero blocks once
+/// gunzipped. Files that can't be read aren't empty.
+fn is_empty_layer(path: &Path) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    if !file.metadata().is_ok_and(|m| m.len() <= EMPTY_LAYER_LIMIT) {
+        return false;
+    }
+    let reader: Box<dyn Read> = match LayerCompression::of_file(path) {
+        Ok(LayerCompression::Gzip) => Box::new(GzDecoder::new(file)),
+        Ok(LayerCompression::None) => Box::new(file),
+        _ => return false,
+    };
+    let mut contents = Vec::new();
+    reader
+        .take(EMPTY_LAYER_LIMIT + 1)
+        .read_to_end(&mut contents)
+        .is_ok_and(|len| len as u64 <= EMPTY_LAYER_LIMIT)
+        && contents.iter().all(|&b| b == 0)
+}
+
+/// Whether the file starts like a layer: compressed, a tar header with a
+/// valid checksum, or the zero block ending an empty tar. Files that can't
+/// be read are assumed to be tars, so reading them reports the error.
+fn sniff_format(path: &Path) -> LayerFormat {
+    let mut block = Vec::with_capacity(512);
+    let read = File::open(path).and_then(|file| file.take(512).read_to_end(&mut block));
+    if read.is_err()
+        || block.is_empty()
+        || LayerCompression::detect(&block) != LayerCompression::None
+    {
+        return LayerFormat::Tar;
+    }
+    let Ok(header) = <&[u8; 512]>::try_from(block.as_slice()) else {
+        return LayerFormat::Unsupported;
+    };
+    // The checksum counts its own field as spaces
+    let sum: u32 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                32
+            } else {
+                u32::from(b)
+            }
+        })
+        .sum();
+    let stored = std::str::from_utf8(&header[148..156])
+        .ok()
+        .and_then(|field| u32::from_str_radix(field.trim_matches(['\0', ' ']), 8).ok());
+    if header.iter().all(|&b| b == 0) || stored == Some(sum) {
+        LayerFormat::Tar
+    } else {
+        LayerFormat::Unsupported
+    }
 }
 
 impl Analyzer {
-    pub fn load_from_path(image_path: String, min_size: u64, no_compression: bool) -> Result<Self> {
-        if image_path.ends_with(".tar")
-            || image_path.ends_with(".tar.gz")
-            || image_path.ends_with(".tar.xz")
-        {
-            let file = File::open(&image_path)
-                .with_context(|| format!("Failed to open image file: {}", image_path))?;
-            Analyzer::load(BufReader::new(file), min_size, no_compression)
-        } else {
-            Err(anyhow!(
-                "Unexpected image string {}, must be an exported tar file",
-                image_path
-            ))
+    pub fn builder() -> AnalyzerBuilder {
+        AnalyzerBuilder::default()
+    }
+
+    /// Loads an exported image tarball. With a `work_dir` option, progress is
+    /// kept there and a rerun against the same image resumes instead of
+    /// restarting.
+    pub fn from_path(image_path: impl AsRef<Path>, options: AnalyzerOptions) -> Result<Self> {
+        let image_path = image_path.as_ref();
+        let name = image_path.to_string_lossy();
+        if !(name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tar.xz")) {
+            return Err(DedupeError::NotAnImage(format!(
+                "{}, must be an exported tar file",
+                name
+            )));
+        }
+
+        let file = File::open(image_path)
+            .with_context(|| format!("Failed to open image file: {}", name))?;
+        // Extraction writes the outer tar's files once more. Layers stay as
+        // they are and are scanned as streams, and staging the rewrite is
+        // checked on its own once the layers it touches are known.
+        let metadata = file.metadata()?;
+        let source = format!("{}:{}:{:?}", name, metadata.len(), metadata.modified().ok());
+
+        let space_dir = options
+            .work_dir
+            .clone()
+            .unwrap_or_else(|| options.temp_dir());
+        let resuming = match &options.work_dir {
+            Some(work_dir) => Checkpoint::open(work_dir)?.is_extracted(&source)?,
+            None => false,
+        };
+        if !resuming {
+            disk_space::ensure_available(&space_dir, metadata.len(), "extracting the image")?;
+        }
+        Self::load_into(BufReader::new(file), &source, options)
+    }
+
+    /// Loads an image from a `docker save` stream. A stream can't be
+    /// identified before it's read, so with a `work_dir` option it's always
+    /// extracted again, hashed on the way, and the scan and rewrite progress
+    /// in the dir is only kept if an earlier run read the same bytes.
+    ///
+    /// A stream's size isn't known up front, so unlike [`Self::from_path`]
+    /// its extraction isn't checked for free space; the rewrite still is.
+    pub fn from_reader<R: Read>(image_stream: R, options: AnalyzerOptions) -> Result<Self> {
+        match options.work_dir.clone() {
+            Some(dir) => Self::load_stream_into(image_stream, &dir, options),
+            None => Self::load_into(image_stream, "stream", options),
         }
     }
 
+    fn load_stream_into<R: Read>(
+        image_stream: R,
+        dir: &Path,
+        options: AnalyzerOptions,
+    ) -> Result<Self> {
+        let start = Instant::now();
+        let checkpoint = Checkpoint::open(dir)?;
+        let previous = checkpoint.extracted_source();
+        checkpoint.restart_extraction()?;
+        let extracted_dir = checkpoint.extracted_dir();
+        let (limits, rootless) = (options.unpack_limits, options.rootless);
+        #[cfg(feature = "sha256")]
+        let (bytes, source) = {
+            let mut reader = DigestReader::new(image_stream);
+            let bytes = unpack::unpack_image(&mut reader, &extracted_dir, limits, rootless)?;
+            // Whatever follows the archive's end blocks is part of the stream
+            std::io::copy(&mut reader, &mut std::io::sink())?;
+            (bytes, Some(format!("stream:{}", reader.finish())))
+        };
+        // Without a hash no two streams are known to be the same
+        #[cfg(not(feature = "sha256"))]
+        let (bytes, source) = (
+            unpack::unpack_image(image_stream, &extracted_dir, limits, rootless)?,
+            None::<String>,
+        );
+        match (&previous, &source) {
+            (Some(previous), Some(source)) if previous == source => {
+                info!("Resuming progress on the same stream in {}", dir.display());
+            }
+            (None, _) => checkpoint.discard_progress()?,
+            (Some(previous), _) => {
+                warn!(
+                    "Work dir {} belongs to {}, discarding its progress",
+                    dir.display(),
+                    previous
+                );
+                checkpoint.discard_progress()?;
+            }
+        }
+        if let Some(source) = &source {
+            checkpoint.mark_extracted(source)?;
+        }
+
+        let analyzer =
+            Self::from_extracted(WorkDir::Persistent(checkpoint), &extracted_dir, options)?;
+        analyzer
+            .timings
+            .record(Phase::Extract, start.elapsed(), bytes);
+        Ok(analyzer)
+    }
+
+    #[deprecated(note = "use Analyzer::builder() or Analyzer::from_path")]
+    pub fn load_from_path(
+        image_path: String,
+        min_size: u64,
+        no_compression: bool,
+        work_dir: Option<PathBuf>,
+    ) -> Result<Self> {
+        let options = AnalyzerOptions {
+            min_size,
+            compression: !no_compression,
+            work_dir,
+            ..Default::default()
+        };
+        Self::from_path(image_path, options)
+    }
+
+    #[deprecated(note = "use Analyzer::builder() or Analyzer::from_reader")]
     pub fn load<R: Read>(image_stream: R, min_size: u64, no_compression: bool) -> Result<Self> {
-        let tmp_dir = tempdir()?;
-        let mut archive = Archive::new(image_stream);
-        let extracted_dir = tmp_dir.path();
-        archive.unpack(extracted_dir)?;
+        let options = AnalyzerOptions {
+            min_size,
+            compression: !no_compression,
+            ..Default::default()
+        };
+        Self::from_reader(image_stream, options)
+    }
+
+    #[deprecated(note = "use Analyzer::builder() with a work_dir")]
+    pub fn load_resumable<R: Read>(
+        image_stream: R,
+        checkpoint: Checkpoint,
+        source: &str,
+        min_size: u64,
+        no_compression: bool,
+    ) -> Result<Self> {
+        let options = AnalyzerOptions {
+            min_size,
+            compression: !no_compression,
+            work_dir: Some(checkpoint.root().to_path_buf()),
+            ..Default::default()
+        };
+        Self::load_into(image_stream, source, options)
+    }
+
+    /// Extracts into a temp dir, or into the checkpoint when a work dir is
+    /// set. `source` identifies the input; when it matches the checkpoint the
+    /// stream isn't read at all.
+    fn load_into<R: Read>(image_stream: R, source: &str, options: AnalyzerOptions) -> Result<Self> {
+        let (limits, rootless) = (options.unpack_limits, options.rootless);
+        Self::load_with(source, options, |dst| {
+            unpack::unpack_image(image_stream, dst, limits, rootless)
+        })
+    }
+
+    /// Like [`Self::load_into`], with `unpack` laying out the `docker save`
+    /// files in the dir it is given and returning the bytes it read.
+    pub(crate) fn load_with(
+        source: &str,
+        options: AnalyzerOptions,
+        unpack: impl FnOnce(&Path) -> Result<u64>,
+    ) -> Result<Self> {
+        let start = Instant::now();
+        let mut bytes = 0;
+        let (work_dir, extracted_dir) = match &options.work_dir {
+            Some(dir) => {
+                let checkpoint = Checkpoint::open(dir)?;
+                let extracted_dir = checkpoint.extracted_dir();
+                if checkpoint.is_extracted(source)? {
+                    info!(
+                        "Resuming from image extracted in {}",
+                        extracted_dir.display()
+                    );
+                } else {
+                    bytes = unpack(&extracted_dir)?;
+                    checkpoint.mark_extracted(source)?;
+                }
+                (WorkDir::Persistent(checkpoint), extracted_dir)
+            }
+            None => {
+                let work_dir = WorkDir::Temp(tempdir_in(options.temp_dir())?);
+                let extracted_dir = work_dir.path().to_path_buf();
+                bytes = unpack(&extracted_dir)?;
+                (work_dir, extracted_dir)
+            }
+        };
 
+        let analyzer = Self::from_extracted(work_dir, &extracted_dir, options)?;
+        analyzer
+            .timings
+            .record(Phase::Extract, start.elapsed(), bytes);
+        Ok(analyzer)
+    }
+
+    fn from_extracted(
+        work_dir: WorkDir,
+        extracted_dir: &Path,
+        options: AnalyzerOptions,
+    ) -> Result<Self> {
         let manifest_file = extracted_dir.join("manifest.json");
         let manifest = Manifest::from_file(&manifest_file)?;
 
-        let config_path = extracted_dir.join(&manifest.config);
+        let config_path = paths::resolve_in_archive(extracted_dir, &manifest.config)?;
         let config = DockerConfig::from_file(&config_path)?;
 
         let layers = manifest
@@ -120,354 +642,717 @@ impl Analyzer {
             .iter()
             .enumerate()
             .map(|(idx, l)| {
-                let layer_path = extracted_dir.join(l);
+                // Some saves point layers at shared blobs through symlinks
+                let layer_path = paths::resolve_in_archive(extracted_dir, l)?;
                 let hash = config.rootfs.diff_ids.get(idx).cloned().unwrap_or_default();
-                Layer {
+                let source = manifest.layer_sources.get(&hash);
+                let format = match source {
+                    Some(source) if source.is_encrypted() => LayerFormat::Encrypted,
+                    Some(source) if !source.is_tar_layer() => LayerFormat::Unsupported,
+                    _ if is_empty_layer(&layer_path) => LayerFormat::Empty,
+                    _ => sniff_format(&layer_path),
+                };
+                if format == LayerFormat::Empty {
+                    debug!(
+                        "Layer {} is empty, passing it through without scanning",
+                        idx
+                    );
+                } else if format != LayerFormat::Tar {
+                    warn!(
+                        "Layer {} is {}, passing it through without scanning",
+                        idx,
+                        source.map_or("not a tar", |s| s.media_type.as_str())
+                    );
+                }
+                Ok(Layer {
                     path: layer_path,
                     layer_index: idx,
                     hash,
-                }
+                    format,
+                    blob_digest: None,
+                })
             })
-            .collect();
+            .collect::<Result<_>>()?;
+
+        #[cfg(feature = "parallel")]
+        let thread_pool = match (&options.thread_pool, options.threads) {
+            (Some(pool), _) => Some(pool.clone()),
+            (None, Some(threads)) => Some(Arc::new(build_thread_pool(threads)?)),
+            (None, None) => None,
+        };
+        #[cfg(feature = "parallel")]
+        let pipelines = options.max_pipelines.map(Permits::new).transpose()?;
 
         info!("{:#?}", manifest);
         Ok(Self {
-            tmp_dir,
+            work_dir,
             layers,
-            min_size,
-            no_compression,
+            excludes: PathMatcher::excludes(&options.excludes, &options.allowed_paths)?
+                .within(&options.scope)?,
+            options,
             original_manifest: manifest,
             original_config: config,
+            timings: Timings::default(),
+            #[cfg(feature = "parallel")]
+            thread_pool,
+            #[cfg(feature = "parallel")]
+            pipelines,
         })
     }
 
-    pub fn scan_files(&self) -> Result<Vec<FileInfo>> {
-        Ok(self
-            .layers
-            .par_iter()
-            .map(|layer| {
-                self.scan_layer(layer)
-                    .map_err(|e| anyhow!("Error scanning layer: {:?} {}", layer, e))
-            })
-            .collect::<Result<Vec<Vec<FileInfo>>, _>>()?
-            .into_iter()
-            .flatten()
-            .collect())
+    pub fn options(&self) -> &AnalyzerOptions {
+        &self.options
     }
 
-    fn scan_layer(&self, layer: &Layer) -> Result<Vec<FileInfo>> {
-        // Grouping by size first then only hashing the files with same size was slower
-        //  due to having to re-decompress the layers for a second pass
-        let mut archive = Archive::new(layer.open_reader()?);
-        let mut files = Vec::new();
-        for entry in archive.entries()? {
-            let mut entry = entry?;
-
-            if !entry.header().entry_type().is_file() {
-                continue;
-            }
+    /// The manifest.json entry of the loaded image.
+    pub fn manifest(&self) -> &Manifest {
+        &self.original_manifest
+    }
 
-            let size = entry.header().size()?;
+    pub fn config(&self) -> &DockerConfig {
+        &self.original_config
+    }
 
-            if size < self.min_size {
-                continue;
-            }
+    #[cfg(feature = "parallel")]
+    /// Runs all parallel work on `pool` instead of rayon's global pool, for
+    /// hosts that already manage their own.
+    pub fn with_thread_pool(mut self, pool: Arc<ThreadPool>) -> Self {
+        self.thread_pool = Some(pool);
+        self
+    }
 
-            let path = entry.path()?.to_string_lossy().to_string();
+    #[cfg(feature = "parallel")]
+    /// Caps parallelism by running on a dedicated pool of `threads` workers.
+    pub fn with_max_parallelism(self, threads: usize) -> Result<Self> {
+        let pool = build_thread_pool(threads)?;
+        Ok(self.with_thread_pool(Arc::new(pool)))
+    }
 
-            if path.contains("/.wh.") || path.ends_with(".wh..wh..opq") {
-                // ignore removed files for now
-                continue;
-            }
-            //let mut hasher = blake3::Hasher::new();
-            //copy(&mut entry, &mut hasher)?;
-            //let hash = hasher.finalize().to_string();
-            // rapidhash ~ 11% faster
-            let hash = rapidhash_v3_file_seeded(&mut entry, &RapidSecrets::seed(0))?;
-            files.push(FileInfo {
-                path,
-                size,
-                hash: hash.to_string(),
-                layer_index: layer.layer_index,
+    /// Runs `op` on every layer, stopping at the first error, and returns
+    /// the results in layer order. With the `parallel` feature layers are
+    /// processed concurrently on the analyzer's pool, largest blob first:
+    /// started last, a giant layer would keep one thread busy long after
+    /// the others ran out of work. No more than
+    /// [`AnalyzerOptions::max_pipelines`] run at once.
+    fn try_map_layers<T: Send>(
+        &self,
+        op: impl Fn(&Layer) -> Result<T> + Sync + Send,
+    ) -> Result<Vec<T>> {
+        #[cfg(feature = "parallel")]
+        {
+            let mut order: Vec<(usize, &Layer)> = self.layers.iter().enumerate().collect();
+            order.sort_by_cached_key(|(_, layer)| {
+                Reverse(fs::metadata(&layer.path).map_or(0, |m| m.len()))
             });
+            // par_bridge hands the layers out in that order as threads free
+            // up, where par_iter would split the list up front
+            let run = || {
+                order
+                    .into_iter()
+                    .par_bridge()
+                    .map(|(position, layer)| {
+                        let _permit = self.pipelines.as_ref().map(Permits::acquire);
+                        Ok((position, op(layer)?))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            };
+            let mut results = match &self.thread_pool {
+                Some(pool) => pool.install(run),
+                None => run(),
+            }?;
+            results.sort_by_key(|&(position, _)| position);
+            Ok(results.into_iter().map(|(_, result)| result).collect())
         }
+        #[cfg(not(feature = "parallel"))]
+        self.layers.iter().map(op).collect()
+    }
 
-        Ok(files)
+    /// Wall time and bytes processed so far, per phase.
+    pub fn timings(&self) -> &Timings {
+        &self.timings
     }
 
-    pub fn find_duplicates(&self) -> Result<Vec<DuplicateInfo>> {
-        let files = self.scan_files()?;
-        info!("Done scanning files...");
-        let mut files_by_hash: HashMap<String, Vec<FileInfo>> = HashMap::new();
-        for file in files {
-            files_by_hash
-                .entry(file.hash.clone())
-                .or_default()
-                .push(file);
-        }
-        Ok(files_by_hash
+    /// Lazily lists every entry of every layer from the tar headers alone,
+    /// without hashing anything.
+    pub fn entries(&self) -> Entries<'_> {
+        Entries::new(&self.layers)
+    }
+
+    /// The `count` largest files across all layers, hidden ones included,
+    /// from the tar headers alone.
+    pub fn largest_files(&self, count: usize) -> Result<Vec<LargeFile>> {
+        let entries: Vec<EntryInfo> = self.entries().collect::<Result<_>>()?;
+        let view = UnionView::build(entries.iter().cloned().map(Ok))?;
+        let mut files: Vec<EntryInfo> = entries
             .into_iter()
-            .filter(|(_, files)| files.len() > 1)
-            .map(|(_, mut files)| {
-                files.sort_by_key(|f| f.layer_index);
-                let target = files.remove(0);
-                let savings = target.size * files.len() as u64;
-                DuplicateInfo {
-                    original: target,
-                    duplicates: files,
-                    total_savings: savings,
-                }
+            .filter(|e| e.kind == EntryKind::File)
+            .collect();
+        files.sort_by_key(|e| Reverse(e.size));
+        files.truncate(count);
+        Ok(files
+            .into_iter()
+            .map(|entry| LargeFile {
+                visible: view.get(&entry.path).is_some_and(|visible| {
+                    visible.layer_index == entry.layer_index && visible.kind == EntryKind::File
+                }),
+                created_by: layer_history(&self.original_config.history, entry.layer_index)
+                    .map(|h| h.created_by.clone()),
+                compression_ratio: None,
+                entry,
             })
-            .sorted_by_key(|d| Reverse(d.total_savings))
             .collect())
     }
 
-    pub fn print_possible_savings(&self, duplicates: &Vec<DuplicateInfo>) -> Result<()> {
-        info!("=============================");
-        info!("Total duplicate files: {}", duplicates.len());
-        info!(
-            "Total duplicate size: {}",
-            format_size(
-                duplicates.iter().map(|f| f.total_savings).sum::<u64>(),
-                BINARY
-            )
-        );
-        info!("=============================");
-        info!("Duplicate files:");
-        for dup_info in duplicates.iter() {
-            info!(
-                "\tOriginal: {}, layer: {} size: {}",
-                dup_info.original.path,
-                dup_info.original.layer_index,
-                format_size(dup_info.original.size, BINARY)
-            );
-            for dup in dup_info.duplicates.iter() {
-                info!("\tDuplicate: {}, layer: {}", dup.path, dup.layer_index);
+    #[cfg(feature = "reports")]
+    /// Sets the `compression_ratio` of each of `files` from a gzipped
+    /// sample of at most [`compressibility::FILE_SAMPLE_SIZE`] bytes,
+    /// reading only the layers they are in.
+    pub fn measure_compressibility(&self, files: &mut [LargeFile]) -> Result<()> {
+        let ratios = self.try_map_layers(|layer| {
+            let wanted: HashSet<&str> = files
+                .iter()
+                .filter(|f| f.entry.layer_index == layer.layer_index)
+                .map(|f| f.entry.path.as_str())
+                .collect();
+            if wanted.is_empty() || layer.format != LayerFormat::Tar {
+                return Ok(Vec::new());
             }
+            let measure = || -> Result<Vec<(usize, String, f64)>> {
+                let mut ratios = Vec::new();
+                let mut archive = Archive::new(layer.open_reader()?);
+                for entry in archive.entries()? {
+                    cancel::check()?;
+                    let entry = entry?;
+                    let path = entry.path()?.to_string_lossy().to_string();
+                    if !wanted.contains(path.as_str()) {
+                        continue;
+                    }
+                    let (sample, _) =
+                        compressibility::sample_stream(entry, compressibility::FILE_SAMPLE_SIZE)?;
+                    let ratio = compressibility::gzip_ratio(&sample);
+                    ratios.push((layer.layer_index, path, ratio));
+                }
+                Ok(ratios)
+            };
+            measure().map_err(|e| layer_error(layer, e))
+        })?;
+        let ratios: HashMap<(usize, String), f64> = ratios
+            .into_iter()
+            .flatten()
+            .map(|(layer_index, path, ratio)| ((layer_index, path), ratio))
+            .collect();
+        for file in files {
+            file.compression_ratio = ratios
+                .get(&(file.entry.layer_index, file.entry.path.clone()))
+                .copied();
         }
-        info!("=============================");
         Ok(())
     }
 
-    pub fn generate_modification_plan(
-        &self,
-        duplicates: Vec<DuplicateInfo>,
-    ) -> Result<HashMap<usize, Vec<DeDupTransaction>>> {
-        Ok(duplicates
-            .iter()
-            .flat_map(|d| d.duplicates.iter().map(move |f| (d, f)))
-            .map(|(d, f)| {
-                (
-                    f.layer_index,
-                    DeDupTransaction {
-                        original_path: d.original.path.clone(),
-                        target_path: f.path.clone(),
-                        link_type: if d.original.layer_index == f.layer_index {
-                            LinkType::Hard
-                        } else {
-                            LinkType::Sym
-                        },
-                    },
-                )
-            })
-            .into_group_map())
+    pub fn scan_files(&self) -> Result<Vec<FileInfo>> {
+        let start = Instant::now();
+        let phase = info_span!("scan", layers = self.layers.len());
+        let _guard = phase.enter();
+        let files = self
+            .try_map_layers(|layer| {
+                self.scan_layer_checkpointed(layer, &phase)
+                    .map_err(|e| layer_error(layer, e))
+            })?
+            .into_iter()
+            .flatten()
+            .collect();
+        self.timings.record(Phase::Scan, start.elapsed(), 0);
+        Ok(files)
     }
 
-    fn build_layer_tar<W: Write>(
-        &self,
-        layer: &Layer,
-        modifications: &Vec<DeDupTransaction>,
-        writer: W,
-    ) -> Result<(W, Sha256Writer)> {
-        let hasher = Sha256Writer::new();
-        let tee = TeeWriter::new(writer, hasher);
-        let buffered_tee = BufWriter::with_capacity(BUFFER_SIZE, tee);
-        let mut builder = Builder::new(buffered_tee);
-
-        builder.follow_symlinks(false);
-
-        let mods_by_target: HashMap<PathBuf, &DeDupTransaction> = modifications
-            .iter()
-            .map(|m| (PathBuf::from(m.target_path.clone()), m))
-            .collect();
+    fn scan_layer_checkpointed(&self, layer: &Layer, parent: &Span) -> Result<Vec<FileInfo>> {
+        // Rayon workers don't inherit the caller's span, so link it explicitly
+        let span = info_span!(
+            parent: parent,
+            "scan_layer",
+            layer = layer.layer_index,
+            digest = %layer.hash,
+            files = field::Empty,
+            bytes = field::Empty,
+            duration_ms = field::Empty,
+        );
+        let _guard = span.enter();
+        let start = Instant::now();
+        let progress = &self.options.progress;
+        progress.layer_scan_started(layer);
+        let checkpoint = self.work_dir.checkpoint();
+        let files = match checkpoint
+            .and_then(|c| c.load_scan(layer, self.options.min_size, self.options.hasher.name()))
+        {
+            // Saved by a run that may have had other excludes or scope
+            Some(mut files) => {
+                files.retain(|f| !self.excludes.is_match(&f.path));
+                files
+            }
+            None => {
+                let files = self.scan_layer(layer)?;
+                if let Some(checkpoint) = checkpoint {
+                    checkpoint.save_scan(
+                        layer,
+                        self.options.min_size,
+                        self.options.hasher.name(),
+                        &files,
+                    )?;
+                }
+                files
+            }
+        };
+        progress.layer_scan_finished(layer, files.len());
+        span.record("files", files.len());
+        span.record("duration_ms", start.elapsed().as_millis() as u64);
+        debug!("Scanned layer");
+        Ok(files)
+    }
 
-        let mut archive = Archive::new(layer.open_reader()?);
+    fn scan_layer(&self, layer: &Layer) -> Result<Vec<FileInfo>> {
+        if layer.format != LayerFormat::Tar {
+            return Ok(Vec::new());
+        }
+        let (files, bytes) = scan::scan_tar(
+            layer.open_reader()?,
+            layer.layer_index,
+            &self.options,
+            &self.excludes,
+            |size| self.options.progress.bytes_hashed(layer, size),
+        )?;
+        self.timings.record(Phase::Scan, Duration::ZERO, bytes);
+        Span::current().record("bytes", bytes);
+        Ok(files)
+    }
 
-        for entry_result in archive.entries()? {
-            let mut entry = entry_result?;
-            let path = entry.path()?.into_owned();
+    pub fn find_duplicates(&self) -> Result<Vec<DuplicateInfo>> {
+        let files = self.scan_files()?;
+        info!("Done scanning files...");
+        Ok(self.group_duplicates(files))
+    }
 
-            if mods_by_target.contains_key(&path) {
-                debug!("Replacing {} with a link", path.display());
-                continue;
+    /// Groups files [`Self::scan_files`] returned, for callers that look
+    /// at the files themselves too.
+    pub fn group_duplicates(&self, files: Vec<FileInfo>) -> Vec<DuplicateInfo> {
+        let mut duplicates = scan::group_duplicates(files);
+        provenance::annotate(
+            &mut duplicates,
+            &self.original_manifest,
+            &self.original_config,
+        );
+        self.options.progress.duplicates_found(&duplicates);
+        duplicates
+    }
+
+    /// Python and Node packages installed more than once, see
+    /// [`crate::packages`]. Takes a pass over every layer of its own.
+    pub fn find_duplicate_packages(&self) -> Result<Vec<DuplicatePackage>> {
+        let packages = self.try_map_layers(|layer| {
+            if layer.format != LayerFormat::Tar {
+                return Ok(Vec::new());
             }
+            packages::scan_layer_packages(layer.open_reader()?, layer.layer_index)
+                .map_err(|e| layer_error(layer, e))
+        })?;
+        Ok(packages::duplicate_packages(
+            packages.into_iter().flatten().collect(),
+        ))
+    }
+
+    /// ELF binaries identical but for debug info, symbol tables or build
+    /// IDs, see [`crate::elf`]. Takes a pass over every layer of its own.
+    pub fn find_elf_near_duplicates(&self) -> Result<Vec<ElfNearDuplicate>> {
+        let files = self.try_map_layers(|layer| {
+            if layer.format != LayerFormat::Tar {
+                return Ok(Vec::new());
+            }
+            elf::scan_layer_elf(
+                layer.open_reader()?,
+                layer.layer_index,
+                &self.options,
+                &self.excludes,
+            )
+            .map_err(|e| layer_error(layer, e))
+        })?;
+        Ok(elf::near_duplicates(files.into_iter().flatten().collect()))
+    }
+
+    /// Bytes added, overwritten later, duplicated and left in the final
+    /// image by each history entry, see [`crate::attribution`].
+    /// `duplicates` are those [`Self::find_duplicates`] found.
+    pub fn attribute_waste(&self, duplicates: &[DuplicateInfo]) -> Result<Vec<StepWaste>> {
+        let entries: Vec<EntryInfo> = self.entries().collect::<Result<_>>()?;
+        let view = UnionView::build(entries.iter().cloned().map(Ok))?;
+        Ok(attribution::attribute_waste(
+            &self.original_config.history,
+            self.layers.len(),
+            &entries,
+            &view,
+            duplicates,
+        ))
+    }
+
+    /// Logs, temp files, core dumps and backup leftovers across all layers,
+    /// hidden ones included, see [`crate::junk`]. From the tar headers alone.
+    pub fn find_junk_files(&self) -> Result<Vec<JunkFile>> {
+        let entries: Vec<EntryInfo> = self.entries().collect::<Result<_>>()?;
+        let view = UnionView::build(entries.iter().cloned().map(Ok))?;
+        Ok(junk::junk_files(&entries, &view))
+    }
+
+    #[cfg(feature = "reports")]
+    /// Projected size of each tar layer recompressed with `zstd -<level>`,
+    /// from a sample of at most `sample` bytes spread over the layer, see
+    /// [`crate::compressibility`]. `program` is the zstd command line tool.
+    pub fn estimate_zstd(
+        &self,
+        level: i32,
+        sample: usize,
+        program: &str,
+    ) -> Result<Vec<LayerCompressionEstimate>> {
+        let estimates = self.try_map_layers(|layer| {
+            if layer.format != LayerFormat::Tar {
+                return Ok(None);
+            }
+            let estimate = || -> Result<LayerCompressionEstimate> {
+                let (data, total) = compressibility::sample_stream(layer.open_reader()?, sample)?;
+                let compressed = compressibility::zstd_size(&data, level, program)?;
+                Ok(LayerCompressionEstimate {
+                    layer_index: layer.layer_index,
+                    blob_bytes: fs::metadata(&layer.path)?.len(),
+                    uncompressed_bytes: total,
+                    sampled_bytes: data.len() as u64,
+                    zstd_bytes: compressibility::project(total, data.len() as u64, compressed),
+                })
+            };
+            estimate().map(Some).map_err(|e| layer_error(layer, e))
+        })?;
+        Ok(estimates.into_iter().flatten().collect())
+    }
 
-            let mut header = entry.header().clone();
-            builder.append_data(&mut header, &path, &mut entry)?;
+    pub fn print_possible_savings(&self, duplicates: &[DuplicateInfo]) -> Result<()> {
+        scan::print_possible_savings(duplicates);
+        Ok(())
+    }
+
+    /// Asks the configured [`DedupPolicy`], or [`crate::policy::Strategy`] when one is
+    /// set, what to do with every duplicate and groups the resulting
+    /// changes by the layer they apply to.
+    pub fn generate_modification_plan(
+        &self,
+        duplicates: Vec<DuplicateInfo>,
+    ) -> Result<HashMap<usize, Vec<DeDupTransaction>>> {
+        if duplicates.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let entries: Vec<EntryInfo> = self.entries().collect::<Result<_>>()?;
+        let link_targets = link_targets(&entries);
+        let ctx = PolicyContext {
+            layers: &self.layers,
+            history: &self.original_config.history,
+            link_targets: &link_targets,
+        };
+        let policy = &self.options.policy;
+        let mut plan: HashMap<usize, Vec<DeDupTransaction>> = HashMap::new();
+        let originals = policy.originals(&duplicates, &ctx);
+        if originals.len() != duplicates.len() {
+            return Err(DedupeError::Policy(format!(
+                "{} originals for {} duplicate groups",
+                originals.len(),
+                duplicates.len()
+            )));
         }
+        for (group, original) in duplicates.iter().zip(originals) {
+            let is_original =
+                |f: &FileInfo| f.layer_index == original.layer_index && f.path == original.path;
+            let members = std::iter::once(&group.original).chain(&group.duplicates);
+            if !members.clone().any(is_original) {
+                return Err(DedupeError::Policy(format!(
+                    "{} in layer {} is not part of its duplicate group",
+                    original.path, original.layer_index
+                )));
+            }
 
-        for modif in modifications {
-            let mut header = tar::Header::new_gnu();
-            header.set_mode(0o777);
-            header.set_uid(0);
-            header.set_gid(0);
-            header.set_mtime(0);
-            header.set_entry_type(tar::EntryType::Symlink);
-            let link_name = PathBuf::from(&modif.original_path);
-            match modif.link_type {
-                LinkType::Sym => {
-                    builder
-                        .append_link(&mut header, &modif.target_path, &link_name)
-                        .with_context(|| {
-                            format!(
-                                "Failed to add symlink {} -> {}",
-                                &modif.target_path, &modif.original_path
-                            )
-                        })?;
+            for duplicate in members.filter(|f| !is_original(f)) {
+                let action = match self.options.strategy {
+                    Some(strategy) => strategy.action(original, duplicate),
+                    None => policy.action(original, duplicate, &ctx),
+                };
+                if action == Action::Skip {
+                    continue;
                 }
-                LinkType::Hard => {
-                    builder
-                        .append_link(&mut header, &modif.target_path, &link_name)
-                        .with_context(|| {
-                            format!(
-                                "Failed to add hardlink as symlink {} -> {}",
-                                &modif.target_path, &modif.original_path
-                            )
-                        })?;
+                if action == Action::Hardlink && duplicate.layer_index != original.layer_index {
+                    return Err(DedupeError::Policy(format!(
+                        "Can't hardlink {} in layer {} to {} in layer {}",
+                        duplicate.path, duplicate.layer_index, original.path, original.layer_index
+                    )));
                 }
+                plan.entry(duplicate.layer_index)
+                    .or_default()
+                    .push(DeDupTransaction {
+                        original_path: original.path.clone(),
+                        original_layer: original.layer_index,
+                        target_path: duplicate.path.clone(),
+                        action,
+                        size: duplicate.size,
+                        hash: duplicate.hash.clone(),
+                    });
             }
         }
+        self.drop_volume_changes(&mut plan)?;
+        self.drop_ignored_changes(&mut plan, &entries)?;
+        self.drop_link_breakage(&mut plan, &entries)?;
+        Ok(plan)
+    }
 
-        let buf_tee = builder.into_inner()?;
-        let tee = buf_tee
-            .into_inner()
-            .map_err(|e| anyhow::anyhow!("Failed to finalize tar file: {}", e))?;
-        Ok(tee.into_inner())
+    /// Drops planned changes of files under the config's `Volumes`, and of
+    /// links to them, unless [`AnalyzerOptions::dedupe_volumes`] is set.
+    /// Containers get fresh volumes seeded from what the image has there,
+    /// or with a bind mount nothing of it at all, so a link from or into
+    /// one can change what they see.
+    fn drop_volume_changes(&self, plan: &mut HashMap<usize, Vec<DeDupTransaction>>) -> Result<()> {
+        let volumes: Vec<&String> = match &self.original_config.config.volumes {
+            Some(volumes) if !self.options.dedupe_volumes => volumes.keys().collect(),
+            _ => return Ok(()),
+        };
+        if volumes.is_empty() {
+            return Ok(());
+        }
+        let dropped = drop_matching(
+            plan,
+            &PathMatcher::new(&volumes)?,
+            "a volume holds it or its original",
+        );
+        if dropped > 0 {
+            info!("Leaving {} duplicates under volumes alone", dropped);
+        }
+        Ok(())
     }
 
-    fn process_layer(
+    /// Drops planned changes of files, or of links to files, the image's
+    /// own [`IGNORE_FILE`] opts out, unless
+    /// [`AnalyzerOptions::ignore_file`] is off.
+    fn drop_ignored_changes(
         &self,
-        layer: &Layer,
-        modifications: &Vec<DeDupTransaction>,
-        output_dir: &Path,
-    ) -> Result<Layer> {
-        let new_layer_filename = if self.no_compression {
-            format!("layer-{}.tar", layer.layer_index)
-        } else {
-            format!("layer-{}.tar.gz", layer.layer_index)
-        };
-        let new_layer_path = output_dir.join(&new_layer_filename);
-        let tar_file = File::create(&new_layer_path)?;
-
-        let uncompressed_hash = if self.no_compression {
-            let (mut tar_file, hasher) = self.build_layer_tar(layer, modifications, tar_file)?;
-            tar_file.flush()?;
-            format!("sha256:{}", hasher.finalize_hex())
-        } else {
-            let gz_encoder = GzEncoder::new(tar_file, Compression::default());
-            let (gz_encoder, hasher) = self.build_layer_tar(layer, modifications, gz_encoder)?;
-            let hash = format!("sha256:{}", hasher.finalize_hex());
-            gz_encoder.finish().context("Failed to finish gzip")?;
-            hash
+        plan: &mut HashMap<usize, Vec<DeDupTransaction>>,
+        entries: &[EntryInfo],
+    ) -> Result<()> {
+        if !self.options.ignore_file {
+            return Ok(());
+        }
+        let Some(patterns) = self.ignore_patterns(entries)? else {
+            return Ok(());
         };
-
-        Ok(Layer {
-            path: new_layer_path,
-            layer_index: layer.layer_index,
-            hash: uncompressed_hash,
-        })
+        let dropped = drop_matching(
+            plan,
+            &PathMatcher::new(&patterns)?,
+            &format!("/{} opts it or its original out", IGNORE_FILE),
+        );
+        if dropped > 0 {
+            info!(
+                "Leaving {} duplicates the image's /{} opts out alone",
+                dropped, IGNORE_FILE
+            );
+        }
+        Ok(())
     }
 
-    fn update_manifest(&self, new_image_dir: &Path, new_layers: &Vec<Layer>) -> Result<()> {
-        let blobs_dir = new_image_dir.join("blobs/sha256");
-        fs::create_dir_all(&blobs_dir)?;
+    /// The patterns of the [`IGNORE_FILE`] containers see, if any.
+    fn ignore_patterns(&self, entries: &[EntryInfo]) -> Result<Option<Vec<String>>> {
+        let whiteout = format!(".wh.{}", IGNORE_FILE);
+        let Some(visible) = entries.iter().rev().find(|e| {
+            let path = paths::normalize(&e.path);
+            path == IGNORE_FILE || path == whiteout
+        }) else {
+            return Ok(None);
+        };
+        if visible.kind != EntryKind::File {
+            if visible.kind != EntryKind::Whiteout {
+                warn!("Ignoring /{}, it isn't a regular file", IGNORE_FILE);
+            }
+            return Ok(None);
+        }
+        if visible.size > MAX_IGNORE_FILE_SIZE {
+            return Err(DedupeError::InvalidOption(format!(
+                "/{} is {} bytes, more than {}",
+                IGNORE_FILE, visible.size, MAX_IGNORE_FILE_SIZE
+            )));
+        }
+        let layer = &self.layers[visible.layer_index];
+        let mut archive = Archive::new(layer.open_reader()?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if paths::normalize(&entry.path()?.to_string_lossy()) != IGNORE_FILE {
+                continue;
+            }
+            let mut contents = String::new();
+            entry
+                .read_to_string(&mut contents)
+                .with_context(|| format!("Failed to read /{}", IGNORE_FILE))
+                .map_err(|e| layer_error(layer, e))?;
+            let patterns = paths::ignore_patterns(&contents);
+            info!(
+                "Honoring {} patterns of /{} in layer {}",
+                patterns.len(),
+                IGNORE_FILE,
+                layer.layer_index
+            );
+            return Ok(Some(patterns));
+        }
+        Ok(None)
+    }
 
-        let mut new_refs = Vec::new();
-        for layer in new_layers {
-            if self.no_compression {
-                let relative_path = format!("blobs/sha256/{}", layer.hash);
-                new_refs.push(relative_path);
-            } else {
-                let digest = {
-                    let file = File::open(&layer.path)?;
-                    let mut reader = BufReader::with_capacity(BUFFER_SIZE, file);
-                    let mut hasher = Sha256Writer::new();
-                    std::io::copy(&mut reader, &mut hasher)?;
-                    hasher.finalize_hex()
-                };
-                let blob_path = blobs_dir.join(&digest);
-                fs::rename(&layer.path, &blob_path)?;
+    /// Drops planned changes that would break links of the image:
+    /// replacing a file other entries of its layer hardlink to (the
+    /// replacement goes at the end of the layer, after the hardlinks
+    /// needing it), and symlinks that, with the links the image already
+    /// has, would form a loop or a chain too deep to open, e.g. `/a`
+    /// becoming a link to `/b` where a higher layer made `/b` a link to
+    /// `/a`. Other symlinks to a replaced file resolve through its link.
+    fn drop_link_breakage(
+        &self,
+        plan: &mut HashMap<usize, Vec<DeDupTransaction>>,
+        entries: &[EntryInfo],
+    ) -> Result<()> {
+        if plan.is_empty() {
+            return Ok(());
+        }
+        let hardlinked: HashSet<(usize, &str)> = entries
+            .iter()
+            .filter(|e| e.kind == EntryKind::Hardlink)
+            .filter_map(|e| Some((e.layer_index, paths::normalize(e.link_target.as_deref()?))))
+            .collect();
+        for (&layer_index, transactions) in plan.iter_mut() {
+            transactions.retain(|transaction| {
+                let path = paths::normalize(&transaction.target_path);
+                let linked = hardlinked.contains(&(layer_index, path));
+                if linked {
+                    warn!(
+                        "Not replacing {} in layer {}, other entries hardlink to it",
+                        transaction.target_path, layer_index
+                    );
+                }
+                !linked
+            });
+        }
 
-                let relative_path = format!("blobs/sha256/{}", digest);
-                new_refs.push(relative_path);
+        let planned = |t: &DeDupTransaction| t.action == Action::Symlink;
+        if plan.values().flatten().any(planned) {
+            let mut view = UnionView::build(entries.iter().cloned().map(Ok))?;
+            for (&layer_index, transactions) in plan.iter() {
+                for transaction in transactions.iter().filter(|t| planned(t)) {
+                    // Only where the duplicate is what containers see
+                    let Some(mut link) = view
+                        .get(&transaction.target_path)
+                        .filter(|e| e.layer_index == layer_index && e.kind == EntryKind::File)
+                        .cloned()
+                    else {
+                        continue;
+                    };
+                    link.kind = EntryKind::Symlink;
+                    link.link_target =
+                        Some(format!("/{}", paths::normalize(&transaction.original_path)));
+                    view.replace(link);
+                }
+            }
+            for (layer_index, transactions) in plan.iter_mut() {
+                transactions.retain(|transaction| {
+                    let loops = planned(transaction) && view.loops(&transaction.target_path);
+                    if loops {
+                        warn!(
+                            "Not linking {} in layer {} to {}, the links would loop",
+                            transaction.target_path, layer_index, transaction.original_path
+                        );
+                    }
+                    !loops
+                });
             }
         }
-        let mut new_manifest = self.original_manifest.clone();
-        new_manifest.layers = new_refs;
-        new_manifest.repo_tags = vec!["test:smaller".to_string()];
-        let new_manifest_path = new_image_dir.join("manifest.json");
-        let _ = new_manifest.write_to_file(&new_manifest_path);
+        plan.retain(|_, transactions| !transactions.is_empty());
         Ok(())
     }
+}
 
-    fn update_config(&self, new_image_dir: &Path, new_layers: &Vec<Layer>) -> Result<()> {
-        let mut new_config = self.original_config.clone();
-        new_config.rootfs.diff_ids = new_layers.iter().map(|l| l.hash.clone()).collect();
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
 
-        let config_path = new_image_dir.join(&self.original_manifest.config);
-        let config_json = new_config.to_json()?;
-        if let Some(parent_dir) = config_path.parent() {
-            fs::create_dir_all(parent_dir)?;
-        } else {
-            return Err(anyhow!(
-                "Unable to get the parent directory for new config file"
-            ));
-        }
-        fs::write(config_path, config_json)?;
-        info!("Finish writing config");
+    use tar::EntryType;
 
-        Ok(())
+    use super::*;
+
+    /// Keeps the first header written and counts the rest.
+    #[derive(Default)]
+    struct HeaderSink {
+        head: Vec<u8>,
+        bytes: u64,
     }
 
-    pub fn create_deduplicated_image<W: Write>(
-        &self,
-        duplicates: Vec<DuplicateInfo>,
-        writer: W,
-    ) -> Result<()> {
-        let work_dir = tempdir()?;
-        let work_path = work_dir.path();
-        let new_layer_dir = work_path.join("new_layers");
-        let staging_dir = work_path.join("staging");
-        fs::create_dir(&new_layer_dir)?;
-        info!("Creating modification plan...");
-        let plan = self.generate_modification_plan(duplicates)?;
-
-        info!("Processing layers...");
-        let new_layers: Result<Vec<_>> = self
-            .layers
-            .par_iter()
-            .map(|layer| match plan.get(&layer.layer_index) {
-                Some(mods) => self.process_layer(layer, mods, &new_layer_dir),
-                None => Ok(layer.clone()),
-            })
-            .collect();
+    impl Write for HeaderSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let keep = 512usize.saturating_sub(self.head.len()).min(buf.len());
+            self.head.extend_from_slice(&buf[..keep]);
+            self.bytes += buf.len() as u64;
+            Ok(buf.len())
+        }
 
-        let new_layers = new_layers?;
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
 
-        info!("Updating configs...");
-        self.update_config(&staging_dir, &new_layers)?;
-        self.update_manifest(&staging_dir, &new_layers)?;
+    /// Copies a single `size` byte file, its size in a PAX record when
+    /// `pax` and in the ustar header otherwise, and returns the header of
+    /// the copy and the bytes written.
+    fn copy_large_file(size: u64, pax: bool) -> (Header, u64) {
+        let mut prefix = Vec::new();
+        if pax {
+            let record = format!(" size={}\n", size);
+            let record = format!("{}{}", record.len() + 2, record);
+            let mut header = Header::new_ustar();
+            header.set_entry_type(EntryType::XHeader);
+            header.set_size(record.len() as u64);
+            header.set_cksum();
+            prefix.extend_from_slice(header.as_bytes());
+            prefix.extend_from_slice(record.as_bytes());
+            prefix.resize(prefix.len().next_multiple_of(512), 0);
+        }
+        let mut header = Header::new_ustar();
+        header.set_path("model.bin").unwrap();
+        header.set_mode(0o644);
+        header.set_size(if pax { 0 } else { size });
+        header.set_cksum();
+        prefix.extend_from_slice(header.as_bytes());
+        let padding = size.next_multiple_of(512) - size + 1024;
+        let source = Cursor::new(prefix)
+            .chain(io::repeat(0).take(size))
+            .chain(io::repeat(0).take(padding));
 
-        info!("Packing new image...");
-        let mut builder = Builder::new(writer);
+        let mut archive = Archive::new(source);
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.size(), size);
+        let mut builder = Builder::new(HeaderSink::default());
+        append_entry(&mut builder, &mut entry, Path::new("model.bin")).unwrap();
+        let sink = builder.into_inner().unwrap();
+        let header = Header::from_byte_slice(&sink.head).clone();
+        (header, sink.bytes)
+    }
 
-        // Add all files from the new image directory
-        builder
-            .append_dir_all(".", staging_dir)
-            .context("Failed to pack final image")?;
+    #[test]
+    fn test_large_entries_keep_their_size() {
+        let written = |size: u64| 512 + size.next_multiple_of(512) + 1024;
 
-        builder.finish().context("Failed to finalize output tar")?;
+        let (header, bytes) = copy_large_file(TAR_MAX_OCTAL_SIZE, false);
+        assert!(header.as_ustar().is_some());
+        assert_eq!(header.size().unwrap(), TAR_MAX_OCTAL_SIZE);
+        assert_eq!(bytes, written(TAR_MAX_OCTAL_SIZE));
 
-        Ok(())
+        for pax in [false, true] {
+            let size = TAR_MAX_OCTAL_SIZE + 1;
+            let (header, bytes) = copy_large_file(size, pax);
+            assert!(header.as_gnu().is_some());
+            assert_eq!(header.size().unwrap(), size);
+            assert_eq!(bytes, written(size));
+        }
     }
 }
@@ -0,0 +1,160 @@
+//! Exporting from the merged rootfs: all of it as one tar, single files,
+//! or whole layers.
+
+use std::collections::HashSet;
+use std::io::{self, BufWriter, Write};
+
+use tar::{Archive, Builder, EntryType};
+use tracing::{info, warn};
+
+use super::{Analyzer, BUFFER_SIZE, Layer, LayerFormat, append_entry};
+use crate::cancel;
+use crate::entries::EntryKind;
+use crate::error::{DedupeError, IoResultExt, Result};
+use crate::union::UnionView;
+
+impl Analyzer {
+    /// The filesystem a container of this image sees, from the tar headers
+    /// alone.
+    pub fn union_view(&self) -> Result<UnionView> {
+        UnionView::build(self.entries())
+    }
+
+    /// Copies the contents of `path`, resolved as a container would, to
+    /// `writer`. Returns the bytes written.
+    pub fn extract_file<W: Write>(&self, path: &str, mut writer: W) -> Result<u64> {
+        let view = self.union_view()?;
+        let entry = view.resolve(path)?;
+        if entry.kind != EntryKind::File {
+            return Err(DedupeError::InvalidOption(format!(
+                "{} is a {:?}, not a file",
+                path, entry.kind
+            )));
+        }
+        let layer = &self.layers[entry.layer_index];
+        let mut archive = Archive::new(layer.open_reader()?);
+        for tar_entry in archive.entries()? {
+            cancel::check()?;
+            let mut tar_entry = tar_entry?;
+            if tar_entry.path()?.to_string_lossy() != entry.path {
+                continue;
+            }
+            let bytes = io::copy(&mut tar_entry, &mut writer)
+                .with_context(|| format!("Failed to write {}", path))?;
+            writer.flush()?;
+            info!(
+                "Extracted {} from layer {} ({} bytes)",
+                entry.path, entry.layer_index, bytes
+            );
+            return Ok(bytes);
+        }
+        Err(DedupeError::Layer {
+            index: layer.layer_index,
+            path: layer.path.clone(),
+            source: Box::new(DedupeError::Io {
+                context: format!("{} vanished from the layer", entry.path),
+                source: io::ErrorKind::NotFound.into(),
+            }),
+        })
+    }
+
+    /// Copies layer `index` to `writer` as an uncompressed tar. Returns the
+    /// bytes written.
+    pub fn extract_layer<W: Write>(&self, index: usize, mut writer: W) -> Result<u64> {
+        let layer = self.layers.get(index).ok_or_else(|| {
+            DedupeError::InvalidOption(format!(
+                "no layer {}, the image has {}",
+                index,
+                self.layers.len()
+            ))
+        })?;
+        let bytes = io::copy(&mut layer.open_reader()?, &mut writer)
+            .with_context(|| format!("Failed to write layer {}", index))?;
+        writer.flush()?;
+        Ok(bytes)
+    }
+
+    /// Writes the merged rootfs as one uncompressed tar: whiteouts applied,
+    /// every path taken with its header from the topmost layer providing
+    /// it, in layer order so hardlink targets precede their links. Returns
+    /// the number of entries written.
+    pub fn flatten<W: Write>(&self, writer: W) -> Result<usize> {
+        if let Some(layer) = self
+            .layers
+            .iter()
+            .find(|l| !matches!(l.format, LayerFormat::Tar | LayerFormat::Empty))
+        {
+            return Err(DedupeError::InvalidOption(format!(
+                "layer {} is {:?} and can't be flattened",
+                layer.layer_index, layer.format
+            )));
+        }
+        let view = self.union_view()?;
+        let mut builder = Builder::new(BufWriter::with_capacity(BUFFER_SIZE, writer));
+        builder.follow_symlinks(false);
+
+        let written = self.append_visible(&view, &self.layers, false, &mut builder)?;
+        builder
+            .into_inner()?
+            .flush()
+            .with_context(|| "Failed to write the flattened tar".to_string())?;
+        info!(
+            "Flattened {} layers into {} entries",
+            self.layers.len(),
+            written
+        );
+        Ok(written)
+    }
+
+    /// Appends the entries of `layers` that `view` shows, in layer order,
+    /// returning how many. Hardlinks whose target isn't there by the time
+    /// they're extracted are dropped; with `lower_layers` the target may
+    /// also come from layers below the view's.
+    pub(super) fn append_visible<W: Write>(
+        &self,
+        view: &UnionView,
+        layers: &[Layer],
+        lower_layers: bool,
+        builder: &mut Builder<W>,
+    ) -> Result<usize> {
+        let sources: HashSet<usize> = view.iter().map(|e| e.layer_index).collect();
+        let mut written = 0;
+        for layer in layers.iter().filter(|l| sources.contains(&l.layer_index)) {
+            let mut archive = Archive::new(layer.open_reader()?);
+            for entry in archive.entries()? {
+                cancel::check()?;
+                let mut entry = entry?;
+                let path = entry.path()?.into_owned();
+                let name = path.to_string_lossy();
+                if view
+                    .get(&name)
+                    .is_none_or(|visible| visible.layer_index != layer.layer_index)
+                {
+                    continue;
+                }
+                if entry.header().entry_type() == EntryType::Link {
+                    let target = entry.link_name()?.unwrap_or_default();
+                    let target = target.to_string_lossy();
+                    let resolves = match view.get(&target) {
+                        Some(t) => {
+                            t.layer_index <= layer.layer_index
+                                && matches!(t.kind, EntryKind::File | EntryKind::Hardlink)
+                        }
+                        None => lower_layers && !view.hides(&target),
+                    };
+                    if !resolves {
+                        warn!(
+                            "Dropping {}: its hardlink target {} is hidden in the merged view",
+                            name, target
+                        );
+                        continue;
+                    }
+                }
+                append_entry(builder, &mut entry, &path)
+                    .with_context(|| format!("Failed to add {}", name))?;
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+}
@@ -0,0 +1,204 @@
+//! The serialized form of a modification plan, for reviewing changes
+//! before applying them: `--save-plan` writes it, `--plan` applies it
+//! without scanning again.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use tar::Archive;
+use tracing::info;
+
+use super::{Analyzer, DeDupTransaction, DuplicateInfo, LayerFormat, layer_error};
+use crate::cancel;
+use crate::error::{DedupeError, Result};
+use crate::policy::Strategy;
+
+/// The changes planned for one layer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlannedLayer {
+    pub layer_index: usize,
+    pub changes: Vec<DeDupTransaction>,
+}
+
+/// A plan together with what it was computed from, so applying it to an
+/// image that changed since can be refused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModificationPlan {
+    /// See [`crate::SCHEMA_VERSION`]
+    pub schema_version: u32,
+    /// [`crate::HasherFactory::name`] of the hashes of the changes
+    pub hasher: String,
+    /// diff_ids of all layers of the analyzed image, bottom first
+    pub diff_ids: Vec<String>,
+    pub duplicate_groups: usize,
+    /// Layers with changes, by index
+    pub layers: Vec<PlannedLayer>,
+}
+
+impl ModificationPlan {
+    /// The changes by layer, as [`Analyzer::generate_modification_plan`]
+    /// returns them.
+    pub fn transactions(&self) -> HashMap<usize, Vec<DeDupTransaction>> {
+        self.layers
+            .iter()
+            .map(|layer| (layer.layer_index, layer.changes.clone()))
+            .collect()
+    }
+}
+
+fn drifted(message: String) -> DedupeError {
+    DedupeError::Plan(message)
+}
+
+impl Analyzer {
+    /// [`Analyzer::generate_modification_plan`] in its serialized form.
+    pub fn plan(&self, duplicates: Vec<DuplicateInfo>) -> Result<ModificationPlan> {
+        if self.options.strategy == Some(Strategy::SharedLayer) {
+            return Err(DedupeError::InvalidOption(
+                "plans of the shared-layer strategy can't be saved".to_string(),
+            ));
+        }
+        let duplicate_groups = duplicates.len();
+        let mut layers: Vec<PlannedLayer> = self
+            .generate_modification_plan(duplicates)?
+            .into_iter()
+            .map(|(layer_index, mut changes)| {
+                changes.sort_by(|a, b| a.target_path.cmp(&b.target_path));
+                PlannedLayer {
+                    layer_index,
+                    changes,
+                }
+            })
+            .collect();
+        layers.sort_by_key(|layer| layer.layer_index);
+        Ok(ModificationPlan {
+            schema_version: crate::SCHEMA_VERSION,
+            hasher: self.options.hasher.name().to_string(),
+            diff_ids: self.layers.iter().map(|l| l.hash.clone()).collect(),
+            duplicate_groups,
+            layers,
+        })
+    }
+
+    /// Checks that `plan` was made for this image: the same layers, by
+    /// diff_id, and every file it changes or links to still there with the
+    /// contents it had. The files are rehashed, which reads the layers the
+    /// plan touches but hashes only the files it names, so plans edited by
+    /// hand can't link a file to one with other contents either.
+    pub fn validate_plan(&self, plan: &ModificationPlan) -> Result<()> {
+        if plan.schema_version > crate::SCHEMA_VERSION {
+            return Err(drifted(format!(
+                "schema version {} is newer than this version supports ({})",
+                plan.schema_version,
+                crate::SCHEMA_VERSION
+            )));
+        }
+        let diff_ids: Vec<&str> = self.layers.iter().map(|l| l.hash.as_str()).collect();
+        if plan.diff_ids.len() != diff_ids.len() {
+            return Err(drifted(format!(
+                "made for an image of {} layers, this one has {}",
+                plan.diff_ids.len(),
+                diff_ids.len()
+            )));
+        }
+        if let Some((index, (planned, actual))) = plan
+            .diff_ids
+            .iter()
+            .zip(&diff_ids)
+            .enumerate()
+            .find(|(_, (planned, actual))| planned != *actual)
+        {
+            return Err(drifted(format!(
+                "layer {} is {}, the plan was made for {}",
+                index, actual, planned
+            )));
+        }
+        let hasher = self.options.hasher.name();
+        if plan.hasher != hasher {
+            return Err(drifted(format!(
+                "its hashes are {}, the analyzer hashes with {}",
+                plan.hasher, hasher
+            )));
+        }
+
+        // (layer, path) -> hash, of every file the plan names
+        let mut expected: HashMap<(usize, &str), &str> = HashMap::new();
+        for layer in &plan.layers {
+            for change in &layer.changes {
+                if layer.layer_index >= diff_ids.len() || change.original_layer >= diff_ids.len() {
+                    return Err(drifted(format!(
+                        "{} refers to a layer the image doesn't have",
+                        change.target_path
+                    )));
+                }
+                for file in [
+                    (layer.layer_index, change.target_path.as_str()),
+                    (change.original_layer, change.original_path.as_str()),
+                ] {
+                    if let Some(other) = expected.insert(file, &change.hash)
+                        && other != change.hash
+                    {
+                        return Err(drifted(format!(
+                            "{} in layer {} has two hashes",
+                            file.1, file.0
+                        )));
+                    }
+                }
+            }
+        }
+
+        info!("Checking the {} files the plan names...", expected.len());
+        let found = self.try_map_layers(|layer| {
+            let wanted: HashSet<&str> = expected
+                .keys()
+                .filter(|(layer_index, _)| *layer_index == layer.layer_index)
+                .map(|(_, path)| *path)
+                .collect();
+            if wanted.is_empty() || layer.format != LayerFormat::Tar {
+                return Ok(Vec::new());
+            }
+            let hash = || -> Result<Vec<(usize, String, String)>> {
+                let mut found = Vec::new();
+                let mut archive = Archive::new(layer.open_reader()?);
+                for entry in archive.entries()? {
+                    cancel::check()?;
+                    let mut entry = entry?;
+                    let path = entry.path()?.to_string_lossy().to_string();
+                    if !wanted.contains(path.as_str()) || !entry.header().entry_type().is_file() {
+                        continue;
+                    }
+                    let digest = self.options.hasher.hash_reader(&mut entry)?;
+                    found.push((layer.layer_index, path, digest.to_hex()));
+                }
+                Ok(found)
+            };
+            hash().map_err(|e| layer_error(layer, e))
+        })?;
+        // The last of several entries of a path is the one that counts
+        let found: HashMap<(usize, String), String> = found
+            .into_iter()
+            .flatten()
+            .map(|(layer_index, path, hash)| ((layer_index, path), hash))
+            .collect();
+        let mut files: Vec<_> = expected.into_iter().collect();
+        files.sort();
+        for ((layer_index, path), hash) in files {
+            match found.get(&(layer_index, path.to_string())) {
+                Some(actual) if actual == hash => {}
+                Some(_) => {
+                    return Err(drifted(format!(
+                        "{} in layer {} has other contents than planned",
+                        path, layer_index
+                    )));
+                }
+                None => {
+                    return Err(drifted(format!(
+                        "{} isn't a file in layer {}",
+                        path, layer_index
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
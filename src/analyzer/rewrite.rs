@@ -0,0 +1,572 @@
+//! Writing the deduplicated image, behind the `rewrite` feature.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use tar::{Archive, Builder, EntryType};
+use tempfile::{TempDir, tempdir_in};
+use tracing::{Span, debug, field, info, info_span, warn};
+
+use super::{
+    BUFFER_SIZE, DeDupTransaction, DuplicateInfo, Layer, LayerCompression, LayerFormat,
+    ModificationPlan, append_entry, layer_error, link_or_copy,
+};
+use crate::analyzer::Analyzer;
+use crate::cancel;
+use crate::checkpoint::fingerprint;
+use crate::digest_writer::{DigestWriter, sha256_digest, sha256_file};
+use crate::disk_space;
+use crate::error::{DedupeError, IoResultExt, Result};
+use crate::paths;
+use crate::pipeline::{DedupeSummary, LayerDigests};
+use crate::policy::{Action, Strategy};
+use crate::schemas::{DockerConfig, HistoryEntry};
+use crate::tee_writer::{MultiWriter, ReportingSink};
+use crate::timings::{CountingReader, Phase, TimedWriter};
+use crate::transport::layout;
+
+/// Namespace of the labels `label_savings` adds, and of annotations
+pub(crate) const LABEL_PREFIX: &str = "org.dedupe";
+
+/// The rewritten image, unpacked. Staging lives in the temp dir unless a
+/// work dir is set, so this must be kept alive while `dir` is read.
+pub(crate) struct StagedImage {
+    pub(super) _tmp_dir: TempDir,
+    pub dir: PathBuf,
+    pub summary: DedupeSummary,
+}
+
+/// Writes the tar `fill` builds to every writer of `writers`, returning
+/// its size.
+fn build_tar(
+    writers: MultiWriter<'_>,
+    fill: impl FnOnce(&mut Builder<&mut dyn Write>) -> Result<()>,
+) -> Result<u64> {
+    let mut buffered = BufWriter::with_capacity(BUFFER_SIZE, writers);
+    let mut builder = Builder::new(&mut buffered as &mut dyn Write);
+    builder.follow_symlinks(false);
+    fill(&mut builder)?;
+    builder.into_inner()?;
+
+    let writers = buffered.into_inner().map_err(|e| DedupeError::Output {
+        context: "Failed to finalize tar file".to_string(),
+        source: e.into_error(),
+    })?;
+    Ok(writers.written())
+}
+
+impl Analyzer {
+    /// Copies `layer` to `builder`, links replacing the duplicates.
+    fn add_deduplicated_entries(
+        &self,
+        layer: &Layer,
+        modifications: &[DeDupTransaction],
+        builder: &mut Builder<&mut dyn Write>,
+    ) -> Result<()> {
+        let mods_by_target: HashMap<PathBuf, &DeDupTransaction> = modifications
+            .iter()
+            .map(|m| (PathBuf::from(m.target_path.clone()), m))
+            .collect();
+
+        let mut archive = Archive::new(CountingReader::new(layer.open_reader()?));
+
+        for entry_result in archive.entries()? {
+            cancel::check()?;
+            let mut entry = entry_result?;
+            let path = entry.path()?.into_owned();
+
+            if mods_by_target.contains_key(&path) {
+                debug!("Replacing {} with a link", path.display());
+                continue;
+            }
+
+            append_entry(builder, &mut entry, &path)
+                .with_context(|| format!("Failed to add {}", path.display()))?;
+        }
+
+        for modif in modifications {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o777);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_size(0);
+            let link_name = match modif.action {
+                // Absolute, so the link resolves no matter where it sits
+                Action::Symlink => {
+                    header.set_entry_type(EntryType::Symlink);
+                    PathBuf::from("/").join(paths::normalize(&modif.original_path))
+                }
+                // Hardlink targets are archive paths
+                Action::Hardlink => {
+                    header.set_entry_type(EntryType::Link);
+                    PathBuf::from(&modif.original_path)
+                }
+                Action::Omit | Action::Skip => continue,
+            };
+            builder
+                .append_link(&mut header, &modif.target_path, &link_name)
+                .with_context(|| {
+                    format!(
+                        "Failed to add {:?} {} -> {}",
+                        modif.action,
+                        &modif.target_path,
+                        link_name.display()
+                    )
+                })?;
+        }
+
+        let bytes = archive.into_inner().count();
+        self.timings.record(Phase::Rewrite, Duration::ZERO, bytes);
+        Span::current().record("bytes", bytes);
+        Ok(())
+    }
+
+    fn process_layer(
+        &self,
+        layer: &Layer,
+        modifications: &[DeDupTransaction],
+        output_dir: &Path,
+    ) -> Result<Layer> {
+        let file_stem = format!("layer-{}", layer.layer_index);
+        self.write_layer(&file_stem, layer.layer_index, output_dir, |builder| {
+            self.add_deduplicated_entries(layer, modifications, builder)
+        })
+    }
+
+    /// Writes a new layer to `<output_dir>/<file_stem>.tar[.gz]`, its
+    /// entries added by `fill`, compressed unless `--no-compression`.
+    pub(super) fn write_layer(
+        &self,
+        file_stem: &str,
+        layer_index: usize,
+        output_dir: &Path,
+        fill: impl FnOnce(&mut Builder<&mut dyn Write>) -> Result<()>,
+    ) -> Result<Layer> {
+        let new_layer_filename = if !self.options.compression {
+            format!("{}.tar", file_stem)
+        } else {
+            format!("{}.tar.gz", file_stem)
+        };
+        let new_layer_path = output_dir.join(&new_layer_filename);
+        let tar_file = File::create(&new_layer_path)?;
+        let mut diff_id = DigestWriter::sink();
+        let mut report =
+            ReportingSink::new(|bytes| self.options.progress.bytes_written(layer_index, bytes));
+
+        // Uncompressed, the blob is the tar; compressed, the encoder's output
+        // is hashed on its way to the file
+        let mut blob = DigestWriter::new(tar_file);
+        let size = if !self.options.compression {
+            let writers = MultiWriter::new()
+                .with(&mut blob)
+                .with(&mut diff_id)
+                .with(&mut report);
+            let size = build_tar(writers, fill)?;
+            blob.flush()?;
+            size
+        } else {
+            let mut gz_encoder = TimedWriter::new(GzEncoder::new(blob, Compression::default()));
+            let writers = MultiWriter::new()
+                .with(&mut gz_encoder)
+                .with(&mut diff_id)
+                .with(&mut report);
+            let size = build_tar(writers, fill)?;
+            let (gz_encoder, elapsed, bytes) = gz_encoder.into_inner();
+            let start = Instant::now();
+            blob = gz_encoder
+                .finish()
+                .with_context(|| "Failed to finish gzip".to_string())?;
+            self.timings
+                .record(Phase::Compress, elapsed + start.elapsed(), bytes);
+            size
+        };
+        let (_, blob_digest) = blob.finish();
+        debug!("Wrote {} ({} bytes uncompressed)", new_layer_filename, size);
+
+        Ok(Layer {
+            path: new_layer_path,
+            layer_index,
+            hash: diff_id.finish().1,
+            format: LayerFormat::Tar,
+            blob_digest: Some(blob_digest),
+        })
+    }
+
+    pub(super) fn rewrite_layer_checkpointed(
+        &self,
+        layer: &Layer,
+        modifications: &[DeDupTransaction],
+        output_dir: &Path,
+        parent: &Span,
+    ) -> Result<Layer> {
+        let span = info_span!(
+            parent: parent,
+            "rewrite_layer",
+            layer = layer.layer_index,
+            digest = %layer.hash,
+            links = modifications.len(),
+            new_digest = field::Empty,
+            bytes = field::Empty,
+            duration_ms = field::Empty,
+        );
+        let _guard = span.enter();
+        let start = Instant::now();
+        let new_layer = self.rewrite_layer_resumable(layer, modifications, output_dir)?;
+        span.record("new_digest", new_layer.hash.as_str());
+        span.record("duration_ms", start.elapsed().as_millis() as u64);
+        debug!("Rewrote layer");
+        Ok(new_layer)
+    }
+
+    fn rewrite_layer_resumable(
+        &self,
+        layer: &Layer,
+        modifications: &[DeDupTransaction],
+        output_dir: &Path,
+    ) -> Result<Layer> {
+        let Some(checkpoint) = self.work_dir.checkpoint() else {
+            return self.process_layer(layer, modifications, output_dir);
+        };
+        let mut sorted_mods: Vec<_> = modifications.iter().collect();
+        sorted_mods.sort_by(|a, b| a.target_path.cmp(&b.target_path));
+        let fingerprint = fingerprint(&(&layer.hash, !self.options.compression, sorted_mods))?;
+
+        if let Some(done) = checkpoint.load_rewrite(layer.layer_index, &fingerprint) {
+            return Ok(done);
+        }
+        let new_layer = self.process_layer(layer, modifications, output_dir)?;
+        checkpoint.save_rewrite(&fingerprint, &new_layer)?;
+        Ok(new_layer)
+    }
+
+    /// Writes the new manifest and returns the blob paths it references.
+    pub(super) fn update_manifest(
+        &self,
+        new_image_dir: &Path,
+        new_layers: &[Layer],
+        config_digest: &str,
+    ) -> Result<Vec<String>> {
+        let blobs_dir = new_image_dir.join("blobs/sha256");
+        fs::create_dir_all(&blobs_dir)?;
+
+        let mut new_refs = Vec::new();
+        for layer in new_layers {
+            let digest = match &layer.blob_digest {
+                Some(digest) => digest.clone(),
+                None => sha256_file(&layer.path)?.0,
+            };
+            let digest = digest.trim_start_matches("sha256:");
+            let blob_path = blobs_dir.join(digest);
+            // Link rather than move so the extracted image and any checkpoint
+            // stay intact
+            link_or_copy(&layer.path, &blob_path)?;
+
+            let relative_path = format!("blobs/sha256/{}", digest);
+            new_refs.push(relative_path);
+        }
+        let mut new_manifest = self.original_manifest.clone();
+        new_manifest.config = self.config_name(config_digest)?;
+        new_manifest.layers = new_refs.clone();
+        new_manifest
+            .layer_sources
+            .retain(|diff_id, _| new_layers.iter().any(|l| &l.hash == diff_id));
+        new_manifest.repo_tags = if self.options.repo_tags.is_empty() {
+            vec!["test:smaller".to_string()]
+        } else {
+            self.options.repo_tags.clone()
+        };
+        let new_manifest_path = new_image_dir.join("manifest.json");
+        let _ = new_manifest.write_to_file(&new_manifest_path);
+        Ok(new_refs)
+    }
+
+    /// Digest of the config the image was loaded with.
+    pub(super) fn original_config_digest(&self) -> Result<String> {
+        let path = paths::resolve_in_archive(
+            &self.work_dir.extracted_dir(),
+            &self.original_manifest.config,
+        )?;
+        Ok(sha256_file(&path)?.0)
+    }
+
+    /// Writes the config for `new_layers`, after `edit` had its say, and
+    /// returns its digest. History entries `edit` adds should take their
+    /// `created` from [`crate::options::Created::timestamp`] too.
+    pub(super) fn update_config(
+        &self,
+        new_image_dir: &Path,
+        new_layers: &[Layer],
+        edit: impl FnOnce(&mut DockerConfig),
+    ) -> Result<String> {
+        let mut new_config = self.original_config.clone();
+        new_config.rootfs.diff_ids = new_layers.iter().map(|l| l.hash.clone()).collect();
+        edit(&mut new_config);
+        new_config.created = self.options.created.timestamp(&new_config.created);
+        let fixed = new_config.reconcile_history();
+        if fixed > 0 {
+            warn!(
+                "Fixed {} history entries to match the {} layers",
+                fixed,
+                new_layers.len()
+            );
+        }
+
+        let config_json = new_config.to_json()?;
+        let digest = sha256_digest(config_json.as_bytes());
+
+        let config_path = new_image_dir.join(self.config_name(&digest)?);
+        if let Some(parent_dir) = config_path.parent() {
+            fs::create_dir_all(parent_dir)?;
+        } else {
+            return Err(DedupeError::Config(
+                "Unable to get the parent directory for new config file".to_string(),
+            ));
+        }
+        fs::write(config_path, &config_json)?;
+        info!("Finish writing config");
+        Ok(digest)
+    }
+
+    /// Where the config with `digest` goes in the new image: the original
+    /// name with the old digest swapped for the new one, so `<hex>.json`
+    /// and `blobs/sha256/<hex>` configs stay named by their content. Other
+    /// names are kept.
+    fn config_name(&self, digest: &str) -> Result<String> {
+        let old = self.original_config_digest()?;
+        let hex = |digest: &str| digest.trim_start_matches("sha256:").to_string();
+        Ok(self
+            .original_manifest
+            .config
+            .replace(&hex(&old), &hex(digest)))
+    }
+
+    /// Temp space needed to stage the rewritten layers. Untouched layers are
+    /// moved rather than copied, so only planned layers count.
+    pub(super) fn estimate_rewrite_space(
+        &self,
+        plan: &HashMap<usize, Vec<DeDupTransaction>>,
+    ) -> Result<u64> {
+        let mut total = 0u64;
+        for layer in self
+            .layers
+            .iter()
+            .filter(|l| plan.contains_key(&l.layer_index))
+        {
+            total += if !self.options.compression {
+                disk_space::uncompressed_size_estimate(
+                    &layer.path,
+                    LayerCompression::of_file(&layer.path)? == LayerCompression::Gzip,
+                )?
+            } else {
+                fs::metadata(&layer.path)?.len()
+            };
+        }
+        Ok(total)
+    }
+
+    pub fn create_deduplicated_image<W: Write>(
+        &self,
+        duplicates: Vec<DuplicateInfo>,
+        writer: W,
+    ) -> Result<DedupeSummary> {
+        let staged = self.stage_deduplicated_image(duplicates)?;
+        self.pack_staged(staged, writer)
+    }
+
+    /// Writes the image `plan`, saved by an earlier run, describes, once
+    /// [`Analyzer::validate_plan`] accepted it.
+    pub fn create_image_from_plan<W: Write>(
+        &self,
+        plan: &ModificationPlan,
+        writer: W,
+    ) -> Result<DedupeSummary> {
+        let staged = self.stage_saved_plan(plan)?;
+        self.pack_staged(staged, writer)
+    }
+
+    /// Packs a staged image into a `docker save` tar.
+    pub(crate) fn pack_staged<W: Write>(
+        &self,
+        staged: StagedImage,
+        writer: W,
+    ) -> Result<DedupeSummary> {
+        cancel::check()?;
+        info!("Packing new image...");
+        let start = Instant::now();
+        layout::add_oci_index(&staged.dir)?;
+        let mut builder = Builder::new(TimedWriter::new(writer));
+
+        // Add all files from the new image directory
+        builder
+            .append_dir_all(".", &staged.dir)
+            .map_err(|source| DedupeError::Output {
+                context: "Failed to pack final image".to_string(),
+                source,
+            })?;
+
+        let (_, _, bytes) = builder
+            .into_inner()
+            .map_err(|source| DedupeError::Output {
+                context: "Failed to finalize output tar".to_string(),
+                source,
+            })?
+            .into_inner();
+        self.timings.record(Phase::Pack, start.elapsed(), bytes);
+        Ok(staged.summary)
+    }
+
+    /// Temp dir plus where to write new layers and where to lay the image
+    /// out, in the checkpoint when there is one.
+    pub(super) fn staging_dirs(&self) -> Result<(TempDir, PathBuf, PathBuf)> {
+        let tmp_dir = tempdir_in(self.options.temp_dir())?;
+        let (new_layer_dir, staging_dir) = match self.work_dir.checkpoint() {
+            Some(checkpoint) => (checkpoint.rewrite_dir(), checkpoint.root().join("staging")),
+            None => (
+                tmp_dir.path().join("new_layers"),
+                tmp_dir.path().join("staging"),
+            ),
+        };
+        if staging_dir.exists() {
+            fs::remove_dir_all(&staging_dir)?;
+        }
+        fs::create_dir_all(&new_layer_dir)?;
+        Ok((tmp_dir, new_layer_dir, staging_dir))
+    }
+
+    /// Rewrites the layers and lays the new image out as an unpacked
+    /// `docker save` dir, which output transports then pack or convert.
+    /// The shared-layer [`Strategy`] stages
+    /// [`Analyzer::create_shared_layer_image`]'s image instead.
+    pub(crate) fn stage_deduplicated_image(
+        &self,
+        duplicates: Vec<DuplicateInfo>,
+    ) -> Result<StagedImage> {
+        if self.options.strategy == Some(Strategy::SharedLayer) {
+            return self.stage_shared_layer_image(duplicates);
+        }
+        let duplicate_groups = duplicates.len();
+        info!("Creating modification plan...");
+        let start = Instant::now();
+        let plan = self.generate_modification_plan(duplicates)?;
+        self.timings.record(Phase::Plan, start.elapsed(), 0);
+        self.stage_modifications(plan, duplicate_groups)
+    }
+
+    /// [`Analyzer::stage_deduplicated_image`] for a saved plan, refused
+    /// when the image changed since.
+    pub(crate) fn stage_saved_plan(&self, plan: &ModificationPlan) -> Result<StagedImage> {
+        let start = Instant::now();
+        self.validate_plan(plan)?;
+        self.timings.record(Phase::Plan, start.elapsed(), 0);
+        self.stage_modifications(plan.transactions(), plan.duplicate_groups)
+    }
+
+    fn stage_modifications(
+        &self,
+        plan: HashMap<usize, Vec<DeDupTransaction>>,
+        duplicate_groups: usize,
+    ) -> Result<StagedImage> {
+        let (tmp_dir, new_layer_dir, staging_dir) = self.staging_dirs()?;
+        let duplicate_files = plan.values().map(Vec::len).sum();
+        let bytes_saved: u64 = plan.values().flatten().map(|m| m.size).sum();
+        disk_space::ensure_available(
+            &new_layer_dir,
+            self.estimate_rewrite_space(&plan)?,
+            "rewriting layers",
+        )?;
+
+        info!("Processing layers...");
+        let start = Instant::now();
+        let phase = info_span!("rewrite", layers = plan.len());
+        let _guard = phase.enter();
+        let new_layers = self.try_map_layers(|layer| match plan.get(&layer.layer_index) {
+            Some(mods) => {
+                self.options.progress.layer_rewrite_started(layer);
+                let new_layer = self
+                    .rewrite_layer_checkpointed(layer, mods, &new_layer_dir, &phase)
+                    .map_err(|e| layer_error(layer, e))?;
+                self.options.progress.layer_rewritten(layer, &new_layer);
+                Ok(new_layer)
+            }
+            None => Ok(layer.clone()),
+        })?;
+        self.timings.record(Phase::Rewrite, start.elapsed(), 0);
+
+        info!("Updating configs...");
+        let old_config_digest = self.original_config_digest()?;
+        let new_config_digest = self.update_config(&staging_dir, &new_layers, |config| {
+            if plan.is_empty() {
+                return;
+            }
+            if self.options.history_entry {
+                config.history.push(HistoryEntry {
+                    created: self
+                        .options
+                        .created
+                        .timestamp(&self.original_config.created),
+                    created_by: format!(
+                        "{} v{}, saved {} bytes",
+                        env!("CARGO_PKG_NAME"),
+                        env!("CARGO_PKG_VERSION"),
+                        bytes_saved
+                    ),
+                    comment: format!("deduplicated {} files", duplicate_files),
+                    empty_layer: true,
+                    author: None,
+                });
+            }
+            if self.options.label_savings {
+                let labels = config.config.labels.get_or_insert_with(BTreeMap::new);
+                labels.insert(
+                    format!("{}.version", LABEL_PREFIX),
+                    env!("CARGO_PKG_VERSION").to_string(),
+                );
+                labels.insert(
+                    format!("{}.original-digest", LABEL_PREFIX),
+                    old_config_digest.clone(),
+                );
+                labels.insert(
+                    format!("{}.bytes-saved", LABEL_PREFIX),
+                    bytes_saved.to_string(),
+                );
+            }
+        })?;
+        let new_refs = self.update_manifest(&staging_dir, &new_layers, &new_config_digest)?;
+
+        let layers = self
+            .layers
+            .iter()
+            .zip(&new_layers)
+            .zip(new_refs)
+            .map(|((old, new), new_blob)| LayerDigests {
+                layer_index: old.layer_index,
+                rewritten: plan.contains_key(&old.layer_index),
+                old_diff_id: old.hash.clone(),
+                new_diff_id: new.hash.clone(),
+                old_blob: self.original_manifest.layers[old.layer_index].clone(),
+                new_blob,
+            })
+            .collect();
+        Ok(StagedImage {
+            _tmp_dir: tmp_dir,
+            dir: staging_dir,
+            summary: DedupeSummary {
+                schema_version: crate::SCHEMA_VERSION,
+                duplicate_groups,
+                duplicate_files,
+                bytes_saved,
+                layers,
+                old_config_digest,
+                new_config_digest,
+                manifest_digest: None,
+                stale_referrers: Vec::new(),
+            },
+        })
+    }
+}
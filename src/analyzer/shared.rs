@@ -0,0 +1,266 @@
+//! Moving one copy of every duplicated file into a new bottom layer,
+//! behind the `rewrite` feature.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+use tar::{Archive, EntryType, Header};
+use tracing::{info, info_span};
+
+use super::rewrite::StagedImage;
+use super::{
+    Analyzer, DeDupTransaction, DuplicateInfo, LayerFormat, append_entry, layer_error, link_targets,
+};
+use crate::cancel;
+use crate::disk_space;
+use crate::entries::EntryInfo;
+use crate::error::{IoResultExt, Result};
+use crate::pipeline::{DedupeSummary, LayerDigests};
+use crate::policy::{Action, PolicyContext};
+use crate::schemas::HistoryEntry;
+use crate::timings::Phase;
+
+/// Directory of the shared layer holding the copies, one
+/// `<hash>/<file name>` per group.
+pub const SHARED_DIR: &str = ".dedupe";
+
+/// Where the copy of `group` goes in the shared layer: named by its hash,
+/// so images sharing files share paths, and keeping the file name of the
+/// original for whoever follows the links.
+fn shared_path(group: &DuplicateInfo) -> String {
+    let name = Path::new(&group.original.path)
+        .file_name()
+        .map_or("file".into(), |name| name.to_string_lossy());
+    format!("{}/{}/{}", SHARED_DIR, group.original.hash, name)
+}
+
+impl Analyzer {
+    /// Writes the image with a copy of every duplicated file moved into a
+    /// new first layer to `writer`, as a `docker save` tar, all copies,
+    /// the original included, becoming symlinks to it. One layer more than
+    /// [`Analyzer::create_deduplicated_image`], but images with the same
+    /// duplicates get the same bottom layer, which registries store and
+    /// nodes pull once for all of them.
+    pub fn create_shared_layer_image<W: Write>(
+        &self,
+        duplicates: Vec<DuplicateInfo>,
+        writer: W,
+    ) -> Result<DedupeSummary> {
+        let staged = self.stage_shared_layer_image(duplicates)?;
+        self.pack_staged(staged, writer)
+    }
+
+    /// The links replacing every copy of each group, by layer. The
+    /// configured policy may still skip duplicates; groups left with
+    /// nothing to link are left alone. Changes of originals save nothing,
+    /// their `size` is 0.
+    fn shared_layer_plan(
+        &self,
+        duplicates: &[DuplicateInfo],
+    ) -> Result<HashMap<usize, Vec<DeDupTransaction>>> {
+        if duplicates.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let entries: Vec<EntryInfo> = self.entries().collect::<Result<_>>()?;
+        let link_targets = link_targets(&entries);
+        let ctx = PolicyContext {
+            layers: &self.layers,
+            history: &self.original_config.history,
+            link_targets: &link_targets,
+        };
+        let policy = &self.options.policy;
+        let mut plan: HashMap<usize, Vec<DeDupTransaction>> = HashMap::new();
+        for group in duplicates {
+            let linked: Vec<_> = group
+                .duplicates
+                .iter()
+                .filter(|d| match self.options.strategy {
+                    Some(strategy) => strategy.action(&group.original, d) != Action::Skip,
+                    None => policy.action(&group.original, d, &ctx) != Action::Skip,
+                })
+                .collect();
+            if linked.is_empty() {
+                continue;
+            }
+            let shared = shared_path(group);
+            let original = &group.original;
+            for (file, size) in
+                std::iter::once((original, 0)).chain(linked.into_iter().map(|d| (d, d.size)))
+            {
+                plan.entry(file.layer_index)
+                    .or_default()
+                    .push(DeDupTransaction {
+                        original_path: shared.clone(),
+                        original_layer: original.layer_index,
+                        target_path: file.path.clone(),
+                        action: Action::Symlink,
+                        size,
+                        hash: file.hash.clone(),
+                    });
+            }
+        }
+        self.drop_volume_changes(&mut plan)?;
+        self.drop_ignored_changes(&mut plan, &entries)?;
+        self.drop_link_breakage(&mut plan, &entries)?;
+        Ok(plan)
+    }
+
+    pub(crate) fn stage_shared_layer_image(
+        &self,
+        duplicates: Vec<DuplicateInfo>,
+    ) -> Result<StagedImage> {
+        info!("Creating modification plan...");
+        let start = Instant::now();
+        let plan = self.shared_layer_plan(&duplicates)?;
+        self.timings.record(Phase::Plan, start.elapsed(), 0);
+
+        let linked: HashSet<&str> = plan
+            .values()
+            .flatten()
+            .map(|t| t.original_path.as_str())
+            .collect();
+        let groups: Vec<(&DuplicateInfo, String)> = duplicates
+            .iter()
+            .map(|group| (group, shared_path(group)))
+            .filter(|(_, shared)| linked.contains(shared.as_str()))
+            .collect();
+        // Shared paths to copy the originals to, by their layer and path
+        let mut moved: HashMap<(usize, &str), &str> = HashMap::new();
+        for (group, shared) in &groups {
+            moved.insert(
+                (group.original.layer_index, group.original.path.as_str()),
+                shared,
+            );
+        }
+        let duplicate_files = plan.values().flatten().filter(|t| t.size > 0).count();
+        let bytes_saved: u64 = plan.values().flatten().map(|t| t.size).sum();
+        let shared_bytes: u64 = groups.iter().map(|(g, _)| g.original.size).sum();
+
+        let (tmp_dir, new_layer_dir, staging_dir) = self.staging_dirs()?;
+        disk_space::ensure_available(
+            &new_layer_dir,
+            self.estimate_rewrite_space(&plan)? + shared_bytes,
+            "rewriting layers",
+        )?;
+
+        info!(
+            "Moving {} files, {} bytes, into a shared layer",
+            groups.len(),
+            shared_bytes
+        );
+        let start = Instant::now();
+        let shared_layer = self.write_layer("shared", 0, &new_layer_dir, |builder| {
+            let mut dirs: Vec<String> = groups
+                .iter()
+                .filter_map(|(_, shared)| Some(shared.rsplit_once('/')?.0.to_string()))
+                .collect();
+            dirs.sort();
+            dirs.dedup();
+            for dir in std::iter::once(SHARED_DIR.to_string()).chain(dirs) {
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_uid(0);
+                header.set_gid(0);
+                header.set_size(0);
+                header.set_mtime(0);
+                builder
+                    .append_data(&mut header, format!("{}/", dir), std::io::empty())
+                    .with_context(|| format!("Failed to add {}", dir))?;
+            }
+            for layer in &self.layers {
+                if layer.format != LayerFormat::Tar
+                    || !moved.keys().any(|(index, _)| *index == layer.layer_index)
+                {
+                    continue;
+                }
+                let mut copied = HashSet::new();
+                let mut archive = Archive::new(layer.open_reader()?);
+                for entry in archive.entries()? {
+                    cancel::check()?;
+                    let mut entry = entry?;
+                    let path = entry.path()?.to_string_lossy().to_string();
+                    let Some(&shared) = moved.get(&(layer.layer_index, path.as_str())) else {
+                        continue;
+                    };
+                    if !entry.header().entry_type().is_file() || !copied.insert(shared) {
+                        continue;
+                    }
+                    append_entry(builder, &mut entry, Path::new(shared))
+                        .with_context(|| format!("Failed to add {}", shared))
+                        .map_err(|e| layer_error(layer, e))?;
+                }
+            }
+            Ok(())
+        })?;
+        info!("Shared layer is {}", shared_layer.hash);
+
+        let phase = info_span!("rewrite", layers = plan.len());
+        let _guard = phase.enter();
+        let rewritten = self.try_map_layers(|layer| match plan.get(&layer.layer_index) {
+            Some(mods) => {
+                self.options.progress.layer_rewrite_started(layer);
+                let new_layer = self
+                    .rewrite_layer_checkpointed(layer, mods, &new_layer_dir, &phase)
+                    .map_err(|e| layer_error(layer, e))?;
+                self.options.progress.layer_rewritten(layer, &new_layer);
+                Ok(new_layer)
+            }
+            None => Ok(layer.clone()),
+        })?;
+        self.timings.record(Phase::Rewrite, start.elapsed(), 0);
+
+        let mut new_layers = vec![shared_layer];
+        new_layers.extend(rewritten);
+        let new_config_digest = self.update_config(&staging_dir, &new_layers, |config| {
+            if config.history.is_empty() {
+                return;
+            }
+            config.history.insert(
+                0,
+                HistoryEntry {
+                    created: self
+                        .options
+                        .created
+                        .timestamp(&self.original_config.created),
+                    created_by: format!("shared copies of {} duplicated files", groups.len()),
+                    comment: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+                    empty_layer: false,
+                    author: None,
+                },
+            );
+        })?;
+        let new_refs = self.update_manifest(&staging_dir, &new_layers, &new_config_digest)?;
+
+        // The shared layer replaces no old layer, so isn't listed
+        let layers = self
+            .layers
+            .iter()
+            .map(|old| LayerDigests {
+                layer_index: old.layer_index,
+                rewritten: plan.contains_key(&old.layer_index),
+                old_diff_id: old.hash.clone(),
+                new_diff_id: new_layers[old.layer_index + 1].hash.clone(),
+                old_blob: self.original_manifest.layers[old.layer_index].clone(),
+                new_blob: new_refs[old.layer_index + 1].clone(),
+            })
+            .collect();
+        Ok(StagedImage {
+            _tmp_dir: tmp_dir,
+            dir: staging_dir,
+            summary: DedupeSummary {
+                schema_version: crate::SCHEMA_VERSION,
+                duplicate_groups: duplicates.len(),
+                duplicate_files,
+                bytes_saved,
+                layers,
+                old_config_digest: self.original_config_digest()?,
+                new_config_digest,
+                manifest_digest: None,
+                stale_referrers: Vec::new(),
+            },
+        })
+    }
+}
@@ -0,0 +1,209 @@
+//! Splitting one large layer into several, behind the `rewrite` feature.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+use tar::Archive;
+use tracing::{info, warn};
+
+use super::rewrite::StagedImage;
+use super::{Analyzer, append_entry};
+use crate::cancel;
+use crate::entries::{EntryInfo, EntryKind, read_layer_entries};
+use crate::error::{DedupeError, IoResultExt, Result};
+use crate::paths;
+use crate::pipeline::{DedupeSummary, LayerDigests};
+use crate::schemas::HistoryEntry;
+
+/// How [`Analyzer::create_split_image`] divides a layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitBy {
+    /// One layer per directory `depth` levels below the root, files
+    /// outside of them going with the first
+    Dir { depth: usize },
+    /// Runs of consecutive entries of at most this many bytes, more only
+    /// when a single file is larger
+    Size(u64),
+}
+
+/// The part of the path to group `entry` by for [`SplitBy::Dir`]: its
+/// first `depth` components, or fewer when it isn't that deep.
+fn dir_key(entry: &EntryInfo, depth: usize) -> String {
+    let path = paths::normalize(&entry.path).trim_end_matches('/');
+    let components: Vec<&str> = path.split('/').collect();
+    let own = if entry.kind == EntryKind::Dir { 0 } else { 1 };
+    let depth = depth.min(components.len().saturating_sub(own));
+    components[..depth].join("/")
+}
+
+/// The part each entry of a layer goes to. Whiteouts and opaque markers
+/// only hide lower layers, so they go to the first part, where they
+/// can't hide the other parts; every entry of a path, and hardlinks with
+/// their target, share a part.
+fn plan_parts(entries: &[EntryInfo], split_by: SplitBy) -> Vec<usize> {
+    let mut by_path: HashMap<&str, usize> = HashMap::new();
+    let mut by_dir: HashMap<String, usize> = HashMap::new();
+    let mut current = 0;
+    let mut current_size = 0;
+    let mut parts = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let path = paths::normalize(&entry.path).trim_end_matches('/');
+        let linked = entry
+            .link_target
+            .as_deref()
+            .filter(|_| entry.kind == EntryKind::Hardlink)
+            .and_then(|target| by_path.get(paths::normalize(target)));
+        let part = if matches!(entry.kind, EntryKind::Whiteout | EntryKind::Opaque) {
+            0
+        } else if let Some(&part) = by_path.get(path).or(linked) {
+            part
+        } else {
+            match split_by {
+                SplitBy::Dir { depth } => {
+                    let next = by_dir.len();
+                    *by_dir.entry(dir_key(entry, depth)).or_insert(next)
+                }
+                SplitBy::Size(budget) => {
+                    if current_size > 0 && current_size + entry.size > budget {
+                        current += 1;
+                        current_size = 0;
+                    }
+                    current_size += entry.size;
+                    current
+                }
+            }
+        };
+        by_path.insert(path, part);
+        parts.push(part);
+    }
+    parts
+}
+
+impl Analyzer {
+    /// Writes the image with layer `index` split into several layers to
+    /// `writer`, as a `docker save` tar. The merged filesystem doesn't
+    /// change; smaller layers pull in parallel and change less often.
+    pub fn create_split_image<W: Write>(
+        &self,
+        index: usize,
+        split_by: SplitBy,
+        writer: W,
+    ) -> Result<DedupeSummary> {
+        let staged = self.stage_split_image(index, split_by)?;
+        self.pack_staged(staged, writer)
+    }
+
+    /// The history with the entry of layer `index` repeated for each part.
+    fn split_history(&self, index: usize, parts: usize) -> Vec<HistoryEntry> {
+        let mut history = Vec::new();
+        let mut layer_index = 0;
+        for entry in &self.original_config.history {
+            if entry.empty_layer || layer_index != index {
+                layer_index += usize::from(!entry.empty_layer);
+                history.push(entry.clone());
+                continue;
+            }
+            layer_index += 1;
+            history.extend((1..=parts).map(|part| HistoryEntry {
+                created: self.options.created.timestamp(&entry.created),
+                comment: format!("part {} of {}", part, parts),
+                ..entry.clone()
+            }));
+        }
+        history
+    }
+
+    pub(crate) fn stage_split_image(&self, index: usize, split_by: SplitBy) -> Result<StagedImage> {
+        let layer = self.layers.get(index).ok_or_else(|| {
+            DedupeError::InvalidOption(format!(
+                "no layer {}, the image has {}",
+                index,
+                self.layers.len()
+            ))
+        })?;
+        if matches!(split_by, SplitBy::Dir { depth: 0 } | SplitBy::Size(0)) {
+            return Err(DedupeError::InvalidOption(
+                "splitting needs a depth or size above 0".to_string(),
+            ));
+        }
+        let (tmp_dir, new_layer_dir, staging_dir) = self.staging_dirs()?;
+        let plan = plan_parts(&read_layer_entries(layer)?, split_by);
+        let part_count = plan.iter().max().map_or(1, |last| last + 1);
+        if part_count == 1 {
+            warn!(
+                "Layer {} can't be split {:?}, keeping it whole",
+                index, split_by
+            );
+        }
+
+        info!("Splitting layer {} into {} layers", index, part_count);
+        let mut parts = Vec::with_capacity(part_count);
+        for part in 0..part_count {
+            let new_layer = self.write_layer(
+                &format!("split-{}-{}", index, part),
+                index + part,
+                &new_layer_dir,
+                |builder| {
+                    let mut archive = Archive::new(layer.open_reader()?);
+                    for (entry, &entry_part) in archive.entries()?.zip(&plan) {
+                        cancel::check()?;
+                        let mut entry = entry?;
+                        if entry_part != part {
+                            continue;
+                        }
+                        let path = entry.path()?.into_owned();
+                        append_entry(builder, &mut entry, &path)
+                            .with_context(|| format!("Failed to add {}", path.display()))?;
+                    }
+                    Ok(())
+                },
+            )?;
+            parts.push(new_layer);
+        }
+
+        let mut new_layers = self.layers[..index].to_vec();
+        new_layers.extend(parts);
+        new_layers.extend_from_slice(&self.layers[index + 1..]);
+        let new_config_digest = self.update_config(&staging_dir, &new_layers, |config| {
+            config.history = self.split_history(index, part_count);
+        })?;
+        let new_refs = self.update_manifest(&staging_dir, &new_layers, &new_config_digest)?;
+
+        let layers = self
+            .layers
+            .iter()
+            .flat_map(|old| {
+                let new_indexes = match old.layer_index {
+                    i if i < index => i..i + 1,
+                    i if i == index => i..i + part_count,
+                    i => i + part_count - 1..i + part_count,
+                };
+                new_indexes.map(|new_index| LayerDigests {
+                    layer_index: old.layer_index,
+                    rewritten: old.layer_index == index,
+                    old_diff_id: old.hash.clone(),
+                    new_diff_id: new_layers[new_index].hash.clone(),
+                    old_blob: self.original_manifest.layers[old.layer_index].clone(),
+                    new_blob: new_refs[new_index].clone(),
+                })
+            })
+            .collect();
+        Ok(StagedImage {
+            _tmp_dir: tmp_dir,
+            dir: staging_dir,
+            summary: DedupeSummary {
+                schema_version: crate::SCHEMA_VERSION,
+                duplicate_groups: 0,
+                duplicate_files: 0,
+                bytes_saved: 0,
+                layers,
+                old_config_digest: self.original_config_digest()?,
+                new_config_digest,
+                manifest_digest: None,
+                stale_referrers: Vec::new(),
+            },
+        })
+    }
+}
@@ -0,0 +1,168 @@
+//! Merging the top layers of an image into one, behind the `rewrite`
+//! feature.
+
+use std::io::Write;
+
+use itertools::Itertools;
+use tar::{Builder, EntryType, Header};
+use tracing::info;
+
+use super::Analyzer;
+use super::rewrite::StagedImage;
+use crate::entries::read_layer_entries;
+use crate::error::{DedupeError, IoResultExt, Result};
+use crate::pipeline::{DedupeSummary, LayerDigests};
+use crate::schemas::HistoryEntry;
+use crate::union::UnionView;
+
+/// An empty file marking a whiteout or opaque directory.
+fn append_marker(builder: &mut Builder<&mut dyn Write>, path: String) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_size(0);
+    header.set_mtime(0);
+    builder
+        .append_data(&mut header, &path, std::io::empty())
+        .with_context(|| format!("Failed to add {}", path))?;
+    Ok(())
+}
+
+/// `.wh.` path of a whiteout or an opaque directory (`name` None).
+fn marker_path(dir: &str, name: Option<&str>) -> String {
+    let marker = match name {
+        Some(name) => format!(".wh.{}", name),
+        None => ".wh..wh..opq".to_string(),
+    };
+    if dir.is_empty() {
+        marker
+    } else {
+        format!("{}/{}", dir, marker)
+    }
+}
+
+impl Analyzer {
+    /// The history once layers `from..` are one: their entries are kept,
+    /// marked `empty_layer`, and one for the squashed layer follows the
+    /// last of them. A config without history keeps none.
+    fn squashed_history(&self, from: usize) -> Vec<HistoryEntry> {
+        let mut history = self.original_config.history.clone();
+        if history.is_empty() {
+            return history;
+        }
+        let mut layer_index = 0;
+        let mut insert_at = history.len();
+        let mut created = self.original_config.created.clone();
+        for (i, entry) in history.iter_mut().enumerate() {
+            if entry.empty_layer {
+                continue;
+            }
+            if layer_index >= from {
+                entry.empty_layer = true;
+                insert_at = i + 1;
+                created = entry.created.clone();
+            }
+            layer_index += 1;
+        }
+        let last = self.layers.len() - 1;
+        history.insert(
+            insert_at.min(history.len()),
+            HistoryEntry {
+                created: self.options.created.timestamp(&created),
+                created_by: format!("squash layers {}-{}", from, last),
+                comment: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+                empty_layer: false,
+                author: None,
+            },
+        );
+        history
+    }
+
+    /// Writes the image with layers `from..` merged into a single layer to
+    /// `writer`, as a `docker save` tar. Whiteouts and replaced files are
+    /// applied within the merged layers, so what they deleted or
+    /// overwrote takes no space at all; whiteouts deleting files of the
+    /// layers below are kept.
+    pub fn create_squashed_image<W: Write>(&self, from: usize, writer: W) -> Result<DedupeSummary> {
+        let staged = self.stage_squashed_image(from)?;
+        self.pack_staged(staged, writer)
+    }
+
+    pub(crate) fn stage_squashed_image(&self, from: usize) -> Result<StagedImage> {
+        if from >= self.layers.len() {
+            return Err(DedupeError::InvalidOption(format!(
+                "can't squash from layer {}, the image has {}",
+                from,
+                self.layers.len()
+            )));
+        }
+        let (tmp_dir, new_layer_dir, staging_dir) = self.staging_dirs()?;
+        let squashed = &self.layers[from..];
+        let view = UnionView::build(squashed.iter().map(read_layer_entries).flatten_ok())?;
+
+        info!("Squashing layers {}..{}", from, self.layers.len() - 1);
+        let mut written = 0;
+        let squashed_layer = self.write_layer(
+            &format!("squashed-{}", from),
+            from,
+            &new_layer_dir,
+            |builder| {
+                // Markers first, so they can't hide the layer's own entries
+                for dir in view.opaque_dirs() {
+                    append_marker(builder, marker_path(dir, None))?;
+                }
+                for path in view.whiteouts() {
+                    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+                    append_marker(builder, marker_path(dir, Some(name)))?;
+                }
+                written = self.append_visible(&view, squashed, true, builder)?;
+                Ok(())
+            },
+        )?;
+        info!(
+            "Squashed {} layers into {} entries",
+            squashed.len(),
+            written
+        );
+
+        let mut new_layers = self.layers[..from].to_vec();
+        new_layers.push(squashed_layer);
+        let new_config_digest = self.update_config(&staging_dir, &new_layers, |config| {
+            config.history = self.squashed_history(from);
+        })?;
+        let new_refs = self.update_manifest(&staging_dir, &new_layers, &new_config_digest)?;
+
+        let layers = self
+            .layers
+            .iter()
+            .map(|old| {
+                let new_index = old.layer_index.min(from);
+                LayerDigests {
+                    layer_index: old.layer_index,
+                    rewritten: old.layer_index >= from,
+                    old_diff_id: old.hash.clone(),
+                    new_diff_id: new_layers[new_index].hash.clone(),
+                    old_blob: self.original_manifest.layers[old.layer_index].clone(),
+                    new_blob: new_refs[new_index].clone(),
+                }
+            })
+            .collect();
+        Ok(StagedImage {
+            _tmp_dir: tmp_dir,
+            dir: staging_dir,
+            summary: DedupeSummary {
+                schema_version: crate::SCHEMA_VERSION,
+                duplicate_groups: 0,
+                duplicate_files: 0,
+                bytes_saved: 0,
+                layers,
+                old_config_digest: self.original_config_digest()?,
+                new_config_digest,
+                manifest_digest: None,
+                stale_referrers: Vec::new(),
+            },
+        })
+    }
+}
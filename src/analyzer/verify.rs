@@ -0,0 +1,215 @@
+//! Structural and digest checks of a loaded image.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use flate2::write::GzDecoder;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use super::{Analyzer, BUFFER_SIZE, Layer, LayerCompression, LayerFormat};
+use crate::cancel::{self, CancellableReader};
+use crate::digest_writer::DigestWriter;
+use crate::error::Result;
+use crate::paths;
+use crate::pipeline::DedupeSummary;
+
+/// One inconsistency found by [`Analyzer::verify`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Problem {
+    /// File the problem is in, relative to the image root
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Verification {
+    /// See [`crate::SCHEMA_VERSION`]
+    pub schema_version: u32,
+    pub layers: usize,
+    /// Files whose contents were hashed
+    pub files_checked: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// The digest a file claims by its name: `<hex>`, `<hex>.json` or
+/// `<hex>.tar`, as `docker save` and OCI layouts name blobs.
+fn named_digest(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let hex = name
+        .strip_suffix(".json")
+        .or_else(|| name.strip_suffix(".tar"))
+        .unwrap_or(name);
+    let is_hex = hex.len() == 64 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    is_hex.then(|| format!("sha256:{}", hex))
+}
+
+/// sha256 of a file as stored, and of its contents once gunzipped.
+fn hash_file(path: &Path) -> Result<(String, String)> {
+    let file = CancellableReader::new(File::open(path)?);
+    let mut reader = BufReader::with_capacity(BUFFER_SIZE, file);
+    if LayerCompression::of_file(path)? != LayerCompression::Gzip {
+        let mut hasher = DigestWriter::sink();
+        io::copy(&mut reader, &mut hasher)?;
+        let (_, digest) = hasher.finish();
+        return Ok((digest.clone(), digest));
+    }
+    let mut writer = DigestWriter::new(GzDecoder::new(DigestWriter::sink()));
+    io::copy(&mut reader, &mut writer)?;
+    let (decoder, stored) = writer.finish();
+    let (_, contents) = decoder.finish()?.finish();
+    Ok((stored, contents))
+}
+
+impl Analyzer {
+    /// Checks that the image is internally consistent: every layer
+    /// manifest.json lists exists, the config's diff_ids match the
+    /// uncompressed layers, and files named after a sha256 digest (the
+    /// config, layer and OCI blobs) hash to it. Problems are collected
+    /// rather than returned as errors, so one run reports all of them.
+    pub fn verify(&self) -> Result<Verification> {
+        let root = self.work_dir.extracted_dir();
+        let relative = |path: &Path| {
+            path.strip_prefix(&root)
+                .unwrap_or(path)
+                .display()
+                .to_string()
+        };
+        let mut problems = Vec::new();
+        let diff_ids = &self.original_config.rootfs.diff_ids;
+        if diff_ids.len() != self.layers.len() {
+            problems.push(Problem {
+                path: self.original_manifest.config.clone(),
+                message: format!(
+                    "lists {} diff_ids for {} layers",
+                    diff_ids.len(),
+                    self.layers.len()
+                ),
+            });
+        }
+
+        let layer_problems = self.try_map_layers(|layer| {
+            let problem = verify_layer(layer);
+            cancel::check()?;
+            Ok(problem.map(|message| Problem {
+                path: relative(&layer.path),
+                message,
+            }))
+        })?;
+        problems.extend(layer_problems.into_iter().flatten());
+        let mut files_checked = self.layers.len();
+
+        // The config, then blobs nothing above covered, e.g. the OCI index
+        // and manifests newer `docker save` versions include
+        let mut checked: HashSet<PathBuf> = self.layers.iter().map(|l| l.path.clone()).collect();
+        let mut others = vec![paths::resolve_in_archive(
+            &root,
+            &self.original_manifest.config,
+        )?];
+        let blobs_dir = root.join("blobs/sha256");
+        if blobs_dir.is_dir() {
+            let mut blobs = fs::read_dir(&blobs_dir)?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<Vec<_>>>()?;
+            blobs.sort();
+            others.extend(blobs);
+        }
+        for path in others {
+            if !checked.insert(path.clone()) {
+                continue;
+            }
+            let Some(expected) = named_digest(&path) else {
+                continue;
+            };
+            cancel::check()?;
+            let (stored, _) = hash_file(&path)?;
+            files_checked += 1;
+            if stored != expected {
+                problems.push(Problem {
+                    path: relative(&path),
+                    message: format!("contents have digest {}", stored),
+                });
+            }
+        }
+
+        for problem in &problems {
+            debug!("{}: {}", problem.path, problem.message);
+        }
+        info!(
+            "Verified {} files, {} problems",
+            files_checked,
+            problems.len()
+        );
+        Ok(Verification {
+            schema_version: crate::SCHEMA_VERSION,
+            layers: self.layers.len(),
+            files_checked,
+            problems,
+        })
+    }
+
+    /// [`Self::verify`] for an image just written, also checking that it is
+    /// the one `summary` describes: the config must have the digest and the
+    /// diff_ids the rewrite reported, or the bookkeeping along the way went
+    /// wrong.
+    pub fn verify_written(&self, summary: &DedupeSummary) -> Result<Verification> {
+        let mut verification = self.verify()?;
+        let config = &self.original_manifest.config;
+        let root = self.work_dir.extracted_dir();
+        let (digest, _) = hash_file(&paths::resolve_in_archive(&root, config)?)?;
+        if digest != summary.new_config_digest {
+            verification.problems.push(Problem {
+                path: config.clone(),
+                message: format!(
+                    "has digest {}, the rewrite reported {}",
+                    digest, summary.new_config_digest
+                ),
+            });
+        }
+        let diff_ids = &self.original_config.rootfs.diff_ids;
+        for layer in &summary.layers {
+            if !diff_ids.contains(&layer.new_diff_id) {
+                verification.problems.push(Problem {
+                    path: config.clone(),
+                    message: format!(
+                        "lacks diff_id {} the rewrite reported for layer {}",
+                        layer.new_diff_id, layer.layer_index
+                    ),
+                });
+            }
+        }
+        Ok(verification)
+    }
+}
+
+/// What's wrong with one layer, if anything.
+fn verify_layer(layer: &Layer) -> Option<String> {
+    if !layer.path.is_file() {
+        return Some("missing, but listed in manifest.json".to_string());
+    }
+    let (stored, contents) = match hash_file(&layer.path) {
+        Ok(digests) => digests,
+        Err(e) => return Some(format!("unreadable: {}", e)),
+    };
+    if let Some(expected) = named_digest(&layer.path)
+        && stored != expected
+    {
+        return Some(format!("contents have digest {}", stored));
+    }
+    // An encrypted layer's diff_id is that of the plaintext
+    if !layer.hash.is_empty() && layer.format != LayerFormat::Encrypted && contents != layer.hash {
+        return Some(format!(
+            "uncompressed digest {} doesn't match diff_id {}",
+            contents, layer.hash
+        ));
+    }
+    None
+}
@@ -0,0 +1,139 @@
+//! Async wrappers for embedding the analyzer in tokio services, behind the
+//! `async` feature.
+//!
+//! Tar handling and hashing stay synchronous. Each step runs on tokio's
+//! blocking pool with the async reader or writer bridged into it, so callers
+//! only `.await` and never park a runtime worker on disk or network I/O.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::io::AsyncRead;
+#[cfg(feature = "rewrite")]
+use tokio::io::AsyncWrite;
+use tokio::task;
+use tokio_util::io::SyncIoBridge;
+
+use crate::analyzer::{Analyzer, DuplicateInfo};
+use crate::error::{DedupeError, Result};
+use crate::options::AnalyzerOptions;
+#[cfg(feature = "rewrite")]
+use crate::pipeline::DedupeSummary;
+use crate::transport::ImageRef;
+
+/// Runs `op` on the blocking pool, forwarding its panics.
+async fn blocking<T, F>(op: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    match task::spawn_blocking(op).await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(_) => Err(DedupeError::Interrupted),
+    }
+}
+
+/// An [`Analyzer`] whose long running steps are `async`. Cheap to clone.
+#[derive(Clone)]
+pub struct AsyncAnalyzer {
+    inner: Arc<Analyzer>,
+}
+
+impl AsyncAnalyzer {
+    /// Loads an image from a `docker save` stream.
+    pub async fn load<R>(image_stream: R, options: AnalyzerOptions) -> Result<Self>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let analyzer =
+            blocking(move || Analyzer::from_reader(SyncIoBridge::new(image_stream), options))
+                .await?;
+        Ok(Self::from(analyzer))
+    }
+
+    /// Loads an image from a `docker save` tarball on disk.
+    pub async fn load_from_path(
+        image_path: impl Into<PathBuf>,
+        options: AnalyzerOptions,
+    ) -> Result<Self> {
+        let image_path = image_path.into();
+        let analyzer = blocking(move || Analyzer::from_path(image_path, options)).await?;
+        Ok(Self::from(analyzer))
+    }
+
+    /// Loads an image from any [`ImageRef`] transport, e.g. pulls it from a
+    /// registry.
+    pub async fn load_image(image: ImageRef, options: AnalyzerOptions) -> Result<Self> {
+        let analyzer = blocking(move || image.load(options)).await?;
+        Ok(Self::from(analyzer))
+    }
+
+    /// The wrapped analyzer, for the cheap synchronous calls.
+    pub fn analyzer(&self) -> &Analyzer {
+        &self.inner
+    }
+
+    pub async fn find_duplicates(&self) -> Result<Vec<DuplicateInfo>> {
+        let inner = self.inner.clone();
+        blocking(move || inner.find_duplicates()).await
+    }
+
+    #[cfg(feature = "rewrite")]
+    /// Writes the deduplicated image to `writer` and shuts it down.
+    pub async fn create_deduplicated_image<W>(
+        &self,
+        duplicates: Vec<DuplicateInfo>,
+        writer: W,
+    ) -> Result<DedupeSummary>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        blocking(move || {
+            let mut writer = SyncIoBridge::new(writer);
+            let summary = inner.create_deduplicated_image(duplicates, &mut writer)?;
+            writer.shutdown().map_err(|source| DedupeError::Output {
+                context: "Failed to close output".to_string(),
+                source,
+            })?;
+            Ok(summary)
+        })
+        .await
+    }
+
+    #[cfg(feature = "rewrite")]
+    /// Writes the deduplicated image to an [`ImageRef`], e.g. pushes it.
+    pub async fn write_image(
+        &self,
+        duplicates: Vec<DuplicateInfo>,
+        image: ImageRef,
+    ) -> Result<DedupeSummary> {
+        let inner = self.inner.clone();
+        blocking(move || image.write(&inner, duplicates)).await
+    }
+}
+
+impl From<Analyzer> for AsyncAnalyzer {
+    fn from(analyzer: Analyzer) -> Self {
+        Self {
+            inner: Arc::new(analyzer),
+        }
+    }
+}
+
+#[cfg(feature = "rewrite")]
+/// Async counterpart of [`crate::dedupe_image`].
+pub async fn dedupe_image<R, W>(
+    image_stream: R,
+    writer: W,
+    options: AnalyzerOptions,
+) -> Result<DedupeSummary>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let analyzer = AsyncAnalyzer::load(image_stream, options).await?;
+    let duplicates = analyzer.find_duplicates().await?;
+    analyzer.create_deduplicated_image(duplicates, writer).await
+}
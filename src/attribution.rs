@@ -0,0 +1,100 @@
+//! Where an image's bytes come from, per Dockerfile step: what each
+//! history entry's layer added, how much of it later layers overwrote or
+//! deleted, and how much duplicates a copy elsewhere in the image.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::DuplicateInfo;
+use crate::entries::{EntryInfo, EntryKind};
+use crate::paths;
+use crate::schemas::HistoryEntry;
+use crate::union::UnionView;
+
+/// One history entry of [`attribute_waste`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepWaste {
+    /// Position in the config's history
+    pub history_index: usize,
+    /// The layer the step created, none for `empty_layer` steps
+    pub layer_index: Option<usize>,
+    pub created_by: String,
+    /// Bytes of the files the layer holds
+    pub added: u64,
+    /// Of those, bytes a higher layer replaces or deletes, so no container
+    /// sees them
+    pub overwritten: u64,
+    /// Of the visible rest, bytes of files identical to a copy in a lower
+    /// layer or earlier in the same one
+    pub duplicated: u64,
+    /// What the step leaves to the final image once deduplicated:
+    /// `added - overwritten - duplicated`
+    pub net: u64,
+}
+
+/// A ledger row per history entry, or per layer of the `layers` without a
+/// history. `entries` are every layer's, in layer order, `view` the union
+/// of them and `duplicates` the image's duplicate groups, whose copies
+/// other than the original count as duplicated.
+pub fn attribute_waste(
+    history: &[HistoryEntry],
+    layers: usize,
+    entries: &[EntryInfo],
+    view: &UnionView,
+    duplicates: &[DuplicateInfo],
+) -> Vec<StepWaste> {
+    let copies: HashSet<(usize, &str)> = duplicates
+        .iter()
+        .flat_map(|group| &group.duplicates)
+        .map(|f| (f.layer_index, paths::normalize(&f.path)))
+        .collect();
+    let mut totals = vec![(0, 0, 0); layers];
+    for entry in entries.iter().filter(|e| e.kind == EntryKind::File) {
+        let Some((added, overwritten, duplicated)) = totals.get_mut(entry.layer_index) else {
+            continue;
+        };
+        *added += entry.size;
+        let visible = view.get(&entry.path).is_some_and(|visible| {
+            visible.layer_index == entry.layer_index && visible.kind == EntryKind::File
+        });
+        if !visible {
+            *overwritten += entry.size;
+        } else if copies.contains(&(entry.layer_index, paths::normalize(&entry.path))) {
+            *duplicated += entry.size;
+        }
+    }
+
+    let row = |history_index, layer_index: Option<usize>, created_by: &str| {
+        let (added, overwritten, duplicated) = layer_index
+            .and_then(|index| totals.get(index).copied())
+            .unwrap_or_default();
+        StepWaste {
+            history_index,
+            layer_index,
+            created_by: created_by.to_string(),
+            added,
+            overwritten,
+            duplicated,
+            net: added - overwritten - duplicated,
+        }
+    };
+    // Configs may leave out the history; then each layer is its own step
+    if history.is_empty() {
+        return (0..layers)
+            .map(|index| row(index, Some(index), ""))
+            .collect();
+    }
+    let mut layer_index = 0;
+    history
+        .iter()
+        .enumerate()
+        .map(|(history_index, step)| {
+            let layer = (!step.empty_layer).then(|| {
+                layer_index += 1;
+                layer_index - 1
+            });
+            row(history_index, layer, &step.created_by)
+        })
+        .collect()
+}
@@ -0,0 +1,187 @@
+//! A committed record of an image's duplicate bytes that CI runs ratchet
+//! against: with `--baseline-file`, a run fails when the image wastes more
+//! than the baseline plus a margin, and `--update-baseline` records what
+//! the run found instead, for when the growth is deliberate or the waste
+//! went down and should stay down.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::DuplicateInfo;
+use crate::error::{DedupeError, IoResultExt, Result};
+
+/// The waste of an image at some point, as `--baseline-file` stores it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Baseline {
+    /// See [`crate::SCHEMA_VERSION`]
+    pub schema_version: u32,
+    pub duplicate_groups: usize,
+    /// Bytes deduplicating the image would save, as in the report
+    pub total_savings: u64,
+}
+
+/// How far a run may go over its baseline and still pass.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Margin {
+    Bytes(u64),
+    /// Of the baseline's `total_savings`
+    Percent(f64),
+}
+
+impl Default for Margin {
+    fn default() -> Self {
+        Margin::Bytes(0)
+    }
+}
+
+impl FromStr for Margin {
+    type Err = DedupeError;
+
+    /// A number of bytes, or a percentage of the baseline: `5%`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            DedupeError::InvalidOption(format!("margin {:?} is neither bytes nor a percentage", s))
+        };
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<f64>() {
+                Ok(percent) if percent.is_finite() && percent >= 0.0 => {
+                    Ok(Margin::Percent(percent))
+                }
+                _ => Err(invalid()),
+            },
+            None => s.trim().parse().map(Margin::Bytes).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl Margin {
+    /// Bytes allowed on top of `baseline`.
+    pub fn allowance(self, baseline: u64) -> u64 {
+        match self {
+            Margin::Bytes(bytes) => bytes,
+            Margin::Percent(percent) => (baseline as f64 * percent / 100.0) as u64,
+        }
+    }
+}
+
+/// Outcome of [`Baseline::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BaselineCheck {
+    pub baseline: u64,
+    pub current: u64,
+    /// Baseline plus margin, the most a passing run wastes
+    pub allowed: u64,
+}
+
+impl BaselineCheck {
+    pub fn passed(&self) -> bool {
+        self.current <= self.allowed
+    }
+
+    pub fn describe(&self) -> String {
+        let change = if self.current >= self.baseline {
+            format!("+{}", format_size(self.current - self.baseline, BINARY))
+        } else {
+            format!("-{}", format_size(self.baseline - self.current, BINARY))
+        };
+        format!(
+            "{} of duplicates against a baseline of {} ({}, {} allowed)",
+            format_size(self.current, BINARY),
+            format_size(self.baseline, BINARY),
+            change,
+            format_size(self.allowed, BINARY)
+        )
+    }
+}
+
+impl Baseline {
+    pub fn new(duplicates: &[DuplicateInfo]) -> Self {
+        Self {
+            schema_version: crate::SCHEMA_VERSION,
+            duplicate_groups: duplicates.len(),
+            total_savings: duplicates.iter().map(|d| d.total_savings).sum(),
+        }
+    }
+
+    /// The baseline in `path`, `None` if there's no such file yet.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let baseline: Baseline = serde_json::from_slice(&contents)?;
+        if baseline.schema_version > crate::SCHEMA_VERSION {
+            return Err(DedupeError::InvalidOption(format!(
+                "{} has schema version {}, newer than this tool's {}",
+                path.display(),
+                baseline.schema_version,
+                crate::SCHEMA_VERSION
+            )));
+        }
+        Ok(Some(baseline))
+    }
+
+    /// Writes the baseline as pretty JSON, ending in a newline so it
+    /// diffs well where it's committed.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        fs::write(path, json).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Compares the waste of `current` to this baseline's.
+    pub fn check(&self, current: &Baseline, margin: Margin) -> BaselineCheck {
+        BaselineCheck {
+            baseline: self.total_savings,
+            current: current.total_savings,
+            allowed: self
+                .total_savings
+                .saturating_add(margin.allowance(self.total_savings)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn baseline(total_savings: u64) -> Baseline {
+        Baseline {
+            schema_version: crate::SCHEMA_VERSION,
+            duplicate_groups: 1,
+            total_savings,
+        }
+    }
+
+    #[test]
+    fn test_parse_margin() {
+        assert_eq!("1000".parse::<Margin>().unwrap(), Margin::Bytes(1000));
+        assert_eq!("2.5%".parse::<Margin>().unwrap(), Margin::Percent(2.5));
+        assert!("-1%".parse::<Margin>().is_err());
+        assert!("1MB".parse::<Margin>().is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let recorded = baseline(1000);
+        let check = recorded.check(&baseline(1050), Margin::Percent(5.0));
+        assert_eq!(check.allowed, 1050);
+        assert!(check.passed());
+        assert!(!recorded.check(&baseline(1001), Margin::default()).passed());
+        assert!(recorded.check(&baseline(10), Margin::Bytes(0)).passed());
+    }
+
+    #[test]
+    fn test_read_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".dedupe-baseline.json");
+        assert_eq!(Baseline::read(&path).unwrap(), None);
+        baseline(42).write(&path).unwrap();
+        assert_eq!(Baseline::read(&path).unwrap(), Some(baseline(42)));
+    }
+}
@@ -0,0 +1,281 @@
+//! Repeated runs of the whole pipeline over one image, timing every phase,
+//! so regressions in hashing or compression show up as lower throughput.
+//! Backs the `bench` subcommand.
+//!
+//! Each run loads the image afresh, so extraction is timed too, and writes
+//! the deduplicated image to nowhere.
+//!
+//! [`gzip_bench`] times gzip alone on one layer, with whichever `flate2`
+//! backend the build uses: comparing backends means running it from
+//! builds with different `flate2` features.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::time::Instant;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::analyzer::{Analyzer, LayerFormat};
+use crate::error::{DedupeError, Result};
+use crate::timings::{Phase, PhaseTiming};
+
+/// One phase over all runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhaseBench {
+    pub phase: Phase,
+    /// Bytes the phase processed in one run
+    pub bytes: u64,
+    pub median_seconds: f64,
+    pub min_seconds: f64,
+    /// `bytes` over `median_seconds`, 0 for phases that count no bytes
+    pub bytes_per_second: f64,
+}
+
+/// Outcome of [`bench`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BenchReport {
+    pub runs: usize,
+    /// Wall time of each run
+    pub run_seconds: Vec<f64>,
+    /// In the order the phases first ran
+    pub phases: Vec<PhaseBench>,
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(f64::total_cmp);
+    match values.len() {
+        0 => 0.0,
+        n if n % 2 == 1 => values[n / 2],
+        n => (values[n / 2 - 1] + values[n / 2]) / 2.0,
+    }
+}
+
+/// Runs `load`, the duplicate search and, if `rewrite`, the rewrite `runs`
+/// times.
+pub fn bench(
+    runs: usize,
+    rewrite: bool,
+    mut load: impl FnMut() -> Result<Analyzer>,
+) -> Result<BenchReport> {
+    let mut timings: Vec<Vec<PhaseTiming>> = Vec::with_capacity(runs);
+    let mut run_seconds = Vec::with_capacity(runs);
+    for run in 1..=runs {
+        let start = Instant::now();
+        let analyzer = load()?;
+        let duplicates = analyzer.find_duplicates()?;
+        if rewrite {
+            analyzer.create_deduplicated_image(duplicates, io::sink())?;
+        }
+        let seconds = start.elapsed().as_secs_f64();
+        info!("Run {} of {}: {:.2}s", run, runs, seconds);
+        run_seconds.push(seconds);
+        timings.push(analyzer.timings().summary());
+    }
+
+    let mut phases: Vec<Phase> = Vec::new();
+    for timing in timings.iter().flatten() {
+        if !phases.contains(&timing.phase) {
+            phases.push(timing.phase);
+        }
+    }
+    let phases = phases
+        .into_iter()
+        .map(|phase| {
+            let of_phase: Vec<&PhaseTiming> = timings
+                .iter()
+                .flatten()
+                .filter(|t| t.phase == phase)
+                .collect();
+            let seconds: Vec<f64> = of_phase.iter().map(|t| t.seconds).collect();
+            let bytes = of_phase.iter().map(|t| t.bytes).max().unwrap_or(0);
+            let median_seconds = median(seconds.clone());
+            PhaseBench {
+                phase,
+                bytes,
+                median_seconds,
+                min_seconds: seconds.into_iter().fold(f64::INFINITY, f64::min),
+                bytes_per_second: if median_seconds > 0.0 {
+                    bytes as f64 / median_seconds
+                } else {
+                    0.0
+                },
+            }
+        })
+        .collect();
+    Ok(BenchReport {
+        runs,
+        run_seconds,
+        phases,
+    })
+}
+
+impl BenchReport {
+    /// One line per phase, then the runs' median wall time.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .phases
+            .iter()
+            .map(|p| {
+                let throughput = if p.bytes_per_second > 0.0 {
+                    format!("{}/s", format_size(p.bytes_per_second as u64, BINARY))
+                } else {
+                    "-".to_string()
+                };
+                format!(
+                    "{:<9} {:>8.2}s median {:>8.2}s min {:>12} {:>14}",
+                    format!("{:?}", p.phase),
+                    p.median_seconds,
+                    p.min_seconds,
+                    format_size(p.bytes, BINARY),
+                    throughput
+                )
+            })
+            .collect();
+        lines.push(format!(
+            "{:<9} {:>8.2}s median over {} runs",
+            "Total",
+            median(self.run_seconds.clone()),
+            self.runs
+        ));
+        lines
+    }
+}
+
+/// Outcome of [`gzip_bench`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GzipBench {
+    pub runs: usize,
+    pub layer_index: usize,
+    /// The layer's tar
+    pub bytes: u64,
+    /// At the level the rewrite compresses layers with
+    pub compressed_bytes: u64,
+    pub gzip_median_seconds: f64,
+    pub gunzip_median_seconds: f64,
+}
+
+/// Gzips and gunzips the largest tar layer of `analyzer` `runs` times in
+/// memory, as the rewrite writes layers and the scan reads them.
+pub fn gzip_bench(runs: usize, analyzer: &Analyzer) -> Result<GzipBench> {
+    let layer = analyzer
+        .layers
+        .iter()
+        .filter(|layer| layer.format == LayerFormat::Tar)
+        .max_by_key(|layer| fs::metadata(&layer.path).map_or(0, |m| m.len()))
+        .ok_or_else(|| DedupeError::NotAnImage("no tar layers to compress".to_string()))?;
+    let mut tar = Vec::new();
+    layer.open_reader()?.read_to_end(&mut tar)?;
+
+    let mut gzip_seconds = Vec::with_capacity(runs);
+    let mut gunzip_seconds = Vec::with_capacity(runs);
+    let mut compressed = Vec::new();
+    for run in 1..=runs {
+        let start = Instant::now();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&tar)?;
+        compressed = encoder.finish()?;
+        gzip_seconds.push(start.elapsed().as_secs_f64());
+
+        let start = Instant::now();
+        io::copy(&mut GzDecoder::new(compressed.as_slice()), &mut io::sink())?;
+        gunzip_seconds.push(start.elapsed().as_secs_f64());
+        info!(
+            "Run {} of {}: gzip {:.2}s, gunzip {:.2}s",
+            run,
+            runs,
+            gzip_seconds[run - 1],
+            gunzip_seconds[run - 1]
+        );
+    }
+    Ok(GzipBench {
+        runs,
+        layer_index: layer.layer_index,
+        bytes: tar.len() as u64,
+        compressed_bytes: compressed.len() as u64,
+        gzip_median_seconds: median(gzip_seconds),
+        gunzip_median_seconds: median(gunzip_seconds),
+    })
+}
+
+impl GzipBench {
+    /// One line each for gzip and gunzip, throughput counted in
+    /// uncompressed bytes.
+    pub fn lines(&self) -> Vec<String> {
+        let line = |name: &str, seconds: f64| {
+            let throughput = if seconds > 0.0 {
+                format!(
+                    "{}/s",
+                    format_size((self.bytes as f64 / seconds) as u64, BINARY)
+                )
+            } else {
+                "-".to_string()
+            };
+            format!(
+                "{:<9} {:>8.2}s median {:>12} {:>14}",
+                name,
+                seconds,
+                format_size(self.bytes, BINARY),
+                throughput
+            )
+        };
+        vec![
+            line("Gzip", self.gzip_median_seconds),
+            line("Gunzip", self.gunzip_median_seconds),
+            format!(
+                "Layer {} compressed to {} over {} runs",
+                self.layer_index,
+                format_size(self.compressed_bytes, BINARY),
+                self.runs
+            ),
+        ]
+    }
+}
+
+/// A `docker save` tarball of `layers` layers of `files` files of
+/// `file_size` bytes each, gzipped. Half the files of every layer are the
+/// same in all layers, the other half unique, so a run has both hashing
+/// and rewriting to do.
+#[cfg(feature = "testing")]
+pub fn synthetic_image(layers: usize, files: usize, file_size: usize) -> Vec<u8> {
+    use crate::testing::{ImageBuilder, LayerBuilder, random_bytes};
+
+    let shared: Vec<Vec<u8>> = (0..files / 2)
+        .map(|i| random_bytes(file_size, i as u64))
+        .collect();
+    let mut image = ImageBuilder::new();
+    for layer in 0..layers {
+        let mut builder = LayerBuilder::new();
+        for (i, contents) in shared.iter().enumerate() {
+            builder = builder.file(
+                &format!("layer{}/shared/{}.bin", layer, i),
+                contents.clone(),
+            );
+        }
+        for i in shared.len()..files {
+            let seed = (layer * files + i) as u64 + files as u64;
+            builder = builder.file(
+                &format!("layer{}/unique/{}.bin", layer, i),
+                random_bytes(file_size, seed),
+            );
+        }
+        image = image.layer(builder);
+    }
+    image.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(vec![3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(vec![4.0, 1.0, 2.0, 3.0]), 2.5);
+        assert_eq!(median(Vec::new()), 0.0);
+    }
+}
@@ -0,0 +1,46 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::{DedupeError, Result};
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Asks every running phase to stop at its next checkpoint. Work unwinds
+/// through normal error returns, so temp dirs are still cleaned up.
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::Relaxed)
+}
+
+/// Called from long-running loops; errors out once a cancel was requested.
+pub fn check() -> Result<()> {
+    if is_cancelled() {
+        Err(DedupeError::Interrupted)
+    } else {
+        Ok(())
+    }
+}
+
+/// Reader that fails once a cancel was requested, so a single huge entry
+/// doesn't delay shutdown until it has been fully processed.
+pub struct CancellableReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> CancellableReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R: Read> Read for CancellableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if is_cancelled() {
+            return Err(io::Error::other("Interrupted"));
+        }
+        self.inner.read(buf)
+    }
+}
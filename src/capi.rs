@@ -0,0 +1,122 @@
+//! Minimal C API, behind the `capi` feature. See `include/docker_duplicate_files.h`.
+//!
+//! Every function reports failure through its return value and leaves a
+//! message for [`ddf_last_error`]. Strings returned by the library must be
+//! released with [`ddf_string_free`].
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::analyzer::Analyzer;
+use crate::error::{DedupeError, IoResultExt, Result};
+use crate::report::Report;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Runs `op`, turning errors and panics into `None` plus a last error.
+fn guard<T>(op: impl FnOnce() -> Result<T>) -> Option<T> {
+    match panic::catch_unwind(AssertUnwindSafe(op)) {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            None
+        }
+        Err(_) => {
+            set_last_error("internal error (panic)".to_string());
+            None
+        }
+    }
+}
+
+/// # Safety
+/// `ptr` must be null or a valid NUL-terminated string.
+unsafe fn path_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str> {
+    if ptr.is_null() {
+        return Err(DedupeError::InvalidOption(format!("{} is null", name)));
+    }
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map_err(|_| DedupeError::InvalidOption(format!("{} is not valid UTF-8", name)))
+}
+
+fn load(image_path: &str, min_size: u64) -> Result<Analyzer> {
+    let mut builder = Analyzer::builder();
+    if min_size > 0 {
+        builder = builder.min_size(min_size);
+    }
+    builder.load_from_path(image_path)
+}
+
+/// Scans the image tarball at `image_path` and returns the JSON report, or
+/// null on failure. `min_size` 0 keeps the default threshold.
+///
+/// # Safety
+/// `image_path` must be null or a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ddf_analyze(image_path: *const c_char, min_size: u64) -> *mut c_char {
+    let report = guard(|| {
+        let image_path = unsafe { path_arg(image_path, "image_path") }?;
+        let analyzer = load(image_path, min_size)?;
+        let duplicates = analyzer.find_duplicates()?;
+        let json = serde_json::to_string(&Report::new(&duplicates, analyzer.timings()))?;
+        Ok(CString::new(json).unwrap_or_default())
+    });
+    report.map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Writes a deduplicated copy of the image at `image_path` to
+/// `output_path`. Returns 0 on success and -1 on failure, in which case no
+/// output file is left behind.
+///
+/// # Safety
+/// Both paths must be null or valid NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ddf_dedupe(
+    image_path: *const c_char,
+    output_path: *const c_char,
+    min_size: u64,
+) -> c_int {
+    let done = guard(|| {
+        let image_path = unsafe { path_arg(image_path, "image_path") }?;
+        let output_path = unsafe { path_arg(output_path, "output_path") }?;
+        let analyzer = load(image_path, min_size)?;
+        let duplicates = analyzer.find_duplicates()?;
+        let output = File::create(output_path)
+            .with_context(|| format!("Failed to create output file: {}", output_path))?;
+        let result = analyzer.create_deduplicated_image(duplicates, BufWriter::new(output));
+        if result.is_err() {
+            let _ = fs::remove_file(output_path);
+        }
+        result.map(|_| ())
+    });
+    if done.is_some() { 0 } else { -1 }
+}
+
+/// Message of the last error on this thread, or null. Valid until the next
+/// call into the library on the same thread; don't free it.
+#[unsafe(no_mangle)]
+pub extern "C" fn ddf_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr()))
+}
+
+/// Frees a string returned by the library. Null is ignored.
+///
+/// # Safety
+/// `s` must come from this library and not have been freed already.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ddf_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
@@ -0,0 +1,121 @@
+//! Identical files whose paths differ only in case, e.g. `lib/Foo.jar` and
+//! `lib/foo.jar`. Usually a copy made by mistake, and on a case-insensitive
+//! filesystem, where the image may end up unpacked, two files that can't
+//! both exist. Found among the duplicate groups, so it takes no pass of its
+//! own.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::analyzer::{DuplicateInfo, FileInfo};
+use crate::paths;
+
+/// Copies of one file at paths that are the same when case-folded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaseVariants {
+    /// The normalized path, lowercased
+    pub folded_path: String,
+    pub hash: String,
+    pub size: u64,
+    /// Every copy, lowest layer first, at two paths or more
+    pub files: Vec<FileInfo>,
+}
+
+/// `path` normalized and lowercased, the way a case-insensitive filesystem
+/// compares it.
+pub fn fold(path: &str) -> String {
+    paths::normalize(path).to_lowercase()
+}
+
+/// The copies within each of `duplicates` that share a case-folded path
+/// without sharing the path, largest first.
+pub fn case_variants(duplicates: &[DuplicateInfo]) -> Vec<CaseVariants> {
+    let mut variants = Vec::new();
+    for group in duplicates {
+        let mut by_folded: BTreeMap<String, Vec<&FileInfo>> = BTreeMap::new();
+        for file in std::iter::once(&group.original).chain(&group.duplicates) {
+            by_folded.entry(fold(&file.path)).or_default().push(file);
+        }
+        for (folded_path, files) in by_folded {
+            let first = paths::normalize(&files[0].path);
+            if files.iter().all(|f| paths::normalize(&f.path) == first) {
+                continue;
+            }
+            variants.push(CaseVariants {
+                folded_path,
+                hash: group.original.hash.clone(),
+                size: group.original.size,
+                files: files.into_iter().cloned().collect(),
+            });
+        }
+    }
+    variants.sort_by(|a, b| {
+        Reverse(a.size)
+            .cmp(&Reverse(b.size))
+            .then_with(|| a.folded_path.cmp(&b.folded_path))
+    });
+    variants
+}
+
+/// Logs every set of variants.
+pub fn print_case_variants(variants: &[CaseVariants]) {
+    if variants.is_empty() {
+        return;
+    }
+    info!("Identical files whose paths differ only in case:");
+    for variant in variants {
+        info!(
+            "\t{} ({})",
+            variant.folded_path,
+            format_size(variant.size, BINARY)
+        );
+        for file in &variant.files {
+            info!("\t\t{}, layer: {}", file.path, file.layer_index);
+        }
+    }
+    info!("=============================");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, layer_index: usize) -> FileInfo {
+        FileInfo {
+            path: path.to_string(),
+            size: 10,
+            hash: "h".to_string(),
+            layer_index,
+            provenance: None,
+        }
+    }
+
+    #[test]
+    fn test_case_variants() {
+        let duplicates = vec![DuplicateInfo {
+            original: file("./app/lib/Foo.jar", 0),
+            duplicates: vec![
+                file("app/lib/foo.jar", 1),
+                file("app/lib/Foo.jar", 1),
+                file("app/other/bar.jar", 1),
+            ],
+            total_savings: 30,
+        }];
+        let variants = case_variants(&duplicates);
+        assert_eq!(variants.len(), 1);
+        assert_eq!(variants[0].folded_path, "app/lib/foo.jar");
+        assert_eq!(variants[0].files.len(), 3);
+
+        // The same path in two layers is no variant
+        let duplicates = vec![DuplicateInfo {
+            original: file("app/lib/Foo.jar", 0),
+            duplicates: vec![file("./app/lib/Foo.jar", 1)],
+            total_savings: 10,
+        }];
+        assert!(case_variants(&duplicates).is_empty());
+    }
+}
@@ -0,0 +1,311 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+use tracing::{debug, warn};
+
+use crate::analyzer::{FileInfo, Layer, LayerFormat};
+#[cfg(feature = "sha256")]
+use crate::digest_writer::DigestWriter;
+use crate::error::{IoResultExt, Result};
+
+const EXTRACTED_MARKER: &str = "extracted.json";
+/// Everything a checkpoint creates under its root, besides the marker
+const SUBDIRS: [&str; 3] = ["extracted", "scan", "rewrite"];
+
+/// Where an Analyzer keeps the extracted image and intermediate files.
+/// Temp work dirs vanish with the Analyzer; persistent ones survive so an
+/// interrupted run can pick up where it left off.
+pub enum WorkDir {
+    Temp(TempDir),
+    Persistent(Checkpoint),
+}
+
+impl WorkDir {
+    pub fn path(&self) -> &Path {
+        match self {
+            WorkDir::Temp(dir) => dir.path(),
+            WorkDir::Persistent(checkpoint) => checkpoint.root(),
+        }
+    }
+
+    /// Where the outer image tar is unpacked
+    pub fn extracted_dir(&self) -> PathBuf {
+        match self {
+            WorkDir::Temp(dir) => dir.path().to_path_buf(),
+            WorkDir::Persistent(checkpoint) => checkpoint.extracted_dir(),
+        }
+    }
+
+    pub fn checkpoint(&self) -> Option<&Checkpoint> {
+        match self {
+            WorkDir::Temp(_) => None,
+            WorkDir::Persistent(checkpoint) => Some(checkpoint),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+struct ExtractRecord {
+    source: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScanRecord {
+    #[serde(default)]
+    schema_version: u32,
+    layer_hash: String,
+    min_size: u64,
+    #[serde(default)]
+    hasher: String,
+    files: Vec<FileInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RewriteRecord {
+    fingerprint: String,
+    path: PathBuf,
+    hash: String,
+    #[serde(default)]
+    blob_digest: Option<String>,
+}
+
+/// Per-layer progress persisted in a user supplied work directory:
+///
+/// ```text
+/// <root>/extracted/         unpacked outer tar
+/// <root>/extracted.json     written once extraction completed
+/// <root>/scan/layer-N.json  scan results of layer N
+/// <root>/rewrite/           rewritten layer blobs and their records
+/// ```
+///
+/// Records are only written after the work they describe has finished, so
+/// anything without a record is redone on resume.
+pub struct Checkpoint {
+    root: PathBuf,
+}
+
+impl Checkpoint {
+    pub fn open(root: &Path) -> Result<Self> {
+        for sub in SUBDIRS {
+            fs::create_dir_all(root.join(sub))
+                .with_context(|| format!("Failed to create work dir {}", root.display()))?;
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn extracted_dir(&self) -> PathBuf {
+        self.root.join("extracted")
+    }
+
+    pub fn rewrite_dir(&self) -> PathBuf {
+        self.root.join("rewrite")
+    }
+
+    /// Whether `source` was already fully extracted. A different source
+    /// invalidates everything in the work dir.
+    pub fn is_extracted(&self, source: &str) -> Result<bool> {
+        match read_record::<ExtractRecord>(&self.root.join(EXTRACTED_MARKER)) {
+            Some(record) if record.source == source => Ok(true),
+            Some(record) => {
+                warn!(
+                    "Work dir {} belongs to {}, discarding it",
+                    self.root.display(),
+                    record.source
+                );
+                self.reset()?;
+                Ok(false)
+            }
+            None => {
+                // A partial extraction can't be trusted
+                fs::remove_dir_all(self.extracted_dir())?;
+                fs::create_dir_all(self.extracted_dir())?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// The source of the last completed extraction, if any.
+    pub fn extracted_source(&self) -> Option<String> {
+        read_record::<ExtractRecord>(&self.root.join(EXTRACTED_MARKER)).map(|record| record.source)
+    }
+
+    /// Empties the extraction for a new one, keeping scan and rewrite
+    /// records until [`Self::mark_extracted`] tells whether they still
+    /// apply.
+    pub fn restart_extraction(&self) -> Result<()> {
+        remove_if_exists(&self.root.join(EXTRACTED_MARKER))?;
+        fs::remove_dir_all(self.extracted_dir())?;
+        fs::create_dir_all(self.extracted_dir())?;
+        Ok(())
+    }
+
+    pub fn mark_extracted(&self, source: &str) -> Result<()> {
+        write_record(
+            &self.root.join(EXTRACTED_MARKER),
+            &ExtractRecord {
+                source: source.to_string(),
+            },
+        )
+    }
+
+    pub fn load_scan(&self, layer: &Layer, min_size: u64, hasher: &str) -> Option<Vec<FileInfo>> {
+        let record = read_record::<ScanRecord>(&self.scan_record_path(layer.layer_index))?;
+        if record.schema_version != crate::SCHEMA_VERSION
+            || record.layer_hash != layer.hash
+            || record.min_size != min_size
+            || record.hasher != hasher
+        {
+            return None;
+        }
+        debug!(
+            "Resuming scan of layer {} from checkpoint",
+            layer.layer_index
+        );
+        Some(record.files)
+    }
+
+    pub fn save_scan(
+        &self,
+        layer: &Layer,
+        min_size: u64,
+        hasher: &str,
+        files: &[FileInfo],
+    ) -> Result<()> {
+        write_record(
+            &self.scan_record_path(layer.layer_index),
+            &ScanRecord {
+                schema_version: crate::SCHEMA_VERSION,
+                layer_hash: layer.hash.clone(),
+                min_size,
+                hasher: hasher.to_string(),
+                files: files.to_vec(),
+            },
+        )
+    }
+
+    /// Returns the previously rewritten layer if it was produced from the
+    /// same input and modifications.
+    pub fn load_rewrite(&self, layer_index: usize, fingerprint: &str) -> Option<Layer> {
+        let record = read_record::<RewriteRecord>(&self.rewrite_record_path(layer_index))?;
+        if record.fingerprint != fingerprint || !record.path.exists() {
+            return None;
+        }
+        debug!("Reusing rewritten layer {} from checkpoint", layer_index);
+        Some(Layer {
+            path: record.path,
+            layer_index,
+            hash: record.hash,
+            format: LayerFormat::Tar,
+            blob_digest: record.blob_digest,
+        })
+    }
+
+    pub fn save_rewrite(&self, fingerprint: &str, layer: &Layer) -> Result<()> {
+        write_record(
+            &self.rewrite_record_path(layer.layer_index),
+            &RewriteRecord {
+                fingerprint: fingerprint.to_string(),
+                path: layer.path.clone(),
+                hash: layer.hash.clone(),
+                blob_digest: layer.blob_digest.clone(),
+            },
+        )
+    }
+
+    /// Discards what the checkpoint created, leaving anything else in the
+    /// work dir alone.
+    pub fn reset(&self) -> Result<()> {
+        remove_if_exists(&self.root.join(EXTRACTED_MARKER))?;
+        self.clear(&SUBDIRS)
+    }
+
+    /// Discards the scan and rewrite records, e.g. of another image.
+    pub fn discard_progress(&self) -> Result<()> {
+        self.clear(&["scan", "rewrite"])
+    }
+
+    fn clear(&self, subs: &[&str]) -> Result<()> {
+        for sub in subs {
+            let dir = self.root.join(sub);
+            if dir.exists() {
+                fs::remove_dir_all(&dir)
+                    .with_context(|| format!("Failed to clear {}", dir.display()))?;
+            }
+        }
+        Self::open(&self.root)?;
+        Ok(())
+    }
+
+    fn scan_record_path(&self, layer_index: usize) -> PathBuf {
+        self.root.join(format!("scan/layer-{}.json", layer_index))
+    }
+
+    fn rewrite_record_path(&self, layer_index: usize) -> PathBuf {
+        self.root
+            .join(format!("rewrite/layer-{}.json", layer_index))
+    }
+}
+
+#[cfg(feature = "sha256")]
+/// Stable digest of anything serializable, used to tell whether a checkpoint
+/// was produced from the same inputs.
+pub fn fingerprint<T: Serialize>(value: &T) -> Result<String> {
+    let mut hasher = DigestWriter::sink();
+    serde_json::to_writer(&mut hasher, value)?;
+    Ok(hasher.finish_hex().1)
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn read_record<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let contents = fs::read(path).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+// Write-then-rename so a crash never leaves a truncated record behind
+fn write_record<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    serde_json::to_writer(&mut file, value)?;
+    file.flush()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_keeps_unrelated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "mine").unwrap();
+        let checkpoint = Checkpoint::open(dir.path()).unwrap();
+        fs::write(checkpoint.extracted_dir().join("manifest.json"), "[]").unwrap();
+        checkpoint.mark_extracted("a.tar").unwrap();
+
+        assert!(!checkpoint.is_extracted("b.tar").unwrap());
+        assert_eq!(checkpoint.extracted_source(), None);
+        assert!(!checkpoint.extracted_dir().join("manifest.json").exists());
+        assert!(checkpoint.rewrite_dir().is_dir());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("notes.txt")).unwrap(),
+            "mine"
+        );
+    }
+}
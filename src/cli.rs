@@ -1,38 +1,847 @@
-use anyhow::{Result, anyhow};
-use clap::Parser;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::analyzer::SplitBy;
+use crate::baseline::Margin;
+use crate::error::{DedupeError, Result};
+use crate::json_schema::Document;
+use crate::options::{AnalyzerBuilder, AnalyzerOptions, Created, HashAlgorithm, UnpackLimits};
+use crate::policy::{CacheFriendlyPolicy, CanonicalPolicy, Strategy};
+use crate::report::ReportFormat;
+use crate::transport::ImageRef;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per event, including layer spans with their digests,
+    /// byte counts and durations
+    Json,
+}
+
+/// What to do about signatures, SBOMs and other artifacts attached to the
+/// original image, which don't cover the rewritten one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Referrers {
+    /// Log them and add them to the report, to be regenerated
+    List,
+    /// Attach copies to the new image, annotated org.dedupe.stale=true
+    Copy,
+}
+
+/// Container runtimes --import loads the written image into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Import {
+    /// With `ctr images import`, which talks to the socket CONTAINERD_ADDRESS
+    /// names, /run/containerd/containerd.sock by default
+    Containerd,
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    /// Docker image to examine. If not specified, stdin will be used
-    #[arg(short, long)]
-    pub image: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
 
-    /// Output file path. Cannot be used with --stdout.
-    #[arg(short, long)]
-    pub output: Option<String>,
+    /// Docker image to examine: a `docker save` tarball, or docker-archive:PATH,
+    /// oci:PATH[:TAG], dir:PATH, docker://REFERENCE, s3://BUCKET/KEY or the
+    /// https:// URL of a tarball. If not specified, stdin will be used
+    #[arg(short, long, global = true)]
+    pub image: Option<ImageRef>,
+
+    /// Output path, in the same syntax as --image. Cannot be used with --stdout.
+    #[arg(short, long, global = true)]
+    pub output: Option<ImageRef>,
 
     /// Write to stdout. Cannot be used with -o.
-    #[arg(long, action = clap::ArgAction::SetTrue)]
+    #[arg(long, action = clap::ArgAction::SetTrue, global = true)]
     pub stdout: bool,
 
     /// minimum size of an object to track
-    #[arg(short, long, default_value_t = 1_000_000)]
+    #[arg(short, long, default_value_t = 1_000_000, global = true)]
     pub min_size: u64,
 
     /// Disable layer compression
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub no_compression: bool,
 
+    /// Content hash used to identify duplicates
+    #[arg(long, value_enum, default_value_t = HashAlgorithm::Rapidhash, global = true)]
+    pub hash: HashAlgorithm,
+
+    /// Glob of image paths to leave alone, e.g. '/usr/share/doc/**'. Repeatable.
+    #[arg(long = "exclude", value_name = "GLOB", global = true)]
+    pub excludes: Vec<String>,
+
+    /// Also deduplicate files under the image's VOLUMEs, which containers
+    /// get copies of or, with bind mounts, don't see at all
+    #[arg(long, global = true)]
+    pub dedupe_volumes: bool,
+
+    /// Don't honor the /.dedupeignore the image may have, listing paths
+    /// its authors want left alone
+    #[arg(long, global = true)]
+    pub no_ignore_file: bool,
+
+    /// Only scan and rewrite files under these directories, e.g.
+    /// '/usr,/opt/app'. Comma-separated or repeatable.
+    #[arg(long, value_name = "DIR", value_delimiter = ',', global = true)]
+    pub scope: Vec<String>,
+
+    /// Glob of protected paths (/etc, /boot, the dynamic loader, shells) to
+    /// deduplicate anyway, e.g. '/etc/ssl/**'. Repeatable.
+    #[arg(long = "allow-path", value_name = "GLOB", global = true)]
+    pub allowed_paths: Vec<String>,
+
+    /// Header to send when downloading an https:// --image, e.g.
+    /// 'Authorization: Bearer TOKEN'. Repeatable.
+    #[arg(long = "http-header", value_name = "NAME: VALUE", global = true)]
+    pub http_headers: Vec<String>,
+
+    /// Directory for temporary files. Defaults to $TMPDIR.
+    #[arg(long, global = true)]
+    pub temp_dir: Option<PathBuf>,
+
+    /// Refuse images whose tar holds more than this many bytes of files
+    #[arg(long, value_name = "BYTES", global = true)]
+    pub max_image_size: Option<u64>,
+
+    /// Skip device nodes and xattrs when unpacking and keep everything
+    /// owner-writable, for running as an unprivileged CI user
+    #[arg(long, global = true)]
+    pub rootless: bool,
+
+    /// Number of worker threads. Defaults to one per CPU.
+    #[arg(short, long, global = true)]
+    pub jobs: Option<usize>,
+
+    /// Decompress or rewrite at most this many layers at once, to bound
+    /// memory and temp disk I/O. Defaults to one per job.
+    #[arg(long, global = true)]
+    pub max_pipelines: Option<usize>,
+
+    /// Log output format. Verbosity can be tuned with RUST_LOG.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    pub log_format: LogFormat,
+
+    /// Keep extracted and rewritten layers in this directory so an
+    /// interrupted run can be resumed by rerunning with the same directory
+    #[arg(long)]
+    pub work_dir: Option<PathBuf>,
+
     /// Print duplicates and exit without creating deduplicated image
     #[arg(long)]
     pub dry_run: bool,
+
+    /// How duplicates are replaced. Without it, by hardlinks within a layer
+    /// and symlinks across layers. report-only is --dry-run.
+    #[arg(long, value_enum)]
+    pub strategy: Option<Strategy>,
+
+    /// Keep the copy in a canonical location (/usr/lib over a vendor dir),
+    /// one other symlinks point to, or one in the --base-layers, instead of
+    /// the lowest layer's
+    #[arg(long)]
+    pub canonical_originals: bool,
+
+    /// Keep the copy matching this glob pattern, before any other rule.
+    /// Implies --canonical-originals. May be repeated.
+    #[arg(long = "prefer-original", value_name = "PATTERN")]
+    pub preferred_originals: Vec<String>,
+
+    /// Keep originals where the fewest layers change, lowest layers first,
+    /// so registries and build caches still hit on the others
+    #[arg(long, conflicts_with_all = ["canonical_originals", "preferred_originals"])]
+    pub cache_friendly_originals: bool,
+
+    /// Also save the modification plan to this file, as JSON, to review it
+    /// and apply it later with --plan
+    #[arg(long, value_name = "PATH", conflicts_with = "plan")]
+    pub save_plan: Option<PathBuf>,
+
+    /// Apply a plan saved by --save-plan instead of scanning, refusing if
+    /// the image changed since. With --dry-run, only checks that it still
+    /// applies.
+    #[arg(long, value_name = "PATH")]
+    pub plan: Option<PathBuf>,
+
+    /// Also report Python and Node packages installed more than once, per
+    /// package and version. Takes an extra pass over the layers.
+    #[arg(long)]
+    pub packages: bool,
+
+    /// Also report logs, temp files, core dumps and editor or backup files,
+    /// duplicated or not. Takes an extra pass over the layer headers.
+    #[arg(long)]
+    pub junk: bool,
+
+    /// Also report ELF binaries identical but for debug info or build IDs,
+    /// and what stripping them saves. Takes a pass over every layer.
+    #[arg(long)]
+    pub elf: bool,
+
+    /// Also report identical files whose paths differ only in case, e.g.
+    /// Foo.jar and foo.jar, which collide on case-insensitive filesystems
+    #[arg(long)]
+    pub case_insensitive: bool,
+
+    /// Count the first N layers as the base image's and report their
+    /// savings apart from the application layers'
+    #[arg(long, value_name = "N", conflicts_with = "base_image")]
+    pub base_layers: Option<usize>,
+
+    /// Like --base-layers, counting the leading layers the image shares
+    /// with this one, in the same syntax as --image
+    #[arg(long, value_name = "IMAGE")]
+    pub base_image: Option<ImageRef>,
+
+    /// Also write the scanned files, layers, duplicate groups and history
+    /// attribution to this SQLite database, or as a SQL script when the
+    /// path ends in .sql. Writing a database runs the sqlite3 command line
+    /// tool, which must be installed
+    #[arg(long, value_name = "PATH")]
+    pub db: Option<PathBuf>,
+
+    /// Also write the layers, scanned files and duplicates as Parquet files
+    /// to this directory, each row naming the image
+    #[arg(long, value_name = "DIR")]
+    pub parquet: Option<PathBuf>,
+
+    /// sqlite3 command line tool to write --db with, looked up on PATH
+    #[arg(
+        long,
+        value_name = "PROGRAM",
+        default_value = "sqlite3",
+        requires = "db"
+    )]
+    pub sqlite3: String,
+
+    /// Add an entry recording the deduplication and its savings to the
+    /// image history, so `docker history` explains the changed digests
+    #[arg(long)]
+    pub history_entry: bool,
+
+    /// Label the image with org.dedupe.version, org.dedupe.original-digest
+    /// and org.dedupe.bytes-saved, so scanners can tell it was processed
+    #[arg(long)]
+    pub label_savings: bool,
+
+    /// Creation time of the rewritten config and the history entries
+    /// added: the original's, now, or the epoch for reproducible builds
+    #[arg(long, value_enum, default_value_t = Created::Keep, global = true)]
+    pub created: Created,
+
+    /// Write a report of the run in this format
+    #[arg(long, value_enum)]
+    pub report: Option<ReportFormat>,
+
+    /// Report destination, a path or s3://BUCKET/KEY. Defaults to stdout;
+    /// required with --stdout.
+    #[arg(long, requires = "report")]
+    pub report_file: Option<PathBuf>,
+
+    /// Write Prometheus gauges of the run (image and duplicate bytes, files
+    /// scanned, layers rewritten, phase durations) to this textfile, e.g.
+    /// for node_exporter's textfile collector
+    #[arg(long, value_name = "PATH")]
+    pub metrics_file: Option<PathBuf>,
+
+    /// Fail the run when the image's duplicate bytes exceed the ones
+    /// recorded in this file, e.g. .dedupe-baseline.json committed next to
+    /// the Dockerfile, by more than --baseline-margin
+    #[arg(long, value_name = "PATH", conflicts_with = "plan")]
+    pub baseline_file: Option<PathBuf>,
+
+    /// Record this run's duplicate bytes in --baseline-file instead of
+    /// checking against it
+    #[arg(long, requires = "baseline_file")]
+    pub update_baseline: bool,
+
+    /// Growth over the baseline that still passes, in bytes or as a
+    /// percentage of it: 5%
+    #[arg(
+        long,
+        value_name = "BYTES|PERCENT",
+        default_value = "0",
+        requires = "baseline_file"
+    )]
+    pub baseline_margin: Margin,
+
+    /// POST a JSON summary of the run to this URL when it finishes, failed
+    /// or not, e.g. a Slack incoming webhook, which shows its `text`
+    #[arg(long, value_name = "URL")]
+    pub webhook: Option<String>,
+
+    /// Header to send with --webhook, e.g. 'Authorization: Bearer TOKEN'.
+    /// Repeatable.
+    #[arg(
+        long = "webhook-header",
+        value_name = "NAME: VALUE",
+        requires = "webhook"
+    )]
+    pub webhook_headers: Vec<String>,
+
+    /// Write the digest of the manifest written to this file. Needs an oci:,
+    /// dir: or docker:// output, `docker save` archives have no manifest
+    /// digest.
+    #[arg(long, global = true)]
+    pub digest_file: Option<PathBuf>,
+
+    /// Shell command to run once the image is written, e.g. to sign it again
+    /// as rewriting invalidates existing signatures: 'cosign sign --yes
+    /// "$DEDUPE_IMAGE"'. It gets the manifest digest in $DEDUPE_DIGEST and
+    /// the image pinned to it, for registries, in $DEDUPE_IMAGE. Same
+    /// outputs as --digest-file.
+    #[arg(long, global = true, value_name = "COMMAND")]
+    pub post_write: Option<String>,
+
+    /// Read the written image back and check its digests against the
+    /// manifest, the config and what the rewrite reported
+    #[arg(long, global = true)]
+    pub verify_output: bool,
+
+    /// Shell command to run in the written image, failing the run if it
+    /// exits non-zero: 'python -c "import numpy"'. Archives are loaded into
+    /// the local runtime first; the image needs a `sh`.
+    #[arg(long, global = true, value_name = "COMMAND")]
+    pub smoke_test: Option<String>,
+
+    /// Container runtime CLI --smoke-test uses
+    #[arg(long, default_value = "docker", global = true)]
+    pub smoke_runtime: String,
+
+    /// Name the image in written archives, which `docker load`, `ctr images
+    /// import` and `nerdctl load` tag it with, e.g. registry.io/app:1.2.
+    /// Repeatable.
+    #[arg(long = "tag", value_name = "NAME")]
+    pub tags: Vec<String>,
+
+    /// Load the image into a container runtime instead of writing it to
+    /// --output or --stdout. Needs --tag.
+    #[arg(long, value_enum, conflicts_with_all = ["output", "stdout"], requires = "tags")]
+    pub import: Option<Import>,
+
+    /// containerd namespace --import containerd loads into. Kubernetes
+    /// nodes use k8s.io.
+    #[arg(long, default_value = "default")]
+    pub containerd_namespace: String,
+
+    /// Look for artifacts attached to an oci: or docker:// input through
+    /// the OCI referrers API, and list or copy them
+    #[arg(long, value_enum, global = true)]
+    pub referrers: Option<Referrers>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[cfg(feature = "serve")]
+    /// Run as an HTTP service that analyzes and deduplicates submitted images
+    Serve(ServeArgs),
+    #[cfg(feature = "proxy")]
+    /// Run as a registry pull-through proxy that reports (and optionally
+    /// deduplicates) the images pulled through it
+    Proxy(ProxyArgs),
+    /// Check that the image's references resolve and its digests match,
+    /// exiting with an error if not
+    Verify(VerifyArgs),
+    /// Export the merged root filesystem, whiteouts applied, as one tar
+    Flatten(FlattenArgs),
+    /// List the files of the image without hashing anything: what a
+    /// container sees by default, or the entries of the given layers
+    Ls(LsArgs),
+    /// Sum file sizes per directory over what a container sees, or over one
+    /// layer
+    Du(DuArgs),
+    /// List the largest files across all layers, with the history entry
+    /// that added each
+    Top(TopArgs),
+    /// Write the deduplication as a shell script of `ln` commands to add to
+    /// the build, instead of rewriting the image
+    Advise(AdviseArgs),
+    /// Estimate the size of each layer recompressed with zstd, from a
+    /// sample, against its current blob. Needs the zstd command line tool
+    Compression(CompressionArgs),
+    /// Per history entry, bytes added, overwritten by later layers,
+    /// duplicated elsewhere and left in the final image
+    Blame(BlameArgs),
+    /// Find layers of the given images that hold nearly the same files but
+    /// have digests of their own, and what makes them differ
+    Similar(SimilarArgs),
+    /// Print the JSON Schema of a document the tool writes
+    Schema(SchemaArgs),
+    /// Copy one file, resolved through the merged view, or a whole layer out
+    /// of the image
+    Extract(ExtractArgs),
+    /// Merge the layers from the given one up into a single layer, writing
+    /// the image to --output or --stdout
+    Squash(SquashArgs),
+    /// Split one layer into several, by directory or size, writing the image
+    /// to --output or --stdout
+    Split(SplitArgs),
+    /// Run scan, plan and rewrite over the image, or a generated one,
+    /// several times and report each phase's throughput
+    Bench(BenchArgs),
+    /// Export an image from the local daemon, deduplicate it and load it
+    /// back under its tag: `docker dedupe` when installed as a CLI plugin
+    Dedupe(DedupeArgs),
+    /// Describe the tool to the docker CLI, which runs this on every binary
+    /// in its cli-plugins directory
+    #[command(name = "docker-cli-plugin-metadata", hide = true)]
+    DockerCliPluginMetadata,
+    /// Pull and analyze every image the workloads in Kubernetes manifests
+    /// or `kubectl get pods -o json` output run, and total the waste per
+    /// workload and namespace
+    Workloads(WorkloadsArgs),
+    /// Analyze the images of a Docker Compose project: the waste in each
+    /// service's image, and files the services could share
+    Compose(ComposeArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ComposeArgs {
+    /// Compose file. Defaults to compose.yaml, compose.yml,
+    /// docker-compose.yaml or docker-compose.yml, whichever is here.
+    pub file: Option<PathBuf>,
+
+    /// Export the images from this runtime CLI's daemon, e.g. docker or
+    /// podman, instead of pulling them; needed for images the project
+    /// builds
+    #[arg(long)]
+    pub runtime: Option<String>,
+
+    /// Print the report as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct WorkloadsArgs {
+    /// A manifest, a directory of them (.yaml, .yml and .json, searched
+    /// recursively) or a `kubectl get -o json` dump
+    pub path: PathBuf,
+
+    /// Print the report as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DedupeArgs {
+    /// Image in the daemon, e.g. myimage:tag
+    pub reference: String,
+
+    /// Tag the deduplicated image with this instead of moving REFERENCE to
+    /// it
+    #[arg(long)]
+    pub tag: Option<String>,
+
+    /// Container runtime CLI to export and load with. Defaults to the docker
+    /// CLI that ran the plugin, or `docker`
+    #[arg(long)]
+    pub runtime: Option<String>,
+
+    /// Print duplicates and exit without loading anything back
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BenchArgs {
+    /// Times to run the whole pipeline
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u64).range(1..))]
+    pub runs: u64,
+
+    /// Only scan and plan, skipping the rewrite
+    #[arg(long)]
+    pub no_rewrite: bool,
+
+    /// Time gzipping and gunzipping the largest layer instead of the
+    /// pipeline, with the `flate2` backend this build uses
+    #[arg(long, conflicts_with = "no_rewrite")]
+    pub gzip: bool,
+
+    /// Benchmark a generated image of this many layers instead of the
+    /// given one
+    #[arg(long, value_name = "LAYERS")]
+    pub synthetic: Option<usize>,
+
+    /// Files per layer of the generated image, half of them duplicated in
+    /// every layer
+    #[arg(
+        long,
+        value_name = "FILES",
+        default_value_t = 20,
+        requires = "synthetic"
+    )]
+    pub synthetic_files: usize,
+
+    /// Size of each generated file
+    #[arg(long, value_name = "BYTES", default_value_t = 1024 * 1024, requires = "synthetic")]
+    pub synthetic_file_size: usize,
+
+    /// Print the results as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SplitArgs {
+    /// Layer to split, counting from 0
+    #[arg(long)]
+    pub layer: usize,
+
+    /// One layer per directory this many levels below the root
+    #[arg(
+        long,
+        value_name = "DEPTH",
+        required_unless_present = "max_size",
+        conflicts_with = "max_size"
+    )]
+    pub by_dir: Option<usize>,
+
+    /// Layers of at most this many bytes, in archive order
+    #[arg(long, value_name = "BYTES")]
+    pub max_size: Option<u64>,
+}
+
+impl SplitArgs {
+    pub fn split_by(&self) -> SplitBy {
+        match (self.by_dir, self.max_size) {
+            (Some(depth), _) => SplitBy::Dir { depth },
+            (None, Some(size)) => SplitBy::Size(size),
+            (None, None) => unreachable!("clap requires --by-dir or --max-size"),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SquashArgs {
+    /// First layer to merge, counting from 0
+    #[arg(long)]
+    pub from: usize,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ExtractArgs {
+    /// File to copy, following symlinks as a container would
+    #[arg(required_unless_present = "layer", conflicts_with = "layer")]
+    pub path: Option<String>,
+
+    /// Copy this layer as an uncompressed tar instead, counting from 0
+    #[arg(long)]
+    pub layer: Option<usize>,
+
+    /// File to write. Defaults to stdout.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SimilarArgs {
+    /// Images to compare, in the same syntax as --image
+    #[arg(required = true, num_args = 2..)]
+    pub images: Vec<ImageRef>,
+
+    /// Share of files two layers must have in common, from 0 to 1
+    #[arg(long, default_value_t = 0.8)]
+    pub threshold: f64,
+
+    /// Print one JSON object per cluster
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SchemaArgs {
+    #[arg(value_enum)]
+    pub document: Document,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CompressionArgs {
+    /// zstd compression level
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(i32).range(1..=22))]
+    pub level: i32,
+
+    /// Bytes of each layer to compress, spread over the layer
+    #[arg(long, value_name = "BYTES", default_value_t = 64 * 1024 * 1024)]
+    pub sample: usize,
+
+    /// zstd command line tool to compress with, looked up on PATH
+    #[arg(long, value_name = "PROGRAM", default_value = "zstd")]
+    pub zstd: String,
+
+    /// Print one JSON object per layer
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct AdviseArgs {
+    /// Script to write. Defaults to stdout.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BlameArgs {
+    /// Print one JSON object per history entry
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TopArgs {
+    /// Number of files to list
+    #[arg(short = 'n', long, default_value_t = 20)]
+    pub count: usize,
+
+    /// Also gzip a sample of each file, to show how much of it would go
+    /// over the wire
+    #[arg(long)]
+    pub compressibility: bool,
+
+    /// Print one JSON object per file
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DuArgs {
+    /// Only count the files of this layer, counting from 0
+    #[arg(long)]
+    pub layer: Option<usize>,
+
+    /// Deepest directory level to report, the root being 0
+    #[arg(short, long, default_value_t = 3)]
+    pub depth: usize,
+
+    /// Largest directories first instead of path order
+    #[arg(long)]
+    pub by_size: bool,
+
+    /// Print one JSON object per directory
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LsArgs {
+    /// Only list the entries of this layer, counting from 0
+    #[arg(long, conflicts_with = "all")]
+    pub layer: Option<usize>,
+
+    /// List the entries of every layer, including hidden ones and whiteouts
+    #[arg(long)]
+    pub all: bool,
+
+    /// Print one JSON object per entry
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct FlattenArgs {
+    /// Tar to write. Defaults to stdout.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+    /// Print the result as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[cfg(feature = "serve")]
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub listen: String,
+
+    /// Keep uploads, reports and output images here. Defaults to a temp dir.
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Number of images processed at the same time
+    #[arg(long, default_value_t = 1)]
+    pub workers: usize,
+
+    /// Refuse uploaded tarballs over this many bytes with 413
+    #[arg(long, value_name = "BYTES", default_value_t = crate::serve::DEFAULT_MAX_UPLOAD)]
+    pub max_upload: u64,
+}
+
+#[cfg(feature = "proxy")]
+#[derive(clap::Args, Debug)]
+pub struct ProxyArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:5001")]
+    pub listen: String,
+
+    /// Registry to forward pulls to
+    #[arg(long, default_value = "docker.io")]
+    pub upstream: String,
+
+    /// Keep stats and deduplicated variants here. Defaults to a temp dir.
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Serve a deduplicated copy of TAG when TAG<SUFFIX> is pulled, e.g. -dedup
+    #[arg(long, value_name = "SUFFIX")]
+    pub dedupe_suffix: Option<String>,
+
+    /// Number of images analyzed at the same time
+    #[arg(long, default_value_t = 1)]
+    pub workers: usize,
+}
+
+/// `NAME: VALUE` headers split into names and values.
+fn split_headers(headers: &[String]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter_map(|header| header.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
 }
 
 impl Args {
+    pub fn analyzer_options(&self) -> Result<AnalyzerOptions> {
+        let mut builder = AnalyzerBuilder::default()
+            .min_size(self.min_size)
+            .compression(!self.no_compression)
+            .hash(self.hash)
+            .excludes(self.excludes.iter().cloned())
+            .allowed_paths(self.allowed_paths.iter().cloned())
+            .scopes(self.scope.iter().cloned())
+            .dedupe_volumes(self.dedupe_volumes)
+            .ignore_file(!self.no_ignore_file)
+            .history_entry(self.history_entry)
+            .label_savings(self.label_savings)
+            .created(self.created)
+            .unpack_limits(UnpackLimits {
+                max_bytes: self.max_image_size,
+                ..UnpackLimits::default()
+            })
+            .rootless(self.rootless)
+            .repo_tags(self.tags.iter().cloned());
+        for (name, value) in split_headers(&self.http_headers) {
+            builder = builder.http_header(name, value);
+        }
+        if let Some(strategy) = self.strategy {
+            builder = builder.strategy(strategy);
+        }
+        if self.canonical_originals || !self.preferred_originals.is_empty() {
+            builder = builder.policy(Arc::new(CanonicalPolicy::new(
+                &self.preferred_originals,
+                self.base_layers.unwrap_or(0),
+            )?));
+        }
+        if self.cache_friendly_originals {
+            builder = builder.policy(Arc::new(CacheFriendlyPolicy));
+        }
+        if let Some(jobs) = self.jobs {
+            builder = builder.threads(jobs);
+        }
+        if let Some(pipelines) = self.max_pipelines {
+            builder = builder.max_pipelines(pipelines);
+        }
+        if let Some(dir) = &self.temp_dir {
+            builder = builder.temp_dir(dir);
+        }
+        if let Some(dir) = &self.work_dir {
+            builder = builder.work_dir(dir);
+        }
+        Ok(builder.options())
+    }
+
+    /// --webhook-header values split into names and values.
+    pub fn webhook_headers(&self) -> Vec<(String, String)> {
+        split_headers(&self.webhook_headers)
+    }
+
+    /// --dry-run, or the report-only strategy.
+    pub fn report_only(&self) -> bool {
+        self.dry_run || self.strategy == Some(Strategy::ReportOnly)
+    }
+
     pub fn validate(&self) -> Result<()> {
-        if !self.dry_run && self.output.is_none() && !self.stdout {
-            return Err(anyhow!("Run must use --dry-run, --output or --stdout"));
+        let has_manifest =
+            matches!(&self.output, Some(output) if !matches!(output, ImageRef::DockerArchive(_)));
+        if (self.digest_file.is_some() || self.post_write.is_some()) && !has_manifest {
+            return Err(DedupeError::InvalidOption(
+                "--digest-file and --post-write need an oci:, dir: or docker:// --output"
+                    .to_string(),
+            ));
+        }
+        if self.verify_output && self.output.is_none() {
+            return Err(DedupeError::InvalidOption(
+                "--verify-output needs an --output".to_string(),
+            ));
+        }
+        let runnable = matches!(
+            &self.output,
+            Some(ImageRef::DockerArchive(_) | ImageRef::Docker(_))
+        );
+        if self.smoke_test.is_some() && !runnable {
+            return Err(DedupeError::InvalidOption(
+                "--smoke-test needs a docker-archive: or docker:// --output".to_string(),
+            ));
+        }
+        let holds_referrers = matches!(
+            &self.output,
+            Some(ImageRef::Oci { .. } | ImageRef::Docker(_))
+        );
+        if self.referrers == Some(Referrers::Copy) && !holds_referrers {
+            return Err(DedupeError::InvalidOption(
+                "--referrers copy needs an oci: or docker:// --output".to_string(),
+            ));
+        }
+        for (option, headers) in [
+            ("--webhook-header", &self.webhook_headers),
+            ("--http-header", &self.http_headers),
+        ] {
+            if let Some(header) = headers.iter().find(|h| !h.contains(':')) {
+                return Err(DedupeError::InvalidOption(format!(
+                    "{} {:?} isn't NAME: VALUE",
+                    option, header
+                )));
+            }
+        }
+        if let Some(output @ ImageRef::Url(_)) = &self.output {
+            return Err(DedupeError::InvalidOption(format!(
+                "--output {} can't be written to, URLs are read only",
+                output
+            )));
+        }
+        match &self.command {
+            Some(Command::Squash(_) | Command::Split(_))
+                if self.output.is_none() && !self.stdout =>
+            {
+                return Err(DedupeError::InvalidOption(
+                    "squash and split must use --output or --stdout".to_string(),
+                ));
+            }
+            Some(_) => return Ok(()),
+            None => {}
+        }
+        if self.strategy == Some(Strategy::SharedLayer) && self.save_plan.is_some() {
+            return Err(DedupeError::InvalidOption(
+                "--save-plan can't save plans of --strategy shared-layer".to_string(),
+            ));
+        }
+        if !self.report_only() && self.output.is_none() && !self.stdout && self.import.is_none() {
+            return Err(DedupeError::InvalidOption(
+                "Run must use --dry-run, --output, --stdout or --import".to_string(),
+            ));
+        }
+        if self.report.is_some() && self.stdout && self.report_file.is_none() {
+            return Err(DedupeError::InvalidOption(
+                "--report with --stdout requires --report-file".to_string(),
+            ));
         }
         Ok(())
     }
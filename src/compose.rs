@@ -0,0 +1,501 @@
+//! The images of a Docker Compose project: duplicate waste within each
+//! service's image, and files the services' images all carry in layers of
+//! their own, which a common base layer would store and pull once.
+//!
+//! Each image keeps the copy of a file in its lowest layer, as
+//! deduplicating it would, so what the project can share is one copy
+//! short of those lowest layers; images with the layer itself in common
+//! already share it.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::analyzer::FileInfo;
+use crate::cancel;
+use crate::error::{DedupeError, Result};
+use crate::yaml;
+
+/// Shared files [`ComposeReport::lines`] lists at most
+const MAX_LISTED: usize = 20;
+
+/// One service of the project.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Service {
+    pub name: String,
+    /// Its `image`, variables substituted, or the `<project>-<service>`
+    /// Compose names images it builds without one
+    pub image: String,
+    /// Has a `build` section, so the image may only exist locally
+    pub built: bool,
+}
+
+/// What the caller's scan of one image found.
+#[derive(Debug, Clone)]
+pub struct ScannedImage {
+    pub diff_ids: Vec<String>,
+    /// Every file scanned, see [`crate::Analyzer::scan_files`]
+    pub files: Vec<FileInfo>,
+    pub duplicate_groups: usize,
+    pub duplicate_bytes: u64,
+}
+
+/// Waste within one service's image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceWaste {
+    pub service: String,
+    pub image: String,
+    pub duplicate_groups: usize,
+    pub duplicate_bytes: u64,
+    /// Why the image couldn't be scanned; it then counts for nothing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A file several services' images hold in layers of their own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedContent {
+    pub hash: String,
+    /// Where the first service has it
+    pub path: String,
+    pub size: u64,
+    /// Distinct layers holding the copy each image keeps
+    pub copies: usize,
+    pub services: Vec<String>,
+    /// `size` times one copy fewer
+    pub bytes: u64,
+}
+
+/// Outcome of [`compose_report`], largest first throughout.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComposeReport {
+    /// See [`crate::SCHEMA_VERSION`]
+    pub schema_version: u32,
+    pub project: String,
+    pub services: Vec<ServiceWaste>,
+    pub shared: Vec<SharedContent>,
+    /// Sum of `bytes` over `shared`
+    pub shared_bytes: u64,
+}
+
+/// `value` with `$VAR`, `${VAR}`, `${VAR:-default}` and `${VAR-default}`
+/// substituted from `lookup`, as Compose does. Unset variables without a
+/// default are empty; `$$` is a `$`.
+pub fn interpolate(value: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(at) = rest.find('$') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+        } else if let Some(braced) = rest.strip_prefix('{')
+            && let Some(end) = braced.find('}')
+        {
+            let expression = &braced[..end];
+            rest = &braced[end + 1..];
+            let (name, default, if_empty) = match expression.split_once(":-") {
+                Some((name, default)) => (name, default, true),
+                None => match expression.split_once('-') {
+                    Some((name, default)) => (name, default, false),
+                    None => (expression.split([':', '?']).next().unwrap_or(""), "", false),
+                },
+            };
+            match lookup(name) {
+                Some(value) if !(if_empty && value.is_empty()) => out.push_str(&value),
+                _ => out.push_str(default),
+            }
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                out.push('$');
+            } else {
+                out.push_str(&lookup(&rest[..end]).unwrap_or_default());
+            }
+            rest = &rest[end..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// `name` as Compose normalizes a project name: lowercase letters, digits,
+/// dashes and underscores.
+fn project_name(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        .collect()
+}
+
+/// The project name and services of a Compose file. `directory` names the
+/// project when the file doesn't; `lookup` resolves variables. YAML the
+/// line-based reader can't follow, e.g. a flow-style service, is an error.
+pub fn parse_compose(
+    contents: &str,
+    directory: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<(String, Vec<Service>)> {
+    let mut project = None;
+    // Name, image and whether it builds, in file order
+    let mut services: Vec<(String, Option<String>, bool)> = Vec::new();
+    let mut in_services = false;
+    let mut service_indent = None;
+    let mut field_indent = None;
+    for line in yaml::lines(contents)? {
+        if line.indent == 0 {
+            in_services = line.key == "services";
+            if line.key == "name" {
+                project = Some(interpolate(line.value, &lookup));
+            }
+            continue;
+        }
+        if !in_services {
+            continue;
+        }
+        if *service_indent.get_or_insert(line.indent) == line.indent {
+            services.push((line.key.to_string(), None, false));
+            field_indent = None;
+        } else if let Some((_, image, built)) = services.last_mut()
+            && *field_indent.get_or_insert(line.indent) == line.indent
+        {
+            match line.key {
+                "image" => *image = Some(interpolate(line.value, &lookup)),
+                "build" => *built = true,
+                _ => {}
+            }
+        }
+    }
+    let project = project_name(project.as_deref().unwrap_or(directory));
+    let services = services
+        .into_iter()
+        .filter_map(|(name, image, built)| {
+            let image = match image {
+                Some(image) => image,
+                None if built => format!("{}-{}", project, name),
+                None => {
+                    warn!("Service {} has neither an image nor a build", name);
+                    return None;
+                }
+            };
+            Some(Service { name, image, built })
+        })
+        .collect();
+    Ok((project, services))
+}
+
+/// `KEY=VALUE` lines of the `.env` file Compose reads next to the file.
+fn dotenv(path: &Path) -> BTreeMap<String, String> {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return BTreeMap::new();
+    };
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            (key.trim().to_string(), value.to_string())
+        })
+        .collect()
+}
+
+/// Reads the Compose file at `path`, substituting variables from the
+/// environment and then from the `.env` file beside it.
+pub fn read_compose(path: &Path) -> Result<(String, Vec<Service>)> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| DedupeError::Manifest(format!("{}: {}", path.display(), e)))?;
+    let directory = path
+        .canonicalize()?
+        .parent()
+        .and_then(|dir| dir.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let env_file = dotenv(&path.with_file_name(".env"));
+    parse_compose(&contents, &directory, |name| {
+        std::env::var(name)
+            .ok()
+            .or_else(|| env_file.get(name).cloned())
+    })
+    .map_err(yaml::in_file(path))
+}
+
+/// Runs `scan` once on each image `services` use and reports what each
+/// service wastes and what the services could share. Images that fail are
+/// reported as such rather than ending the run, unless it was cancelled.
+pub fn compose_report<E: Display>(
+    project: &str,
+    services: &[Service],
+    mut scan: impl FnMut(&str) -> Result<ScannedImage, E>,
+) -> Result<ComposeReport> {
+    let mut scanned: BTreeMap<&str, std::result::Result<ScannedImage, String>> = BTreeMap::new();
+    for service in services {
+        if scanned.contains_key(service.image.as_str()) {
+            continue;
+        }
+        cancel::check()?;
+        let result = scan(&service.image).map_err(|e| e.to_string());
+        if let Err(e) = &result {
+            cancel::check()?;
+            warn!("Skipping {}: {}", service.image, e);
+        }
+        scanned.insert(&service.image, result);
+    }
+
+    let mut service_waste: Vec<ServiceWaste> = services
+        .iter()
+        .map(|service| {
+            let (groups, bytes, error) = match &scanned[service.image.as_str()] {
+                Ok(image) => (image.duplicate_groups, image.duplicate_bytes, None),
+                Err(e) => (0, 0, Some(e.clone())),
+            };
+            ServiceWaste {
+                service: service.name.clone(),
+                image: service.image.clone(),
+                duplicate_groups: groups,
+                duplicate_bytes: bytes,
+                error,
+            }
+        })
+        .collect();
+    service_waste.sort_by_key(|s| Reverse(s.duplicate_bytes));
+
+    // Per content hash, the layer each image keeps its copy in, with the
+    // services running the image
+    let mut kept: BTreeMap<&str, (&FileInfo, BTreeMap<&str, BTreeSet<&str>>)> = BTreeMap::new();
+    for (image, result) in &scanned {
+        let Ok(scan) = result else {
+            continue;
+        };
+        let mut lowest: BTreeMap<&str, &FileInfo> = BTreeMap::new();
+        for file in &scan.files {
+            let entry = lowest.entry(&file.hash).or_insert(file);
+            if file.layer_index < entry.layer_index {
+                *entry = file;
+            }
+        }
+        let names = services.iter().filter(|s| s.image == *image);
+        for (hash, file) in lowest {
+            let Some(diff_id) = scan.diff_ids.get(file.layer_index) else {
+                continue;
+            };
+            let (_, layers) = kept.entry(hash).or_insert((file, BTreeMap::new()));
+            layers
+                .entry(diff_id)
+                .or_default()
+                .extend(names.clone().map(|s| s.name.as_str()));
+        }
+    }
+    let mut shared: Vec<SharedContent> = kept
+        .into_iter()
+        .filter(|(_, (_, layers))| layers.len() > 1)
+        .map(|(hash, (file, layers))| {
+            let services: BTreeSet<&str> = layers.values().flatten().copied().collect();
+            SharedContent {
+                hash: hash.to_string(),
+                path: file.path.clone(),
+                size: file.size,
+                copies: layers.len(),
+                services: services.into_iter().map(str::to_string).collect(),
+                bytes: file.size * (layers.len() as u64 - 1),
+            }
+        })
+        .collect();
+    shared.sort_by_key(|s| Reverse(s.bytes));
+
+    Ok(ComposeReport {
+        schema_version: crate::SCHEMA_VERSION,
+        project: project.to_string(),
+        services: service_waste,
+        shared_bytes: shared.iter().map(|s| s.bytes).sum(),
+        shared,
+    })
+}
+
+impl ComposeReport {
+    /// Services, then the files they could share, the largest
+    /// [`MAX_LISTED`] of them.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("Services of {}:", self.project)];
+        lines.extend(self.services.iter().map(|s| match &s.error {
+            Some(error) => format!("  {:>10}  {} {} ({})", "-", s.service, s.image, error),
+            None => format!(
+                "  {:>10}  {} {} ({} groups)",
+                format_size(s.duplicate_bytes, BINARY),
+                s.service,
+                s.image,
+                s.duplicate_groups
+            ),
+        }));
+        lines.push(format!(
+            "Shared across services: {} in {} files",
+            format_size(self.shared_bytes, BINARY),
+            self.shared.len()
+        ));
+        lines.extend(self.shared.iter().take(MAX_LISTED).map(|s| {
+            format!(
+                "  {:>10}  {} ({} copies of {}, in {})",
+                format_size(s.bytes, BINARY),
+                s.path,
+                s.copies,
+                format_size(s.size, BINARY),
+                s.services.join(", ")
+            )
+        }));
+        if self.shared.len() > MAX_LISTED {
+            lines.push(format!("  ... {} more", self.shared.len() - MAX_LISTED));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "TAG" => Some("1.4".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_interpolate() {
+        assert_eq!(interpolate("web:$TAG", lookup), "web:1.4");
+        assert_eq!(interpolate("web:${TAG}-slim", lookup), "web:1.4-slim");
+        assert_eq!(interpolate("web:${UNSET:-latest}", lookup), "web:latest");
+        assert_eq!(interpolate("web:${EMPTY:-latest}", lookup), "web:latest");
+        assert_eq!(interpolate("web:${EMPTY-latest}", lookup), "web:");
+        assert_eq!(interpolate("cost: $$5", lookup), "cost: $5");
+    }
+
+    #[test]
+    fn test_parse_compose() {
+        let compose = r#"
+services:
+  web:
+    image: "registry.io/web:${TAG}"  # the app
+    environment:
+      image: not-an-image
+  worker:
+    build:
+      context: ./worker
+  db:
+    image: postgres:16
+    ports:
+      - "5432:5432"
+volumes:
+  data:
+"#;
+        let (project, services) = parse_compose(compose, "My Shop", lookup).unwrap();
+        assert_eq!(project, "myshop");
+        let images: Vec<(&str, &str, bool)> = services
+            .iter()
+            .map(|s| (s.name.as_str(), s.image.as_str(), s.built))
+            .collect();
+        assert_eq!(
+            images,
+            [
+                ("web", "registry.io/web:1.4", false),
+                ("worker", "myshop-worker", true),
+                ("db", "postgres:16", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_compose_unsupported() {
+        let scripted = r#"
+services:
+  web:
+    command: >-
+      sh -c 'cat <<EOF
+      image: not-an-image
+      EOF'
+    image: web:${TAG}
+    healthcheck:
+      test: ["CMD", "curl", "-f", "http://localhost"]
+"#;
+        let (_, services) = parse_compose(scripted, "shop", lookup).unwrap();
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].image, "web:1.4");
+
+        let flow = "services:\n  web:\n    image: web:1.4\n  db: {image: postgres:16}\n";
+        match parse_compose(flow, "shop", lookup) {
+            Err(DedupeError::Yaml(message)) => {
+                assert_eq!(message, "line 4: flow mappings aren't supported")
+            }
+            other => panic!("expected a YAML error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compose_report() {
+        let file = |path: &str, hash: &str, layer_index: usize| FileInfo {
+            path: path.to_string(),
+            size: 100,
+            hash: hash.to_string(),
+            layer_index,
+            provenance: None,
+        };
+        let service = |name: &str, image: &str| Service {
+            name: name.to_string(),
+            image: image.to_string(),
+            built: false,
+        };
+        let services = [
+            service("web", "web"),
+            service("worker", "worker"),
+            service("cron", "worker"),
+            service("db", "private"),
+        ];
+        let report = compose_report("shop", &services, |image| match image {
+            // Both on the same base layer, with the same runtime installed
+            // on top of it by each
+            "web" => Ok(ScannedImage {
+                diff_ids: vec!["base".to_string(), "web-app".to_string()],
+                files: vec![
+                    file("lib/libc.so", "libc", 0),
+                    file("app/runtime.so", "runtime", 1),
+                    file("app/copy.so", "runtime", 1),
+                ],
+                duplicate_groups: 1,
+                duplicate_bytes: 100,
+            }),
+            "worker" => Ok(ScannedImage {
+                diff_ids: vec!["base".to_string(), "worker-app".to_string()],
+                files: vec![
+                    file("lib/libc.so", "libc", 0),
+                    file("srv/runtime.so", "runtime", 1),
+                ],
+                duplicate_groups: 0,
+                duplicate_bytes: 0,
+            }),
+            _ => Err("unauthorized"),
+        })
+        .unwrap();
+        assert_eq!(report.services.len(), 4);
+        assert_eq!(report.services[0].service, "web");
+        assert!(report.services.iter().any(|s| s.error.is_some()));
+        assert_eq!(report.shared.len(), 1);
+        assert_eq!(report.shared[0].hash, "runtime");
+        assert_eq!(report.shared[0].services, ["cron", "web", "worker"]);
+        assert_eq!(report.shared_bytes, 100);
+    }
+}
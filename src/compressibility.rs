@@ -0,0 +1,179 @@
+//! How well layer data compresses, to weigh switching layers from gzip to
+//! zstd against deduplicating them. Nothing is rewritten: a sample spread
+//! over each layer is compressed and the ratio projected onto the whole
+//! layer. The same goes for single large files, whose duplicates cost far
+//! less on the wire when they gzip away.
+//!
+//! zstd runs through its command line tool, which must be installed.
+
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+use crate::cancel;
+use crate::error::{IoResultExt, Result, spawn_error};
+
+/// Size of the chunks a sample is made of
+const CHUNK_SIZE: usize = 1024 * 1024;
+/// Bytes of a single file [`gzip_ratio`] is measured on at most
+pub const FILE_SAMPLE_SIZE: usize = 4 * CHUNK_SIZE;
+
+/// Chunks of a stream spread evenly over it, at most `limit` bytes of them
+/// whatever the stream's length: whenever the sample is full every other
+/// chunk is dropped and only every other one taken from then on.
+pub fn sample_stream<R: Read>(mut reader: R, limit: usize) -> Result<(Vec<u8>, u64)> {
+    let max_chunks = (limit / CHUNK_SIZE).max(1);
+    let mut chunks: Vec<Vec<u8>> = Vec::new();
+    let (mut stride, mut index, mut total) = (1u64, 0u64, 0u64);
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    loop {
+        cancel::check()?;
+        let mut filled = 0;
+        while filled < CHUNK_SIZE {
+            let n = reader.read(&mut chunk[filled..])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if filled == 0 {
+            break;
+        }
+        total += filled as u64;
+        if index % stride == 0 {
+            chunks.push(chunk[..filled].to_vec());
+            if chunks.len() > max_chunks {
+                chunks = chunks.into_iter().step_by(2).collect();
+                stride *= 2;
+            }
+        }
+        index += 1;
+    }
+    Ok((chunks.concat(), total))
+}
+
+/// Bytes `data` compresses to with `zstd -<level>`, run as `program`.
+/// [`crate::DedupeError::MissingTool`] if there's no such program.
+pub fn zstd_size(data: &[u8], level: i32, program: &str) -> Result<u64> {
+    let mut command = Command::new(program);
+    if level > 19 {
+        command.arg("--ultra");
+    }
+    let mut child = command
+        .args([&format!("-{}", level), "-c", "-q"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| spawn_error(e, program, "install zstd or point --zstd at it"))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let size = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(data));
+        let size = io::copy(&mut stdout, &mut io::sink());
+        writer
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("writer panicked")))?;
+        size
+    })
+    .with_context(|| format!("Failed to compress with {}", program))?;
+    let status = child
+        .wait()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !status.success() {
+        return Err(io::Error::other(format!("exited with {}", status)))
+            .with_context(|| format!("Failed to compress with {}", program));
+    }
+    Ok(size)
+}
+
+/// Compressed size over original size of `data` with gzip at its default
+/// level, the way layers are usually pushed. 1 for empty data.
+pub fn gzip_ratio(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 1.0;
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    if encoder.write_all(data).is_err() {
+        return 1.0;
+    }
+    let compressed = encoder.finish().map(|c| c.len()).unwrap_or(data.len());
+    compressed as f64 / data.len() as f64
+}
+
+/// Projected zstd size of one layer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerCompressionEstimate {
+    pub layer_index: usize,
+    /// Size of the blob as stored, gzipped or not
+    pub blob_bytes: u64,
+    pub uncompressed_bytes: u64,
+    pub sampled_bytes: u64,
+    /// Uncompressed size times the sample's zstd ratio
+    pub zstd_bytes: u64,
+}
+
+impl LayerCompressionEstimate {
+    /// Bytes switching would save on the wire, negative when zstd is larger.
+    pub fn change(&self) -> i64 {
+        self.zstd_bytes as i64 - self.blob_bytes as i64
+    }
+}
+
+/// `sampled` bytes compressing to `compressed`, scaled up to `total`.
+pub fn project(total: u64, sampled: u64, compressed: u64) -> u64 {
+    if sampled == 0 {
+        return 0;
+    }
+    (total as f64 * compressed as f64 / sampled as f64).round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_stays_bounded_and_spread() {
+        let data: Vec<u8> = (0..10 * CHUNK_SIZE)
+            .map(|i| (i / CHUNK_SIZE) as u8)
+            .collect();
+        let (sample, total) = sample_stream(data.as_slice(), 3 * CHUNK_SIZE).unwrap();
+        assert_eq!(total, data.len() as u64);
+        assert!(sample.len() <= 3 * CHUNK_SIZE);
+        // Chunks from the start and the second half both made it in
+        assert_eq!(sample[0], 0);
+        assert!(*sample.last().unwrap() >= 5);
+
+        let (sample, total) = sample_stream(&b"short"[..], 3 * CHUNK_SIZE).unwrap();
+        assert_eq!((sample.as_slice(), total), (&b"short"[..], 5));
+        assert_eq!(project(100, 10, 5), 50);
+    }
+
+    #[test]
+    fn test_missing_zstd_is_named() {
+        let err = zstd_size(b"data", 3, "zstd-not-installed").unwrap_err();
+        assert!(matches!(
+            &err,
+            crate::DedupeError::MissingTool { program, .. } if program == "zstd-not-installed"
+        ));
+        assert!(err.to_string().contains("--zstd"));
+    }
+
+    #[test]
+    fn test_gzip_ratio() {
+        assert!(gzip_ratio(&[0u8; 100_000]) < 0.01);
+        let mut state = 1u64;
+        let noise: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect();
+        assert!(gzip_ratio(&noise) > 0.99);
+        assert_eq!(gzip_ratio(&[]), 1.0);
+    }
+}
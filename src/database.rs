@@ -0,0 +1,383 @@
+//! The whole inventory of a scan as a small relational schema, for SQL over
+//! large analyses: joins with other inventories, trends across builds.
+//!
+//! ```text
+//! layers(layer_index, diff_id, blob, format, created, created_by)
+//! files(layer_index, path, size, hash)
+//! duplicate_groups(group_id, hash, size, savings, original_layer, original_path)
+//! duplicates(group_id, layer_index, path)
+//! history(history_index, layer_index, created_by, added, overwritten, duplicated, net)
+//! ```
+//!
+//! Written as a SQL script, fed to the sqlite3 command line tool for
+//! a database, so writing one needs `sqlite3` installed. The script drops
+//! the tables first, so it can be loaded into the same database again.
+//!
+//! For fleet-wide analyses [`write_parquet`] writes `layers`, `files` and
+//! `duplicates` as Parquet files instead, each row carrying the image it
+//! comes from so the files of many runs can be queried as one table.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::Analyzer;
+use crate::analyzer::{DuplicateInfo, FileInfo, LayerFormat};
+use crate::attribution::StepWaste;
+use crate::error::{IoResultExt, Result, spawn_error};
+use crate::parquet::{self, Column};
+use crate::schemas::layer_history;
+
+const SCHEMA: &str = "\
+DROP TABLE IF EXISTS duplicates;
+DROP TABLE IF EXISTS duplicate_groups;
+DROP TABLE IF EXISTS files;
+DROP TABLE IF EXISTS history;
+DROP TABLE IF EXISTS layers;
+CREATE TABLE layers (
+  layer_index INTEGER PRIMARY KEY,
+  diff_id TEXT NOT NULL,
+  blob TEXT NOT NULL,
+  format TEXT NOT NULL,
+  created TEXT,
+  created_by TEXT
+);
+CREATE TABLE files (
+  layer_index INTEGER NOT NULL REFERENCES layers,
+  path TEXT NOT NULL,
+  size INTEGER NOT NULL,
+  hash TEXT NOT NULL
+);
+CREATE TABLE duplicate_groups (
+  group_id INTEGER PRIMARY KEY,
+  hash TEXT NOT NULL,
+  size INTEGER NOT NULL,
+  savings INTEGER NOT NULL,
+  original_layer INTEGER NOT NULL REFERENCES layers,
+  original_path TEXT NOT NULL
+);
+CREATE TABLE duplicates (
+  group_id INTEGER NOT NULL REFERENCES duplicate_groups,
+  layer_index INTEGER NOT NULL REFERENCES layers,
+  path TEXT NOT NULL
+);
+CREATE TABLE history (
+  history_index INTEGER PRIMARY KEY,
+  layer_index INTEGER REFERENCES layers,
+  created_by TEXT NOT NULL,
+  added INTEGER NOT NULL,
+  overwritten INTEGER NOT NULL,
+  duplicated INTEGER NOT NULL,
+  net INTEGER NOT NULL
+);
+";
+
+fn format_name(format: LayerFormat) -> &'static str {
+    match format {
+        LayerFormat::Tar => "tar",
+        LayerFormat::Encrypted => "encrypted",
+        LayerFormat::Unsupported => "unsupported",
+        LayerFormat::Empty => "empty",
+    }
+}
+
+/// `value` as a SQL string literal. A literal can't hold NUL, which the
+/// sqlite3 tool would take for the end of the script, so those are
+/// spliced in with `char(0)`.
+fn quote(value: &str) -> String {
+    format!(
+        "'{}'",
+        value.replace('\'', "''").replace('\0', "' || char(0) || '")
+    )
+}
+
+/// Writes the SQL script creating and filling the tables. `files` are those
+/// [`Analyzer::scan_files`] found, `duplicates` their groups and `steps`
+/// the ledger of [`Analyzer::attribute_waste`].
+pub fn write_sql<W: Write>(
+    mut writer: W,
+    analyzer: &Analyzer,
+    files: &[FileInfo],
+    duplicates: &[DuplicateInfo],
+    steps: &[StepWaste],
+) -> io::Result<()> {
+    writeln!(writer, "PRAGMA user_version = {};", crate::SCHEMA_VERSION)?;
+    writeln!(writer, "BEGIN;")?;
+    writer.write_all(SCHEMA.as_bytes())?;
+    let history = &analyzer.config().history;
+    let blobs = &analyzer.manifest().layers;
+    for layer in &analyzer.layers {
+        let step = layer_history(history, layer.layer_index);
+        writeln!(
+            writer,
+            "INSERT INTO layers VALUES ({}, {}, {}, {}, {}, {});",
+            layer.layer_index,
+            quote(&layer.hash),
+            quote(blobs.get(layer.layer_index).map_or("", String::as_str)),
+            quote(format_name(layer.format)),
+            step.map_or("NULL".to_string(), |s| quote(&s.created)),
+            step.map_or("NULL".to_string(), |s| quote(&s.created_by))
+        )?;
+    }
+    for file in files {
+        writeln!(
+            writer,
+            "INSERT INTO files VALUES ({}, {}, {}, {});",
+            file.layer_index,
+            quote(&file.path),
+            file.size,
+            quote(&file.hash)
+        )?;
+    }
+    for (group_id, group) in duplicates.iter().enumerate() {
+        writeln!(
+            writer,
+            "INSERT INTO duplicate_groups VALUES ({}, {}, {}, {}, {}, {});",
+            group_id,
+            quote(&group.original.hash),
+            group.original.size,
+            group.total_savings,
+            group.original.layer_index,
+            quote(&group.original.path)
+        )?;
+        for duplicate in &group.duplicates {
+            writeln!(
+                writer,
+                "INSERT INTO duplicates VALUES ({}, {}, {});",
+                group_id,
+                duplicate.layer_index,
+                quote(&duplicate.path)
+            )?;
+        }
+    }
+    for step in steps {
+        writeln!(
+            writer,
+            "INSERT INTO history VALUES ({}, {}, {}, {}, {}, {}, {});",
+            step.history_index,
+            step.layer_index
+                .map_or("NULL".to_string(), |index| index.to_string()),
+            quote(&step.created_by),
+            step.added,
+            step.overwritten,
+            step.duplicated,
+            step.net
+        )?;
+    }
+    writeln!(writer, "CREATE INDEX files_hash ON files (hash);")?;
+    writeln!(writer, "COMMIT;")?;
+    writer.flush()
+}
+
+/// Writes the inventory to the SQLite database at `path` through `program`,
+/// the sqlite3 command line tool, or as the SQL script itself when `path`
+/// ends in `.sql`.
+pub fn write_database(
+    path: &Path,
+    program: &str,
+    analyzer: &Analyzer,
+    files: &[FileInfo],
+    duplicates: &[DuplicateInfo],
+    steps: &[StepWaste],
+) -> Result<()> {
+    if path.extension().is_some_and(|ext| ext == "sql") {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        return write_sql(BufWriter::new(file), analyzer, files, duplicates, steps)
+            .with_context(|| format!("Failed to write {}", path.display()));
+    }
+    let mut child = Command::new(program)
+        .arg("-bail")
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            spawn_error(
+                e,
+                program,
+                "install sqlite3, point --sqlite3 at it, or write a .sql script instead",
+            )
+        })?;
+    let stdin = child.stdin.take().expect("stdin is piped");
+    let written = write_sql(BufWriter::new(stdin), analyzer, files, duplicates, steps);
+    let status = child
+        .wait()
+        .with_context(|| format!("Failed to run {}", program))?;
+    written.with_context(|| format!("Failed to write {}", path.display()))?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{} exited with {}",
+            program, status
+        )))
+        .with_context(|| format!("Failed to write {}", path.display()));
+    }
+    Ok(())
+}
+
+/// Writes `layers.parquet`, `files.parquet` and `duplicates.parquet` to
+/// `dir`, creating it if needed. `image` names the image in every row;
+/// `duplicates` has a row per copy, originals included, by group.
+pub fn write_parquet(
+    dir: &Path,
+    image: &str,
+    analyzer: &Analyzer,
+    files: &[FileInfo],
+    duplicates: &[DuplicateInfo],
+) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let write = |name: &str, columns: Vec<(&str, Column)>| -> Result<()> {
+        let path = dir.join(name);
+        let file =
+            File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        parquet::write_table(BufWriter::new(file), &columns)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    };
+    let images = |rows: usize| Column::Utf8(vec![image.to_string(); rows]);
+
+    let history = &analyzer.config().history;
+    let blobs = &analyzer.manifest().layers;
+    let layers = &analyzer.layers;
+    let step = |index: usize| layer_history(history, index);
+    write(
+        "layers.parquet",
+        vec![
+            ("image", images(layers.len())),
+            (
+                "layer_index",
+                Column::Int64(layers.iter().map(|l| l.layer_index as i64).collect()),
+            ),
+            (
+                "diff_id",
+                Column::Utf8(layers.iter().map(|l| l.hash.clone()).collect()),
+            ),
+            (
+                "blob",
+                Column::Utf8(
+                    layers
+                        .iter()
+                        .map(|l| blobs.get(l.layer_index).cloned().unwrap_or_default())
+                        .collect(),
+                ),
+            ),
+            (
+                "format",
+                Column::Utf8(
+                    layers
+                        .iter()
+                        .map(|l| format_name(l.format).to_string())
+                        .collect(),
+                ),
+            ),
+            (
+                "created",
+                Column::Utf8(
+                    layers
+                        .iter()
+                        .map(|l| {
+                            step(l.layer_index)
+                                .map(|s| s.created.clone())
+                                .unwrap_or_default()
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "created_by",
+                Column::Utf8(
+                    layers
+                        .iter()
+                        .map(|l| {
+                            step(l.layer_index)
+                                .map(|s| s.created_by.clone())
+                                .unwrap_or_default()
+                        })
+                        .collect(),
+                ),
+            ),
+        ],
+    )?;
+
+    write(
+        "files.parquet",
+        vec![
+            ("image", images(files.len())),
+            (
+                "layer_index",
+                Column::Int64(files.iter().map(|f| f.layer_index as i64).collect()),
+            ),
+            (
+                "path",
+                Column::Utf8(files.iter().map(|f| f.path.clone()).collect()),
+            ),
+            (
+                "size",
+                Column::Int64(files.iter().map(|f| f.size as i64).collect()),
+            ),
+            (
+                "hash",
+                Column::Utf8(files.iter().map(|f| f.hash.clone()).collect()),
+            ),
+        ],
+    )?;
+
+    let copies: Vec<(usize, bool, &FileInfo)> = duplicates
+        .iter()
+        .enumerate()
+        .flat_map(|(group_id, group)| {
+            std::iter::once((group_id, true, &group.original))
+                .chain(group.duplicates.iter().map(move |d| (group_id, false, d)))
+        })
+        .collect();
+    write(
+        "duplicates.parquet",
+        vec![
+            ("image", images(copies.len())),
+            (
+                "group_id",
+                Column::Int64(copies.iter().map(|(id, _, _)| *id as i64).collect()),
+            ),
+            (
+                "original",
+                Column::Boolean(copies.iter().map(|(_, original, _)| *original).collect()),
+            ),
+            (
+                "layer_index",
+                Column::Int64(
+                    copies
+                        .iter()
+                        .map(|(_, _, f)| f.layer_index as i64)
+                        .collect(),
+                ),
+            ),
+            (
+                "path",
+                Column::Utf8(copies.iter().map(|(_, _, f)| f.path.clone()).collect()),
+            ),
+            (
+                "size",
+                Column::Int64(copies.iter().map(|(_, _, f)| f.size as i64).collect()),
+            ),
+            (
+                "hash",
+                Column::Utf8(copies.iter().map(|(_, _, f)| f.hash.clone()).collect()),
+            ),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("usr/lib/it's.so"), "'usr/lib/it''s.so'");
+        assert_eq!(quote("''"), "''''''");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("echo a\0b"), "'echo a' || char(0) || 'b'");
+        // Paths that aren't UTF-8 are scanned lossily, to U+FFFD
+        let path = String::from_utf8_lossy(b"usr/bin/caf\xe9");
+        assert_eq!(quote(&path), "'usr/bin/caf\u{fffd}'");
+    }
+}
@@ -0,0 +1,155 @@
+//! sha256 digests of whatever is written through, the one way layer
+//! diff_ids, blob and config digests are computed. ring is the backend.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+use ring::digest::{Context, SHA256};
+
+use crate::error::Result;
+
+/// Read buffer of [`sha256_file`]
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Passes writes on to `W`, hashing the bytes it accepts. Over
+/// [`io::Sink`], the default, it only hashes.
+pub struct DigestWriter<W = io::Sink> {
+    inner: W,
+    context: Context,
+}
+
+impl<W: Write> DigestWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            context: Context::new(&SHA256),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// The writer and the digest of everything written, as
+    /// `sha256:<hex>`.
+    pub fn finish(self) -> (W, String) {
+        let (inner, hex) = self.finish_hex();
+        (inner, format!("sha256:{}", hex))
+    }
+
+    /// The writer and the hex digest of everything written.
+    pub fn finish_hex(self) -> (W, String) {
+        let hex = self
+            .context
+            .finish()
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        (self.inner, hex)
+    }
+}
+
+impl DigestWriter {
+    /// A writer that only hashes.
+    pub fn sink() -> Self {
+        Self::new(io::sink())
+    }
+}
+
+impl Default for DigestWriter {
+    fn default() -> Self {
+        Self::sink()
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.context.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Passes reads on from `R`, hashing the bytes they return.
+pub struct DigestReader<R> {
+    inner: R,
+    context: Context,
+}
+
+impl<R: Read> DigestReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            context: Context::new(&SHA256),
+        }
+    }
+
+    /// The digest of everything read, as `sha256:<hex>`.
+    pub fn finish(self) -> String {
+        let hasher = DigestWriter {
+            inner: io::sink(),
+            context: self.context,
+        };
+        hasher.finish().1
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.context.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// `sha256:<hex>` of `data`.
+pub fn sha256_digest(data: &[u8]) -> String {
+    let mut hasher = DigestWriter::sink();
+    hasher.context.update(data);
+    hasher.finish().1
+}
+
+/// `sha256:<hex>` of a file's contents, and its size.
+pub fn sha256_file(path: &Path) -> Result<(String, u64)> {
+    let mut reader = BufReader::with_capacity(BUFFER_SIZE, File::open(path)?);
+    let mut hasher = DigestWriter::sink();
+    let size = io::copy(&mut reader, &mut hasher)?;
+    Ok((hasher.finish().1, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_writer_passes_bytes_on() {
+        let mut writer = DigestWriter::new(Vec::new());
+        writer.write_all(b"abc").unwrap();
+        let (written, digest) = writer.finish();
+        assert_eq!(written, b"abc");
+        assert_eq!(
+            digest,
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(sha256_digest(b"abc"), digest);
+    }
+
+    #[test]
+    fn test_digest_reader_matches_writer() {
+        let mut reader = DigestReader::new(&b"abc"[..]);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"abc");
+        assert_eq!(reader.finish(), sha256_digest(b"abc"));
+    }
+}
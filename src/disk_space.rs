@@ -0,0 +1,136 @@
+//! Free space checks before the phases that write to disk, so a run fails
+//! up front with the space it needs rather than mid-way with ENOSPC:
+//!
+//! - extracting a `docker save` file or a pull: the tar or the blobs'
+//!   sizes. Layers are scanned as streams and never decompressed to disk.
+//! - staging the rewrite: the layers being rewritten, at their
+//!   uncompressed size when the output is uncompressed.
+//!
+//! Images read from a stream have no size up front, so only their rewrite
+//! is checked.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use humansize::{BINARY, format_size};
+use tracing::debug;
+
+use crate::error::{DedupeError, IoResultExt, Result};
+
+/// Slack added to every estimate for manifests, configs and tar padding
+const HEADROOM: u64 = 64 * 1024 * 1024;
+/// Gzip's trailer holds the uncompressed size modulo this
+const ISIZE_WRAP: u64 = 1 << 32;
+/// Compression ratio a large layer whose gzip trailer may have wrapped is
+/// assumed to reach at least
+const WRAP_RATIO: u64 = 4;
+
+/// Fails early when the filesystem holding `dir` can't fit `required` bytes,
+/// instead of letting the run die mid-way with ENOSPC.
+pub fn ensure_available(dir: &Path, required: u64, phase: &str) -> Result<()> {
+    let required = required.saturating_add(HEADROOM);
+    let available = fs4::available_space(dir)
+        .with_context(|| format!("Failed to query free space of {}", dir.display()))?;
+    debug!(
+        "{}: need ~{}, {} available in {}",
+        phase,
+        format_size(required, BINARY),
+        format_size(available, BINARY),
+        dir.display()
+    );
+
+    if available < required {
+        return Err(DedupeError::InsufficientSpace {
+            dir: dir.to_path_buf(),
+            phase: phase.to_string(),
+            required,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// Best-effort uncompressed size of a layer blob. Gzip stores the input size
+/// modulo 2^32 in its trailer, so this never reports less than the blob
+/// itself, and for blobs over 1 GiB, whose layers may be past 4 GiB, never
+/// less than four times the blob: the smallest size the trailer could
+/// stand for from there, which may overestimate.
+pub fn uncompressed_size_estimate(path: &Path, gzipped: bool) -> Result<u64> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if !gzipped || len < 4 {
+        return Ok(len);
+    }
+
+    file.seek(SeekFrom::End(-4))?;
+    let mut trailer = [0u8; 4];
+    file.read_exact(&mut trailer)?;
+    let size = u64::from(u32::from_le_bytes(trailer));
+    let floor = len.saturating_mul(WRAP_RATIO);
+    if floor <= ISIZE_WRAP || size >= floor {
+        return Ok(size.max(len));
+    }
+    let wraps = (floor - size).div_ceil(ISIZE_WRAP);
+    Ok(size.saturating_add(wraps.saturating_mul(ISIZE_WRAP)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    use super::*;
+
+    #[test]
+    fn test_ensure_available() {
+        let dir = tempfile::tempdir().unwrap();
+        ensure_available(dir.path(), 0, "extracting the image").unwrap();
+        let err = ensure_available(dir.path(), u64::MAX - HEADROOM, "rewriting layers");
+        assert!(matches!(
+            err,
+            Err(DedupeError::InsufficientSpace { phase, .. }) if phase == "rewriting layers"
+        ));
+    }
+
+    #[test]
+    fn test_uncompressed_size_estimate() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = vec![7u8; 100_000];
+        let tar = dir.path().join("layer.tar");
+        fs::write(&tar, &data).unwrap();
+        assert_eq!(uncompressed_size_estimate(&tar, false).unwrap(), 100_000);
+
+        let gz = dir.path().join("layer.tar.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        fs::write(&gz, encoder.finish().unwrap()).unwrap();
+        assert!(fs::metadata(&gz).unwrap().len() < 100_000);
+        assert_eq!(uncompressed_size_estimate(&gz, true).unwrap(), 100_000);
+        assert_eq!(
+            uncompressed_size_estimate(&gz, false).unwrap(),
+            fs::metadata(&gz).unwrap().len()
+        );
+
+        // A trailer claiming less than the blob, e.g. past 4 GiB, is ignored
+        let short = dir.path().join("short.gz");
+        fs::write(&short, [0xff, 0xff, 0xff, 0xff, 1, 0, 0, 0]).unwrap();
+        assert_eq!(uncompressed_size_estimate(&short, true).unwrap(), 8);
+
+        // A 1.5 GiB blob whose trailer says 2 GiB may well be a 6 GiB
+        // layer; sparse, so the test doesn't write it
+        let wrapped = dir.path().join("wrapped.gz");
+        let file = File::create(&wrapped).unwrap();
+        file.set_len(3 << 29).unwrap();
+        (&file).seek(SeekFrom::End(-4)).unwrap();
+        (&file).write_all(&(1u32 << 31).to_le_bytes()).unwrap();
+        assert_eq!(uncompressed_size_estimate(&wrapped, true).unwrap(), 6 << 30);
+        assert_eq!(
+            uncompressed_size_estimate(&wrapped, false).unwrap(),
+            3 << 29
+        );
+    }
+}
@@ -0,0 +1,336 @@
+//! ELF binaries that differ only in what `strip` removes: debug info,
+//! symbol tables and the build ID. Their bytes differ, so deduplication
+//! rightly leaves them alone, but stripping them, or splitting the debug
+//! info out, makes them identical and saves the debug bytes as well.
+//!
+//! Binaries are compared by a hash over their loaded sections, the build ID
+//! note left out, rather than over the whole file.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+use tar::Archive;
+use tracing::{debug, info};
+
+use crate::cancel;
+use crate::error::Result;
+use crate::options::AnalyzerOptions;
+use crate::paths::{self, PathMatcher};
+
+const MAGIC: &[u8] = b"\x7fELF";
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: u64 = 0x2;
+const NT_GNU_BUILD_ID: u32 = 3;
+const BUILD_ID_SECTION: &str = ".note.gnu.build-id";
+
+/// What [`inspect`] tells about an ELF file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfInfo {
+    /// Hash of the machine, the class and the loaded sections but the build
+    /// ID, the same for copies differing only in what `strip` removes
+    pub stripped_hash: String,
+    /// Bytes of debug sections and symbol tables, which `strip` removes
+    pub debug_bytes: u64,
+    /// The GNU build ID, hex encoded
+    pub build_id: Option<String>,
+}
+
+/// One copy of an [`ElfNearDuplicate`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ElfFile {
+    pub path: String,
+    pub layer_index: usize,
+    pub size: u64,
+    /// Hash of the whole file
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_id: Option<String>,
+    pub debug_bytes: u64,
+}
+
+/// ELF files with the same loaded sections but different bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ElfNearDuplicate {
+    pub stripped_hash: String,
+    /// Lowest layer first
+    pub files: Vec<ElfFile>,
+    /// Bytes stripping every copy frees
+    pub strip_savings: u64,
+    /// Bytes deduplicating the stripped copies frees on top
+    pub dedupe_savings: u64,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+    wide: bool,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.data
+            .get(offset..offset.checked_add(N)?)?
+            .try_into()
+            .ok()
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.bytes(offset)?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.bytes(offset)?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u64(&self, offset: usize) -> Option<u64> {
+        let bytes = self.bytes(offset)?;
+        Some(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    /// A 64-bit field in ELF64, a 32-bit one in ELF32.
+    fn word(&self, offset: usize) -> Option<u64> {
+        if self.wide {
+            self.u64(offset)
+        } else {
+            self.u32(offset).map(u64::from)
+        }
+    }
+}
+
+struct Section<'a> {
+    name: &'a str,
+    kind: u32,
+    flags: u64,
+    data: Option<&'a [u8]>,
+    size: u64,
+}
+
+fn sections<'a>(elf: &Reader<'a>) -> Option<Vec<Section<'a>>> {
+    // Offsets of e_shoff, e_shentsize, e_shnum, e_shstrndx and, within a
+    // section header, of sh_flags, sh_offset and sh_size
+    let (shoff, shentsize, shnum, shstrndx, flags, offset, size) = if elf.wide {
+        (0x28, 0x3a, 0x3c, 0x3e, 8, 24, 32)
+    } else {
+        (0x20, 0x2e, 0x30, 0x32, 8, 16, 20)
+    };
+    let table = usize::try_from(elf.word(shoff)?).ok()?;
+    let entry_size = usize::from(elf.u16(shentsize)?);
+    let count = usize::from(elf.u16(shnum)?);
+    let header = |index: usize| table.checked_add(index.checked_mul(entry_size)?);
+    let raw: Vec<(u32, u32, u64, u64, u64)> = (0..count)
+        .map(|index| {
+            let at = header(index)?;
+            Some((
+                elf.u32(at)?,
+                elf.u32(at + 4)?,
+                elf.word(at + flags)?,
+                elf.word(at + offset)?,
+                elf.word(at + size)?,
+            ))
+        })
+        .collect::<Option<_>>()?;
+    let data = |offset: u64, size: u64| {
+        let start = usize::try_from(offset).ok()?;
+        elf.data
+            .get(start..start.checked_add(usize::try_from(size).ok()?)?)
+    };
+    let (_, _, _, names_offset, names_size) = *raw.get(usize::from(elf.u16(shstrndx)?))?;
+    let names = data(names_offset, names_size)?;
+    raw.iter()
+        .map(|&(name, kind, flags, offset, size)| {
+            let name = names.get(usize::try_from(name).ok()?..)?;
+            let end = name.iter().position(|&b| b == 0)?;
+            Some(Section {
+                name: std::str::from_utf8(&name[..end]).ok()?,
+                kind,
+                flags,
+                data: if kind == SHT_NOBITS {
+                    None
+                } else {
+                    data(offset, size)
+                },
+                size,
+            })
+        })
+        .collect()
+}
+
+/// The description of the GNU build ID note in `note`, hex encoded.
+fn build_id(elf: &Reader<'_>, note: &[u8]) -> Option<String> {
+    let note = Reader { data: note, ..*elf };
+    let name_size = usize::try_from(note.u32(0)?).ok()?;
+    let desc_size = usize::try_from(note.u32(4)?).ok()?;
+    if note.u32(8)? != NT_GNU_BUILD_ID {
+        return None;
+    }
+    let desc = 12 + name_size.next_multiple_of(4);
+    let id = note.data.get(desc..desc.checked_add(desc_size)?)?;
+    Some(id.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Whether `strip` removes the section named `name`.
+fn is_debug(name: &str) -> bool {
+    name.starts_with(".debug")
+        || name.starts_with(".zdebug")
+        || name == ".symtab"
+        || name == ".strtab"
+}
+
+/// Looks into `data` if it's an ELF file with a section table, hashing it
+/// with the analyzer's hasher.
+pub fn inspect(data: &[u8], options: &AnalyzerOptions) -> Option<ElfInfo> {
+    if !data.starts_with(MAGIC) {
+        return None;
+    }
+    let elf = Reader {
+        data,
+        wide: *data.get(4)? == 2,
+        big_endian: *data.get(5)? == 2,
+    };
+    let mut hasher = options.hasher.new_hasher();
+    // EI_CLASS, EI_DATA and e_machine
+    hasher.update(&data[4..6]);
+    hasher.update(&elf.u16(0x12)?.to_le_bytes());
+    let mut debug_bytes = 0;
+    let mut build_id_hex = None;
+    for section in sections(&elf)? {
+        if section.name == BUILD_ID_SECTION {
+            build_id_hex = section.data.and_then(|note| build_id(&elf, note));
+        } else if section.flags & SHF_ALLOC != 0 {
+            hasher.update(section.name.as_bytes());
+            hasher.update(&section.kind.to_le_bytes());
+            hasher.update(&section.size.to_le_bytes());
+            hasher.update(section.data.unwrap_or_default());
+        } else if is_debug(section.name) {
+            debug_bytes += section.data.map_or(0, |d| d.len() as u64);
+        }
+    }
+    Some(ElfInfo {
+        stripped_hash: hasher.finalize().to_hex(),
+        debug_bytes,
+        build_id: build_id_hex,
+    })
+}
+
+/// Every ELF file in the uncompressed layer tar `reader` that passes
+/// `min_size` and `excludes`.
+pub fn scan_layer_elf<R: Read>(
+    reader: R,
+    layer_index: usize,
+    options: &AnalyzerOptions,
+    excludes: &PathMatcher,
+) -> Result<Vec<(ElfFile, String)>> {
+    let mut archive = Archive::new(reader);
+    let mut files = Vec::new();
+    let mut data = Vec::new();
+    for entry in archive.entries()? {
+        cancel::check()?;
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let size = entry.size();
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if size < MAGIC.len() as u64 || size < options.min_size || excludes.is_match(&path) {
+            continue;
+        }
+        let mut magic = [0; 4];
+        entry.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            continue;
+        }
+        data.clear();
+        data.extend_from_slice(&magic);
+        entry.read_to_end(&mut data)?;
+        let Some(info) = inspect(&data, options) else {
+            debug!(
+                "Skipping {} in layer {}, no section table",
+                path, layer_index
+            );
+            continue;
+        };
+        files.push((
+            ElfFile {
+                path: paths::normalize(&path).to_string(),
+                layer_index,
+                size,
+                hash: options.hasher.hash_bytes(&data).to_hex(),
+                build_id: info.build_id,
+                debug_bytes: info.debug_bytes,
+            },
+            info.stripped_hash,
+        ));
+    }
+    Ok(files)
+}
+
+/// Groups `files`, each with its stripped hash, into the near-duplicates
+/// of two contents or more, largest savings first.
+pub fn near_duplicates(files: Vec<(ElfFile, String)>) -> Vec<ElfNearDuplicate> {
+    let mut by_hash: BTreeMap<String, Vec<ElfFile>> = BTreeMap::new();
+    for (file, stripped_hash) in files {
+        by_hash.entry(stripped_hash).or_default().push(file);
+    }
+    let mut groups: Vec<ElfNearDuplicate> = by_hash
+        .into_iter()
+        .filter(|(_, files)| files.iter().any(|f| f.hash != files[0].hash))
+        .map(|(stripped_hash, mut files)| {
+            files.sort_by_key(|f| f.layer_index);
+            let stripped = files
+                .iter()
+                .map(|f| f.size.saturating_sub(f.debug_bytes))
+                .min();
+            ElfNearDuplicate {
+                stripped_hash,
+                strip_savings: files.iter().map(|f| f.debug_bytes).sum(),
+                dedupe_savings: stripped.unwrap_or(0) * (files.len() as u64 - 1),
+                files,
+            }
+        })
+        .collect();
+    groups.sort_by_key(|g| Reverse(g.strip_savings + g.dedupe_savings));
+    groups
+}
+
+/// Logs every group and what stripping saves.
+pub fn print_elf_near_duplicates(groups: &[ElfNearDuplicate]) {
+    if groups.is_empty() {
+        return;
+    }
+    info!("Binaries identical but for debug info or build IDs:");
+    for group in groups {
+        info!(
+            "\t{} copies, stripping saves {}, deduplicating them then {}",
+            group.files.len(),
+            format_size(group.strip_savings, BINARY),
+            format_size(group.dedupe_savings, BINARY)
+        );
+        for file in &group.files {
+            info!(
+                "\t\t{} (layer {}, {} debug, build ID {})",
+                file.path,
+                file.layer_index,
+                format_size(file.debug_bytes, BINARY),
+                file.build_id.as_deref().unwrap_or("none")
+            );
+        }
+    }
+    info!("=============================");
+}
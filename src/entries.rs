@@ -0,0 +1,174 @@
+use std::fmt;
+use std::vec::IntoIter;
+
+use serde::{Deserialize, Serialize};
+use tar::{Archive, EntryType};
+
+use crate::analyzer::{Layer, LayerFormat};
+use crate::cancel;
+use crate::error::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Hardlink,
+    /// `.wh.<name>`, deletes `<name>` from lower layers
+    Whiteout,
+    /// `.wh..wh..opq`, hides the lower contents of its directory
+    Opaque,
+    Other,
+}
+
+/// Metadata of a single layer entry, read from its tar header only.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntryInfo {
+    pub path: String,
+    pub size: u64,
+    pub layer_index: usize,
+    pub kind: EntryKind,
+    pub link_target: Option<String>,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    pub mtime: u64,
+}
+
+/// A file of [`crate::Analyzer::largest_files`], with where it came from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LargeFile {
+    #[serde(flatten)]
+    pub entry: EntryInfo,
+    /// False when a higher layer deletes or replaces it, so it takes space
+    /// without being seen by containers
+    pub visible: bool,
+    /// `created_by` of the layer's history entry
+    pub created_by: Option<String>,
+    /// Gzipped size over size of a sample of the file, set by
+    /// [`crate::Analyzer::measure_compressibility`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression_ratio: Option<f64>,
+}
+
+/// `tar tv` style: `-rw-r--r-- 0/0       1234 L2 path -> target`, where
+/// `L2` is the layer the entry comes from.
+impl fmt::Display for EntryInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            EntryKind::File => '-',
+            EntryKind::Dir => 'd',
+            EntryKind::Symlink => 'l',
+            EntryKind::Hardlink => 'h',
+            EntryKind::Whiteout | EntryKind::Opaque => 'w',
+            EntryKind::Other => '?',
+        };
+        let permissions: String = (0..9)
+            .map(|bit| {
+                if self.mode & (0o400 >> bit) == 0 {
+                    '-'
+                } else {
+                    ['r', 'w', 'x'][bit % 3]
+                }
+            })
+            .collect();
+        write!(
+            f,
+            "{}{} {:>9} {:>12} L{:<3} {}",
+            kind,
+            permissions,
+            format!("{}/{}", self.uid, self.gid),
+            self.size,
+            self.layer_index,
+            self.path
+        )?;
+        if let Some(target) = &self.link_target {
+            let arrow = if self.kind == EntryKind::Hardlink {
+                "link to"
+            } else {
+                "->"
+            };
+            write!(f, " {} {}", arrow, target)?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn entry_kind(path: &str, entry_type: EntryType) -> EntryKind {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    match entry_type {
+        _ if name == ".wh..wh..opq" => EntryKind::Opaque,
+        _ if name.starts_with(".wh.") => EntryKind::Whiteout,
+        EntryType::Regular | EntryType::Continuous => EntryKind::File,
+        EntryType::Directory => EntryKind::Dir,
+        EntryType::Symlink => EntryKind::Symlink,
+        EntryType::Link => EntryKind::Hardlink,
+        _ => EntryKind::Other,
+    }
+}
+
+/// Lists all entries of one layer without reading their contents.
+pub fn read_layer_entries(layer: &Layer) -> Result<Vec<EntryInfo>> {
+    let mut archive = Archive::new(layer.open_reader()?);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        cancel::check()?;
+        let entry = entry?;
+        let header = entry.header();
+        let path = entry.path()?.to_string_lossy().to_string();
+        let kind = entry_kind(&path, header.entry_type());
+        entries.push(EntryInfo {
+            link_target: entry
+                .link_name()?
+                .map(|target| target.to_string_lossy().to_string()),
+            size: header.size()?,
+            mode: header.mode()?,
+            uid: header.uid()?,
+            gid: header.gid()?,
+            mtime: header.mtime()?,
+            layer_index: layer.layer_index,
+            kind,
+            path,
+        });
+    }
+    Ok(entries)
+}
+
+/// Iterator over the entries of every layer in manifest order, created by
+/// [`crate::Analyzer::entries`]. A layer is only decompressed once the
+/// iterator reaches it, and its own headers are buffered while it is drained.
+/// Layers that aren't tars are skipped.
+pub struct Entries<'a> {
+    layers: std::slice::Iter<'a, Layer>,
+    current: IntoIter<EntryInfo>,
+}
+
+impl<'a> Entries<'a> {
+    pub(crate) fn new(layers: &'a [Layer]) -> Self {
+        Self {
+            layers: layers.iter(),
+            current: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Result<EntryInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.current.next() {
+                return Some(Ok(entry));
+            }
+            let layer = self.layers.next()?;
+            if layer.format != LayerFormat::Tar {
+                continue;
+            }
+            match read_layer_entries(layer) {
+                Ok(entries) => self.current = entries.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
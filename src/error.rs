@@ -0,0 +1,162 @@
+use std::io;
+use std::path::PathBuf;
+
+use humansize::{BINARY, format_size};
+use thiserror::Error;
+
+pub type Result<T, E = DedupeError> = std::result::Result<T, E>;
+
+/// Errors returned by the library. Wrapped causes stay reachable through
+/// [`std::error::Error::source`].
+#[derive(Debug, Error)]
+pub enum DedupeError {
+    /// The input isn't a `docker save` tarball
+    #[error("Not an exported image: {0}")]
+    NotAnImage(String),
+
+    #[error("Unsupported compression: {0}")]
+    UnsupportedCompression(String),
+
+    /// The layer is encrypted, see [`crate::schemas::Descriptor::is_encrypted`]
+    #[error("Encrypted layer: {0}")]
+    Encrypted(String),
+
+    /// The layer isn't a tar, see [`crate::analyzer::LayerFormat`]
+    #[error("Unsupported layer: {0}")]
+    UnsupportedLayer(String),
+
+    /// An entry of the image tar was unsafe to write, or the image broke
+    /// one of the [`crate::unpack::UnpackLimits`]
+    #[error("Refusing to unpack: {0}")]
+    Unpack(String),
+
+    #[error("Invalid manifest.json: {0}")]
+    Manifest(String),
+
+    #[error("Invalid image config: {0}")]
+    Config(String),
+
+    /// A Compose file or Kubernetes manifest uses YAML beyond what
+    /// [`crate::compose`] and [`crate::workloads`] read, e.g. flow style
+    #[error("Unsupported YAML: {0}")]
+    Yaml(String),
+
+    /// Scanning or rewriting a single layer failed
+    #[error("Layer {index} ({}): {source}", path.display())]
+    Layer {
+        index: usize,
+        path: PathBuf,
+        #[source]
+        source: Box<DedupeError>,
+    },
+
+    #[error("Failed to hash {path}: {source}")]
+    Hashing {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /// Writing the deduplicated image failed
+    #[error("{context}: {source}")]
+    Output {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error(
+        "Not enough free space in {} for {phase}: need ~{}, only {} available. \
+         Free up space or point TMPDIR at a larger filesystem.",
+        dir.display(),
+        format_size(*required, BINARY),
+        format_size(*available, BINARY)
+    )]
+    InsufficientSpace {
+        dir: PathBuf,
+        phase: String,
+        required: u64,
+        available: u64,
+    },
+
+    /// A [`crate::policy::DedupPolicy`] asked for something impossible
+    #[error("Dedup policy: {0}")]
+    Policy(String),
+
+    /// A saved [`crate::analyzer::ModificationPlan`] doesn't fit the image
+    /// it's applied to
+    #[error("Modification plan: {0}")]
+    Plan(String),
+
+    /// A registry refused or failed a request
+    #[error("Registry: {0}")]
+    Registry(String),
+
+    /// An object store or other HTTP server refused or failed a request
+    #[error("HTTP: {0}")]
+    Http(String),
+
+    /// A webhook refused or failed a notification
+    #[error("Webhook: {0}")]
+    Webhook(String),
+
+    /// An external program, e.g. `sqlite3`, couldn't be found
+    #[error("{program} isn't installed or not on PATH: {hint}")]
+    MissingTool { program: String, hint: String },
+
+    #[error("Invalid option: {0}")]
+    InvalidOption(String),
+
+    #[error("Interrupted")]
+    Interrupted,
+
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl From<io::Error> for DedupeError {
+    fn from(source: io::Error) -> Self {
+        DedupeError::Io {
+            context: "I/O error".to_string(),
+            source,
+        }
+    }
+}
+
+#[cfg(feature = "reports")]
+/// The error of spawning `program`: [`DedupeError::MissingTool`] with
+/// `hint` if it doesn't exist.
+pub(crate) fn spawn_error(source: io::Error, program: &str, hint: &str) -> DedupeError {
+    if source.kind() == io::ErrorKind::NotFound {
+        DedupeError::MissingTool {
+            program: program.to_string(),
+            hint: hint.to_string(),
+        }
+    } else {
+        DedupeError::Io {
+            context: format!("Failed to run {}", program),
+            source,
+        }
+    }
+}
+
+/// Attaches a description to I/O errors, like `anyhow::Context` does.
+pub trait IoResultExt<T> {
+    fn with_context<F: FnOnce() -> String>(self, context: F) -> Result<T>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn with_context<F: FnOnce() -> String>(self, context: F) -> Result<T> {
+        self.map_err(|source| DedupeError::Io {
+            context: context(),
+            source,
+        })
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+use std::io::{self, Read};
+
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+use rapidhash::v3::{RapidSecrets, rapidhash_v3_file_seeded, rapidhash_v3_seeded};
+#[cfg(feature = "sha256")]
+use ring::digest::{Context, SHA256};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Output of a [`ContentHasher`]. Each hasher always produces digests of the
+/// same length, so digests from one hasher can be compared byte for byte.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Digest(Vec<u8>);
+
+impl Digest {
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        Self(bytes.into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        let mut hex = String::with_capacity(self.0.len() * 2);
+        for &b in &self.0 {
+            hex.push(char::from(DIGITS[usize::from(b >> 4)]));
+            hex.push(char::from(DIGITS[usize::from(b & 0xf)]));
+        }
+        hex
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+/// Incremental hash over one file's contents.
+pub trait ContentHasher: Send {
+    fn update(&mut self, data: &[u8]);
+
+    fn finalize(self: Box<Self>) -> Digest;
+}
+
+/// Creates a [`ContentHasher`] per file. Implement this to plug in your own
+/// hash, e.g. a keyed one so digests can be compared across tenants without
+/// revealing file contents.
+pub trait HasherFactory: Send + Sync {
+    /// Stable identifier, recorded in checkpoints so cached scans made with a
+    /// different hasher are discarded.
+    fn name(&self) -> &str;
+
+    fn new_hasher(&self) -> Box<dyn ContentHasher>;
+
+    /// Hashes everything `reader` yields. Override when the hash has a faster
+    /// pull-based implementation.
+    fn hash_reader(&self, reader: &mut dyn Read) -> io::Result<Digest> {
+        feed(self.new_hasher(), reader)
+    }
+
+    /// Hashes `data`, a whole file already in memory, the way the analyzer
+    /// hashes small files. Must match [`HasherFactory::hash_reader`] over
+    /// the same bytes.
+    fn hash_bytes(&self, data: &[u8]) -> Digest {
+        let mut hasher = self.new_hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+fn feed(mut hasher: Box<dyn ContentHasher>, reader: &mut dyn Read) -> io::Result<Digest> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// The built-in hashes. `Blake3` and `Sha256` need the features of the same
+/// name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum HashAlgorithm {
+    /// Fast non-cryptographic hash, fine for images you trust
+    #[default]
+    Rapidhash,
+    /// Fast cryptographic hash
+    #[cfg(feature = "blake3")]
+    Blake3,
+    /// Collision resistant and widely available, but the slowest
+    #[cfg(feature = "sha256")]
+    Sha256,
+}
+
+impl HasherFactory for HashAlgorithm {
+    fn name(&self) -> &str {
+        match self {
+            HashAlgorithm::Rapidhash => "rapidhash",
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => "blake3",
+            #[cfg(feature = "sha256")]
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    fn new_hasher(&self) -> Box<dyn ContentHasher> {
+        match self {
+            HashAlgorithm::Rapidhash => Box::new(RapidHasher::default()),
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => Box::new(Blake3Hasher(blake3::Hasher::new())),
+            #[cfg(feature = "sha256")]
+            HashAlgorithm::Sha256 => Box::new(Sha256Hasher(Context::new(&SHA256))),
+        }
+    }
+
+    fn hash_reader(&self, reader: &mut dyn Read) -> io::Result<Digest> {
+        match self {
+            // rapidhash ~ 11% faster than blake3 when streamed by its own reader
+            HashAlgorithm::Rapidhash => {
+                let hash = rapidhash_v3_file_seeded(reader, &RapidSecrets::seed(0))?;
+                Ok(Digest::new(hash.to_be_bytes()))
+            }
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                io::copy(reader, &mut hasher)?;
+                Ok(Digest::new(hasher.finalize().as_bytes().to_vec()))
+            }
+            #[cfg(feature = "sha256")]
+            HashAlgorithm::Sha256 => feed(self.new_hasher(), reader),
+        }
+    }
+
+    fn hash_bytes(&self, data: &[u8]) -> Digest {
+        match self {
+            HashAlgorithm::Rapidhash => {
+                Digest::new(rapidhash_v3_seeded(data, &RapidSecrets::seed(0)).to_be_bytes())
+            }
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => Digest::new(blake3::hash(data).as_bytes().to_vec()),
+            #[cfg(feature = "sha256")]
+            HashAlgorithm::Sha256 => {
+                Digest::new(ring::digest::digest(&SHA256, data).as_ref().to_vec())
+            }
+        }
+    }
+}
+
+/// rapidhash has no incremental API, so this buffers the whole file. The
+/// analyzer goes through [`HasherFactory::hash_reader`] instead.
+#[derive(Default)]
+struct RapidHasher {
+    data: Vec<u8>,
+}
+
+impl ContentHasher for RapidHasher {
+    fn update(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    fn finalize(self: Box<Self>) -> Digest {
+        let hash = rapidhash_v3_seeded(&self.data, &RapidSecrets::seed(0));
+        Digest::new(hash.to_be_bytes())
+    }
+}
+
+#[cfg(feature = "blake3")]
+struct Blake3Hasher(blake3::Hasher);
+
+#[cfg(feature = "blake3")]
+impl ContentHasher for Blake3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self: Box<Self>) -> Digest {
+        Digest::new(self.0.finalize().as_bytes().to_vec())
+    }
+}
+
+#[cfg(feature = "sha256")]
+struct Sha256Hasher(Context);
+
+#[cfg(feature = "sha256")]
+impl ContentHasher for Sha256Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self: Box<Self>) -> Digest {
+        Digest::new(self.0.finish().as_ref().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_incremental_matches_reader() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7) as u8).collect();
+        for algorithm in [
+            HashAlgorithm::Rapidhash,
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3,
+            #[cfg(feature = "sha256")]
+            HashAlgorithm::Sha256,
+        ] {
+            let mut hasher = algorithm.new_hasher();
+            for chunk in data.chunks(1000) {
+                hasher.update(chunk);
+            }
+            let streamed = algorithm.hash_reader(&mut data.as_slice()).unwrap();
+            assert_eq!(
+                algorithm.hash_bytes(&data),
+                streamed,
+                "{}",
+                algorithm.name()
+            );
+            assert_eq!(hasher.finalize(), streamed, "{}", algorithm.name());
+        }
+    }
+
+    #[test]
+    fn test_to_hex() {
+        assert_eq!(
+            Digest::new(vec![0x00, 0x0f, 0xa5, 0xff]).to_hex(),
+            "000fa5ff"
+        );
+        assert_eq!(Digest::new(Vec::new()).to_hex(), "");
+    }
+}
@@ -0,0 +1,743 @@
+//! JSON Schemas (draft 2020-12) of the documents the tool writes, for
+//! consumers to validate against and generate code from; printed by the
+//! `schema` subcommand.
+//!
+//! The schemas are built from the serde types themselves: each struct lists
+//! its fields once below, in a pattern that stops compiling when a field is
+//! added or removed, and enum values are whatever serde serializes the
+//! variants to. Objects don't forbid additional properties, since adding a
+//! field keeps [`crate::SCHEMA_VERSION`].
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+use serde_json::{Map, Value, json};
+
+use crate::analyzer::{DeDupTransaction, DuplicateInfo, FileInfo, ModificationPlan, PlannedLayer};
+use crate::case_variants::CaseVariants;
+use crate::elf::{ElfFile, ElfNearDuplicate};
+use crate::entries::{EntryInfo, EntryKind};
+use crate::junk::{JunkFile, JunkKind};
+use crate::libraries::{LibraryVersion, SharedLibrary};
+use crate::ownership::{SavingsSplit, Share};
+use crate::packages::{DuplicatePackage, Ecosystem, PackageInstall};
+use crate::pipeline::{DedupeSummary, LayerDigests};
+use crate::policy::Action;
+use crate::provenance::LayerProvenance;
+#[cfg(feature = "reports")]
+use crate::report::Report;
+use crate::schemas::{Descriptor, Platform};
+use crate::timings::{Phase, PhaseTiming};
+
+/// Named schemas, the `$defs` of a document.
+pub type Definitions = Map<String, Value>;
+
+/// A type with a JSON Schema.
+pub trait JsonSchema {
+    /// The type's schema: inline for primitives and containers, a `$ref`
+    /// into `defs` for structs and enums, which are added to it.
+    fn schema(defs: &mut Definitions) -> Value;
+}
+
+/// A struct, whose properties can be flattened into another's.
+pub trait ObjectSchema: JsonSchema {
+    fn object(defs: &mut Definitions) -> Value;
+}
+
+/// The documents there are schemas for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Document {
+    /// `--report json`
+    #[cfg(feature = "reports")]
+    Report,
+    /// What a rewrite reports about the image it wrote, the report's
+    /// `rewrite` section
+    Summary,
+    /// `--save-plan`
+    Plan,
+}
+
+/// The schema of `document`, standalone.
+pub fn document_schema(document: Document) -> Value {
+    match document {
+        #[cfg(feature = "reports")]
+        Document::Report => root::<Report>("Report"),
+        Document::Summary => root::<DedupeSummary>("DedupeSummary"),
+        Document::Plan => root::<ModificationPlan>("ModificationPlan"),
+    }
+}
+
+/// A complete schema document for `T`, titled `title`.
+pub fn root<T: JsonSchema>(title: &str) -> Value {
+    let mut defs = Definitions::new();
+    let reference = T::schema(&mut defs);
+    let mut schema = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": title,
+    });
+    schema["$ref"] = reference["$ref"].clone();
+    schema["$defs"] = Value::Object(defs);
+    schema
+}
+
+/// Adds `name` to `defs`, built by `definition` the first time, and refers
+/// to it.
+fn reference(
+    defs: &mut Definitions,
+    name: &str,
+    definition: impl FnOnce(&mut Definitions) -> Value,
+) -> Value {
+    if !defs.contains_key(name) {
+        // Placeholder, for types that refer to themselves
+        defs.insert(name.to_string(), Value::Null);
+        let schema = definition(defs);
+        defs.insert(name.to_string(), schema);
+    }
+    json!({ "$ref": format!("#/$defs/{}", name) })
+}
+
+fn camel_case(name: &str) -> String {
+    let mut parts = name.split('_');
+    let mut camel = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    camel
+}
+
+/// Fields, by their serialized names, of an object under construction.
+#[derive(Default)]
+struct Properties {
+    properties: Map<String, Value>,
+    required: Vec<Value>,
+}
+
+impl Properties {
+    fn add(&mut self, name: String, schema: Value, required: bool) {
+        if required {
+            self.required.push(Value::String(name.clone()));
+        }
+        self.properties.insert(name, schema);
+    }
+
+    /// Takes in the properties of a `#[serde(flatten)]` field's object.
+    fn flatten(&mut self, object: Value) {
+        if let Some(properties) = object["properties"].as_object() {
+            self.properties.extend(properties.clone());
+        }
+        if let Some(required) = object["required"].as_array() {
+            self.required.extend(required.iter().cloned());
+        }
+    }
+
+    fn into_schema(self, title: &str) -> Value {
+        json!({
+            "title": title,
+            "type": "object",
+            "properties": self.properties,
+            "required": self.required,
+        })
+    }
+}
+
+macro_rules! property {
+    ($defs:ident, $props:ident, $name:expr, #[optional] $fty:ty) => {
+        $props.add($name, <$fty>::schema($defs), false)
+    };
+    ($defs:ident, $props:ident, $name:expr, #[flatten] $fty:ty) => {
+        $props.flatten(<$fty>::object($defs))
+    };
+    ($defs:ident, $props:ident, $name:expr, $fty:ty) => {
+        $props.add($name, <$fty>::schema($defs), true)
+    };
+}
+
+/// Implements [`ObjectSchema`] for a struct from all of its fields, marked
+/// `#[optional]` when serde may leave them out or `#[flatten]`; `camel` for
+/// `#[serde(rename_all = "camelCase")]`.
+macro_rules! object_schema {
+    ($ty:ident $(<$lt:lifetime>)?, camel { $($fields:tt)* }) => {
+        object_schema!(@impl $ty [$($lt)?] camel { $($fields)* });
+    };
+    ($ty:ident $(<$lt:lifetime>)? { $($fields:tt)* }) => {
+        object_schema!(@impl $ty [$($lt)?] snake { $($fields)* });
+    };
+    (@impl $ty:ident [$($lt:lifetime)?] $case:ident {
+        $($(#[$attr:ident])? $field:ident: $fty:ty),* $(,)?
+    }) => {
+        impl JsonSchema for $ty $(<$lt>)? {
+            fn schema(defs: &mut Definitions) -> Value {
+                reference(defs, stringify!($ty), <Self as ObjectSchema>::object)
+            }
+        }
+
+        impl ObjectSchema for $ty $(<$lt>)? {
+            fn object(defs: &mut Definitions) -> Value {
+                // Stops compiling when the struct's fields change
+                #[allow(dead_code)]
+                fn exhaustive(value: &$ty $(<$lt>)?) {
+                    let $ty { $($field: _),* } = value;
+                }
+                let mut props = Properties::default();
+                $(
+                    property!(
+                        defs,
+                        props,
+                        object_schema!(@name $case $field),
+                        $(#[$attr])? $fty
+                    );
+                )*
+                props.into_schema(stringify!($ty))
+            }
+        }
+    };
+    (@name camel $field:ident) => {
+        camel_case(stringify!($field))
+    };
+    (@name snake $field:ident) => {
+        stringify!($field).to_string()
+    };
+}
+
+/// Implements [`JsonSchema`] for a fieldless enum from all of its variants.
+macro_rules! string_enum_schema {
+    ($ty:ident { $($variant:ident),* $(,)? }) => {
+        impl JsonSchema for $ty {
+            fn schema(defs: &mut Definitions) -> Value {
+                // Stops compiling when the enum's variants change
+                #[allow(dead_code)]
+                fn exhaustive(value: &$ty) {
+                    match value {
+                        $($ty::$variant => {}),*
+                    }
+                }
+                reference(defs, stringify!($ty), |_| {
+                    let values: Vec<Value> = [$($ty::$variant),*]
+                        .iter()
+                        .map(|v| serde_json::to_value(v).unwrap_or_default())
+                        .collect();
+                    json!({ "title": stringify!($ty), "type": "string", "enum": values })
+                })
+            }
+        }
+    };
+}
+
+macro_rules! primitive_schema {
+    ($($ty:ty => $schema:tt),* $(,)?) => {
+        $(
+            impl JsonSchema for $ty {
+                fn schema(_: &mut Definitions) -> Value {
+                    json!($schema)
+                }
+            }
+        )*
+    };
+}
+
+primitive_schema! {
+    bool => { "type": "boolean" },
+    u32 => { "type": "integer", "minimum": 0 },
+    u64 => { "type": "integer", "minimum": 0 },
+    usize => { "type": "integer", "minimum": 0 },
+    i64 => { "type": "integer" },
+    f64 => { "type": "number" },
+    String => { "type": "string" },
+}
+
+impl<T: JsonSchema> JsonSchema for Vec<T> {
+    fn schema(defs: &mut Definitions) -> Value {
+        json!({ "type": "array", "items": T::schema(defs) })
+    }
+}
+
+impl<T: JsonSchema + Clone> JsonSchema for Cow<'_, [T]> {
+    fn schema(defs: &mut Definitions) -> Value {
+        Vec::<T>::schema(defs)
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Option<T> {
+    fn schema(defs: &mut Definitions) -> Value {
+        json!({ "anyOf": [T::schema(defs), { "type": "null" }] })
+    }
+}
+
+impl<V: JsonSchema> JsonSchema for HashMap<String, V> {
+    fn schema(defs: &mut Definitions) -> Value {
+        json!({ "type": "object", "additionalProperties": V::schema(defs) })
+    }
+}
+
+#[cfg(feature = "reports")]
+object_schema!(Report<'_> {
+    schema_version: u32,
+    duplicate_groups: usize,
+    total_savings: u64,
+    duplicates: Cow<'_, [DuplicateInfo]>,
+    timings: Vec<PhaseTiming>,
+    #[optional]
+    shared_libraries: Vec<SharedLibrary>,
+    #[optional]
+    duplicate_packages: Vec<DuplicatePackage>,
+    #[optional]
+    junk_files: Vec<JunkFile>,
+    #[optional]
+    case_variants: Vec<CaseVariants>,
+    #[optional]
+    elf_near_duplicates: Vec<ElfNearDuplicate>,
+    #[optional]
+    ownership: Option<SavingsSplit>,
+    #[optional]
+    rewrite: Option<DedupeSummary>,
+});
+
+object_schema!(FileInfo {
+    path: String,
+    size: u64,
+    hash: String,
+    layer_index: usize,
+    #[optional]
+    provenance: Option<LayerProvenance>,
+});
+
+object_schema!(LayerProvenance {
+    diff_id: String,
+    #[optional]
+    blob_digest: Option<String>,
+    #[optional]
+    created_by: Option<String>,
+    #[optional]
+    created: Option<String>,
+});
+
+object_schema!(DuplicateInfo {
+    original: FileInfo,
+    duplicates: Vec<FileInfo>,
+    total_savings: u64,
+});
+
+string_enum_schema!(Phase {
+    Extract,
+    Scan,
+    Plan,
+    Rewrite,
+    Compress,
+    Pack
+});
+
+object_schema!(PhaseTiming {
+    phase: Phase,
+    seconds: f64,
+    bytes: u64,
+});
+
+object_schema!(LibraryVersion {
+    hash: String,
+    size: u64,
+    versions: Vec<String>,
+    files: Vec<FileInfo>,
+});
+
+object_schema!(SharedLibrary {
+    name: String,
+    copies: usize,
+    versions: Vec<LibraryVersion>,
+    duplicate_bytes: u64,
+    total_bytes: u64,
+});
+
+string_enum_schema!(Ecosystem { Python, Node });
+
+object_schema!(PackageInstall {
+    path: String,
+    layer_index: usize,
+    size: u64,
+});
+
+object_schema!(DuplicatePackage {
+    ecosystem: Ecosystem,
+    name: String,
+    version: String,
+    installs: Vec<PackageInstall>,
+    duplicate_bytes: u64,
+});
+
+string_enum_schema!(EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Hardlink,
+    Whiteout,
+    Opaque,
+    Other
+});
+
+object_schema!(EntryInfo {
+    path: String,
+    size: u64,
+    layer_index: usize,
+    kind: EntryKind,
+    link_target: Option<String>,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    mtime: u64,
+});
+
+string_enum_schema!(JunkKind {
+    Log,
+    Temp,
+    CoreDump,
+    Backup
+});
+
+object_schema!(JunkFile {
+    #[flatten]
+    entry: EntryInfo,
+    kind: JunkKind,
+    visible: bool,
+});
+
+object_schema!(CaseVariants {
+    folded_path: String,
+    hash: String,
+    size: u64,
+    files: Vec<FileInfo>,
+});
+
+object_schema!(ElfFile {
+    path: String,
+    layer_index: usize,
+    size: u64,
+    hash: String,
+    #[optional]
+    build_id: Option<String>,
+    debug_bytes: u64,
+});
+
+object_schema!(ElfNearDuplicate {
+    stripped_hash: String,
+    files: Vec<ElfFile>,
+    strip_savings: u64,
+    dedupe_savings: u64,
+});
+
+object_schema!(Share {
+    groups: usize,
+    savings: u64,
+});
+
+object_schema!(SavingsSplit {
+    base_layers: usize,
+    base: Share,
+    application: Share,
+});
+
+object_schema!(LayerDigests {
+    layer_index: usize,
+    rewritten: bool,
+    old_diff_id: String,
+    new_diff_id: String,
+    old_blob: String,
+    new_blob: String,
+});
+
+object_schema!(Platform {
+    architecture: String,
+    os: String,
+    #[optional]
+    variant: Option<String>,
+});
+
+object_schema!(Descriptor, camel {
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[optional]
+    artifact_type: Option<String>,
+    #[optional]
+    platform: Option<Platform>,
+    #[optional]
+    annotations: Option<HashMap<String, String>>,
+});
+
+object_schema!(DedupeSummary {
+    schema_version: u32,
+    duplicate_groups: usize,
+    duplicate_files: usize,
+    bytes_saved: u64,
+    layers: Vec<LayerDigests>,
+    old_config_digest: String,
+    new_config_digest: String,
+    #[optional]
+    manifest_digest: Option<String>,
+    #[optional]
+    stale_referrers: Vec<Descriptor>,
+});
+
+string_enum_schema!(Action {
+    Symlink,
+    Hardlink,
+    Omit,
+    Skip
+});
+
+object_schema!(DeDupTransaction {
+    original_path: String,
+    original_layer: usize,
+    target_path: String,
+    action: Action,
+    size: u64,
+    hash: String,
+});
+
+object_schema!(PlannedLayer {
+    layer_index: usize,
+    changes: Vec<DeDupTransaction>,
+});
+
+object_schema!(ModificationPlan {
+    schema_version: u32,
+    hasher: String,
+    diff_ids: Vec<String>,
+    duplicate_groups: usize,
+    layers: Vec<PlannedLayer>,
+});
+
+#[cfg(all(test, feature = "reports"))]
+mod tests {
+    use super::*;
+    use crate::timings::Timings;
+
+    /// Checks `value` against the subset of JSON Schema generated above,
+    /// rejecting properties the schema doesn't know so renames show up.
+    fn validate(schema: &Value, value: &Value, root: &Value, at: &str) {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let name = reference.trim_start_matches("#/$defs/");
+            return validate(&root["$defs"][name], value, root, at);
+        }
+        if let Some(options) = schema["anyOf"].as_array() {
+            if value.is_null() {
+                return;
+            }
+            return validate(&options[0], value, root, at);
+        }
+        if let Some(values) = schema["enum"].as_array() {
+            assert!(
+                values.contains(value),
+                "{}: {} not in {:?}",
+                at,
+                value,
+                values
+            );
+            return;
+        }
+        match schema["type"].as_str().unwrap() {
+            "object" => {
+                let object = value
+                    .as_object()
+                    .unwrap_or_else(|| panic!("{}: not an object", at));
+                for required in schema["required"].as_array().into_iter().flatten() {
+                    let required = required.as_str().unwrap();
+                    assert!(
+                        object.contains_key(required),
+                        "{}: {} missing",
+                        at,
+                        required
+                    );
+                }
+                for (key, field) in object {
+                    let at = format!("{}.{}", at, key);
+                    match schema["properties"].get(key) {
+                        Some(property) => validate(property, field, root, &at),
+                        None if schema.get("additionalProperties").is_some() => {
+                            validate(&schema["additionalProperties"], field, root, &at)
+                        }
+                        None => panic!("{}: not in the schema", at),
+                    }
+                }
+            }
+            "array" => {
+                for (i, item) in value.as_array().unwrap().iter().enumerate() {
+                    validate(&schema["items"], item, root, &format!("{}[{}]", at, i));
+                }
+            }
+            "integer" => assert!(value.is_u64() || value.is_i64(), "{}: {}", at, value),
+            "number" => assert!(value.is_number(), "{}: {}", at, value),
+            "string" => assert!(value.is_string(), "{}: {}", at, value),
+            "boolean" => assert!(value.is_boolean(), "{}: {}", at, value),
+            other => panic!("{}: unexpected type {}", at, other),
+        }
+    }
+
+    fn file(path: &str, layer_index: usize) -> FileInfo {
+        FileInfo {
+            path: path.to_string(),
+            size: 10,
+            hash: "h".to_string(),
+            layer_index,
+            provenance: None,
+        }
+    }
+
+    #[test]
+    fn test_report_matches_schema() {
+        let duplicates = vec![DuplicateInfo {
+            original: FileInfo {
+                provenance: Some(LayerProvenance {
+                    diff_id: "sha256:aa".to_string(),
+                    blob_digest: None,
+                    created_by: Some("RUN make".to_string()),
+                    created: None,
+                }),
+                ..file("a", 0)
+            },
+            duplicates: vec![file("b", 1)],
+            total_savings: 10,
+        }];
+        let timings = Timings::default();
+        timings.record(Phase::Scan, std::time::Duration::from_millis(5), 10);
+        let summary = DedupeSummary {
+            schema_version: crate::SCHEMA_VERSION,
+            duplicate_groups: 1,
+            duplicate_files: 1,
+            bytes_saved: 10,
+            layers: vec![LayerDigests {
+                layer_index: 1,
+                rewritten: true,
+                old_diff_id: "sha256:1".to_string(),
+                new_diff_id: "sha256:2".to_string(),
+                old_blob: "blobs/sha256/1".to_string(),
+                new_blob: "blobs/sha256/2".to_string(),
+            }],
+            old_config_digest: "sha256:3".to_string(),
+            new_config_digest: "sha256:4".to_string(),
+            manifest_digest: Some("sha256:5".to_string()),
+            stale_referrers: vec![Descriptor {
+                media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+                digest: "sha256:6".to_string(),
+                size: 100,
+                artifact_type: Some("application/spdx+json".to_string()),
+                platform: Some(Platform {
+                    architecture: "arm64".to_string(),
+                    os: "linux".to_string(),
+                    variant: Some("v8".to_string()),
+                }),
+                annotations: Some(HashMap::from([("k".to_string(), "v".to_string())])),
+            }],
+        };
+        let report = Report::new(&duplicates, &timings)
+            .with_shared_libraries(vec![SharedLibrary {
+                name: "libssl".to_string(),
+                copies: 2,
+                versions: vec![LibraryVersion {
+                    hash: "h".to_string(),
+                    size: 10,
+                    versions: vec!["3".to_string()],
+                    files: vec![file("usr/lib/libssl.so.3", 0)],
+                }],
+                duplicate_bytes: 10,
+                total_bytes: 20,
+            }])
+            .with_duplicate_packages(vec![DuplicatePackage {
+                ecosystem: Ecosystem::Python,
+                name: "numpy".to_string(),
+                version: "1.26.4".to_string(),
+                installs: vec![PackageInstall {
+                    path: "site-packages/numpy-1.26.4.dist-info".to_string(),
+                    layer_index: 1,
+                    size: 10,
+                }],
+                duplicate_bytes: 10,
+            }])
+            .with_junk_files(vec![JunkFile {
+                entry: EntryInfo {
+                    path: "tmp/x".to_string(),
+                    size: 1,
+                    layer_index: 0,
+                    kind: EntryKind::File,
+                    link_target: None,
+                    mode: 0o644,
+                    uid: 0,
+                    gid: 0,
+                    mtime: 0,
+                },
+                kind: JunkKind::CoreDump,
+                visible: false,
+            }])
+            .with_case_variants(vec![CaseVariants {
+                folded_path: "app/foo.jar".to_string(),
+                hash: "h".to_string(),
+                size: 10,
+                files: vec![file("app/Foo.jar", 0), file("app/foo.jar", 1)],
+            }])
+            .with_elf_near_duplicates(vec![ElfNearDuplicate {
+                stripped_hash: "s".to_string(),
+                files: vec![ElfFile {
+                    path: "usr/bin/x".to_string(),
+                    layer_index: 0,
+                    size: 10,
+                    hash: "h".to_string(),
+                    build_id: None,
+                    debug_bytes: 4,
+                }],
+                strip_savings: 4,
+                dedupe_savings: 6,
+            }])
+            .with_ownership(SavingsSplit {
+                base_layers: 1,
+                base: Share::default(),
+                application: Share {
+                    groups: 1,
+                    savings: 10,
+                },
+            })
+            .with_rewrite(summary);
+
+        let schema = document_schema(Document::Report);
+        let value = serde_json::to_value(&report).unwrap();
+        validate(&schema, &value, &schema, "report");
+        assert_eq!(
+            schema["$defs"]["Descriptor"]["required"],
+            json!(["mediaType", "digest", "size"])
+        );
+        assert!(schema["$defs"]["JunkFile"]["properties"]["link_target"].is_object());
+
+        let schema = document_schema(Document::Summary);
+        let value = serde_json::to_value(report.rewrite.unwrap()).unwrap();
+        validate(&schema, &value, &schema, "summary");
+
+        let plan = ModificationPlan {
+            schema_version: crate::SCHEMA_VERSION,
+            hasher: "rapidhash".to_string(),
+            diff_ids: vec!["sha256:aa".to_string(), "sha256:bb".to_string()],
+            duplicate_groups: 1,
+            layers: vec![PlannedLayer {
+                layer_index: 1,
+                changes: vec![DeDupTransaction {
+                    original_path: "a".to_string(),
+                    original_layer: 0,
+                    target_path: "b".to_string(),
+                    action: Action::Symlink,
+                    size: 10,
+                    hash: "ab".to_string(),
+                }],
+            }],
+        };
+        let schema = document_schema(Document::Plan);
+        validate(
+            &schema,
+            &serde_json::to_value(plan).unwrap(),
+            &schema,
+            "plan",
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Files that should never ship in an image, duplicated or not: logs, temp
+//! files, core dumps and editor or backup leftovers. Deleting them in a
+//! later layer doesn't help, the bytes stay in the layer that added them,
+//! so hidden ones are reported too.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::entries::{EntryInfo, EntryKind};
+use crate::paths;
+use crate::union::UnionView;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JunkKind {
+    /// Under `/var/log`
+    Log,
+    /// Under `/tmp` or `/var/tmp`
+    Temp,
+    /// `core` or `core.<pid>`
+    CoreDump,
+    /// `*~`, `*.swp`, `*.bak`, `*.orig`, `#*#` and the like
+    Backup,
+}
+
+/// A file of [`junk_files`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JunkFile {
+    #[serde(flatten)]
+    pub entry: EntryInfo,
+    pub kind: JunkKind,
+    /// False when a higher layer deletes or replaces it, which frees no
+    /// space
+    pub visible: bool,
+}
+
+/// What kind of junk the file at `path` is, if any.
+pub fn classify(path: &str) -> Option<JunkKind> {
+    let path = paths::normalize(path);
+    let name = path.rsplit('/').next().unwrap_or(path);
+    if path.starts_with("var/log/") {
+        return Some(JunkKind::Log);
+    }
+    if path.starts_with("tmp/") || path.starts_with("var/tmp/") {
+        return Some(JunkKind::Temp);
+    }
+    let pid = name.strip_prefix("core.");
+    if name == "core"
+        || pid.is_some_and(|pid| !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return Some(JunkKind::CoreDump);
+    }
+    const BACKUP_SUFFIXES: [&str; 6] = ["~", ".swp", ".swo", ".bak", ".orig", ".rej"];
+    let emacs =
+        name.starts_with(".#") || name.len() > 2 && name.starts_with('#') && name.ends_with('#');
+    if emacs || name == ".DS_Store" || BACKUP_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) {
+        return Some(JunkKind::Backup);
+    }
+    None
+}
+
+/// Every non-empty junk file among `entries`, largest first. `view` is the
+/// union of them, to tell which are still visible.
+pub fn junk_files(entries: &[EntryInfo], view: &UnionView) -> Vec<JunkFile> {
+    let mut junk: Vec<JunkFile> = entries
+        .iter()
+        .filter(|e| e.kind == EntryKind::File && e.size > 0)
+        .filter_map(|entry| {
+            Some(JunkFile {
+                kind: classify(&entry.path)?,
+                visible: view.get(&entry.path).is_some_and(|visible| {
+                    visible.layer_index == entry.layer_index && visible.kind == EntryKind::File
+                }),
+                entry: entry.clone(),
+            })
+        })
+        .collect();
+    junk.sort_by_key(|j| Reverse(j.entry.size));
+    junk
+}
+
+/// Logs the total per kind and the largest files.
+pub fn print_junk_files(junk: &[JunkFile]) {
+    if junk.is_empty() {
+        return;
+    }
+    let mut kinds: BTreeMap<JunkKind, (usize, u64)> = BTreeMap::new();
+    for file in junk {
+        let (count, size) = kinds.entry(file.kind).or_default();
+        *count += 1;
+        *size += file.entry.size;
+    }
+    info!("Files that shouldn't be in an image:");
+    for (kind, (count, size)) in kinds {
+        info!(
+            "\t{:?}: {} files, {}",
+            kind,
+            count,
+            format_size(size, BINARY)
+        );
+    }
+    for file in junk.iter().take(20) {
+        info!(
+            "\t\t{} (layer {}, {}{})",
+            file.entry.path,
+            file.entry.layer_index,
+            format_size(file.entry.size, BINARY),
+            if file.visible { "" } else { ", hidden" }
+        );
+    }
+    info!("=============================");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify("var/log/apt/history.log"), Some(JunkKind::Log));
+        assert_eq!(classify("./tmp/build.tar"), Some(JunkKind::Temp));
+        assert_eq!(classify("app/core.1234"), Some(JunkKind::CoreDump));
+        assert_eq!(classify("app/core"), Some(JunkKind::CoreDump));
+        assert_eq!(classify("app/core.js"), None);
+        assert_eq!(classify("etc/nginx.conf~"), Some(JunkKind::Backup));
+        assert_eq!(classify("src/.main.rs.swp"), Some(JunkKind::Backup));
+        assert_eq!(classify("src/#main.rs#"), Some(JunkKind::Backup));
+        assert_eq!(classify("usr/lib/libc.so.6"), None);
+        assert_eq!(classify("var/logs/x"), None);
+    }
+}
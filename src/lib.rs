@@ -1,8 +1,92 @@
+pub mod advice;
 pub mod analyzer;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod attribution;
+pub mod baseline;
+#[cfg(feature = "rewrite")]
+pub mod bench;
+pub mod cancel;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod case_variants;
+pub mod checkpoint;
+#[cfg(feature = "cli")]
 pub mod cli;
+#[cfg(feature = "reports")]
+pub mod compose;
+#[cfg(feature = "reports")]
+pub mod compressibility;
+#[cfg(feature = "reports")]
+pub mod database;
+#[cfg(feature = "sha256")]
+pub mod digest_writer;
+pub mod disk_space;
+pub mod elf;
+pub mod entries;
+pub mod error;
+pub mod hasher;
+#[cfg(feature = "reports")]
+pub mod json_schema;
+pub mod junk;
+pub mod libraries;
+#[cfg(feature = "reports")]
+pub mod metrics;
+#[cfg(feature = "network")]
+pub mod notify;
+pub mod options;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod ownership;
+pub mod packages;
+#[cfg(feature = "reports")]
+pub mod parquet;
+pub mod paths;
+pub mod pipeline;
+pub mod policy;
+pub mod progress;
+pub mod provenance;
+#[cfg(feature = "proxy")]
+pub mod proxy;
+#[cfg(feature = "reports")]
+pub mod report;
+pub mod scan;
 pub mod schemas;
-pub mod sha_writer;
+#[cfg(feature = "serve")]
+pub mod serve;
+#[cfg(feature = "reports")]
+pub mod similarity;
+pub mod stream;
 pub mod tee_writer;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod timings;
+pub mod transport;
+pub mod union;
+pub mod unpack;
+#[cfg(feature = "reports")]
+pub mod workloads;
+#[cfg(feature = "reports")]
+pub(crate) mod yaml;
+
+/// Version of the JSON wire format shared by reports, summaries and
+/// checkpoint caches; every top-level document carries it as
+/// `schema_version`. Adding fields keeps the version, renaming, removing or
+/// changing the meaning of one bumps it.
+pub const SCHEMA_VERSION: u32 = 1;
 
 pub use analyzer::Analyzer;
+pub use error::{DedupeError, Result};
+pub use hasher::{ContentHasher, Digest, HasherFactory};
+pub use options::{AnalyzerBuilder, AnalyzerOptions, HashAlgorithm};
+#[cfg(feature = "rewrite")]
+pub use pipeline::dedupe_image;
+pub use pipeline::{DedupeSummary, LayerDigests};
+pub use policy::{
+    Action, CacheFriendlyPolicy, CanonicalPolicy, DedupPolicy, DefaultPolicy, PolicyContext,
+    Strategy,
+};
+pub use progress::ProgressSink;
 pub use schemas::{Manifest, ManifestFile};
+pub use stream::{StreamScan, scan_stream};
+pub use transport::ImageRef;
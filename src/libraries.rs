@@ -0,0 +1,202 @@
+//! Shared libraries copied, or vendored in several versions, across an
+//! image. They are the most common source of duplicate bytes and the
+//! easiest to fix, so they are reported by library rather than per file.
+//!
+//! Libraries are recognized by the soname convention of their file name,
+//! `lib<name>.so[.<version>]`, among the files [`crate::Analyzer::scan_files`]
+//! hashed; their contents aren't parsed.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::analyzer::FileInfo;
+
+/// One content of a library, and every file holding it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryVersion {
+    pub hash: String,
+    pub size: u64,
+    /// Versions the file names give, e.g. `3` and `3.0.2`, deduplicated.
+    /// Empty for an unversioned `libfoo.so`.
+    pub versions: Vec<String>,
+    pub files: Vec<FileInfo>,
+}
+
+/// A library present more than once, under any name or version.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedLibrary {
+    /// File name up to `.so`, e.g. `libssl`
+    pub name: String,
+    /// Files holding it, in all versions
+    pub copies: usize,
+    /// Different contents, largest first
+    pub versions: Vec<LibraryVersion>,
+    /// Bytes of the copies beyond one per version, which deduplication
+    /// saves
+    pub duplicate_bytes: u64,
+    /// Bytes of all copies
+    pub total_bytes: u64,
+}
+
+/// `libssl appears 3× in 2 versions (12.00 MiB, 4.00 MiB duplicated)`
+impl fmt::Display for SharedLibrary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} appears {}× in {} version{} ({}, {} duplicated)",
+            self.name,
+            self.copies,
+            self.versions.len(),
+            if self.versions.len() == 1 { "" } else { "s" },
+            format_size(self.total_bytes, BINARY),
+            format_size(self.duplicate_bytes, BINARY)
+        )
+    }
+}
+
+/// Splits `libssl.so.3.0.2` into `libssl` and `3.0.2`. Anything that isn't
+/// a `lib*.so` name with a numeric version, if any, is `None`.
+pub fn parse_soname(path: &str) -> Option<(&str, &str)> {
+    let file_name = path.rsplit('/').next()?;
+    let (name, version) = file_name.split_once(".so")?;
+    let version = match version {
+        "" => "",
+        version => version.strip_prefix('.')?,
+    };
+    let numeric = version
+        .split('.')
+        .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()));
+    if !name.starts_with("lib") || name.len() == 3 || !(version.is_empty() || numeric) {
+        return None;
+    }
+    Some((name, version))
+}
+
+/// Libraries among `files` with more than one copy, most bytes first.
+pub fn shared_libraries(files: &[FileInfo]) -> Vec<SharedLibrary> {
+    let mut by_name: BTreeMap<&str, BTreeMap<&str, LibraryVersion>> = BTreeMap::new();
+    for file in files {
+        let Some((name, version)) = parse_soname(&file.path) else {
+            continue;
+        };
+        let entry = by_name
+            .entry(name)
+            .or_default()
+            .entry(&file.hash)
+            .or_insert_with(|| LibraryVersion {
+                hash: file.hash.clone(),
+                size: file.size,
+                versions: Vec::new(),
+                files: Vec::new(),
+            });
+        if !version.is_empty() && !entry.versions.iter().any(|v| v == version) {
+            entry.versions.push(version.to_string());
+        }
+        entry.files.push(file.clone());
+    }
+    let mut libraries: Vec<SharedLibrary> = by_name
+        .into_iter()
+        .map(|(name, versions)| {
+            let mut versions: Vec<LibraryVersion> = versions.into_values().collect();
+            versions.sort_by_key(|v| Reverse(v.size));
+            SharedLibrary {
+                name: name.to_string(),
+                copies: versions.iter().map(|v| v.files.len()).sum(),
+                duplicate_bytes: versions
+                    .iter()
+                    .map(|v| v.size * (v.files.len() as u64 - 1))
+                    .sum(),
+                total_bytes: versions.iter().map(|v| v.size * v.files.len() as u64).sum(),
+                versions,
+            }
+        })
+        .filter(|library| library.copies > 1)
+        .collect();
+    libraries.sort_by_key(|library| Reverse(library.total_bytes));
+    libraries
+}
+
+/// Logs every library and where each of its versions lives.
+pub fn print_shared_libraries(libraries: &[SharedLibrary]) {
+    if libraries.is_empty() {
+        return;
+    }
+    info!("Shared libraries present more than once:");
+    for library in libraries {
+        info!("\t{}", library);
+        for version in &library.versions {
+            let paths: Vec<String> = version
+                .files
+                .iter()
+                .map(|f| format!("{} (layer {})", f.path, f.layer_index))
+                .collect();
+            info!(
+                "\t\t{} {}: {}",
+                if version.versions.is_empty() {
+                    "unversioned".to_string()
+                } else {
+                    version.versions.join(", ")
+                },
+                format_size(version.size, BINARY),
+                paths.join(", ")
+            );
+        }
+    }
+    info!("=============================");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, hash: &str, size: u64, layer_index: usize) -> FileInfo {
+        FileInfo {
+            path: path.to_string(),
+            size,
+            hash: hash.to_string(),
+            layer_index,
+            provenance: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_soname() {
+        assert_eq!(
+            parse_soname("usr/lib/libssl.so.3.0.2"),
+            Some(("libssl", "3.0.2"))
+        );
+        assert_eq!(parse_soname("opt/libz.so"), Some(("libz", "")));
+        assert_eq!(parse_soname("usr/lib/libfoo.so.bak"), None);
+        assert_eq!(parse_soname("usr/lib/ld-linux.so.2"), None);
+        assert_eq!(parse_soname("usr/lib/lib.so"), None);
+        assert_eq!(parse_soname("usr/lib/libfoo.sock"), None);
+    }
+
+    #[test]
+    fn test_versions_grouped_by_contents() {
+        let files = vec![
+            file("usr/lib/libssl.so.3", "a", 100, 0),
+            file("opt/app/libssl.so.3", "a", 100, 1),
+            file("opt/other/libssl.so.1.1", "b", 80, 2),
+            file("usr/lib/libz.so.1", "c", 50, 0),
+        ];
+        let libraries = shared_libraries(&files);
+        assert_eq!(libraries.len(), 1);
+        let libssl = &libraries[0];
+        assert_eq!(libssl.copies, 3);
+        assert_eq!(libssl.versions.len(), 2);
+        assert_eq!(libssl.duplicate_bytes, 100);
+        assert_eq!(libssl.total_bytes, 280);
+        assert_eq!(libssl.versions[1].versions, vec!["1.1"]);
+        assert!(
+            libssl
+                .to_string()
+                .starts_with("libssl appears 3× in 2 versions")
+        );
+    }
+}
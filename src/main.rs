@@ -1,66 +1,1202 @@
+use std::cmp::Reverse;
 use std::fs::File;
-use std::io::{self, BufReader, Write};
+use std::io::{self, BufReader, BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{ChildStdin, ExitCode, Stdio};
+use std::time::Instant;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use chrono::Local;
 use clap::Parser;
-use docker_duplicate_files::analyzer::Analyzer;
-use docker_duplicate_files::cli::Args;
-use env_logger::Builder;
-use log::info;
+use docker_duplicate_files::advice::link_script;
+use docker_duplicate_files::analyzer::{Analyzer, DuplicateInfo, Layer, ModificationPlan};
+use docker_duplicate_files::baseline::Baseline;
+use docker_duplicate_files::bench::{bench, gzip_bench, synthetic_image};
+use docker_duplicate_files::cancel;
+use docker_duplicate_files::case_variants::{case_variants, print_case_variants};
+use docker_duplicate_files::cli::{
+    Args, BenchArgs, Command, DedupeArgs, Import, LogFormat, Referrers,
+};
+use docker_duplicate_files::compose::{ScannedImage, compose_report, read_compose};
+use docker_duplicate_files::database;
+use docker_duplicate_files::elf::print_elf_near_duplicates;
+use docker_duplicate_files::entries::{EntryInfo, read_layer_entries};
+use docker_duplicate_files::json_schema::document_schema;
+use docker_duplicate_files::junk::print_junk_files;
+use docker_duplicate_files::libraries::{print_shared_libraries, shared_libraries};
+use docker_duplicate_files::metrics::{RunMetrics, write_textfile};
+use docker_duplicate_files::notify::{Notification, post};
+use docker_duplicate_files::options::AnalyzerOptions;
+use docker_duplicate_files::ownership::{
+    SavingsSplit, base_layer_count, print_savings_split, split_savings,
+};
+use docker_duplicate_files::packages::print_duplicate_packages;
+use docker_duplicate_files::proxy::{ProxyOptions, proxy};
+use docker_duplicate_files::report::Report;
+use docker_duplicate_files::scan::print_possible_savings;
+use docker_duplicate_files::scan_stream;
+use docker_duplicate_files::serve::{ServeOptions, serve};
+use docker_duplicate_files::similarity::{LayerFiles, cluster_layers};
+use docker_duplicate_files::timings::{Phase, Timings};
+use docker_duplicate_files::transport::s3::{Location, Upload};
+use docker_duplicate_files::union::disk_usage;
+use docker_duplicate_files::workloads::{fleet_report, read_workloads};
+use docker_duplicate_files::{DedupeSummary, ImageRef};
+use humansize::{BINARY, format_size};
+use itertools::Itertools;
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Span, Subscriber, error, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::format::{self, FmtSpan, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::{FmtContext, FormattedFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     let args = Args::parse();
     args.validate()?;
+    let exporting = init_logging(&args);
 
-    let mut builder = Builder::new();
+    ctrlc::set_handler(|| {
+        if cancel::is_cancelled() {
+            // Second signal: the user doesn't want to wait for cleanup
+            std::process::exit(130);
+        }
+        warn!("Interrupted, cleaning up (press Ctrl-C again to exit immediately)");
+        cancel::cancel();
+    })?;
 
-    builder.format(|buf, record| {
-        writeln!(
-            buf,
-            "[{}] {}: {}",
-            Local::now().format("%Y-%m-%d %H:%M:%S"),
-            record.level(),
-            record.args()
-        )
-    });
-
-    if args.stdout {
-        builder.filter_level(log::LevelFilter::Warn);
+    // One trace per run when exporting, without prefixing every log line
+    // with the span otherwise
+    let root = if exporting {
+        info_span!("run")
     } else {
-        builder.filter_level(log::LevelFilter::Info);
+        Span::none()
+    };
+    let webhook = args
+        .webhook
+        .clone()
+        .map(|url| (url, args.webhook_headers()));
+    let mut notification = Notification::new(
+        args.image
+            .as_ref()
+            .map_or("-".to_string(), |i| i.to_string()),
+    );
+    let result = root.in_scope(|| run(args, &mut notification));
+    if let Some((url, headers)) = webhook {
+        notification.finish(result.as_ref().err().map(|e| e.to_string()));
+        match post(&url, &headers, &notification) {
+            Ok(()) => info!("Notified {}", url),
+            Err(e) => warn!("{}", e),
+        }
+    }
+    match result {
+        Ok(()) => Ok(ExitCode::SUCCESS),
+        Err(_) if cancel::is_cancelled() => {
+            error!("Interrupted, no output was written");
+            Ok(ExitCode::from(130))
+        }
+        Err(e) => Err(e),
     }
-    builder.init();
+}
 
-    let analyzer = if let Some(image_path) = args.image {
-        info!("Running on image: {}", image_path);
-        Analyzer::load_from_path(image_path, args.min_size, args.no_compression)?
+/// Logs go to stderr at info (warn with --stdout) unless RUST_LOG says
+/// otherwise, either as plain text or as one JSON object per line. Returns
+/// whether spans are also exported to OpenTelemetry.
+fn init_logging(args: &Args) -> bool {
+    let default_level = if args.stdout {
+        LevelFilter::WARN
     } else {
-        info!("Running on image from stdin");
-        let stdin = io::stdin();
-        let reader = BufReader::new(stdin.lock());
-        Analyzer::load(reader, args.min_size, args.no_compression)?
+        LevelFilter::INFO
     };
+    let filter = EnvFilter::builder()
+        .with_default_directive(default_level.into())
+        .from_env_lossy();
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(io::stderr().is_terminal())
+        .with_writer(io::stderr);
+    #[cfg(feature = "otel")]
+    let otlp = docker_duplicate_files::otel::Otlp::from_env().map(|otlp| otlp.install());
+    #[cfg(not(feature = "otel"))]
+    let otlp: Option<tracing_subscriber::layer::Identity> = None;
+    let exporting = otlp.is_some();
+    match args.log_format {
+        LogFormat::Text => builder.event_format(TextFormat).finish().with(otlp).init(),
+        LogFormat::Json => builder
+            .json()
+            .with_span_events(FmtSpan::CLOSE)
+            .with_current_span(true)
+            .finish()
+            .with(otlp)
+            .init(),
+    }
+    exporting
+}
+
+/// `[2024-01-01 12:00:00] INFO: message`, prefixed with the active spans
+struct TextFormat;
+
+impl<S, N> FormatEvent<S, N> for TextFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        write!(
+            writer,
+            "[{}] {}: ",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            event.metadata().level()
+        )?;
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                write!(writer, "{}", span.name())?;
+                if let Some(fields) = span.extensions().get::<FormattedFields<N>>()
+                    && !fields.is_empty()
+                {
+                    write!(writer, "{{{}}}", fields)?;
+                }
+                write!(writer, ": ")?;
+            }
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+fn run(args: Args, notification: &mut Notification) -> Result<()> {
+    let options = args.analyzer_options()?;
+    if let Some(command) = &args.command {
+        return run_command(&args, command, options);
+    }
+    if let Some(path) = &args.plan {
+        return apply_plan(&args, options, path, notification);
+    }
+    if args.report_only()
+        && args.image.is_none()
+        && args.work_dir.is_none()
+        && !args.packages
+        && !args.junk
+        && !args.elf
+        && args.db.is_none()
+        && args.parquet.is_none()
+        && args.metrics_file.is_none()
+        && args.save_plan.is_none()
+    {
+        return dry_run_streaming(&args, options, notification);
+    }
+    let analyzer = load_image(&args, options.clone())?;
 
     info!("Finding duplicates...");
-    let duplicates = analyzer.find_duplicates()?;
+    let files = analyzer.scan_files()?;
+    let libraries = shared_libraries(&files);
+    let files_scanned = files.len();
+    let inventory = (args.db.is_some() || args.parquet.is_some()).then(|| files.clone());
+    let duplicates = analyzer.group_duplicates(files);
+    notification.record_duplicates(&duplicates);
     let _ = analyzer.print_possible_savings(&duplicates);
+    print_shared_libraries(&libraries);
+    if let (Some(path), Some(files)) = (&args.db, &inventory) {
+        info!("Writing inventory to {}", path.display());
+        let steps = analyzer.attribute_waste(&duplicates)?;
+        database::write_database(path, &args.sqlite3, &analyzer, files, &duplicates, &steps)?;
+    }
+    if let (Some(dir), Some(files)) = (&args.parquet, &inventory) {
+        info!("Writing Parquet tables to {}", dir.display());
+        let image = args
+            .image
+            .as_ref()
+            .map_or("-".to_string(), |i| i.to_string());
+        database::write_parquet(dir, &image, &analyzer, files, &duplicates)?;
+    }
+    let mut report = Report::new(&duplicates, analyzer.timings()).with_shared_libraries(libraries);
+    if args.packages {
+        info!("Finding duplicated packages...");
+        let packages = analyzer.find_duplicate_packages()?;
+        print_duplicate_packages(&packages);
+        report = report.with_duplicate_packages(packages);
+    }
+    if args.junk {
+        info!("Looking for files that shouldn't be in the image...");
+        let junk = analyzer.find_junk_files()?;
+        print_junk_files(&junk);
+        report = report.with_junk_files(junk);
+    }
+    if args.elf {
+        info!("Looking for binaries differing only in debug info...");
+        let groups = analyzer.find_elf_near_duplicates()?;
+        print_elf_near_duplicates(&groups);
+        report = report.with_elf_near_duplicates(groups);
+    }
+    if args.case_insensitive {
+        let variants = case_variants(&duplicates);
+        print_case_variants(&variants);
+        report = report.with_case_variants(variants);
+    }
+    let diff_ids = &analyzer.config().rootfs.diff_ids;
+    if let Some(split) = split_ownership(&args, options, diff_ids, &duplicates)? {
+        report = report.with_ownership(split);
+    }
 
-    if args.dry_run {
+    if let Some(path) = &args.save_plan {
+        let plan = analyzer.plan(duplicates.clone())?;
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &plan)?;
+        info!(
+            "Saved the plan, {} changes in {} layers, to {}",
+            plan.layers.iter().map(|l| l.changes.len()).sum::<usize>(),
+            plan.layers.len(),
+            path.display()
+        );
+    }
+
+    if args.report_only() {
         info!("Dry run mode: exiting without creating deduplicated image");
-        return Ok(());
+        analyzer.timings().print_summary();
+        write_metrics(&args, &analyzer, files_scanned, &duplicates, None)?;
+        write_report(&args, report)?;
+        return check_baseline(&args, &duplicates, notification);
     }
 
-    if let Some(output_path_str) = args.output {
-        info!("Writing deduplicated image to {}", output_path_str);
-        let output_file = File::create(&output_path_str)
-            .with_context(|| format!("Failed to create output file: {}", output_path_str))?;
-        analyzer.create_deduplicated_image(duplicates, output_file)?;
+    let summary = if let Some(output) = &args.output {
+        info!("Writing deduplicated image to {}", output);
+        let mut summary = output.write(&analyzer, duplicates.clone())?;
+        after_write(&args, output, &mut summary)?;
+        summary
+    } else if let Some(runtime) = args.import {
+        import_image(&args, runtime, |writer| {
+            analyzer.create_deduplicated_image(duplicates.clone(), writer)
+        })?
     } else {
         info!("Writing deduplicated image to stdout");
         let stdout = io::stdout();
         let writer = stdout.lock();
-        analyzer.create_deduplicated_image(duplicates, writer)?;
+        analyzer.create_deduplicated_image(duplicates.clone(), writer)?
+    };
+    info!(
+        "Rewrote {} of {} layers, saved {}",
+        summary.layers.iter().filter(|l| l.rewritten).count(),
+        summary.layers.len(),
+        format_size(summary.bytes_saved, BINARY)
+    );
+    summary.print_digests();
+    analyzer.timings().print_summary();
+    write_metrics(&args, &analyzer, files_scanned, &duplicates, Some(&summary))?;
+    let report = Report {
+        timings: analyzer.timings().summary(),
+        ..report
+    };
+    notification.record_rewrite(summary.clone());
+    write_report(&args, report.with_rewrite(summary))?;
+    check_baseline(&args, &duplicates, notification)
+}
+
+/// --plan: checks the saved plan against the image and, unless --dry-run,
+/// writes the image it describes.
+fn apply_plan(
+    args: &Args,
+    options: AnalyzerOptions,
+    path: &Path,
+    notification: &mut Notification,
+) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let plan: ModificationPlan = serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Failed to read the plan in {}", path.display()))?;
+    let analyzer = load_image(args, options)?;
+    if args.report_only() {
+        analyzer.validate_plan(&plan)?;
+        info!("The plan in {} still applies", path.display());
+        return Ok(());
+    }
+    let summary = if let Some(output) = &args.output {
+        info!("Writing planned image to {}", output);
+        let mut summary = output.write_plan(&analyzer, &plan)?;
+        after_write(args, output, &mut summary)?;
+        summary
+    } else if let Some(runtime) = args.import {
+        import_image(args, runtime, |writer| {
+            analyzer.create_image_from_plan(&plan, writer)
+        })?
+    } else {
+        info!("Writing planned image to stdout");
+        analyzer.create_image_from_plan(&plan, io::stdout().lock())?
+    };
+    info!(
+        "Rewrote {} of {} layers, saved {}",
+        summary.layers.iter().filter(|l| l.rewritten).count(),
+        summary.layers.len(),
+        format_size(summary.bytes_saved, BINARY)
+    );
+    summary.print_digests();
+    analyzer.timings().print_summary();
+    notification.record_rewrite(summary);
+    Ok(())
+}
+
+/// Handles --referrers, --verify-output and --smoke-test, writes
+/// --digest-file and runs --post-write for the image just written to
+/// `output`.
+fn after_write(args: &Args, output: &ImageRef, summary: &mut DedupeSummary) -> Result<()> {
+    let changed = summary.layers.iter().any(|l| l.rewritten);
+    if changed && args.post_write.is_none() && matches!(args.image, Some(ImageRef::Docker(_))) {
+        warn!("Layer digests changed, so signatures of the original image don't cover this one");
+    }
+    if let (Some(mode), Some(image)) = (args.referrers, &args.image) {
+        summary.stale_referrers = match (mode, &summary.manifest_digest) {
+            (Referrers::Copy, Some(digest)) => image.copy_referrers(output, digest)?,
+            _ => image.referrers()?,
+        };
+        for referrer in &summary.stale_referrers {
+            warn!(
+                "{} {} is attached to the original image and must be regenerated",
+                referrer
+                    .artifact_type
+                    .as_deref()
+                    .unwrap_or(&referrer.media_type),
+                referrer.digest
+            );
+        }
+    }
+    if args.verify_output {
+        info!("Verifying {}", output);
+        // The work dir holds the input's checkpoint, which loading the
+        // output there would discard
+        let mut options = args.analyzer_options()?;
+        options.work_dir = None;
+        let verification = output.load(options)?.verify_written(summary)?;
+        for problem in &verification.problems {
+            error!("{}: {}", problem.path, problem.message);
+        }
+        if !verification.is_ok() {
+            bail!(
+                "{} failed verification with {} problems",
+                output,
+                verification.problems.len()
+            );
+        }
+    }
+    smoke_test(args, output, summary)?;
+    let Some(digest) = &summary.manifest_digest else {
+        return Ok(());
+    };
+    if let Some(path) = &args.digest_file {
+        std::fs::write(path, format!("{}\n", digest))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        info!("Wrote manifest digest {} to {}", digest, path.display());
+    }
+    if let Some(command) = &args.post_write {
+        let image = output.pinned(digest);
+        info!("Running post-write command for {}", image);
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("DEDUPE_DIGEST", digest)
+            .env("DEDUPE_IMAGE", &image)
+            .status()
+            .with_context(|| format!("Failed to run {}", command))?;
+        if !status.success() {
+            bail!("Post-write command {:?} failed: {}", command, status);
+        }
+    }
+    Ok(())
+}
+
+/// --import: streams the archive `write` produces into the runtime's
+/// import command, which names the image by --tag.
+fn import_image(
+    args: &Args,
+    runtime: Import,
+    write: impl FnOnce(ChildStdin) -> docker_duplicate_files::Result<DedupeSummary>,
+) -> Result<DedupeSummary> {
+    let Import::Containerd = runtime;
+    info!(
+        "Importing the deduplicated image into containerd namespace {}",
+        args.containerd_namespace
+    );
+    let mut child = std::process::Command::new("ctr")
+        .args(["--namespace", &args.containerd_namespace])
+        .args(["images", "import", "-"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Failed to run ctr images import")?;
+    let stdin = child.stdin.take().context("ctr has no stdin")?;
+    // ctr fails on the truncated archive if writing does, so the write
+    // error is the one to report
+    let written = write(stdin);
+    let status = child.wait()?;
+    let summary = written?;
+    if !status.success() {
+        bail!("ctr images import failed: {}", status);
+    }
+    Ok(summary)
+}
+
+/// Runs the --smoke-test command in the image written to `output` with
+/// the local container runtime, loading `docker save` archives into it
+/// first. The run fails if the command does.
+fn smoke_test(args: &Args, output: &ImageRef, summary: &DedupeSummary) -> Result<()> {
+    let Some(command) = &args.smoke_test else {
+        return Ok(());
+    };
+    let runtime = &args.smoke_runtime;
+    let image = match (output, &summary.manifest_digest) {
+        (ImageRef::DockerArchive(path), _) => {
+            info!("Loading {} into {}", path.display(), runtime);
+            let status = std::process::Command::new(runtime)
+                .args(["load", "-q", "-i"])
+                .arg(path)
+                .status()
+                .with_context(|| format!("Failed to run {} load", runtime))?;
+            if !status.success() {
+                bail!("{} load failed: {}", runtime, status);
+            }
+            // Runtimes take the config digest as the image ID
+            summary.new_config_digest.clone()
+        }
+        (ImageRef::Docker(_), Some(digest)) => output.pinned(digest),
+        _ => bail!("--smoke-test can't run {}", output),
+    };
+    info!("Smoke testing {} with {:?}", image, command);
+    let status = std::process::Command::new(runtime)
+        .args(["run", "--rm", &image, "sh", "-c", command])
+        .status()
+        .with_context(|| format!("Failed to run {} run", runtime))?;
+    if !status.success() {
+        bail!("Smoke test {:?} failed in {}: {}", command, image, status);
+    }
+    info!("Smoke test passed");
+    Ok(())
+}
+
+/// `docker save`s `reference` from the daemon `runtime` talks to into
+/// `path`.
+fn save_image(runtime: &str, reference: &str, path: &Path) -> Result<()> {
+    info!("Exporting {} from {}", reference, runtime);
+    let status = std::process::Command::new(runtime)
+        .args(["save", "-o"])
+        .arg(path)
+        .arg(reference)
+        .status()
+        .with_context(|| format!("Failed to run {} save", runtime))?;
+    if !status.success() {
+        bail!("{} save {} failed: {}", runtime, reference, status);
+    }
+    Ok(())
+}
+
+/// `dedupe`: saves the image from the daemon, deduplicates it and loads
+/// it back under its tag, or --tag. The original image stays in the
+/// daemon, untagged once its tag moved, until pruned.
+fn docker_dedupe(args: &DedupeArgs, mut options: AnalyzerOptions) -> Result<()> {
+    // The docker CLI tells plugins where it is, for calling back into it
+    let runtime = args
+        .runtime
+        .clone()
+        .or_else(|| std::env::var("DOCKER_CLI_PLUGIN_ORIGINAL_CLI_COMMAND").ok())
+        .unwrap_or_else(|| "docker".to_string());
+    let tag = args.tag.as_ref().unwrap_or(&args.reference);
+    if tag.contains('@') || tag.starts_with("sha256:") {
+        bail!(
+            "{} isn't a tag, name the deduplicated image with --tag",
+            tag
+        );
+    }
+    options.repo_tags = vec![tag.clone()];
+    let dir = tempfile::Builder::new()
+        .prefix("docker-dedupe")
+        .tempdir_in(options.temp_dir())?;
+
+    let exported = dir.path().join("image.tar");
+    save_image(&runtime, &args.reference, &exported)?;
+    let analyzer = Analyzer::from_path(&exported, options)?;
+    let duplicates = analyzer.find_duplicates()?;
+    let _ = analyzer.print_possible_savings(&duplicates);
+    if duplicates.is_empty() {
+        info!(
+            "Nothing to deduplicate, leaving {} as it is",
+            args.reference
+        );
+        return Ok(());
+    }
+    if args.dry_run {
+        info!("Dry run mode: exiting without loading a deduplicated image");
+        return Ok(());
+    }
+
+    let deduped = dir.path().join("deduped.tar");
+    let summary = ImageRef::DockerArchive(deduped.clone()).write(&analyzer, duplicates)?;
+    info!("Loading the deduplicated image into {} as {}", runtime, tag);
+    let status = std::process::Command::new(&runtime)
+        .args(["load", "-q", "-i"])
+        .arg(&deduped)
+        .status()
+        .with_context(|| format!("Failed to run {} load", runtime))?;
+    if !status.success() {
+        bail!("{} load failed: {}", runtime, status);
+    }
+    info!(
+        "Saved {}, {} is now {}, was {}",
+        format_size(summary.bytes_saved, BINARY),
+        tag,
+        summary.new_config_digest,
+        summary.old_config_digest
+    );
+    Ok(())
+}
+
+fn load_image(args: &Args, options: AnalyzerOptions) -> Result<Analyzer> {
+    let analyzer = if let Some(image) = &args.image {
+        info!("Running on image: {}", image);
+        image.load(options)?
+    } else {
+        info!("Running on image from stdin");
+        let stdin = io::stdin();
+        Analyzer::from_reader(BufReader::new(stdin.lock()), options)?
+    };
+    Ok(analyzer)
+}
+
+/// Times the pipeline, or gzip alone with `--gzip`, over what `load`
+/// returns and prints the results.
+fn run_bench(
+    bench_args: &BenchArgs,
+    mut load: impl FnMut() -> docker_duplicate_files::Result<Analyzer>,
+) -> Result<()> {
+    let runs = bench_args.runs as usize;
+    if bench_args.gzip {
+        let report = gzip_bench(runs, &load()?)?;
+        if bench_args.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print_lines(report.lines().into_iter())?;
+        }
+        return Ok(());
+    }
+    let report = bench(runs, !bench_args.no_rewrite, load)?;
+    if bench_args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_lines(report.lines().into_iter())?;
+    }
+    Ok(())
+}
+
+fn run_command(args: &Args, command: &Command, options: AnalyzerOptions) -> Result<()> {
+    match command {
+        Command::Serve(serve_args) => serve(ServeOptions {
+            listen: serve_args.listen.clone(),
+            data_dir: serve_args.data_dir.clone(),
+            workers: serve_args.workers,
+            max_upload: serve_args.max_upload,
+            analyzer: options,
+        })?,
+        Command::Proxy(proxy_args) => proxy(ProxyOptions {
+            listen: proxy_args.listen.clone(),
+            upstream: proxy_args.upstream.clone(),
+            data_dir: proxy_args.data_dir.clone(),
+            dedupe_suffix: proxy_args.dedupe_suffix.clone(),
+            workers: proxy_args.workers,
+            analyzer: options,
+        })?,
+        Command::Flatten(flatten_args) => {
+            let analyzer = load_image(args, options)?;
+            match &flatten_args.output {
+                Some(path) => {
+                    let file = File::create(path)
+                        .with_context(|| format!("Failed to create {}", path.display()))?;
+                    analyzer.flatten(file)?;
+                    info!("Wrote the flattened rootfs to {}", path.display());
+                }
+                None => {
+                    analyzer.flatten(io::stdout().lock())?;
+                }
+            }
+        }
+        Command::Extract(extract_args) => {
+            let analyzer = load_image(args, options)?;
+            let extract = |writer: &mut dyn Write| match (&extract_args.path, extract_args.layer) {
+                (Some(path), _) => analyzer.extract_file(path, writer),
+                (None, Some(index)) => analyzer.extract_layer(index, writer),
+                (None, None) => unreachable!("clap requires a path or --layer"),
+            };
+            match &extract_args.output {
+                Some(path) => {
+                    let mut file = File::create(path)
+                        .with_context(|| format!("Failed to create {}", path.display()))?;
+                    let bytes = extract(&mut file)?;
+                    info!("Wrote {} bytes to {}", bytes, path.display());
+                }
+                None => {
+                    extract(&mut io::stdout().lock())?;
+                }
+            }
+        }
+        Command::Squash(squash_args) => {
+            let analyzer = load_image(args, options)?;
+            let summary = match &args.output {
+                Some(output) => {
+                    info!("Writing squashed image to {}", output);
+                    let mut summary = output.write_squashed(&analyzer, squash_args.from)?;
+                    after_write(args, output, &mut summary)?;
+                    summary
+                }
+                None => analyzer.create_squashed_image(squash_args.from, io::stdout().lock())?,
+            };
+            info!(
+                "Squashed {} layers into one",
+                summary.layers.iter().filter(|l| l.rewritten).count()
+            );
+            summary.print_digests();
+        }
+        Command::Split(split_args) => {
+            let analyzer = load_image(args, options)?;
+            let split_by = split_args.split_by();
+            let summary = match &args.output {
+                Some(output) => {
+                    info!("Writing split image to {}", output);
+                    let mut summary = output.write_split(&analyzer, split_args.layer, split_by)?;
+                    after_write(args, output, &mut summary)?;
+                    summary
+                }
+                None => {
+                    analyzer.create_split_image(split_args.layer, split_by, io::stdout().lock())?
+                }
+            };
+            info!(
+                "Split layer {} into {}",
+                split_args.layer,
+                summary.layers.iter().filter(|l| l.rewritten).count()
+            );
+            summary.print_digests();
+        }
+        Command::Ls(ls_args) => {
+            let analyzer = load_image(args, options)?;
+            let entries: Vec<EntryInfo> = match ls_args.layer {
+                Some(index) => read_layer_entries(layer(&analyzer, index)?)?,
+                None if ls_args.all => analyzer.entries().collect::<Result<_, _>>()?,
+                None => analyzer.union_view()?.iter().cloned().collect(),
+            };
+            print_lines(entries.iter().map(|entry| {
+                if ls_args.json {
+                    serde_json::to_string(entry).unwrap_or_default()
+                } else {
+                    entry.to_string()
+                }
+            }))?;
+        }
+        Command::Du(du_args) => {
+            let analyzer = load_image(args, options)?;
+            let entries: Vec<EntryInfo> = match du_args.layer {
+                Some(index) => read_layer_entries(layer(&analyzer, index)?)?,
+                None => analyzer.union_view()?.iter().cloned().collect(),
+            };
+            let mut usage = disk_usage(&entries, du_args.depth);
+            if du_args.by_size {
+                usage.sort_by_key(|dir| Reverse(dir.size));
+            }
+            print_lines(usage.iter().map(|dir| {
+                if du_args.json {
+                    return serde_json::to_string(dir).unwrap_or_default();
+                }
+                let layers = dir.layers.iter().map(|l| format!("L{}", l)).join(",");
+                format!(
+                    "{:>12} {:>8} files  {:<12} {}",
+                    format_size(dir.size, BINARY),
+                    dir.files,
+                    layers,
+                    dir.path
+                )
+            }))?;
+        }
+        Command::Top(top_args) => {
+            let analyzer = load_image(args, options)?;
+            let mut files = analyzer.largest_files(top_args.count)?;
+            if top_args.compressibility {
+                analyzer.measure_compressibility(&mut files)?;
+            }
+            print_lines(files.iter().map(|file| {
+                if top_args.json {
+                    return serde_json::to_string(file).unwrap_or_default();
+                }
+                let mut line = format!("{:>12} ", format_size(file.entry.size, BINARY));
+                if let Some(ratio) = file.compression_ratio {
+                    let gzipped = (file.entry.size as f64 * ratio) as u64;
+                    line.push_str(&format!(
+                        "{:>12} gzipped {:>4.0}% ",
+                        format_size(gzipped, BINARY),
+                        ratio * 100.0
+                    ));
+                }
+                line.push_str(&format!(
+                    "L{:<3} {:<6} {}",
+                    file.entry.layer_index,
+                    if file.visible { "" } else { "hidden" },
+                    file.entry.path
+                ));
+                if let Some(created_by) = &file.created_by {
+                    line.push_str(&format!("  ({})", shorten(created_by)));
+                }
+                line
+            }))?;
+        }
+        Command::Similar(similar_args) => {
+            let mut layers = Vec::new();
+            for image in &similar_args.images {
+                info!("Scanning {}", image);
+                let analyzer = image.load(options.clone())?;
+                let files = analyzer.scan_files()?;
+                let diff_ids = &analyzer.config().rootfs.diff_ids;
+                layers.extend(LayerFiles::by_layer(&image.to_string(), diff_ids, files));
+            }
+            let clusters = cluster_layers(&layers, similar_args.threshold);
+            if clusters.is_empty() {
+                info!("No near-identical layers found");
+            }
+            print_lines(clusters.iter().map(|cluster| {
+                if similar_args.json {
+                    return serde_json::to_string(cluster).unwrap_or_default();
+                }
+                let mut lines = cluster
+                    .layers
+                    .iter()
+                    .map(|layer| {
+                        format!(
+                            "{} L{} {} ({} files, {})",
+                            layer.image,
+                            layer.layer_index,
+                            layer.diff_id,
+                            layer.files,
+                            format_size(layer.bytes, BINARY)
+                        )
+                    })
+                    .join("\n  ~ ");
+                for difference in &cluster.differences {
+                    lines.push_str(&format!(
+                        "\n    {} L{}, {:.0}% similar: {}",
+                        difference.image,
+                        difference.layer_index,
+                        difference.similarity * 100.0,
+                        difference.suggestion
+                    ));
+                }
+                lines
+            }))?;
+        }
+        Command::Advise(advise_args) => {
+            let analyzer = load_image(args, options)?;
+            let plan = analyzer.generate_modification_plan(analyzer.find_duplicates()?)?;
+            let script = link_script(&plan, &analyzer.config().history);
+            match &advise_args.output {
+                Some(path) => {
+                    std::fs::write(path, script)
+                        .with_context(|| format!("Failed to write {}", path.display()))?;
+                    info!(
+                        "Wrote {} changes to {}",
+                        plan.values().map(Vec::len).sum::<usize>(),
+                        path.display()
+                    );
+                }
+                None => print!("{}", script),
+            }
+        }
+        Command::Schema(schema_args) => {
+            let schema = document_schema(schema_args.document);
+            print_lines(std::iter::once(
+                serde_json::to_string_pretty(&schema).unwrap_or_default(),
+            ))?;
+        }
+        Command::Compression(compression_args) => {
+            let analyzer = load_image(args, options)?;
+            let estimates = analyzer.estimate_zstd(
+                compression_args.level,
+                compression_args.sample,
+                &compression_args.zstd,
+            )?;
+            let signed = |bytes: i64| {
+                let sign = if bytes < 0 { "-" } else { "+" };
+                format!("{}{}", sign, format_size(bytes.unsigned_abs(), BINARY))
+            };
+            print_lines(estimates.iter().map(|estimate| {
+                if compression_args.json {
+                    return serde_json::to_string(estimate).unwrap_or_default();
+                }
+                format!(
+                    "L{:<3} {:>12} -> {:>12} zstd  {:>13}  ({} uncompressed, {} sampled)",
+                    estimate.layer_index,
+                    format_size(estimate.blob_bytes, BINARY),
+                    format_size(estimate.zstd_bytes, BINARY),
+                    signed(estimate.change()),
+                    format_size(estimate.uncompressed_bytes, BINARY),
+                    format_size(estimate.sampled_bytes, BINARY)
+                )
+            }))?;
+            let (blobs, zstd) = estimates.iter().fold((0, 0), |(blobs, zstd), e| {
+                (blobs + e.blob_bytes, zstd + e.zstd_bytes)
+            });
+            info!(
+                "Total: {} -> {} with zstd -{} ({})",
+                format_size(blobs, BINARY),
+                format_size(zstd, BINARY),
+                compression_args.level,
+                signed(zstd as i64 - blobs as i64)
+            );
+        }
+        Command::Blame(blame_args) => {
+            let analyzer = load_image(args, options)?;
+            let duplicates = analyzer.find_duplicates()?;
+            let ledger = analyzer.attribute_waste(&duplicates)?;
+            if !blame_args.json {
+                println!(
+                    "{:>12} {:>12} {:>12} {:>12}  LAYER STEP",
+                    "ADDED", "OVERWRITTEN", "DUPLICATED", "NET"
+                );
+            }
+            print_lines(ledger.iter().map(|step| {
+                if blame_args.json {
+                    return serde_json::to_string(step).unwrap_or_default();
+                }
+                format!(
+                    "{:>12} {:>12} {:>12} {:>12}  {:<5} {}",
+                    format_size(step.added, BINARY),
+                    format_size(step.overwritten, BINARY),
+                    format_size(step.duplicated, BINARY),
+                    format_size(step.net, BINARY),
+                    step.layer_index
+                        .map_or("-".to_string(), |l| format!("L{}", l)),
+                    shorten(&step.created_by)
+                )
+            }))?;
+        }
+        Command::Bench(bench_args) => {
+            let mut options = options;
+            if options.work_dir.take().is_some() {
+                warn!("Ignoring --work-dir, resumed runs would skip the work being timed");
+            }
+            match bench_args.synthetic {
+                Some(layers) => {
+                    let image = synthetic_image(
+                        layers,
+                        bench_args.synthetic_files,
+                        bench_args.synthetic_file_size,
+                    );
+                    info!(
+                        "Benchmarking a generated image of {} layers, {}",
+                        layers,
+                        format_size(image.len() as u64, BINARY)
+                    );
+                    run_bench(bench_args, || {
+                        Analyzer::from_reader(image.as_slice(), options.clone())
+                    })?
+                }
+                None => {
+                    let image = args
+                        .image
+                        .as_ref()
+                        .context("bench needs --image or --synthetic, stdin can't be read twice")?;
+                    info!("Benchmarking {}", image);
+                    run_bench(bench_args, || image.load(options.clone()))?
+                }
+            }
+        }
+        Command::Dedupe(dedupe_args) => docker_dedupe(dedupe_args, options)?,
+        Command::DockerCliPluginMetadata => {
+            let metadata = serde_json::json!({
+                "SchemaVersion": "0.1.0",
+                "Vendor": "docker_duplicate_files",
+                "Version": env!("CARGO_PKG_VERSION"),
+                "ShortDescription": "Deduplicate files across the layers of an image",
+            });
+            println!("{}", serde_json::to_string_pretty(&metadata)?);
+        }
+        Command::Workloads(workloads_args) => {
+            let workloads = read_workloads(&workloads_args.path)?;
+            info!(
+                "Found {} workloads in {}",
+                workloads.len(),
+                workloads_args.path.display()
+            );
+            let report = fleet_report(&workloads, |image| {
+                info!("Analyzing {}", image);
+                let image: ImageRef = format!("docker://{}", image).parse()?;
+                let duplicates = image.load(options.clone())?.find_duplicates()?;
+                let bytes = duplicates.iter().map(|d| d.total_savings).sum();
+                anyhow::Ok((duplicates.len(), bytes))
+            })?;
+            if workloads_args.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print_lines(report.lines().into_iter())?;
+            }
+        }
+        Command::Compose(compose_args) => {
+            let path = match &compose_args.file {
+                Some(path) => path.clone(),
+                None => COMPOSE_FILES
+                    .iter()
+                    .map(PathBuf::from)
+                    .find(|path| path.exists())
+                    .context("No compose.yaml or docker-compose.yml here, name the file")?,
+            };
+            let (project, services) = read_compose(&path)?;
+            info!("Found {} services in project {}", services.len(), project);
+            let report = compose_report(&project, &services, |image| {
+                let analyzer = match &compose_args.runtime {
+                    Some(runtime) => {
+                        let dir = tempfile::Builder::new()
+                            .prefix("compose")
+                            .tempdir_in(options.temp_dir())?;
+                        let exported = dir.path().join("image.tar");
+                        save_image(runtime, image, &exported)?;
+                        Analyzer::from_path(&exported, options.clone())?
+                    }
+                    None => {
+                        info!("Pulling {}", image);
+                        let image: ImageRef = format!("docker://{}", image).parse()?;
+                        image.load(options.clone())?
+                    }
+                };
+                let files = analyzer.scan_files()?;
+                let duplicates = analyzer.group_duplicates(files.clone());
+                anyhow::Ok(ScannedImage {
+                    diff_ids: analyzer.config().rootfs.diff_ids.clone(),
+                    files,
+                    duplicate_groups: duplicates.len(),
+                    duplicate_bytes: duplicates.iter().map(|d| d.total_savings).sum(),
+                })
+            })?;
+            if compose_args.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print_lines(report.lines().into_iter())?;
+            }
+        }
+        Command::Verify(verify_args) => {
+            let verification = load_image(args, options)?.verify()?;
+            if verify_args.json {
+                println!("{}", serde_json::to_string_pretty(&verification)?);
+            } else {
+                for problem in &verification.problems {
+                    println!("{}: {}", problem.path, problem.message);
+                }
+            }
+            if !verification.is_ok() {
+                bail!("{} problems found", verification.problems.len());
+            }
+            info!(
+                "{} layers and {} files verified",
+                verification.layers, verification.files_checked
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Where `compose` looks for the project's file, in this order, as
+/// `docker compose` does
+const COMPOSE_FILES: [&str; 4] = [
+    "compose.yaml",
+    "compose.yml",
+    "docker-compose.yaml",
+    "docker-compose.yml",
+];
+
+/// Longest history `created_by` shown by `top` and `blame`
+const CREATED_BY_WIDTH: usize = 60;
+
+/// `created_by` cut to [`CREATED_BY_WIDTH`], marked with `...` if it was.
+fn shorten(created_by: &str) -> String {
+    let mut short: String = created_by.chars().take(CREATED_BY_WIDTH).collect();
+    if short.len() < created_by.len() {
+        short.push_str("...");
+    }
+    short
+}
+
+fn layer(analyzer: &Analyzer, index: usize) -> Result<&Layer> {
+    analyzer.layers.get(index).with_context(|| {
+        format!(
+            "No layer {}, the image has {}",
+            index,
+            analyzer.layers.len()
+        )
+    })
+}
+
+/// Prints to stdout, stopping quietly when the reader goes away (`| head`).
+fn print_lines(mut lines: impl Iterator<Item = String>) -> Result<()> {
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let result = lines
+        .try_for_each(|line| writeln!(stdout, "{}", line))
+        .and_then(|_| stdout.flush());
+    match result {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// A dry run over stdin only needs one pass, so nothing is written to disk.
+fn dry_run_streaming(
+    args: &Args,
+    options: AnalyzerOptions,
+    notification: &mut Notification,
+) -> Result<()> {
+    info!("Dry run: scanning image from stdin without extracting it");
+    let start = Instant::now();
+    let stdin = io::stdin();
+    let scan = scan_stream(stdin.lock(), &options)?;
+    let timings = Timings::default();
+    timings.record(Phase::Scan, start.elapsed(), scan.bytes);
+
+    let duplicates = scan.find_duplicates();
+    notification.record_duplicates(&duplicates);
+    let libraries = shared_libraries(&scan.files);
+    print_possible_savings(&duplicates);
+    print_shared_libraries(&libraries);
+    let mut report = Report::new(&duplicates, &timings).with_shared_libraries(libraries);
+    if args.case_insensitive {
+        let variants = case_variants(&duplicates);
+        print_case_variants(&variants);
+        report = report.with_case_variants(variants);
+    }
+    let diff_ids = &scan.config.rootfs.diff_ids;
+    if let Some(split) = split_ownership(args, options, diff_ids, &duplicates)? {
+        report = report.with_ownership(split);
+    }
+    timings.print_summary();
+    write_report(args, report)?;
+    check_baseline(args, &duplicates, notification)
+}
+
+/// Splits the savings at --base-layers, or after the layers the image
+/// shares with --base-image, logging both shares.
+fn split_ownership(
+    args: &Args,
+    options: AnalyzerOptions,
+    diff_ids: &[String],
+    duplicates: &[DuplicateInfo],
+) -> Result<Option<SavingsSplit>> {
+    let base_layers = match (&args.base_image, args.base_layers) {
+        (Some(base), _) => {
+            info!("Loading base image {}", base);
+            let base_ids = base.load(options)?.config().rootfs.diff_ids.clone();
+            let count = base_layer_count(diff_ids, &base_ids);
+            if count == 0 {
+                warn!("The image shares no layers with {}", base);
+            }
+            count
+        }
+        (None, Some(count)) => count,
+        (None, None) => return Ok(None),
+    };
+    let split = split_savings(duplicates, base_layers);
+    print_savings_split(&split);
+    Ok(Some(split))
+}
+
+/// Writes --metrics-file, if given, and counts the run for OpenTelemetry.
+fn write_metrics(
+    args: &Args,
+    analyzer: &Analyzer,
+    files_scanned: usize,
+    duplicates: &[DuplicateInfo],
+    summary: Option<&DedupeSummary>,
+) -> Result<()> {
+    let image = args
+        .image
+        .as_ref()
+        .map_or("-".to_string(), |i| i.to_string());
+    let run = RunMetrics::new(image, analyzer, files_scanned, duplicates, summary);
+    #[cfg(feature = "otel")]
+    docker_duplicate_files::otel::record_run(&run);
+    let Some(path) = &args.metrics_file else {
+        return Ok(());
+    };
+    Ok(write_textfile(path, &[run])?)
+}
+
+/// --baseline-file: records the run's waste with --update-baseline,
+/// otherwise fails the run if it grew past the recorded one by more than
+/// --baseline-margin. Runs last, so reports and images are written either
+/// way.
+fn check_baseline(
+    args: &Args,
+    duplicates: &[DuplicateInfo],
+    notification: &mut Notification,
+) -> Result<()> {
+    let Some(path) = &args.baseline_file else {
+        return Ok(());
+    };
+    let current = Baseline::new(duplicates);
+    let recorded = Baseline::read(path)?;
+    if args.update_baseline {
+        current.write(path)?;
+        match recorded {
+            Some(recorded) => info!(
+                "Updated the baseline in {}: {} of duplicates, was {}",
+                path.display(),
+                format_size(current.total_savings, BINARY),
+                format_size(recorded.total_savings, BINARY)
+            ),
+            None => info!(
+                "Recorded a baseline of {} of duplicates in {}",
+                format_size(current.total_savings, BINARY),
+                path.display()
+            ),
+        }
+        return Ok(());
+    }
+    let Some(recorded) = recorded else {
+        bail!(
+            "No baseline in {}, create one with --update-baseline",
+            path.display()
+        );
+    };
+    let check = recorded.check(&current, args.baseline_margin);
+    notification.record_baseline(check);
+    if !check.passed() {
+        bail!("Waste grew past the baseline: {}", check.describe());
+    }
+    info!("Within the baseline: {}", check.describe());
+    if check.current < check.baseline {
+        info!(
+            "Run with --update-baseline to lower the baseline in {}",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+fn write_report(args: &Args, report: Report) -> Result<()> {
+    let Some(format) = args.report else {
+        return Ok(());
+    };
+    let bucket = args
+        .report_file
+        .as_ref()
+        .and_then(|path| path.to_str()?.strip_prefix("s3://"));
+    if let Some(location) = bucket {
+        let location: Location = location.parse()?;
+        let mut upload = Upload::new(&location);
+        report.write(format, &mut upload)?;
+        upload.finish()?;
+        info!("Uploaded the report to {}", location);
+        return Ok(());
+    }
+    match &args.report_file {
+        Some(path) => {
+            let file = File::create(path)
+                .with_context(|| format!("Failed to create report file: {}", path.display()))?;
+            report.write(format, BufWriter::new(file))?;
+        }
+        None => report.write(format, io::stdout().lock())?,
     }
     Ok(())
 }
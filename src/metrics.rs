@@ -0,0 +1,189 @@
+//! Prometheus gauges of a run, for tracking image bloat over time: written
+//! as a textfile for node_exporter's textfile collector by `--metrics-file`,
+//! and served per job on `/metrics` by [`crate::serve`].
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Analyzer;
+use crate::analyzer::DuplicateInfo;
+use crate::error::{IoResultExt, Result};
+use crate::pipeline::DedupeSummary;
+use crate::timings::PhaseTiming;
+
+/// What one run measured.
+#[derive(Debug, Clone)]
+pub struct RunMetrics {
+    /// The `image` label, how the image was given
+    pub image: String,
+    /// Sum of the layer blobs as stored
+    pub image_total_bytes: u64,
+    pub duplicate_groups: usize,
+    /// What deduplication saves, or saved when the image was rewritten
+    pub duplicate_bytes: u64,
+    pub files_scanned: usize,
+    pub layers: usize,
+    /// None on dry runs
+    pub layers_rewritten: Option<usize>,
+    pub phases: Vec<PhaseTiming>,
+    /// Seconds since the epoch the run finished at
+    pub finished: u64,
+}
+
+impl RunMetrics {
+    /// Metrics of a run over `analyzer`'s image that scanned `files_scanned`
+    /// files into `duplicates` and, unless it was a dry run, wrote `summary`.
+    pub fn new(
+        image: String,
+        analyzer: &Analyzer,
+        files_scanned: usize,
+        duplicates: &[DuplicateInfo],
+        summary: Option<&DedupeSummary>,
+    ) -> Self {
+        Self {
+            image,
+            image_total_bytes: analyzer
+                .layers
+                .iter()
+                .filter_map(|layer| fs::metadata(&layer.path).ok())
+                .map(|metadata| metadata.len())
+                .sum(),
+            duplicate_groups: duplicates.len(),
+            duplicate_bytes: summary.map_or_else(
+                || duplicates.iter().map(|d| d.total_savings).sum(),
+                |s| s.bytes_saved,
+            ),
+            files_scanned,
+            layers: analyzer.layers.len(),
+            layers_rewritten: summary.map(|s| s.layers.iter().filter(|l| l.rewritten).count()),
+            phases: analyzer.timings().summary(),
+            finished: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        }
+    }
+}
+
+/// `value` escaped for a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+/// The text exposition format of `runs`, one series per run and metric.
+pub fn render(runs: &[RunMetrics]) -> String {
+    let mut text = String::new();
+    let mut gauge = |name: &str, help: &str, values: &mut dyn Iterator<Item = (String, f64)>| {
+        let _ = writeln!(text, "# HELP dedupe_{} {}", name, help);
+        let _ = writeln!(text, "# TYPE dedupe_{} gauge", name);
+        for (labels, value) in values {
+            let _ = writeln!(text, "dedupe_{}{{{}}} {}", name, labels, value);
+        }
+    };
+    let label = |run: &RunMetrics| format!("image=\"{}\"", escape(&run.image));
+    let each = |value: fn(&RunMetrics) -> Option<f64>| {
+        runs.iter()
+            .filter_map(move |run| Some((label(run), value(run)?)))
+    };
+    gauge(
+        "image_total_bytes",
+        "Size of the image's layer blobs as stored",
+        &mut each(|r| Some(r.image_total_bytes as f64)),
+    );
+    gauge(
+        "duplicate_bytes",
+        "Bytes taken by duplicate files, saved when rewritten",
+        &mut each(|r| Some(r.duplicate_bytes as f64)),
+    );
+    gauge(
+        "duplicate_groups",
+        "Sets of files with identical contents",
+        &mut each(|r| Some(r.duplicate_groups as f64)),
+    );
+    gauge(
+        "files_scanned",
+        "Files hashed, those of at least the minimum size",
+        &mut each(|r| Some(r.files_scanned as f64)),
+    );
+    gauge(
+        "layers",
+        "Layers of the image",
+        &mut each(|r| Some(r.layers as f64)),
+    );
+    gauge(
+        "layers_rewritten",
+        "Layers the deduplicated image changed, absent on dry runs",
+        &mut each(|r| r.layers_rewritten.map(|n| n as f64)),
+    );
+    gauge(
+        "phase_duration_seconds",
+        "Time spent per phase of the run",
+        &mut runs.iter().flat_map(|run| {
+            run.phases.iter().map(move |p| {
+                (
+                    format!("{},phase=\"{}\"", label(run), phase_name(p)),
+                    p.seconds,
+                )
+            })
+        }),
+    );
+    gauge(
+        "last_run_timestamp_seconds",
+        "When the run finished",
+        &mut each(|r| Some(r.finished as f64)),
+    );
+    text
+}
+
+fn phase_name(timing: &PhaseTiming) -> String {
+    format!("{:?}", timing.phase).to_lowercase()
+}
+
+/// Writes `runs` to `path` through a temp file renamed into place, so the
+/// textfile collector never reads a partial file.
+pub fn write_textfile(path: &Path, runs: &[RunMetrics]) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, render(runs))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    fs::rename(&temp, path).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timings::Phase;
+
+    #[test]
+    fn test_render() {
+        let run = RunMetrics {
+            image: "docker-archive:my \"app\".tar".to_string(),
+            image_total_bytes: 1000,
+            duplicate_groups: 2,
+            duplicate_bytes: 300,
+            files_scanned: 10,
+            layers: 3,
+            layers_rewritten: None,
+            phases: vec![PhaseTiming {
+                phase: Phase::Scan,
+                seconds: 1.5,
+                bytes: 1000,
+            }],
+            finished: 1700000000,
+        };
+        let text = render(&[run]);
+        let label = r#"image="docker-archive:my \"app\".tar""#;
+        assert!(text.contains("# TYPE dedupe_duplicate_bytes gauge\n"));
+        assert!(text.contains(&format!("dedupe_duplicate_bytes{{{}}} 300\n", label)));
+        assert!(text.contains(&format!(
+            "dedupe_phase_duration_seconds{{{},phase=\"scan\"}} 1.5\n",
+            label
+        )));
+        // Dry runs leave the gauge without series
+        assert!(!text.contains("dedupe_layers_rewritten{"));
+    }
+}
@@ -0,0 +1,157 @@
+//! Webhook notifications once a run finishes, so scheduled scans alert
+//! their owners without a wrapper script: [`Notification`] collects what
+//! the run found and [`post`] sends it as JSON.
+//!
+//! The body carries a one-line `text` besides the numbers, which is all
+//! Slack and Mattermost incoming webhooks show; other endpoints read the
+//! rest.
+
+use std::time::Duration;
+
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+use ureq::Agent;
+
+use crate::analyzer::DuplicateInfo;
+use crate::baseline::BaselineCheck;
+use crate::error::{DedupeError, Result};
+use crate::pipeline::DedupeSummary;
+
+/// What a finished run reports to the webhook.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    /// See [`crate::SCHEMA_VERSION`]
+    pub schema_version: u32,
+    /// The run in one line, for chat webhooks
+    pub text: String,
+    pub image: String,
+    pub succeeded: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Absent if the run failed before finding duplicates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_groups: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_savings: Option<u64>,
+    /// The waste against `--baseline-file`, when checked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<BaselineCheck>,
+    /// Old and new digests of the written image, absent on dry runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<DedupeSummary>,
+}
+
+impl Notification {
+    pub fn new(image: impl Into<String>) -> Self {
+        Self {
+            schema_version: crate::SCHEMA_VERSION,
+            text: String::new(),
+            image: image.into(),
+            succeeded: false,
+            error: None,
+            duplicate_groups: None,
+            total_savings: None,
+            baseline: None,
+            rewrite: None,
+        }
+    }
+
+    pub fn record_duplicates(&mut self, duplicates: &[DuplicateInfo]) {
+        self.duplicate_groups = Some(duplicates.len());
+        self.total_savings = Some(duplicates.iter().map(|d| d.total_savings).sum());
+    }
+
+    pub fn record_baseline(&mut self, check: BaselineCheck) {
+        self.baseline = Some(check);
+    }
+
+    pub fn record_rewrite(&mut self, summary: DedupeSummary) {
+        self.rewrite = Some(summary);
+    }
+
+    /// Marks the run finished, failed with `error` if there is one, and
+    /// writes the `text`.
+    pub fn finish(&mut self, error: Option<String>) {
+        self.succeeded = error.is_none();
+        let mut parts = Vec::new();
+        if let (Some(bytes), Some(groups)) = (self.total_savings, self.duplicate_groups) {
+            parts.push(format!(
+                "{} of duplicates in {} groups",
+                format_size(bytes, BINARY),
+                groups
+            ));
+        }
+        // A failed check is the error already
+        if let (Some(check), None) = (&self.baseline, &error) {
+            parts.push(format!(
+                "baseline {} ({} allowed)",
+                format_size(check.baseline, BINARY),
+                format_size(check.allowed, BINARY)
+            ));
+        }
+        if let Some(summary) = &self.rewrite {
+            parts.push(format!(
+                "saved {}",
+                format_size(summary.bytes_saved, BINARY)
+            ));
+        }
+        match &error {
+            Some(error) => parts.push(format!("failed: {}", error)),
+            None if parts.is_empty() => parts.push("finished".to_string()),
+            None => {}
+        }
+        let text = format!("{}: {}", self.image, parts.join(", "));
+        self.text = text;
+        self.error = error;
+    }
+}
+
+/// POSTs `notification` to `url` as JSON, with the extra `headers`, e.g.
+/// for authorization. Honors the proxy the environment configures.
+pub fn post(url: &str, headers: &[(String, String)], notification: &Notification) -> Result<()> {
+    let agent: Agent = Agent::config_builder()
+        .http_status_as_error(false)
+        .timeout_global(Some(Duration::from_secs(30)))
+        .build()
+        .into();
+    let mut request = agent.post(url).header("Content-Type", "application/json");
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    let body = serde_json::to_vec(notification)?;
+    match request.send(&body[..]) {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => Err(DedupeError::Webhook(format!(
+            "{} answered {}",
+            url,
+            response.status()
+        ))),
+        Err(e) => Err(DedupeError::Webhook(format!("{}: {}", url, e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finish() {
+        let mut notification = Notification::new("app.tar");
+        notification.duplicate_groups = Some(2);
+        notification.total_savings = Some(3 * 1024 * 1024);
+        notification.record_baseline(BaselineCheck {
+            baseline: 1024 * 1024,
+            current: 3 * 1024 * 1024,
+            allowed: 2 * 1024 * 1024,
+        });
+        notification.finish(Some("Waste grew past the baseline".to_string()));
+        assert!(!notification.succeeded);
+        assert_eq!(
+            notification.text,
+            "app.tar: 3 MiB of duplicates in 2 groups, failed: Waste grew past the baseline"
+        );
+        let json = serde_json::to_value(&notification).unwrap();
+        assert_eq!(json["baseline"]["allowed"], 2 * 1024 * 1024);
+        assert!(json.get("rewrite").is_none());
+    }
+}
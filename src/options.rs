@@ -0,0 +1,340 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+#[cfg(feature = "parallel")]
+use rayon::ThreadPool;
+
+use crate::analyzer::Analyzer;
+use crate::error::Result;
+pub use crate::hasher::HashAlgorithm;
+use crate::hasher::HasherFactory;
+use crate::policy::{DedupPolicy, DefaultPolicy, Strategy};
+use crate::progress::{NoProgress, ProgressSink};
+use crate::schemas::format_timestamp;
+pub use crate::unpack::UnpackLimits;
+
+/// The `created` time a rewrite gives the config and the history entries
+/// it adds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Created {
+    /// The original image's
+    #[default]
+    Keep,
+    /// The time of the rewrite
+    Now,
+    /// 1970-01-01T00:00:00Z, for reproducible output
+    Epoch,
+}
+
+impl Created {
+    /// The timestamp to write where the original image has `original`.
+    pub fn timestamp(self, original: &str) -> String {
+        match self {
+            Created::Keep => original.to_string(),
+            Created::Now => format_timestamp(
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+            ),
+            Created::Epoch => format_timestamp(0),
+        }
+    }
+}
+
+/// Everything that configures an [`Analyzer`]. Prefer [`Analyzer::builder`]
+/// over filling this in by hand so new knobs don't break callers.
+#[derive(Clone)]
+pub struct AnalyzerOptions {
+    /// Files smaller than this are not considered for deduplication
+    pub min_size: u64,
+    /// Gzip rewritten layers
+    pub compression: bool,
+    /// Content hash used to find identical files
+    pub hasher: Arc<dyn HasherFactory>,
+    /// Glob patterns of image paths that are never scanned or rewritten
+    pub excludes: Vec<String>,
+    /// Glob patterns of [`crate::paths::PROTECTED_PATHS`] to rewrite anyway
+    pub allowed_paths: Vec<String>,
+    /// Also rewrite files under the config's `Volumes`, which are left
+    /// alone by default
+    pub dedupe_volumes: bool,
+    /// Leave the paths the image's [`crate::analyzer::IGNORE_FILE`] lists
+    /// alone
+    pub ignore_file: bool,
+    /// Directories or glob patterns that alone are scanned and rewritten;
+    /// everything when empty
+    pub scope: Vec<String>,
+    #[cfg(feature = "parallel")]
+    /// Run on a dedicated pool with this many threads
+    pub threads: Option<usize>,
+    #[cfg(feature = "parallel")]
+    /// Run on this pool instead of rayon's global one. Takes precedence over
+    /// `threads`.
+    pub thread_pool: Option<Arc<ThreadPool>>,
+    #[cfg(feature = "parallel")]
+    /// Decompress or rewrite at most this many layers at once, bounding
+    /// memory and temp disk I/O whatever the number of threads
+    pub max_pipelines: Option<usize>,
+    /// Where temporary files go. Defaults to the system temp dir.
+    pub temp_dir: Option<PathBuf>,
+    /// Persistent work dir that makes runs resumable, see [`crate::checkpoint`]
+    pub work_dir: Option<PathBuf>,
+    pub progress: Arc<dyn ProgressSink>,
+    /// Decides what happens to each duplicate
+    pub policy: Arc<dyn DedupPolicy>,
+    /// Overrides the actions `policy` picks, which still picks the
+    /// originals; `None` leaves it all to `policy`
+    pub strategy: Option<Strategy>,
+    /// Record the deduplication and its savings in the config's history,
+    /// for `docker history`
+    pub history_entry: bool,
+    /// Label rewritten images with the tool version, the original image ID
+    /// and the bytes saved (`org.dedupe.*`), for fleet scanners
+    pub label_savings: bool,
+    /// `created` of rewritten configs and of the history entries added
+    pub created: Created,
+    /// What unpacking the image tar may write
+    pub unpack_limits: UnpackLimits,
+    /// Unpack the way an unprivileged user can, see [`crate::unpack`]
+    pub rootless: bool,
+    /// Tags `docker load` gives the `docker save` archives written;
+    /// `test:smaller` when empty
+    pub repo_tags: Vec<String>,
+    /// Headers sent when downloading `https://` images, e.g. authorization
+    pub http_headers: Vec<(String, String)>,
+}
+
+impl Default for AnalyzerOptions {
+    fn default() -> Self {
+        Self {
+            min_size: 1_000_000,
+            compression: true,
+            hasher: Arc::new(HashAlgorithm::default()),
+            excludes: Vec::new(),
+            allowed_paths: Vec::new(),
+            dedupe_volumes: false,
+            ignore_file: true,
+            scope: Vec::new(),
+            #[cfg(feature = "parallel")]
+            threads: None,
+            #[cfg(feature = "parallel")]
+            thread_pool: None,
+            #[cfg(feature = "parallel")]
+            max_pipelines: None,
+            temp_dir: None,
+            work_dir: None,
+            progress: Arc::new(NoProgress),
+            policy: Arc::new(DefaultPolicy),
+            strategy: None,
+            history_entry: false,
+            label_savings: false,
+            created: Created::Keep,
+            unpack_limits: UnpackLimits::default(),
+            rootless: false,
+            repo_tags: Vec::new(),
+            http_headers: Vec::new(),
+        }
+    }
+}
+
+impl AnalyzerOptions {
+    pub fn temp_dir(&self) -> PathBuf {
+        self.temp_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+}
+
+#[derive(Default)]
+pub struct AnalyzerBuilder {
+    options: AnalyzerOptions,
+}
+
+impl AnalyzerBuilder {
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.options.min_size = min_size;
+        self
+    }
+
+    pub fn compression(mut self, compression: bool) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    pub fn hash(mut self, hash: HashAlgorithm) -> Self {
+        self.options.hasher = Arc::new(hash);
+        self
+    }
+
+    /// Uses a custom hash instead of one of the built-in ones.
+    pub fn hasher(mut self, hasher: Arc<dyn HasherFactory>) -> Self {
+        self.options.hasher = hasher;
+        self
+    }
+
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.options.excludes.push(pattern.into());
+        self
+    }
+
+    pub fn excludes<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.options
+            .excludes
+            .extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Lets files under `pattern` be rewritten even where
+    /// [`crate::paths::PROTECTED_PATHS`] protects them.
+    pub fn allow_path(mut self, pattern: impl Into<String>) -> Self {
+        self.options.allowed_paths.push(pattern.into());
+        self
+    }
+
+    pub fn allowed_paths<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.options
+            .allowed_paths
+            .extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn dedupe_volumes(mut self, dedupe_volumes: bool) -> Self {
+        self.options.dedupe_volumes = dedupe_volumes;
+        self
+    }
+
+    pub fn ignore_file(mut self, ignore_file: bool) -> Self {
+        self.options.ignore_file = ignore_file;
+        self
+    }
+
+    /// Only scans and rewrites files under `root`, which may be a glob
+    /// pattern. Repeatable; without it, the whole image is.
+    pub fn scope(mut self, root: impl Into<String>) -> Self {
+        self.options.scope.push(root.into());
+        self
+    }
+
+    pub fn scopes<I, S>(mut self, roots: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.options.scope.extend(roots.into_iter().map(Into::into));
+        self
+    }
+
+    #[cfg(feature = "parallel")]
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.options.threads = Some(threads);
+        self
+    }
+
+    #[cfg(feature = "parallel")]
+    pub fn thread_pool(mut self, pool: Arc<ThreadPool>) -> Self {
+        self.options.thread_pool = Some(pool);
+        self
+    }
+
+    #[cfg(feature = "parallel")]
+    pub fn max_pipelines(mut self, pipelines: usize) -> Self {
+        self.options.max_pipelines = Some(pipelines);
+        self
+    }
+
+    pub fn temp_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.temp_dir = Some(dir.into());
+        self
+    }
+
+    pub fn work_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.work_dir = Some(dir.into());
+        self
+    }
+
+    pub fn progress(mut self, sink: Arc<dyn ProgressSink>) -> Self {
+        self.options.progress = sink;
+        self
+    }
+
+    pub fn policy(mut self, policy: Arc<dyn DedupPolicy>) -> Self {
+        self.options.policy = policy;
+        self
+    }
+
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.options.strategy = Some(strategy);
+        self
+    }
+
+    pub fn history_entry(mut self, history_entry: bool) -> Self {
+        self.options.history_entry = history_entry;
+        self
+    }
+
+    pub fn label_savings(mut self, label_savings: bool) -> Self {
+        self.options.label_savings = label_savings;
+        self
+    }
+
+    pub fn created(mut self, created: Created) -> Self {
+        self.options.created = created;
+        self
+    }
+
+    pub fn unpack_limits(mut self, limits: UnpackLimits) -> Self {
+        self.options.unpack_limits = limits;
+        self
+    }
+
+    pub fn rootless(mut self, rootless: bool) -> Self {
+        self.options.rootless = rootless;
+        self
+    }
+
+    /// Tags written `docker save` archives `tag`. Repeatable.
+    pub fn repo_tag(mut self, tag: impl Into<String>) -> Self {
+        self.options.repo_tags.push(tag.into());
+        self
+    }
+
+    pub fn repo_tags<I, S>(mut self, tags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.options
+            .repo_tags
+            .extend(tags.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn http_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.options.http_headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn options(self) -> AnalyzerOptions {
+        self.options
+    }
+
+    /// Loads an image from a `docker save` stream.
+    pub fn load<R: Read>(self, image_stream: R) -> Result<Analyzer> {
+        Analyzer::from_reader(image_stream, self.options)
+    }
+
+    /// Loads an image from a `docker save` tarball on disk.
+    pub fn load_from_path(self, image_path: impl AsRef<Path>) -> Result<Analyzer> {
+        Analyzer::from_path(image_path, self.options)
+    }
+}
@@ -0,0 +1,439 @@
+//! OpenTelemetry export over OTLP/HTTP with JSON bodies, behind the `otel`
+//! feature: the tool's spans (`scan`, `scan_layer`, `rewrite`, ...) as
+//! traces, and counters of the images processed and the bytes scanned and
+//! saved as metrics.
+//!
+//! Configured through the standard `OTEL_EXPORTER_OTLP_ENDPOINT`, which
+//! turns it on, and `OTEL_SERVICE_NAME`. A trace is sent once the span at
+//! its root closes, a run of the command line tool or a job of
+//! [`crate::serve`]; counters whenever [`record_run`] is called. Export
+//! failures are logged and otherwise ignored.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rapidhash::v3::{RapidSecrets, rapidhash_v3_seeded};
+use serde_json::{Value, json};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Subscriber, warn};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use ureq::Agent;
+
+use crate::metrics::RunMetrics;
+use crate::timings::Phase;
+
+/// Where [`record_run`] reports to, once installed
+static EXPORTER: OnceLock<Otlp> = OnceLock::new();
+
+/// An OTLP/HTTP exporter.
+#[derive(Clone)]
+pub struct Otlp {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Base URL, without the `/v1/traces` or `/v1/metrics` path
+    endpoint: String,
+    resource: Value,
+    agent: Agent,
+    /// Start of the counters, in Unix nanoseconds
+    started: u64,
+    /// Finished spans waiting for their root to close
+    spans: Mutex<Vec<Value>>,
+    counters: Mutex<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    images: u64,
+    bytes_scanned: u64,
+    bytes_saved: u64,
+}
+
+impl Otlp {
+    /// The exporter `OTEL_EXPORTER_OTLP_ENDPOINT` (e.g.
+    /// `http://localhost:4318`) asks for, if any.
+    pub fn from_env() -> Option<Self> {
+        let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+        let service = std::env::var("OTEL_SERVICE_NAME")
+            .unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string());
+        Some(Self::new(endpoint.trim_end_matches('/'), &service))
+    }
+
+    pub fn new(endpoint: &str, service: &str) -> Self {
+        let agent = Agent::config_builder()
+            .http_status_as_error(false)
+            .timeout_global(Some(Duration::from_secs(10)))
+            .build()
+            .into();
+        Self {
+            inner: Arc::new(Inner {
+                endpoint: endpoint.to_string(),
+                resource: json!({ "attributes": [attribute("service.name", json!({ "stringValue": service }))] }),
+                agent,
+                started: unix_nanos(),
+                spans: Mutex::new(Vec::new()),
+                counters: Mutex::new(Counters::default()),
+            }),
+        }
+    }
+
+    /// Makes this the exporter of [`record_run`] and returns the layer that
+    /// exports spans, to add to the subscriber.
+    pub fn install(self) -> OtlpLayer {
+        let layer = OtlpLayer {
+            inner: self.inner.clone(),
+        };
+        let _ = EXPORTER.set(self);
+        layer
+    }
+}
+
+/// Adds a finished run to the counters and exports them, if an exporter is
+/// installed.
+pub fn record_run(run: &RunMetrics) {
+    let Some(otlp) = EXPORTER.get() else {
+        return;
+    };
+    let points = {
+        let mut counters = otlp.inner.counters.lock().unwrap();
+        counters.images += 1;
+        counters.bytes_scanned += run
+            .phases
+            .iter()
+            .filter(|p| p.phase == Phase::Scan)
+            .map(|p| p.bytes)
+            .sum::<u64>();
+        if run.layers_rewritten.is_some() {
+            counters.bytes_saved += run.duplicate_bytes;
+        }
+        [
+            (
+                "dedupe.images",
+                "{image}",
+                "Images processed",
+                counters.images,
+            ),
+            (
+                "dedupe.bytes_scanned",
+                "By",
+                "Layer bytes read while scanning",
+                counters.bytes_scanned,
+            ),
+            (
+                "dedupe.bytes_saved",
+                "By",
+                "Bytes removed from rewritten images",
+                counters.bytes_saved,
+            ),
+        ]
+    };
+    let now = unix_nanos();
+    let metrics: Vec<Value> = points
+        .iter()
+        .map(|(name, unit, description, value)| {
+            json!({
+                "name": name,
+                "unit": unit,
+                "description": description,
+                "sum": {
+                    // Cumulative
+                    "aggregationTemporality": 2,
+                    "isMonotonic": true,
+                    "dataPoints": [{
+                        "startTimeUnixNano": otlp.inner.started.to_string(),
+                        "timeUnixNano": now.to_string(),
+                        "asInt": value.to_string(),
+                    }],
+                },
+            })
+        })
+        .collect();
+    otlp.inner.post(
+        "/v1/metrics",
+        json!({
+            "resourceMetrics": [{
+                "resource": otlp.inner.resource,
+                "scopeMetrics": [{ "scope": scope(), "metrics": metrics }],
+            }],
+        }),
+    );
+}
+
+impl Inner {
+    fn export_spans(&self) {
+        let spans = std::mem::take(&mut *self.spans.lock().unwrap());
+        if spans.is_empty() {
+            return;
+        }
+        self.post(
+            "/v1/traces",
+            json!({
+                "resourceSpans": [{
+                    "resource": self.resource,
+                    "scopeSpans": [{ "scope": scope(), "spans": spans }],
+                }],
+            }),
+        );
+    }
+
+    fn post(&self, path: &str, body: Value) {
+        let url = format!("{}{}", self.endpoint, path);
+        let body = serde_json::to_vec(&body).unwrap_or_default();
+        match self
+            .agent
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .send(&body[..])
+        {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => warn!("OTLP export to {} failed: {}", url, response.status()),
+            Err(e) => warn!("OTLP export to {} failed: {}", url, e),
+        }
+    }
+}
+
+fn scope() -> Value {
+    json!({ "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") })
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// An id unique enough for traces and spans, without a random source:
+/// the time and a counter, hashed.
+fn random_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let seed = [
+        unix_nanos(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        std::process::id() as u64,
+    ];
+    let bytes: Vec<u8> = seed.iter().flat_map(|n| n.to_le_bytes()).collect();
+    rapidhash_v3_seeded(&bytes, &RapidSecrets::seed(0))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// What the layer keeps in each span's extensions.
+struct SpanState {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent: Option<[u8; 8]>,
+    start: u64,
+    /// OTLP attributes, in the order first recorded
+    attributes: Vec<(String, Value)>,
+}
+
+/// Span fields as OTLP attribute values.
+struct FieldVisitor<'a>(&'a mut Vec<(String, Value)>);
+
+impl FieldVisitor<'_> {
+    fn set(&mut self, field: &Field, value: Value) {
+        match self.0.iter_mut().find(|(key, _)| key == field.name()) {
+            Some((_, existing)) => *existing = value,
+            None => self.0.push((field.name().to_string(), value)),
+        }
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        // int64 values are strings in OTLP's JSON encoding
+        self.set(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field, json!({ "doubleValue": value }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, json!({ "boolValue": value }));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, json!({ "stringValue": value }));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.set(field, json!({ "stringValue": format!("{:?}", value) }));
+    }
+}
+
+/// Collects this crate's spans for [`Otlp`], see [`Otlp::install`].
+pub struct OtlpLayer {
+    inner: Arc<Inner>,
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if !span
+            .metadata()
+            .target()
+            .starts_with(env!("CARGO_CRATE_NAME"))
+        {
+            return;
+        }
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            let state = extensions.get::<SpanState>()?;
+            Some((state.trace_id, state.span_id))
+        });
+        let trace_id = match parent {
+            Some((trace_id, _)) => trace_id,
+            None => {
+                let mut trace_id = [0; 16];
+                trace_id[..8].copy_from_slice(&random_id().to_le_bytes());
+                trace_id[8..].copy_from_slice(&random_id().to_le_bytes());
+                trace_id
+            }
+        };
+        let mut state = SpanState {
+            trace_id,
+            span_id: random_id().to_le_bytes(),
+            parent: parent.map(|(_, span_id)| span_id),
+            start: unix_nanos(),
+            attributes: Vec::new(),
+        };
+        attrs.record(&mut FieldVisitor(&mut state.attributes));
+        span.extensions_mut().insert(state);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if let Some(state) = span.extensions_mut().get_mut::<SpanState>() {
+            values.record(&mut FieldVisitor(&mut state.attributes));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(state) = span.extensions_mut().remove::<SpanState>() else {
+            return;
+        };
+        let mut otlp_span = json!({
+            "traceId": hex(&state.trace_id),
+            "spanId": hex(&state.span_id),
+            "name": span.name(),
+            // Internal
+            "kind": 1,
+            "startTimeUnixNano": state.start.to_string(),
+            "endTimeUnixNano": unix_nanos().to_string(),
+            "attributes": state
+                .attributes
+                .into_iter()
+                .map(|(key, value)| attribute(&key, value))
+                .collect::<Vec<_>>(),
+        });
+        if let Some(parent) = state.parent {
+            otlp_span["parentSpanId"] = Value::String(hex(&parent));
+        }
+        self.inner.spans.lock().unwrap().push(otlp_span);
+        if state.parent.is_none() {
+            self.inner.export_spans();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    /// Accepts one request and returns its path and JSON body.
+    fn collect_one(listener: TcpListener) -> thread::JoinHandle<(String, Value)> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let path = line.split(' ').nth(1).unwrap().to_string();
+            let mut length = 0;
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':')
+                    && name.eq_ignore_ascii_case("content-length")
+                {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            (&stream)
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            (path, serde_json::from_slice(&body).unwrap())
+        })
+    }
+
+    #[test]
+    fn test_trace_export() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let collector = collect_one(listener);
+        let layer = OtlpLayer {
+            inner: Otlp::new(&endpoint, "test").inner,
+        };
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = info_span!("scan", layers = 2).entered();
+            let child = info_span!("scan_layer", files = tracing::field::Empty);
+            child.record("files", 3);
+        });
+
+        let (path, body) = collector.join().unwrap();
+        assert_eq!(path, "/v1/traces");
+        let spans = &body["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        let [child, root] = [&spans[0], &spans[1]];
+        assert_eq!(child["name"], "scan_layer");
+        assert_eq!(child["traceId"], root["traceId"]);
+        assert_eq!(child["parentSpanId"], root["spanId"]);
+        assert!(root.get("parentSpanId").is_none());
+        assert_eq!(
+            child["attributes"],
+            json!([{ "key": "files", "value": { "intValue": "3" } }])
+        );
+        assert_eq!(root["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(
+            body["resourceSpans"][0]["resource"]["attributes"][0]["value"]["stringValue"],
+            "test"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Splits duplicate savings between the base image and the layers built on
+//! top of it. Duplicates living entirely in base layers are for whoever
+//! maintains the base image; the application team can only fix the ones
+//! its own layers introduce.
+
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::analyzer::DuplicateInfo;
+
+/// Duplicate groups and savings on one side of the boundary.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Share {
+    pub groups: usize,
+    pub savings: u64,
+}
+
+/// Outcome of [`split_savings`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavingsSplit {
+    /// Layers below this index belong to the base image
+    pub base_layers: usize,
+    /// Groups all of whose copies are in base layers
+    pub base: Share,
+    /// Groups with a copy in an application layer. Their savings are the
+    /// sizes of the copies in application layers, which are the ones a
+    /// rewrite replaces; copies among the base layers count as base.
+    pub application: Share,
+}
+
+/// Number of leading layers `image` shares with `base`, by diff_id.
+pub fn base_layer_count(image: &[String], base: &[String]) -> usize {
+    image.iter().zip(base).take_while(|(a, b)| a == b).count()
+}
+
+/// Attributes each duplicate copy to the side of `base_layers` it lives on.
+pub fn split_savings(duplicates: &[DuplicateInfo], base_layers: usize) -> SavingsSplit {
+    let mut split = SavingsSplit {
+        base_layers,
+        base: Share::default(),
+        application: Share::default(),
+    };
+    for group in duplicates {
+        let in_base = |layer_index: usize| layer_index < base_layers;
+        for duplicate in &group.duplicates {
+            if in_base(duplicate.layer_index) {
+                split.base.savings += duplicate.size;
+            } else {
+                split.application.savings += duplicate.size;
+            }
+        }
+        if in_base(group.original.layer_index)
+            && group.duplicates.iter().all(|d| in_base(d.layer_index))
+        {
+            split.base.groups += 1;
+        } else {
+            split.application.groups += 1;
+        }
+    }
+    split
+}
+
+/// Logs both shares.
+pub fn print_savings_split(split: &SavingsSplit) {
+    info!(
+        "Base image (layers 0-{}): {} groups, {} to save",
+        split.base_layers.saturating_sub(1),
+        split.base.groups,
+        format_size(split.base.savings, BINARY)
+    );
+    info!(
+        "Application (layers {}+): {} groups, {} to save",
+        split.base_layers,
+        split.application.groups,
+        format_size(split.application.savings, BINARY)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::FileInfo;
+
+    fn file(path: &str, layer_index: usize) -> FileInfo {
+        FileInfo {
+            path: path.to_string(),
+            size: 10,
+            hash: "h".to_string(),
+            layer_index,
+            provenance: None,
+        }
+    }
+
+    #[test]
+    fn test_split_by_layer_of_each_copy() {
+        let duplicates = vec![
+            DuplicateInfo {
+                original: file("a", 0),
+                duplicates: vec![file("b", 1)],
+                total_savings: 10,
+            },
+            DuplicateInfo {
+                original: file("c", 0),
+                duplicates: vec![file("d", 1), file("e", 2)],
+                total_savings: 20,
+            },
+        ];
+        let split = split_savings(&duplicates, 2);
+        assert_eq!((split.base.groups, split.base.savings), (1, 20));
+        assert_eq!(
+            (split.application.groups, split.application.savings),
+            (1, 10)
+        );
+        let ids = |ids: &[&str]| ids.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            base_layer_count(&ids(&["a", "b", "c"]), &ids(&["a", "b"])),
+            2
+        );
+        assert_eq!(base_layer_count(&ids(&["a", "b"]), &ids(&["x"])), 0);
+    }
+}
@@ -0,0 +1,256 @@
+//! Python and Node packages installed more than once: the same name and
+//! version in several `site-packages` or `node_modules` trees, or
+//! reinstalled by a later layer. They are reported per package rather
+//! than per file, as that's the level they get fixed at.
+//!
+//! Python packages are found by their `<name>-<version>.dist-info`
+//! directories and sized by the `RECORD` listing their files; Node ones by
+//! a `node_modules/<name>/package.json`, sized by the files below it
+//! outside nested `node_modules`. Both take a pass over every layer, as
+//! they need small files the scan doesn't hash.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::Read;
+
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+use tar::Archive;
+use tracing::{debug, info};
+
+use crate::cancel;
+use crate::entries::{EntryKind, entry_kind};
+use crate::error::Result;
+use crate::paths;
+
+/// `package.json` and `RECORD` files larger than this aren't read
+const MAX_METADATA_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ecosystem {
+    Python,
+    Node,
+}
+
+impl fmt::Display for Ecosystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Ecosystem::Python => "python",
+            Ecosystem::Node => "node",
+        })
+    }
+}
+
+/// One installed copy of a package.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageInstall {
+    /// The `.dist-info` directory, or the package directory under
+    /// `node_modules`
+    pub path: String,
+    pub layer_index: usize,
+    /// Bytes of the package's files
+    pub size: u64,
+}
+
+/// A package found in a layer, see [`scan_layer_packages`].
+#[derive(Debug, Clone)]
+pub struct Package {
+    pub ecosystem: Ecosystem,
+    /// Normalized: lowercase, with `_` and `.` as `-` for Python
+    pub name: String,
+    pub version: String,
+    pub install: PackageInstall,
+}
+
+/// A package and version installed more than once.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicatePackage {
+    pub ecosystem: Ecosystem,
+    pub name: String,
+    pub version: String,
+    /// In layer order
+    pub installs: Vec<PackageInstall>,
+    /// Bytes of every install but the largest
+    pub duplicate_bytes: u64,
+}
+
+/// `numpy 1.26.4 (python) installed 3× in 2 layers, 60.00 MiB duplicated`
+impl fmt::Display for DuplicatePackage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut layers: Vec<usize> = self.installs.iter().map(|i| i.layer_index).collect();
+        layers.dedup();
+        write!(
+            f,
+            "{} {} ({}) installed {}× in {} layer{}, {} duplicated",
+            self.name,
+            self.version,
+            self.ecosystem,
+            self.installs.len(),
+            layers.len(),
+            if layers.len() == 1 { "" } else { "s" },
+            format_size(self.duplicate_bytes, BINARY)
+        )
+    }
+}
+
+/// `site-packages/numpy-1.26.4.dist-info` to `numpy` and `1.26.4`.
+fn parse_dist_info(dir: &str) -> Option<(String, &str)> {
+    let name = dir.rsplit('/').next()?.strip_suffix(".dist-info")?;
+    let (name, version) = name.split_once('-')?;
+    let name = name.to_ascii_lowercase().replace(['_', '.'], "-");
+    (!name.is_empty() && !version.is_empty()).then_some((name, version))
+}
+
+/// The package directory of a `node_modules/<name>/package.json` or
+/// `node_modules/@scope/<name>/package.json`, and the name.
+fn node_package_root(path: &str) -> Option<(&str, &str)> {
+    let root = path.strip_suffix("/package.json")?;
+    let (_, name) = root.rsplit_once("node_modules/")?;
+    let parts = name.split('/').count();
+    let scoped = name.starts_with('@');
+    (parts == 1 && !scoped || parts == 2 && scoped).then_some((root, name))
+}
+
+/// Sum of the sizes a `RECORD` lists, as `path,hash,size` lines.
+fn record_size(record: &str) -> u64 {
+    record
+        .lines()
+        .filter_map(|line| line.rsplit(',').next()?.trim().parse::<u64>().ok())
+        .sum()
+}
+
+#[derive(Deserialize)]
+struct PackageJson {
+    name: Option<String>,
+    version: Option<String>,
+}
+
+/// Every Python and Node package in the uncompressed layer tar `reader`.
+pub fn scan_layer_packages<R: Read>(reader: R, layer_index: usize) -> Result<Vec<Package>> {
+    let mut archive = Archive::new(reader);
+    let mut packages = Vec::new();
+    let mut node_roots: Vec<(String, String, String)> = Vec::new();
+    // Files below a node_modules, by normalized path, to size packages by
+    let mut node_files: BTreeMap<String, u64> = BTreeMap::new();
+    for entry in archive.entries()? {
+        cancel::check()?;
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if entry_kind(&path, entry.header().entry_type()) != EntryKind::File {
+            continue;
+        }
+        let path = paths::normalize(&path).to_string();
+        let size = entry.size();
+        if path.contains("node_modules/") {
+            node_files.insert(path.clone(), size);
+        }
+        let wanted = path.ends_with(".dist-info/RECORD") || node_package_root(&path).is_some();
+        if !wanted || size > MAX_METADATA_SIZE {
+            continue;
+        }
+        let mut contents = String::new();
+        if entry.read_to_string(&mut contents).is_err() {
+            debug!("Skipping {} in layer {}, not UTF-8", path, layer_index);
+            continue;
+        }
+        if let Some(dir) = path.strip_suffix("/RECORD") {
+            let Some((name, version)) = parse_dist_info(dir) else {
+                continue;
+            };
+            packages.push(Package {
+                ecosystem: Ecosystem::Python,
+                name,
+                version: version.to_string(),
+                install: PackageInstall {
+                    path: dir.to_string(),
+                    layer_index,
+                    size: record_size(&contents),
+                },
+            });
+        } else if let Some((root, dir_name)) = node_package_root(&path) {
+            let Ok(package) = serde_json::from_str::<PackageJson>(&contents) else {
+                debug!("Skipping {} in layer {}, not a package", path, layer_index);
+                continue;
+            };
+            let Some(version) = package.version else {
+                continue;
+            };
+            let name = package.name.unwrap_or_else(|| dir_name.to_string());
+            node_roots.push((root.to_string(), name, version));
+        }
+    }
+    for (root, name, version) in node_roots {
+        let prefix = format!("{}/", root);
+        let nested = format!("{}node_modules/", prefix);
+        let size = node_files
+            .range(prefix.clone()..)
+            .take_while(|(path, _)| path.starts_with(&prefix))
+            .filter(|(path, _)| !path.starts_with(&nested))
+            .map(|(_, size)| size)
+            .sum();
+        packages.push(Package {
+            ecosystem: Ecosystem::Node,
+            name,
+            version,
+            install: PackageInstall {
+                path: root,
+                layer_index,
+                size,
+            },
+        });
+    }
+    Ok(packages)
+}
+
+/// Packages installed more than once, most duplicated bytes first.
+pub fn duplicate_packages(packages: Vec<Package>) -> Vec<DuplicatePackage> {
+    let mut by_package: HashMap<(Ecosystem, String, String), Vec<PackageInstall>> = HashMap::new();
+    for package in packages {
+        by_package
+            .entry((package.ecosystem, package.name, package.version))
+            .or_default()
+            .push(package.install);
+    }
+    let mut duplicates: Vec<DuplicatePackage> = by_package
+        .into_iter()
+        .filter(|(_, installs)| installs.len() > 1)
+        .map(|((ecosystem, name, version), mut installs)| {
+            installs.sort_by(|a, b| (a.layer_index, &a.path).cmp(&(b.layer_index, &b.path)));
+            let total: u64 = installs.iter().map(|i| i.size).sum();
+            let largest = installs.iter().map(|i| i.size).max().unwrap_or_default();
+            DuplicatePackage {
+                ecosystem,
+                name,
+                version,
+                installs,
+                duplicate_bytes: total - largest,
+            }
+        })
+        .collect();
+    duplicates.sort_by(|a, b| {
+        (Reverse(a.duplicate_bytes), &a.name).cmp(&(Reverse(b.duplicate_bytes), &b.name))
+    });
+    duplicates
+}
+
+/// Logs every duplicated package and where it is installed.
+pub fn print_duplicate_packages(packages: &[DuplicatePackage]) {
+    if packages.is_empty() {
+        return;
+    }
+    info!("Packages installed more than once:");
+    for package in packages {
+        info!("\t{}", package);
+        for install in &package.installs {
+            info!(
+                "\t\t{} (layer {}, {})",
+                install.path,
+                install.layer_index,
+                format_size(install.size, BINARY)
+            );
+        }
+    }
+    info!("=============================");
+}
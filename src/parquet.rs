@@ -0,0 +1,492 @@
+//! Just enough of the Parquet format to write flat tables of required
+//! 64-bit integer, boolean and UTF-8 columns: PLAIN encoded, uncompressed,
+//! one data page per column and row group. DuckDB, Spark, pandas and the
+//! like load the result without parsing anything row by row.
+//!
+//! Metadata is Thrift in the compact protocol, written by hand below; see
+//! `parquet.thrift` in the parquet-format repository for the field ids.
+
+use std::io::{self, Write};
+
+/// Rows per row group, so no page gets near the format's 2 GiB limit
+const ROW_GROUP_ROWS: usize = 1 << 20;
+const MAGIC: &[u8] = b"PAR1";
+
+/// Values of one column.
+#[derive(Debug, Clone)]
+pub enum Column {
+    Int64(Vec<i64>),
+    Boolean(Vec<bool>),
+    Utf8(Vec<String>),
+}
+
+impl Column {
+    fn len(&self) -> usize {
+        match self {
+            Column::Int64(values) => values.len(),
+            Column::Boolean(values) => values.len(),
+            Column::Utf8(values) => values.len(),
+        }
+    }
+
+    /// Parquet physical type: INT64, BOOLEAN or BYTE_ARRAY
+    fn physical_type(&self) -> i32 {
+        match self {
+            Column::Int64(_) => 2,
+            Column::Boolean(_) => 0,
+            Column::Utf8(_) => 6,
+        }
+    }
+
+    /// PLAIN encoding of rows `start..end`.
+    fn plain(&self, start: usize, end: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        match self {
+            Column::Int64(values) => {
+                for value in &values[start..end] {
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            Column::Boolean(values) => {
+                data.resize((end - start).div_ceil(8), 0);
+                for (i, value) in values[start..end].iter().enumerate() {
+                    data[i / 8] |= u8::from(*value) << (i % 8);
+                }
+            }
+            Column::Utf8(values) => {
+                for value in &values[start..end] {
+                    data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                    data.extend_from_slice(value.as_bytes());
+                }
+            }
+        }
+        data
+    }
+}
+
+/// Thrift compact protocol, the subset Parquet metadata needs.
+#[derive(Default)]
+struct Compact {
+    buf: Vec<u8>,
+    /// Last field id written in each open struct
+    fields: Vec<i16>,
+}
+
+const BINARY: u8 = 8;
+const I32: u8 = 5;
+const I64: u8 = 6;
+const LIST: u8 = 9;
+const STRUCT: u8 = 12;
+
+impl Compact {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn zigzag(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn field(&mut self, id: i16, kind: u8) {
+        let last = self
+            .fields
+            .last_mut()
+            .expect("fields are written inside a struct");
+        let delta = id - std::mem::replace(last, id);
+        if (1..=15).contains(&delta) {
+            self.buf.push((delta as u8) << 4 | kind);
+        } else {
+            self.buf.push(kind);
+            self.zigzag(id as i64);
+        }
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, I32);
+        self.zigzag(value as i64);
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, I64);
+        self.zigzag(value);
+    }
+
+    fn string(&mut self, id: i16, value: &str) {
+        self.field(id, BINARY);
+        self.raw_string(value);
+    }
+
+    fn raw_string(&mut self, value: &str) {
+        self.varint(value.len() as u64);
+        self.buf.extend_from_slice(value.as_bytes());
+    }
+
+    fn list(&mut self, id: i16, kind: u8, len: usize) {
+        self.field(id, LIST);
+        if len < 15 {
+            self.buf.push((len as u8) << 4 | kind);
+        } else {
+            self.buf.push(0xf0 | kind);
+            self.varint(len as u64);
+        }
+    }
+
+    /// Opens a struct, as field `id` of the current one or, without an id,
+    /// as a list element or the top-level value.
+    fn begin(&mut self, id: Option<i16>) {
+        if let Some(id) = id {
+            self.field(id, STRUCT);
+        }
+        self.fields.push(0);
+    }
+
+    fn end(&mut self) {
+        self.buf.push(0);
+        self.fields.pop();
+    }
+}
+
+/// Offsets and sizes of one column chunk, for the footer.
+struct ChunkMeta {
+    offset: u64,
+    size: u64,
+    values: usize,
+}
+
+/// Writes `columns` as a Parquet file, in row groups of up to
+/// [`ROW_GROUP_ROWS`] rows. All columns must have the same length.
+pub fn write_table<W: Write>(mut writer: W, columns: &[(&str, Column)]) -> io::Result<()> {
+    let rows = columns.first().map_or(0, |(_, c)| c.len());
+    if columns.iter().any(|(_, c)| c.len() != rows) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "columns of different lengths",
+        ));
+    }
+    writer.write_all(MAGIC)?;
+    let mut position = MAGIC.len() as u64;
+    let mut row_groups: Vec<Vec<ChunkMeta>> = Vec::new();
+    let mut start = 0;
+    while start < rows {
+        let end = (start + ROW_GROUP_ROWS).min(rows);
+        let mut chunks = Vec::new();
+        for (_, column) in columns {
+            let data = column.plain(start, end);
+            let mut header = Compact::default();
+            header.begin(None);
+            // PageHeader: DATA_PAGE, uncompressed and compressed sizes
+            header.i32(1, 0);
+            header.i32(2, data.len() as i32);
+            header.i32(3, data.len() as i32);
+            // DataPageHeader: values, PLAIN, RLE levels (none, all required)
+            header.begin(Some(5));
+            header.i32(1, (end - start) as i32);
+            header.i32(2, 0);
+            header.i32(3, 3);
+            header.i32(4, 3);
+            header.end();
+            header.end();
+            writer.write_all(&header.buf)?;
+            writer.write_all(&data)?;
+            let size = (header.buf.len() + data.len()) as u64;
+            chunks.push(ChunkMeta {
+                offset: position,
+                size,
+                values: end - start,
+            });
+            position += size;
+        }
+        row_groups.push(chunks);
+        start = end;
+    }
+
+    let mut footer = Compact::default();
+    footer.begin(None);
+    footer.i32(1, 1);
+    // Schema: the root, then one required leaf per column
+    footer.list(2, STRUCT, columns.len() + 1);
+    footer.begin(None);
+    footer.string(4, "schema");
+    footer.i32(5, columns.len() as i32);
+    footer.end();
+    for (name, column) in columns {
+        footer.begin(None);
+        footer.i32(1, column.physical_type());
+        footer.i32(3, 0);
+        footer.string(4, name);
+        if let Column::Utf8(_) = column {
+            // ConvertedType UTF8
+            footer.i32(6, 0);
+        }
+        footer.end();
+    }
+    footer.i64(3, rows as i64);
+    footer.list(4, STRUCT, row_groups.len());
+    for chunks in &row_groups {
+        footer.begin(None);
+        footer.list(1, STRUCT, chunks.len());
+        for ((name, column), chunk) in columns.iter().zip(chunks) {
+            footer.begin(None);
+            footer.i64(2, chunk.offset as i64);
+            footer.begin(Some(3));
+            footer.i32(1, column.physical_type());
+            footer.list(2, I32, 1);
+            footer.zigzag(0);
+            footer.list(3, BINARY, 1);
+            footer.raw_string(name);
+            footer.i32(4, 0);
+            footer.i64(5, chunk.values as i64);
+            footer.i64(6, chunk.size as i64);
+            footer.i64(7, chunk.size as i64);
+            footer.i64(9, chunk.offset as i64);
+            footer.end();
+            footer.end();
+        }
+        footer.i64(2, chunks.iter().map(|c| c.size).sum::<u64>() as i64);
+        footer.i64(3, chunks.first().map_or(0, |c| c.values) as i64);
+        footer.end();
+    }
+    footer.string(
+        6,
+        concat!(
+            env!("CARGO_PKG_NAME"),
+            " version ",
+            env!("CARGO_PKG_VERSION")
+        ),
+    );
+    footer.end();
+    writer.write_all(&footer.buf)?;
+    writer.write_all(&(footer.buf.len() as u32).to_le_bytes())?;
+    writer.write_all(MAGIC)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// A decoded Thrift compact value
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Int(i64),
+        Binary(Vec<u8>),
+        List(Vec<Value>),
+        Struct(BTreeMap<i16, Value>),
+    }
+
+    impl Value {
+        fn int(&self, id: i16) -> i64 {
+            match self.field(id) {
+                Value::Int(value) => *value,
+                other => panic!("field {} is {:?}", id, other),
+            }
+        }
+
+        fn string(&self, id: i16) -> &str {
+            match self.field(id) {
+                Value::Binary(value) => std::str::from_utf8(value).unwrap(),
+                other => panic!("field {} is {:?}", id, other),
+            }
+        }
+
+        fn list(&self, id: i16) -> &[Value] {
+            match self.field(id) {
+                Value::List(values) => values,
+                other => panic!("field {} is {:?}", id, other),
+            }
+        }
+
+        fn field(&self, id: i16) -> &Value {
+            match self {
+                Value::Struct(fields) => &fields[&id],
+                other => panic!("{:?} isn't a struct", other),
+            }
+        }
+    }
+
+    /// Reads back what [`Compact`] writes, to check files independently of
+    /// the writer's bookkeeping.
+    struct Reader<'a> {
+        buf: &'a [u8],
+        pos: usize,
+    }
+
+    impl Reader<'_> {
+        fn byte(&mut self) -> u8 {
+            self.pos += 1;
+            self.buf[self.pos - 1]
+        }
+
+        fn varint(&mut self) -> u64 {
+            let mut value = 0;
+            for shift in (0..64).step_by(7) {
+                let byte = self.byte();
+                value |= u64::from(byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            value
+        }
+
+        fn zigzag(&mut self) -> i64 {
+            let value = self.varint();
+            (value >> 1) as i64 ^ -((value & 1) as i64)
+        }
+
+        fn value(&mut self, kind: u8) -> Value {
+            match kind {
+                I32 | I64 => Value::Int(self.zigzag()),
+                BINARY => {
+                    let len = self.varint() as usize;
+                    self.pos += len;
+                    Value::Binary(self.buf[self.pos - len..self.pos].to_vec())
+                }
+                LIST => {
+                    let header = self.byte();
+                    let len = match header >> 4 {
+                        15 => self.varint() as usize,
+                        len => usize::from(len),
+                    };
+                    Value::List((0..len).map(|_| self.value(header & 0xf)).collect())
+                }
+                STRUCT => {
+                    let mut fields = BTreeMap::new();
+                    let mut last = 0;
+                    loop {
+                        let header = self.byte();
+                        if header == 0 {
+                            return Value::Struct(fields);
+                        }
+                        last = match header >> 4 {
+                            0 => self.zigzag() as i16,
+                            delta => last + i16::from(delta),
+                        };
+                        fields.insert(last, self.value(header & 0xf));
+                    }
+                }
+                kind => panic!("unexpected type {}", kind),
+            }
+        }
+    }
+
+    #[test]
+    fn test_compact_protocol() {
+        let mut compact = Compact::default();
+        compact.begin(None);
+        compact.i32(1, -1);
+        compact.i64(20, 300);
+        compact.list(21, BINARY, 1);
+        compact.raw_string("ab");
+        compact.end();
+        // Short field headers, zigzag varints, the long form for a delta > 15
+        assert_eq!(
+            compact.buf,
+            [
+                0x15, 0x01, 0x06, 0x28, 0xd8, 0x04, 0x19, 0x18, 0x02, b'a', b'b', 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn test_table_layout() {
+        let mut file = Vec::new();
+        write_table(
+            &mut file,
+            &[
+                ("size", Column::Int64(vec![1, 2, 3])),
+                ("original", Column::Boolean(vec![true, false, true])),
+                (
+                    "path",
+                    Column::Utf8(vec!["a".into(), "bc".into(), "".into()]),
+                ),
+            ],
+        )
+        .unwrap();
+        assert!(file.starts_with(MAGIC) && file.ends_with(MAGIC));
+        let footer_len =
+            u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap());
+        assert!((footer_len as usize) < file.len());
+        // PLAIN values follow each page header
+        let windows = |needle: &[u8]| file.windows(needle.len()).any(|w| w == needle);
+        assert!(windows(&[1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]));
+        assert!(windows(&[
+            1, 0, 0, 0, b'a', 2, 0, 0, 0, b'b', b'c', 0, 0, 0, 0
+        ]));
+        assert!(windows(b"path"));
+
+        let mut mismatched = Vec::new();
+        let columns = [
+            ("a", Column::Int64(vec![1])),
+            ("b", Column::Int64(Vec::new())),
+        ];
+        assert!(write_table(&mut mismatched, &columns).is_err());
+    }
+
+    #[test]
+    fn test_file_metadata_reads_back() {
+        let mut file = Vec::new();
+        let path = Column::Utf8(vec!["a".into(), "bc".into(), "".into()]);
+        let columns = [
+            ("size", Column::Int64(vec![1, 2, 3])),
+            ("original", Column::Boolean(vec![true, false, true])),
+            ("path", path),
+        ];
+        write_table(&mut file, &columns).unwrap();
+
+        // PAR1, pages, FileMetaData, its length and PAR1 again
+        let footer_len =
+            u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap()) as usize;
+        let footer_start = file.len() - 8 - footer_len;
+        let mut reader = Reader {
+            buf: &file[..file.len() - 8],
+            pos: footer_start,
+        };
+        let metadata = reader.value(STRUCT);
+        assert_eq!(reader.pos, file.len() - 8, "footer length is exact");
+
+        assert_eq!(metadata.int(1), 1);
+        assert_eq!(metadata.int(3), 3);
+        let schema = metadata.list(2);
+        assert_eq!(schema[0].int(5), 3);
+        let names: Vec<&str> = schema[1..].iter().map(|leaf| leaf.string(4)).collect();
+        assert_eq!(names, ["size", "original", "path"]);
+        assert_eq!(schema[3].int(6), 0, "UTF8 converted type");
+
+        let row_groups = metadata.list(4);
+        assert_eq!(row_groups.len(), 1);
+        assert_eq!(row_groups[0].int(3), 3);
+        let mut expected_offset = MAGIC.len() as i64;
+        for (chunk, (name, column)) in row_groups[0].list(1).iter().zip(&columns) {
+            let chunk_meta = chunk.field(3);
+            assert_eq!(chunk_meta.int(1), i64::from(column.physical_type()));
+            assert_eq!(
+                chunk_meta.list(3),
+                [Value::Binary(name.as_bytes().to_vec())]
+            );
+            assert_eq!(chunk_meta.int(5), 3);
+            let offset = chunk_meta.int(9);
+            assert_eq!(offset, expected_offset);
+
+            // The page header at the offset is followed by the PLAIN values
+            let mut reader = Reader {
+                buf: &file,
+                pos: offset as usize,
+            };
+            let page = reader.value(STRUCT);
+            let data = column.plain(0, 3);
+            assert_eq!(page.int(3), data.len() as i64);
+            assert_eq!(page.field(5).int(1), 3);
+            assert_eq!(&file[reader.pos..reader.pos + data.len()], data.as_slice());
+            assert_eq!(chunk_meta.int(7), (reader.pos + data.len()) as i64 - offset);
+            expected_offset += chunk_meta.int(7);
+        }
+        assert_eq!(expected_offset as usize, footer_start);
+    }
+}
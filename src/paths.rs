@@ -0,0 +1,278 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::error::{DedupeError, Result};
+
+/// Symlinks followed before a path is taken for a loop
+pub(crate) const MAX_LINKS: usize = 40;
+
+/// Strips the `./` and `/` prefixes tar entries and users put in front of
+/// paths, so `/usr/lib`, `./usr/lib` and `usr/lib` all compare equal.
+pub fn normalize(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else {
+            return path;
+        }
+    }
+}
+
+/// Where `path`, as manifest.json names it, is in the archive unpacked at
+/// `root`. Symlinks along it are followed the way the archive means them:
+/// absolute targets start at the archive root, and links leading outside it
+/// are refused rather than followed to the host's files.
+pub fn resolve_in_archive(root: &Path, path: &str) -> Result<PathBuf> {
+    let outside = || DedupeError::Manifest(format!("{} points outside the archive", path));
+    let mut pending: Vec<String> = path.split('/').rev().map(str::to_string).collect();
+    let mut resolved: Vec<String> = Vec::new();
+    let mut links = 0;
+    while let Some(part) = pending.pop() {
+        match part.as_str() {
+            "" | "." => continue,
+            ".." => {
+                resolved.pop().ok_or_else(outside)?;
+                continue;
+            }
+            _ => {}
+        }
+        let candidate = resolved
+            .iter()
+            .fold(root.to_path_buf(), |p, c| p.join(c))
+            .join(&part);
+        let is_link = fs::symlink_metadata(&candidate).is_ok_and(|m| m.file_type().is_symlink());
+        if !is_link {
+            resolved.push(part);
+            continue;
+        }
+        links += 1;
+        if links > MAX_LINKS {
+            return Err(DedupeError::Manifest(format!(
+                "{}: too many levels of links",
+                path
+            )));
+        }
+        let target = fs::read_link(&candidate)?;
+        let target = target.to_string_lossy();
+        if target.starts_with('/') {
+            resolved.clear();
+        }
+        pending.extend(target.split('/').rev().map(str::to_string));
+    }
+    Ok(resolved.iter().fold(root.to_path_buf(), |p, c| p.join(c)))
+}
+
+/// The archive path a symlink at `link` pointing to `target` names, or None
+/// when it leads outside the archive.
+pub(crate) fn link_target(link: &str, target: &str) -> Option<String> {
+    let mut resolved: Vec<&str> = Vec::new();
+    if !target.starts_with('/') {
+        resolved.extend(normalize(link).split('/'));
+        resolved.pop();
+    }
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                resolved.pop()?;
+            }
+            _ => resolved.push(part),
+        }
+    }
+    Some(resolved.join("/"))
+}
+
+/// Paths never rewritten unless allowed: a link in place of config, boot
+/// files, the dynamic loader or a shell breaks the image in ways that are
+/// hard to trace back.
+pub const PROTECTED_PATHS: &[&str] = &[
+    "etc",
+    "boot",
+    "lib/ld-*.so*",
+    "lib64/ld-*.so*",
+    "lib/*/ld-*.so*",
+    "usr/lib/ld-*.so*",
+    "usr/lib64/ld-*.so*",
+    "usr/lib/*/ld-*.so*",
+    "bin/sh",
+    "bin/ash",
+    "bin/bash",
+    "bin/dash",
+    "bin/busybox",
+    "usr/bin/sh",
+    "usr/bin/bash",
+    "usr/bin/dash",
+];
+
+/// A set of glob patterns matched against normalized image paths. A pattern
+/// naming a directory also matches everything below it.
+#[derive(Debug, Clone, Default)]
+pub struct PathMatcher {
+    set: GlobSet,
+    protected: GlobSet,
+    allowed: GlobSet,
+    /// Everything outside it matches, when not empty
+    scope: GlobSet,
+}
+
+fn glob_set<S: AsRef<str>>(patterns: &[S]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = normalize(pattern.as_ref()).trim_end_matches('/');
+        builder.add(glob(pattern)?);
+        builder.add(glob(&format!("{}/**", pattern))?);
+    }
+    builder
+        .build()
+        .map_err(|e| DedupeError::InvalidOption(e.to_string()))
+}
+
+impl PathMatcher {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self> {
+        Ok(Self {
+            set: glob_set(patterns)?,
+            ..Self::default()
+        })
+    }
+
+    /// Matches `excludes` and, layered under them, the
+    /// [`PROTECTED_PATHS`] not covered by `allowed`.
+    pub fn excludes<S: AsRef<str>>(excludes: &[S], allowed: &[S]) -> Result<Self> {
+        Ok(Self {
+            set: glob_set(excludes)?,
+            protected: glob_set(PROTECTED_PATHS)?,
+            allowed: glob_set(allowed)?,
+            ..Self::default()
+        })
+    }
+
+    /// Also matches everything outside the directories or patterns of
+    /// `scope`, unless it's empty.
+    pub fn within<S: AsRef<str>>(mut self, scope: &[S]) -> Result<Self> {
+        self.scope = glob_set(scope)?;
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.protected.is_empty() && self.scope.is_empty()
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        let path = normalize(path);
+        self.set.is_match(path)
+            || (self.protected.is_match(path) && !self.allowed.is_match(path))
+            || (!self.scope.is_empty() && !self.scope.is_match(path))
+    }
+}
+
+/// The patterns of an ignore file: one per line, blank lines and lines
+/// starting with `#` skipped.
+pub fn ignore_patterns(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+fn glob(pattern: &str) -> Result<Glob> {
+    Glob::new(pattern)
+        .map_err(|e| DedupeError::InvalidOption(format!("Invalid path pattern {}: {}", pattern, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("./usr/lib"), "usr/lib");
+        assert_eq!(normalize("/usr/lib"), "usr/lib");
+        assert_eq!(normalize(".//usr/lib"), "usr/lib");
+        assert_eq!(normalize("usr/lib"), "usr/lib");
+    }
+
+    #[test]
+    fn test_resolve_in_archive() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        fs::create_dir_all(root.join("blobs/sha256")).unwrap();
+        fs::write(root.join("blobs/sha256/abc"), "layer").unwrap();
+        fs::create_dir(root.join("1")).unwrap();
+        std::os::unix::fs::symlink("../blobs/sha256/abc", root.join("1/layer.tar")).unwrap();
+        std::os::unix::fs::symlink("/blobs/sha256/abc", root.join("2")).unwrap();
+        std::os::unix::fs::symlink("../../etc/passwd", root.join("3")).unwrap();
+        std::os::unix::fs::symlink("4", root.join("4")).unwrap();
+
+        let blob = root.join("blobs/sha256/abc");
+        assert_eq!(resolve_in_archive(root, "1/layer.tar").unwrap(), blob);
+        assert_eq!(resolve_in_archive(root, "2").unwrap(), blob);
+        assert_eq!(resolve_in_archive(root, "blobs/sha256/abc").unwrap(), blob);
+        assert!(resolve_in_archive(root, "3").is_err());
+        assert!(resolve_in_archive(root, "4").is_err());
+
+        assert_eq!(
+            link_target("./1/layer.tar", "../blobs/sha256/abc").as_deref(),
+            Some("blobs/sha256/abc")
+        );
+        assert_eq!(link_target("1/layer.tar", "/abc").as_deref(), Some("abc"));
+        assert_eq!(link_target("1/layer.tar", "../../abc"), None);
+    }
+
+    #[test]
+    fn test_protected_paths() {
+        let matcher = PathMatcher::excludes(&["opt/cache"], &[] as &[&str]).unwrap();
+        for path in [
+            "/etc/passwd",
+            "boot/vmlinuz",
+            "lib/x86_64-linux-gnu/ld-linux-x86-64.so.2",
+            "lib/ld-musl-x86_64.so.1",
+            "bin/sh",
+            "opt/cache/a",
+        ] {
+            assert!(matcher.is_match(path), "{}", path);
+        }
+        assert!(!matcher.is_match("usr/lib/libc.so.6"));
+        assert!(!matcher.is_match("etcetera"));
+
+        let matcher = PathMatcher::excludes(&["opt/cache"], &["etc/ssl"]).unwrap();
+        assert!(matcher.is_match("etc/passwd"));
+        assert!(!matcher.is_match("etc/ssl/certs/ca.pem"));
+        assert!(matcher.is_match("opt/cache/a"));
+    }
+
+    #[test]
+    fn test_matcher_covers_subtrees() {
+        let matcher = PathMatcher::new(&["/usr/share/doc/", "*.pyc"]).unwrap();
+        assert!(matcher.is_match("usr/share/doc/readme"));
+        assert!(matcher.is_match("./usr/share/doc"));
+        assert!(matcher.is_match("app/x.pyc"));
+        assert!(!matcher.is_match("usr/share/docs/readme"));
+    }
+
+    #[test]
+    fn test_ignore_patterns() {
+        let contents = "# vendored, signed\n/opt/vendor/**\n\n  *.jar  \n#x\n";
+        assert_eq!(ignore_patterns(contents), ["/opt/vendor/**", "*.jar"]);
+    }
+
+    #[test]
+    fn test_scope_excludes_everything_outside() {
+        let matcher = PathMatcher::excludes(&["usr/share/doc"], &[])
+            .unwrap()
+            .within(&["/usr", "/opt/app/"])
+            .unwrap();
+        assert!(!matcher.is_match("usr/lib/libc.so.6"));
+        assert!(!matcher.is_match("./opt/app/lib/a.jar"));
+        assert!(matcher.is_match("usr/share/doc/readme"));
+        assert!(matcher.is_match("opt/other/a.jar"));
+        assert!(matcher.is_match("usrlocal/a"));
+        assert!(!matcher.is_empty());
+    }
+}
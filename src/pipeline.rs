@@ -0,0 +1,91 @@
+#[cfg(feature = "rewrite")]
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::schemas::Descriptor;
+
+#[cfg(feature = "rewrite")]
+use crate::analyzer::Analyzer;
+#[cfg(feature = "rewrite")]
+use crate::error::Result;
+#[cfg(feature = "rewrite")]
+use crate::options::AnalyzerOptions;
+
+/// Old and new identity of one layer after a rewrite.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerDigests {
+    pub layer_index: usize,
+    pub rewritten: bool,
+    /// sha256 of the uncompressed layer, as listed in the config's diff_ids
+    pub old_diff_id: String,
+    pub new_diff_id: String,
+    /// Blob path referenced by manifest.json
+    pub old_blob: String,
+    pub new_blob: String,
+}
+
+/// Outcome of writing a deduplicated image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DedupeSummary {
+    /// See [`crate::SCHEMA_VERSION`]
+    pub schema_version: u32,
+    pub duplicate_groups: usize,
+    pub duplicate_files: usize,
+    pub bytes_saved: u64,
+    /// One per pair of old and new layer: squashing pairs several old
+    /// layers with the same new one, splitting one old layer with several
+    pub layers: Vec<LayerDigests>,
+    /// sha256 of the config, which is also the image ID `docker images`
+    /// shows
+    pub old_config_digest: String,
+    pub new_config_digest: String,
+    /// Digest of the manifest written, what registries and signatures
+    /// refer to. `docker save` archives have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest_digest: Option<String>,
+    /// Signatures, SBOMs and other artifacts attached to the original
+    /// image, which don't vouch for the new one, when asked to look for them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stale_referrers: Vec<Descriptor>,
+}
+
+impl DedupeSummary {
+    /// Logs what each digest became, for updating references to the image.
+    pub fn print_digests(&self) {
+        info!("Digests (old -> new):");
+        info!(
+            "	config / image ID: {} -> {}",
+            self.old_config_digest, self.new_config_digest
+        );
+        for layer in &self.layers {
+            if !layer.rewritten {
+                info!(
+                    "	layer {}: unchanged {}",
+                    layer.layer_index, layer.old_diff_id
+                );
+                continue;
+            }
+            info!(
+                "	layer {}: {} -> {}",
+                layer.layer_index, layer.old_diff_id, layer.new_diff_id
+            );
+            info!("		blob {} -> {}", layer.old_blob, layer.new_blob);
+        }
+    }
+}
+
+#[cfg(feature = "rewrite")]
+/// Reads a `docker save` stream, deduplicates it and writes the new image to
+/// `writer`, all temp files included. For finer control drive an
+/// [`Analyzer`] directly.
+pub fn dedupe_image<R: Read, W: Write>(
+    image_stream: R,
+    writer: W,
+    options: AnalyzerOptions,
+) -> Result<DedupeSummary> {
+    let analyzer = Analyzer::from_reader(image_stream, options)?;
+    let duplicates = analyzer.find_duplicates()?;
+    analyzer.create_deduplicated_image(duplicates, writer)
+}
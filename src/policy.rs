@@ -0,0 +1,281 @@
+use std::collections::{BTreeMap, HashSet};
+
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::{DuplicateInfo, FileInfo, Layer};
+use crate::error::Result;
+use crate::paths::{self, PathMatcher};
+use crate::schemas::{HistoryEntry, layer_history};
+
+/// What happens to a duplicate file in the rewritten image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Replace it with an absolute symlink to the original
+    Symlink,
+    /// Replace it with a hardlink to the original. Only possible when both
+    /// live in the same layer.
+    Hardlink,
+    /// Drop it from its layer without a replacement, e.g. because the same
+    /// path with the same contents is already visible from a lower layer
+    Omit,
+    /// Leave it alone
+    Skip,
+}
+
+/// The mechanism a rewrite deduplicates with, for all duplicates at once,
+/// as deployments need: read-only root filesystems, overlay drivers that
+/// handle some links badly, pipelines squashing the image anyway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Symlinks only, also within a layer
+    Symlink,
+    /// Hardlinks only, so duplicates in other layers than their original
+    /// are left alone
+    Hardlink,
+    /// Only drop copies a later layer added again at the path of their
+    /// original, leaving no link behind
+    Omit,
+    /// Move a copy of each duplicated file into a new bottom layer and
+    /// symlink every copy to it, see
+    /// [`crate::Analyzer::create_shared_layer_image`]
+    SharedLayer,
+    /// Change nothing, only report
+    ReportOnly,
+}
+
+impl Strategy {
+    /// What to do with `duplicate`, given the chosen `original`. The
+    /// shared layer links copies the way [`DefaultPolicy`] does until it
+    /// moves them.
+    pub fn action(self, original: &FileInfo, duplicate: &FileInfo) -> Action {
+        match self {
+            Strategy::Symlink => Action::Symlink,
+            Strategy::Hardlink if original.layer_index == duplicate.layer_index => Action::Hardlink,
+            Strategy::Omit
+                if original.layer_index != duplicate.layer_index
+                    && paths::normalize(&original.path) == paths::normalize(&duplicate.path) =>
+            {
+                Action::Omit
+            }
+            Strategy::SharedLayer => default_action(original, duplicate),
+            Strategy::Hardlink | Strategy::Omit | Strategy::ReportOnly => Action::Skip,
+        }
+    }
+}
+
+/// Image details available to a [`DedupPolicy`].
+pub struct PolicyContext<'a> {
+    pub layers: &'a [Layer],
+    pub history: &'a [HistoryEntry],
+    /// Normalized paths the image's symlinks point to
+    pub link_targets: &'a HashSet<String>,
+}
+
+impl PolicyContext<'_> {
+    /// The history entry that created layer `layer_index`, see
+    /// [`layer_history`].
+    pub fn history_for(&self, layer_index: usize) -> Option<&HistoryEntry> {
+        layer_history(self.history, layer_index)
+    }
+}
+
+/// Decides how each group of identical files is deduplicated. Implement it
+/// to encode your own rules, e.g. never touch `/etc` or always keep the copy
+/// under `/opt/vendor`, and pass it via [`crate::AnalyzerBuilder::policy`].
+///
+/// All methods default to the built-in behaviour, see [`DefaultPolicy`].
+pub trait DedupPolicy: Send + Sync {
+    /// The copy every other file in `group` is linked to. Must be
+    /// `group.original` or one of `group.duplicates`.
+    fn original<'a>(&self, group: &'a DuplicateInfo, _ctx: &PolicyContext<'_>) -> &'a FileInfo {
+        &group.original
+    }
+
+    /// The originals of all `groups` at once, in their order, for policies
+    /// weighing groups against each other. Defaults to
+    /// [`DedupPolicy::original`] of each.
+    fn originals<'a>(
+        &self,
+        groups: &'a [DuplicateInfo],
+        ctx: &PolicyContext<'_>,
+    ) -> Vec<&'a FileInfo> {
+        groups
+            .iter()
+            .map(|group| self.original(group, ctx))
+            .collect()
+    }
+
+    /// What to do with `duplicate`, given the chosen `original`.
+    fn action(
+        &self,
+        original: &FileInfo,
+        duplicate: &FileInfo,
+        _ctx: &PolicyContext<'_>,
+    ) -> Action {
+        default_action(original, duplicate)
+    }
+}
+
+/// Keeps the copy in the lowest layer and links all others to it: hardlinks
+/// within that layer, symlinks from later ones.
+pub struct DefaultPolicy;
+
+impl DedupPolicy for DefaultPolicy {}
+
+pub fn default_action(original: &FileInfo, duplicate: &FileInfo) -> Action {
+    if original.layer_index == duplicate.layer_index {
+        Action::Hardlink
+    } else {
+        Action::Symlink
+    }
+}
+
+/// Directories software is installed to by the distribution, whose copies
+/// are the ones people expect to find
+const CANONICAL_DIRS: &[&str] = &[
+    "usr/lib",
+    "usr/lib64",
+    "usr/libexec",
+    "usr/bin",
+    "usr/sbin",
+    "usr/share",
+    "lib",
+    "lib64",
+    "bin",
+    "sbin",
+];
+
+/// Path components of bundled, vendored or cached copies
+const VENDORED_DIRS: &[&str] = &[
+    "vendor",
+    "_vendor",
+    "vendored",
+    "node_modules",
+    "third_party",
+    ".cache",
+    "tmp",
+];
+
+/// 0 for paths under [`CANONICAL_DIRS`], 2 for paths through one of
+/// [`VENDORED_DIRS`], 1 for the rest.
+fn location_rank(path: &str) -> u8 {
+    let path = paths::normalize(path);
+    let mut dirs = path.split('/');
+    dirs.next_back();
+    if dirs.any(|dir| VENDORED_DIRS.contains(&dir)) {
+        2
+    } else if CANONICAL_DIRS.iter().any(|dir| {
+        path.strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+    }) {
+        0
+    } else {
+        1
+    }
+}
+
+/// Keeps the copy people expect to survive rather than the lowest one.
+/// In order, it prefers copies matching one of the `preferred` patterns,
+/// copies in the first `protected_layers` layers (a base image's, left
+/// untouched that way), copies other symlinks point to, and copies in
+/// [`CANONICAL_DIRS`] over others and over vendored ones, e.g.
+/// `/usr/lib/libssl.so.3` over `/opt/app/vendor/libssl.so.3`. Ties go to
+/// the lowest layer. Actions are [`DefaultPolicy`]'s, so copies below
+/// their original become symlinks up to it.
+#[derive(Debug, Clone, Default)]
+pub struct CanonicalPolicy {
+    preferred: PathMatcher,
+    protected_layers: usize,
+}
+
+impl CanonicalPolicy {
+    pub fn new<S: AsRef<str>>(preferred: &[S], protected_layers: usize) -> Result<Self> {
+        Ok(Self {
+            preferred: PathMatcher::new(preferred)?,
+            protected_layers,
+        })
+    }
+}
+
+impl DedupPolicy for CanonicalPolicy {
+    fn original<'a>(&self, group: &'a DuplicateInfo, ctx: &PolicyContext<'_>) -> &'a FileInfo {
+        std::iter::once(&group.original)
+            .chain(&group.duplicates)
+            .min_by_key(|f| {
+                (
+                    !self.preferred.is_match(&f.path),
+                    f.layer_index >= self.protected_layers,
+                    !ctx.link_targets.contains(paths::normalize(&f.path)),
+                    location_rank(&f.path),
+                    f.layer_index,
+                )
+            })
+            .unwrap_or(&group.original)
+    }
+}
+
+/// Keeps the originals where as few layers as possible change, so
+/// registries and build caches still hit on the others. Layers are kept
+/// intact lowest first, base layers being the most widely shared: a layer
+/// can be when it holds a single copy of each of its groups and none of
+/// them keeps a copy elsewhere, all copies in other layers then linking to
+/// it. Groups no intact layer keeps a copy of keep the lowest one, in a
+/// layer that changes anyway.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheFriendlyPolicy;
+
+impl DedupPolicy for CacheFriendlyPolicy {
+    fn originals<'a>(
+        &self,
+        groups: &'a [DuplicateInfo],
+        _ctx: &PolicyContext<'_>,
+    ) -> Vec<&'a FileInfo> {
+        let mut copies: BTreeMap<usize, Vec<(usize, &FileInfo)>> = BTreeMap::new();
+        for (index, group) in groups.iter().enumerate() {
+            for file in std::iter::once(&group.original).chain(&group.duplicates) {
+                copies
+                    .entry(file.layer_index)
+                    .or_default()
+                    .push((index, file));
+            }
+        }
+        let mut originals: Vec<Option<&FileInfo>> = vec![None; groups.len()];
+        for layer_copies in copies.values() {
+            let mut seen = HashSet::new();
+            let intact = layer_copies
+                .iter()
+                .all(|&(index, _)| seen.insert(index) && originals[index].is_none());
+            if intact {
+                for &(index, file) in layer_copies {
+                    originals[index] = Some(file);
+                }
+            }
+        }
+        originals
+            .into_iter()
+            .zip(groups)
+            .map(|(original, group)| original.unwrap_or(&group.original))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location_rank() {
+        assert_eq!(location_rank("/usr/lib/libssl.so.3"), 0);
+        assert_eq!(location_rank("./lib/x86_64-linux-gnu/libc.so.6"), 0);
+        assert_eq!(location_rank("/opt/app/libssl.so.3"), 1);
+        assert_eq!(location_rank("/usr/library/libssl.so.3"), 1);
+        assert_eq!(location_rank("/opt/app/vendor/libssl.so.3"), 2);
+        assert_eq!(location_rank("/usr/lib/node_modules/x/index.js"), 2);
+        assert_eq!(location_rank("/srv/tmp"), 1);
+    }
+}
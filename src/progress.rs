@@ -0,0 +1,31 @@
+use crate::analyzer::{DuplicateInfo, Layer};
+
+/// Receives progress events from an [`crate::Analyzer`], e.g. to drive a
+/// progress bar. Every method defaults to a no-op so implementors only pick
+/// the events they care about.
+///
+/// Layers are scanned and rewritten in parallel, so calls for different
+/// layers may arrive concurrently and interleaved.
+pub trait ProgressSink: Send + Sync {
+    fn layer_scan_started(&self, _layer: &Layer) {}
+
+    fn layer_scan_finished(&self, _layer: &Layer, _files: usize) {}
+
+    /// Called after each file is hashed with the file's size.
+    fn bytes_hashed(&self, _layer: &Layer, _bytes: u64) {}
+
+    fn duplicates_found(&self, _duplicates: &[DuplicateInfo]) {}
+
+    fn layer_rewrite_started(&self, _layer: &Layer) {}
+
+    /// Called as the tar of new layer `layer_index` is written, with the
+    /// uncompressed bytes written since the last call.
+    fn bytes_written(&self, _layer_index: usize, _bytes: u64) {}
+
+    fn layer_rewritten(&self, _old: &Layer, _new: &Layer) {}
+}
+
+/// The default sink, which ignores everything.
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {}
@@ -0,0 +1,90 @@
+//! Where a layer came from, as reported next to every duplicate: its
+//! digests and the history entry that created it. Layer indices alone
+//! stop meaning anything once layers are reordered, squashed or the
+//! report is read against another build.
+
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::DuplicateInfo;
+use crate::schemas::{DockerConfig, Manifest, layer_history};
+
+/// The identity of the layer a [`crate::analyzer::FileInfo`] is in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayerProvenance {
+    /// sha256 of the uncompressed layer, as listed in the config's diff_ids
+    pub diff_id: String,
+    /// Digest of the blob as stored, when the manifest names it by one:
+    /// OCI layouts and foreign layers. Legacy `docker save` archives don't.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob_digest: Option<String>,
+    /// `created_by` of the layer's history entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    /// `created` of the layer's history entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+}
+
+/// The digest a manifest path such as `blobs/sha256/<hex>` names its blob
+/// by.
+fn digest_of_path(path: &str) -> Option<String> {
+    let mut parts = path.rsplit('/');
+    let (hex, algorithm) = (parts.next()?, parts.next()?);
+    let is_hex = hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit());
+    (is_hex && parts.next() == Some("blobs")).then(|| format!("{}:{}", algorithm, hex))
+}
+
+/// Of layer `layer_index` of the image with `manifest` and `config`.
+pub fn layer_provenance(
+    manifest: &Manifest,
+    config: &DockerConfig,
+    layer_index: usize,
+) -> LayerProvenance {
+    let diff_id = config
+        .rootfs
+        .diff_ids
+        .get(layer_index)
+        .cloned()
+        .unwrap_or_default();
+    let blob_digest = manifest
+        .layer_sources
+        .get(&diff_id)
+        .map(|source| source.digest.clone())
+        .or_else(|| digest_of_path(manifest.layers.get(layer_index)?));
+    let history = layer_history(&config.history, layer_index);
+    let non_empty = |s: &String| !s.is_empty();
+    LayerProvenance {
+        diff_id,
+        blob_digest,
+        created_by: history.map(|h| h.created_by.clone()).filter(non_empty),
+        created: history.map(|h| h.created.clone()).filter(non_empty),
+    }
+}
+
+/// Sets the provenance of every file of `duplicates`.
+pub fn annotate(duplicates: &mut [DuplicateInfo], manifest: &Manifest, config: &DockerConfig) {
+    let layers: Vec<LayerProvenance> = (0..manifest.layers.len())
+        .map(|layer_index| layer_provenance(manifest, config, layer_index))
+        .collect();
+    for group in duplicates {
+        for file in std::iter::once(&mut group.original).chain(&mut group.duplicates) {
+            file.provenance = layers.get(file.layer_index).cloned();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_of_path() {
+        let hex = "a".repeat(64);
+        assert_eq!(
+            digest_of_path(&format!("blobs/sha256/{}", hex)),
+            Some(format!("sha256:{}", hex))
+        );
+        assert_eq!(digest_of_path(&format!("{}/layer.tar", hex)), None);
+        assert_eq!(digest_of_path("blobs/sha256/abc"), None);
+    }
+}
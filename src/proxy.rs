@@ -0,0 +1,593 @@
+//! Registry pull-through proxy, behind the `proxy` feature and run by the
+//! `proxy` subcommand.
+//!
+//! Clients pull from the proxy as if it were the upstream registry:
+//! manifests and blobs are forwarded unchanged, and every image pulled by
+//! tag is analyzed in the background. `GET /stats` lists the results.
+//!
+//! With a `dedupe_suffix` such as `-dedup`, pulling `<tag>-dedup` serves a
+//! deduplicated copy of `<tag>`. It's built on its first pull, which waits
+//! for it, and kept in the data directory keyed by the upstream digest.
+//!
+//! Pushes aren't supported. Upstream requests use the proxy's credentials
+//! (see [`crate::transport::registry`]); clients aren't authenticated.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Request, Response, ResponseBox};
+use tracing::{error, info, warn};
+
+use crate::digest_writer::sha256_digest;
+use crate::error::{DedupeError, Result};
+use crate::options::AnalyzerOptions;
+use crate::schemas::ImageManifest;
+use crate::serve::{JobState, accept, bind, json, json_header, open_data_dir, shared_options};
+use crate::transport::ImageRef;
+use crate::transport::layout::{
+    DOCKER_MANIFEST, DOCKER_MANIFEST_LIST, OCI_INDEX, OCI_MANIFEST, digest_hex,
+};
+use crate::transport::registry::{self, Client, Reference};
+
+const STATS_FILE: &str = "stats.json";
+const VARIANTS_DIR: &str = "variants";
+/// Upstream tokens usually last five minutes
+const CLIENT_TTL: Duration = Duration::from_secs(240);
+
+pub struct ProxyOptions {
+    /// `host:port` to listen on
+    pub listen: String,
+    /// Registry to forward to, e.g. `docker.io` or `localhost:5000`
+    pub upstream: String,
+    /// Where stats and deduplicated variants are kept. Defaults to a temp
+    /// dir removed on exit.
+    pub data_dir: Option<PathBuf>,
+    /// Tag suffix deduplicated variants are served under. None serves only
+    /// upstream images.
+    pub dedupe_suffix: Option<String>,
+    /// Number of images analyzed at the same time
+    pub workers: usize,
+    pub analyzer: AnalyzerOptions,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:5001".to_string(),
+            upstream: "docker.io".to_string(),
+            data_dir: None,
+            dedupe_suffix: None,
+            workers: 1,
+            analyzer: AnalyzerOptions::default(),
+        }
+    }
+}
+
+/// An entry of `GET /stats`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageStats {
+    /// See [`crate::SCHEMA_VERSION`]
+    pub schema_version: u32,
+    /// `repository:tag` as pulled
+    pub image: String,
+    /// Digest of the manifest clients were last served
+    pub digest: String,
+    pub pulls: u64,
+    #[serde(flatten)]
+    pub state: JobState,
+}
+
+struct Job {
+    image: String,
+    reference: Reference,
+}
+
+struct State {
+    upstream: String,
+    data_dir: PathBuf,
+    dedupe_suffix: Option<String>,
+    options: AnalyzerOptions,
+    clients: Mutex<HashMap<String, (Instant, Arc<Client>)>>,
+    stats: Mutex<BTreeMap<String, ImageStats>>,
+    /// Blobs of built variants, by digest
+    variant_blobs: Mutex<HashMap<String, PathBuf>>,
+    /// Held while a variant is built
+    building: Mutex<()>,
+    queue: Mutex<Sender<Job>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Route<'a> {
+    Base,
+    Stats,
+    Manifest { name: &'a str, reference: &'a str },
+    Blob { name: &'a str, digest: &'a str },
+    Unknown,
+}
+
+fn route(path: &str) -> Route<'_> {
+    if path == "/stats" {
+        return Route::Stats;
+    }
+    let Some(rest) = path.strip_prefix("/v2/") else {
+        return Route::Unknown;
+    };
+    if rest.is_empty() {
+        return Route::Base;
+    }
+    if let Some((name, reference)) = rest.rsplit_once("/manifests/") {
+        return Route::Manifest { name, reference };
+    }
+    if let Some((name, digest)) = rest.rsplit_once("/blobs/") {
+        return Route::Blob { name, digest };
+    }
+    Route::Unknown
+}
+
+impl State {
+    /// `name` with a tag or digest, on the upstream registry
+    fn reference(&self, name: &str, tag_or_digest: &str) -> Result<Reference> {
+        let separator = if tag_or_digest.contains(':') {
+            '@'
+        } else {
+            ':'
+        };
+        format!("{}/{}{}{}", self.upstream, name, separator, tag_or_digest).parse()
+    }
+
+    fn client(&self, reference: &Reference) -> Result<Arc<Client>> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some((connected, client)) = clients.get(&reference.repository)
+            && connected.elapsed() < CLIENT_TTL
+        {
+            return Ok(client.clone());
+        }
+        let client = Arc::new(Client::connect(reference, "pull")?);
+        clients.insert(
+            reference.repository.clone(),
+            (Instant::now(), client.clone()),
+        );
+        Ok(client)
+    }
+
+    /// Forwards to upstream, reconnecting once if the token expired early.
+    fn forward(
+        &self,
+        reference: &Reference,
+        head: bool,
+        path: &str,
+        accept: &str,
+    ) -> Result<ureq::http::Response<ureq::Body>> {
+        let response = self.client(reference)?.forward(head, path, accept)?;
+        if response.status() != 401 {
+            return Ok(response);
+        }
+        self.clients.lock().unwrap().remove(&reference.repository);
+        self.client(reference)?.forward(head, path, accept)
+    }
+
+    fn save_stats(&self, stats: &BTreeMap<String, ImageStats>) {
+        let path = self.data_dir.join(STATS_FILE);
+        let partial = path.with_extension("partial");
+        let result = serde_json::to_vec_pretty(&stats.values().collect::<Vec<_>>())
+            .map_err(io::Error::from)
+            .and_then(|data| fs::write(&partial, data))
+            .and_then(|_| fs::rename(&partial, &path));
+        if let Err(e) = result {
+            warn!("Failed to save {}: {}", path.display(), e);
+        }
+    }
+
+    /// Counts a pull of `reference` and queues its analysis if the tag
+    /// points somewhere new.
+    fn record_pull(&self, reference: &Reference, digest: &str) {
+        let Some(tag) = &reference.tag else {
+            return;
+        };
+        let image = format!("{}:{}", reference.repository, tag);
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats.entry(image.clone()).or_insert_with(|| ImageStats {
+            schema_version: crate::SCHEMA_VERSION,
+            image: image.clone(),
+            digest: String::new(),
+            pulls: 0,
+            state: JobState::Queued,
+        });
+        entry.pulls += 1;
+        if entry.digest != digest {
+            entry.digest = digest.to_string();
+            entry.state = JobState::Queued;
+            let reference = Reference {
+                tag: None,
+                digest: Some(digest.to_string()),
+                ..reference.clone()
+            };
+            let _ = self.queue.lock().unwrap().send(Job { image, reference });
+        }
+        self.save_stats(&stats);
+    }
+
+    /// Updates the stats of a job unless the tag has moved on since.
+    fn set_state(&self, job: &Job, state: JobState) {
+        let mut stats = self.stats.lock().unwrap();
+        if let Some(entry) = stats.get_mut(&job.image)
+            && Some(&entry.digest) == job.reference.digest.as_ref()
+        {
+            entry.state = state;
+            self.save_stats(&stats);
+        }
+    }
+
+    fn register_blobs(&self, dir: &Path) -> Result<()> {
+        let mut blobs = self.variant_blobs.lock().unwrap();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let digest = format!("sha256:{}", path.file_name().unwrap().to_string_lossy());
+            if digest_hex(&digest).is_ok() {
+                blobs.insert(digest, path);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Proxies until Ctrl-C (see [`crate::cancel`]).
+pub fn proxy(options: ProxyOptions) -> Result<()> {
+    let probe: Reference = format!("{}/probe", options.upstream).parse()?;
+    if probe.registry != options.upstream {
+        return Err(DedupeError::InvalidOption(format!(
+            "{} isn't a registry host",
+            options.upstream
+        )));
+    }
+    let (data_dir, _temp_dir) = open_data_dir(options.data_dir, &options.analyzer, "ddf-proxy")?;
+    let server = bind(&options.listen)?;
+
+    let stats_path = data_dir.join(STATS_FILE);
+    let stats: Vec<ImageStats> = if stats_path.exists() {
+        serde_json::from_slice(&fs::read(&stats_path)?)?
+    } else {
+        Vec::new()
+    };
+    let (sender, receiver) = mpsc::channel();
+    let state = Arc::new(State {
+        upstream: options.upstream,
+        dedupe_suffix: options.dedupe_suffix,
+        options: shared_options(options.analyzer),
+        clients: Mutex::new(HashMap::new()),
+        stats: Mutex::new(
+            stats
+                .into_iter()
+                .map(|entry| (entry.image.clone(), entry))
+                .collect(),
+        ),
+        variant_blobs: Mutex::new(HashMap::new()),
+        building: Mutex::new(()),
+        queue: Mutex::new(sender),
+        data_dir,
+    });
+    let variants = state.data_dir.join(VARIANTS_DIR);
+    if variants.exists() {
+        for entry in fs::read_dir(&variants)? {
+            let path = entry?.path();
+            if path.join("manifest.json").exists() {
+                state.register_blobs(&path)?;
+            }
+        }
+    }
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..options.workers.max(1) {
+        let state = state.clone();
+        let receiver = receiver.clone();
+        thread::spawn(move || worker(&state, &receiver));
+    }
+
+    info!(
+        "Proxying {} on http://{}, data in {}",
+        state.upstream,
+        options.listen,
+        state.data_dir.display()
+    );
+    accept(&server, &state, handle)
+}
+
+fn worker(state: &State, receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let Ok(job) = receiver.lock().unwrap().recv() else {
+            return;
+        };
+        state.set_state(&job, JobState::Running);
+        let result = registry::pull(&job.reference, state.options.clone())
+            .and_then(|analyzer| analyzer.find_duplicates());
+        let new_state = match result {
+            Ok(duplicates) => {
+                let bytes_saved = duplicates.iter().map(|d| d.total_savings).sum();
+                info!(
+                    "{}: {} duplicate groups, {} bytes to save",
+                    job.image,
+                    duplicates.len(),
+                    bytes_saved
+                );
+                JobState::Done {
+                    duplicate_groups: duplicates.len(),
+                    bytes_saved,
+                }
+            }
+            Err(e) => {
+                error!("{}: {}", job.image, e);
+                JobState::Failed {
+                    error: e.to_string(),
+                }
+            }
+        };
+        state.set_state(&job, new_state);
+    }
+}
+
+/// A response header. Values relayed from upstream may not be valid ones,
+/// which is upstream's fault: a 502 rather than a panic.
+fn header(name: &str, value: &str) -> Result<Header> {
+    Header::from_bytes(name, value)
+        .map_err(|_| DedupeError::Registry(format!("invalid {} header {:?}", name, value)))
+}
+
+/// Whether `digest` is `algorithm:hex`, e.g. `sha256:ab12...`, and so safe
+/// to put in an upstream URL.
+fn is_digest(digest: &str) -> bool {
+    digest.split_once(':').is_some_and(|(algorithm, hex)| {
+        !algorithm.is_empty()
+            && algorithm
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"+._-".contains(&b))
+            && !hex.is_empty()
+            && hex.bytes().all(|b| b.is_ascii_hexdigit())
+    })
+}
+
+/// A distribution API error body
+fn registry_error(status: u16, code: &str, message: impl Into<String>) -> ResponseBox {
+    json(
+        status,
+        &serde_json::json!({ "errors": [{ "code": code, "message": message.into() }] }),
+    )
+}
+
+fn error_response(e: &DedupeError) -> ResponseBox {
+    match e {
+        DedupeError::InvalidOption(_) => registry_error(400, "NAME_INVALID", e.to_string()),
+        _ => registry_error(502, "UNKNOWN", e.to_string()),
+    }
+}
+
+fn handle(state: &State, request: Request) -> io::Result<()> {
+    let url = request.url().to_string();
+    let path = url.split_once('?').map_or(url.as_str(), |(path, _)| path);
+    let head = *request.method() == Method::Head;
+    let accept = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Accept"))
+        .map(|h| h.value.to_string())
+        .unwrap_or_else(|| {
+            [
+                OCI_INDEX,
+                OCI_MANIFEST,
+                DOCKER_MANIFEST_LIST,
+                DOCKER_MANIFEST,
+            ]
+            .join(", ")
+        });
+    let result = match route(path) {
+        _ if !head && *request.method() != Method::Get => {
+            Ok(registry_error(405, "UNSUPPORTED", "the proxy is read-only"))
+        }
+        Route::Base => header("Docker-Distribution-API-Version", "registry/2.0").map(|version| {
+            Response::from_string("{}")
+                .with_header(json_header())
+                .with_header(version)
+                .boxed()
+        }),
+        Route::Stats => Ok(json(
+            200,
+            &state.stats.lock().unwrap().values().collect::<Vec<_>>(),
+        )),
+        Route::Manifest { name, reference } => manifest(state, name, reference, head, &accept),
+        Route::Blob { name, digest } => blob(state, name, digest, head),
+        Route::Unknown => Ok(registry_error(404, "UNSUPPORTED", "not found")),
+    };
+    request.respond(result.unwrap_or_else(|e| error_response(&e)))
+}
+
+fn content_length(response: &ureq::http::Response<ureq::Body>) -> Option<usize> {
+    response
+        .headers()
+        .get("content-length")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Builds a response from an upstream one, keeping the headers clients
+/// rely on.
+fn relay(
+    upstream: &ureq::http::Response<ureq::Body>,
+    body: impl Read + Send + 'static,
+) -> Result<ResponseBox> {
+    let headers = ["content-type", "docker-content-digest"]
+        .iter()
+        .filter_map(|name| {
+            let value = upstream.headers().get(*name)?.to_str().ok()?;
+            Some(header(name, value))
+        })
+        .collect::<Result<_>>()?;
+    Ok(Response::new(
+        upstream.status().as_u16().into(),
+        headers,
+        body,
+        content_length(upstream),
+        None,
+    )
+    .boxed())
+}
+
+fn manifest(
+    state: &State,
+    name: &str,
+    tag_or_digest: &str,
+    head: bool,
+    accept: &str,
+) -> Result<ResponseBox> {
+    let reference = state.reference(name, tag_or_digest)?;
+    if let (Some(suffix), Some(tag)) = (&state.dedupe_suffix, &reference.tag)
+        && let Some(base) = tag.strip_suffix(suffix.as_str()).filter(|b| !b.is_empty())
+    {
+        let base = Reference {
+            tag: Some(base.to_string()),
+            ..reference.clone()
+        };
+        return variant_manifest(state, &base);
+    }
+
+    let path = format!("manifests/{}", tag_or_digest);
+    let mut upstream = state.forward(&reference, head, &path, accept)?;
+    if head {
+        let digest = upstream
+            .headers()
+            .get("docker-content-digest")
+            .and_then(|value| value.to_str().ok());
+        if upstream.status() == 200
+            && let Some(digest) = digest
+        {
+            state.record_pull(&reference, digest);
+        }
+        return relay(&upstream, io::empty());
+    }
+    let document = upstream
+        .body_mut()
+        .read_to_vec()
+        .map_err(|e| DedupeError::Registry(format!("{}: {}", reference, e)))?;
+    if upstream.status() == 200 {
+        state.record_pull(&reference, &sha256_digest(&document));
+    }
+    relay(&upstream, io::Cursor::new(document))
+}
+
+/// Serves the deduplicated variant of `base`, building it first if needed.
+fn variant_manifest(state: &State, base: &Reference) -> Result<ResponseBox> {
+    let (digest, _) = state.client(base)?.resolve(base.manifest_reference())?;
+    let dir = state.data_dir.join(VARIANTS_DIR).join(digest_hex(&digest)?);
+    let manifest_path = dir.join("manifest.json");
+    if !manifest_path.exists() {
+        let _building = state.building.lock().unwrap();
+        if !manifest_path.exists() {
+            build_variant(state, base, &digest, &dir)?;
+        }
+    }
+    let document = fs::read(&manifest_path)?;
+    let media_type = std::str::from_utf8(&document)
+        .ok()
+        .and_then(|document| document.parse::<ImageManifest>().ok())
+        .and_then(|manifest| manifest.media_type)
+        .unwrap_or_else(|| DOCKER_MANIFEST.to_string());
+    Ok(Response::from_data(document.clone())
+        .with_header(header("Content-Type", &media_type)?)
+        .with_header(header("Docker-Content-Digest", &sha256_digest(&document))?)
+        .boxed())
+}
+
+fn build_variant(state: &State, base: &Reference, digest: &str, dir: &Path) -> Result<()> {
+    info!("Building the deduplicated variant of {}", base);
+    let pinned = Reference {
+        digest: Some(digest.to_string()),
+        ..base.clone()
+    };
+    let analyzer = registry::pull(&pinned, state.options.clone())?;
+    let duplicates = analyzer.find_duplicates()?;
+    let partial = dir.with_extension("partial");
+    let _ = fs::remove_dir_all(&partial);
+    let summary = ImageRef::Dir(partial.clone()).write(&analyzer, duplicates)?;
+    fs::rename(&partial, dir)?;
+    info!("{}: variant saves {} bytes", base, summary.bytes_saved);
+    state.register_blobs(dir)
+}
+
+fn blob(state: &State, name: &str, digest: &str, head: bool) -> Result<ResponseBox> {
+    if !is_digest(digest) {
+        return Ok(registry_error(
+            400,
+            "DIGEST_INVALID",
+            format!("{} isn't an algorithm:hex digest", digest),
+        ));
+    }
+    let variant_blob = state.variant_blobs.lock().unwrap().get(digest).cloned();
+    if let Some(path) = variant_blob {
+        return Ok(Response::from_file(File::open(&path)?)
+            .with_header(header("Content-Type", "application/octet-stream")?)
+            .with_header(header("Docker-Content-Digest", digest)?)
+            .boxed());
+    }
+    let reference = state.reference(name, digest)?;
+    let path = format!("blobs/{}", digest);
+    let mut upstream = state.forward(&reference, head, &path, "*/*")?;
+    if head {
+        return relay(&upstream, io::empty());
+    }
+    let body = mem::replace(upstream.body_mut(), ureq::Body::builder().data(""));
+    relay(&upstream, body.into_reader())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes() {
+        assert_eq!(route("/v2/"), Route::Base);
+        assert_eq!(route("/stats"), Route::Stats);
+        assert_eq!(
+            route("/v2/library/alpine/manifests/3.20"),
+            Route::Manifest {
+                name: "library/alpine",
+                reference: "3.20"
+            }
+        );
+        assert_eq!(
+            route("/v2/team/blobs/app/blobs/sha256:ab"),
+            Route::Blob {
+                name: "team/blobs/app",
+                digest: "sha256:ab"
+            }
+        );
+        assert_eq!(route("/v2/_catalog"), Route::Unknown);
+        assert_eq!(route("/images"), Route::Unknown);
+    }
+
+    #[test]
+    fn test_is_digest() {
+        assert!(is_digest(&format!("sha256:{}", "ab".repeat(32))));
+        assert!(is_digest("sha512+b64u.v2:0F"));
+        assert!(!is_digest("sha256:"));
+        assert!(!is_digest(":abcd"));
+        assert!(!is_digest("sha256:ab/../../tags/list"));
+        assert!(!is_digest("sha256:ab?ns=evil"));
+        assert!(!is_digest("latest"));
+    }
+
+    #[test]
+    fn test_invalid_upstream_header() {
+        assert!(header("Docker-Content-Digest", "sha256:ab").is_ok());
+        assert!(matches!(
+            header("Content-Type", "text/plain; charset=café"),
+            Err(DedupeError::Registry(_))
+        ));
+    }
+}
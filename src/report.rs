@@ -0,0 +1,475 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::{DuplicateInfo, FileInfo};
+use crate::case_variants::CaseVariants;
+use crate::elf::ElfNearDuplicate;
+use crate::error::{IoResultExt, Result};
+use crate::junk::JunkFile;
+use crate::libraries::SharedLibrary;
+use crate::ownership::SavingsSplit;
+use crate::packages::DuplicatePackage;
+use crate::pipeline::DedupeSummary;
+use crate::timings::{PhaseTiming, Timings};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum ReportFormat {
+    Json,
+    /// Graphviz graph of the layers, with an edge from the layer of each
+    /// original to every layer holding copies of it, weighted by bytes
+    Dot,
+    /// GitHub Actions: `::warning` annotations for the largest groups, and a
+    /// Markdown summary appended to `$GITHUB_STEP_SUMMARY`
+    Github,
+    /// Markdown for pull request comments and wikis: totals, the duplicates
+    /// per layer and the largest groups with the steps that copied them
+    Md,
+}
+
+/// Duplicate groups the Markdown summary lists
+const SUMMARY_GROUPS: usize = 20;
+/// Duplicate groups annotated on GitHub
+const ANNOTATED_GROUPS: usize = 10;
+/// Characters of a history entry's `created_by` the Markdown tables show
+const CREATED_BY_LEN: usize = 60;
+
+/// Machine readable summary of a run.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Report<'a> {
+    /// See [`crate::SCHEMA_VERSION`]
+    pub schema_version: u32,
+    pub duplicate_groups: usize,
+    pub total_savings: u64,
+    pub duplicates: Cow<'a, [DuplicateInfo]>,
+    pub timings: Vec<PhaseTiming>,
+    /// Libraries present more than once, see [`crate::libraries`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_libraries: Vec<SharedLibrary>,
+    /// Python and Node packages installed more than once, when asked for,
+    /// see [`crate::packages`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub duplicate_packages: Vec<DuplicatePackage>,
+    /// Logs, temp files and the like, when asked for, see [`crate::junk`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub junk_files: Vec<JunkFile>,
+    /// Identical files whose paths differ only in case, when asked for,
+    /// see [`crate::case_variants`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub case_variants: Vec<CaseVariants>,
+    /// Binaries identical but for debug info or build IDs, when asked
+    /// for, see [`crate::elf`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub elf_near_duplicates: Vec<ElfNearDuplicate>,
+    /// Savings in base-image layers and in the application's, when a
+    /// boundary was given, see [`crate::ownership`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ownership: Option<SavingsSplit>,
+    /// Old and new digests of the written image, absent on dry runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<DedupeSummary>,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(duplicates: &'a [DuplicateInfo], timings: &Timings) -> Self {
+        Self {
+            schema_version: crate::SCHEMA_VERSION,
+            duplicate_groups: duplicates.len(),
+            total_savings: duplicates.iter().map(|d| d.total_savings).sum(),
+            duplicates: Cow::Borrowed(duplicates),
+            timings: timings.summary(),
+            shared_libraries: Vec::new(),
+            duplicate_packages: Vec::new(),
+            junk_files: Vec::new(),
+            case_variants: Vec::new(),
+            elf_near_duplicates: Vec::new(),
+            ownership: None,
+            rewrite: None,
+        }
+    }
+
+    pub fn with_shared_libraries(mut self, libraries: Vec<SharedLibrary>) -> Self {
+        self.shared_libraries = libraries;
+        self
+    }
+
+    pub fn with_duplicate_packages(mut self, packages: Vec<DuplicatePackage>) -> Self {
+        self.duplicate_packages = packages;
+        self
+    }
+
+    pub fn with_junk_files(mut self, junk: Vec<JunkFile>) -> Self {
+        self.junk_files = junk;
+        self
+    }
+
+    pub fn with_case_variants(mut self, variants: Vec<CaseVariants>) -> Self {
+        self.case_variants = variants;
+        self
+    }
+
+    pub fn with_elf_near_duplicates(mut self, groups: Vec<ElfNearDuplicate>) -> Self {
+        self.elf_near_duplicates = groups;
+        self
+    }
+
+    pub fn with_ownership(mut self, split: SavingsSplit) -> Self {
+        self.ownership = Some(split);
+        self
+    }
+
+    pub fn with_rewrite(mut self, summary: DedupeSummary) -> Self {
+        self.rewrite = Some(summary);
+        self
+    }
+
+    pub fn write<W: Write>(&self, format: ReportFormat, mut writer: W) -> Result<()> {
+        match format {
+            ReportFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, self)?;
+                writeln!(writer)?;
+            }
+            ReportFormat::Dot => writer.write_all(self.dot().as_bytes())?,
+            ReportFormat::Md => writer.write_all(self.markdown().as_bytes())?,
+            ReportFormat::Github => {
+                writer.write_all(self.annotations().as_bytes())?;
+                match env::var_os("GITHUB_STEP_SUMMARY") {
+                    Some(path) => {
+                        let mut summary = OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(&path)
+                            .with_context(|| {
+                                format!("Failed to open {}", Path::new(&path).display())
+                            })?;
+                        summary.write_all(self.markdown().as_bytes())?;
+                    }
+                    // Outside of Actions, say what the summary would be
+                    None => writer.write_all(self.markdown().as_bytes())?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Totals, then the duplicates per layer and the largest groups as
+    /// tables, and counts of the other findings present.
+    fn markdown(&self) -> String {
+        let mut md = String::from("## Duplicate files\n\n");
+        let _ = writeln!(
+            md,
+            "**{}** duplicate groups, **{}** to save.\n",
+            self.duplicate_groups,
+            format_size(self.total_savings, BINARY)
+        );
+        if let Some(rewrite) = &self.rewrite {
+            let _ = writeln!(
+                md,
+                "Rewrote {} of {} layers, saved {}.\n",
+                rewrite.layers.iter().filter(|l| l.rewritten).count(),
+                rewrite.layers.len(),
+                format_size(rewrite.bytes_saved, BINARY)
+            );
+        }
+        if !self.duplicates.is_empty() {
+            md.push_str(&self.layer_table());
+            md.push_str(
+                "| Savings | Copies | Original | Duplicates | Copied by |\n\
+                 |---:|---:|---|---|---|\n",
+            );
+            for group in self.duplicates.iter().take(SUMMARY_GROUPS) {
+                let duplicates: Vec<String> = group
+                    .duplicates
+                    .iter()
+                    .map(|d| format!("`{}` (layer {})", table_cell(&d.path), d.layer_index))
+                    .collect();
+                let mut steps: Vec<String> = Vec::new();
+                for duplicate in &group.duplicates {
+                    let step = created_by(duplicate);
+                    if !step.is_empty() && !steps.contains(&step) {
+                        steps.push(step);
+                    }
+                }
+                let _ = writeln!(
+                    md,
+                    "| {} | {} | `{}` (layer {}) | {} | {} |",
+                    format_size(group.total_savings, BINARY),
+                    group.duplicates.len() + 1,
+                    table_cell(&group.original.path),
+                    group.original.layer_index,
+                    duplicates.join("<br>"),
+                    steps.join("<br>")
+                );
+            }
+            if self.duplicates.len() > SUMMARY_GROUPS {
+                let _ = writeln!(
+                    md,
+                    "\n…and {} more groups.",
+                    self.duplicates.len() - SUMMARY_GROUPS
+                );
+            }
+            md.push('\n');
+        }
+        let mut finding = |count: usize, what: &str, bytes: u64| {
+            if count > 0 {
+                let _ = writeln!(md, "- {} {}, {}", count, what, format_size(bytes, BINARY));
+            }
+        };
+        finding(
+            self.shared_libraries.len(),
+            "libraries present more than once",
+            self.shared_libraries
+                .iter()
+                .map(|l| l.duplicate_bytes)
+                .sum(),
+        );
+        finding(
+            self.duplicate_packages.len(),
+            "packages installed more than once",
+            self.duplicate_packages
+                .iter()
+                .map(|p| p.duplicate_bytes)
+                .sum(),
+        );
+        finding(
+            self.junk_files.len(),
+            "logs, temp and backup files",
+            self.junk_files.iter().map(|j| j.entry.size).sum(),
+        );
+        finding(
+            self.case_variants.len(),
+            "files copied to paths differing only in case",
+            self.case_variants
+                .iter()
+                .map(|v| v.size * (v.files.len() as u64 - 1))
+                .sum(),
+        );
+        finding(
+            self.elf_near_duplicates.len(),
+            "binaries identical but for debug info, saving when stripped",
+            self.elf_near_duplicates
+                .iter()
+                .map(|g| g.strip_savings + g.dedupe_savings)
+                .sum(),
+        );
+        md
+    }
+
+    /// A row per layer with duplicates in it: the originals it holds, the
+    /// copies of files elsewhere and the bytes those take, and the history
+    /// entry that created it.
+    fn layer_table(&self) -> String {
+        // (originals, copies, duplicated bytes, created_by) by layer
+        let mut layers: BTreeMap<usize, (usize, usize, u64, String)> = BTreeMap::new();
+        for group in self.duplicates.iter() {
+            let original = layers.entry(group.original.layer_index).or_default();
+            original.0 += 1;
+            original.3 = created_by(&group.original);
+            for duplicate in &group.duplicates {
+                let layer = layers.entry(duplicate.layer_index).or_default();
+                layer.1 += 1;
+                layer.2 += duplicate.size;
+                layer.3 = created_by(duplicate);
+            }
+        }
+        let mut md = String::from(
+            "| Layer | Originals | Copies | Duplicated | Created by |\n\
+             |---:|---:|---:|---:|---|\n",
+        );
+        for (layer_index, (originals, copies, bytes, step)) in layers {
+            let _ = writeln!(
+                md,
+                "| {} | {} | {} | {} | {} |",
+                layer_index,
+                originals,
+                copies,
+                format_size(bytes, BINARY),
+                step
+            );
+        }
+        md.push('\n');
+        md
+    }
+
+    /// A `::warning` workflow command per group among the largest.
+    fn annotations(&self) -> String {
+        let mut text = String::new();
+        for group in self.duplicates.iter().take(ANNOTATED_GROUPS) {
+            let copies: Vec<String> = group
+                .duplicates
+                .iter()
+                .map(|d| format!("{} (layer {})", d.path, d.layer_index))
+                .collect();
+            let message = format!(
+                "{} (layer {}) is duplicated as {}: {} to save",
+                group.original.path,
+                group.original.layer_index,
+                copies.join(", "),
+                format_size(group.total_savings, BINARY)
+            );
+            let _ = writeln!(
+                text,
+                "::warning title=Duplicate files::{}",
+                escape_command(&message)
+            );
+        }
+        text
+    }
+
+    /// `digraph` of the layers the duplicates are in. Each node carries the
+    /// bytes its layer duplicates, each edge the groups and bytes copied from
+    /// one layer into another; a loop is a layer duplicating its own files.
+    fn dot(&self) -> String {
+        let mut edges: BTreeMap<(usize, usize), (usize, u64)> = BTreeMap::new();
+        let mut layers: BTreeMap<usize, u64> = BTreeMap::new();
+        for group in self.duplicates.iter() {
+            layers.entry(group.original.layer_index).or_default();
+            let mut targets: BTreeMap<usize, u64> = BTreeMap::new();
+            for duplicate in &group.duplicates {
+                *targets.entry(duplicate.layer_index).or_default() += duplicate.size;
+            }
+            for (layer_index, bytes) in targets {
+                *layers.entry(layer_index).or_default() += bytes;
+                let (groups, total) = edges
+                    .entry((group.original.layer_index, layer_index))
+                    .or_default();
+                *groups += 1;
+                *total += bytes;
+            }
+        }
+        let heaviest = edges.values().map(|(_, bytes)| *bytes).max().unwrap_or(1);
+        let mut dot = String::from(
+            "digraph duplicates {\n  rankdir=LR;\n  node [shape=box, fontname=monospace];\n",
+        );
+        for (layer_index, bytes) in &layers {
+            let _ = writeln!(
+                dot,
+                "  layer{} [label=\"layer {}\\n{} duplicated\"];",
+                layer_index,
+                layer_index,
+                format_size(*bytes, BINARY)
+            );
+        }
+        for ((from, to), (groups, bytes)) in &edges {
+            let _ = writeln!(
+                dot,
+                "  layer{} -> layer{} [label=\"{}, {} group{}\", weight={}, penwidth={:.1}];",
+                from,
+                to,
+                format_size(*bytes, BINARY),
+                groups,
+                if *groups == 1 { "" } else { "s" },
+                bytes,
+                1.0 + 7.0 * *bytes as f64 / heaviest as f64
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// The `created_by` of the layer of `file`, shortened to
+/// [`CREATED_BY_LEN`] characters, as a table cell's code span. Empty
+/// without provenance.
+fn created_by(file: &FileInfo) -> String {
+    let Some(step) = file
+        .provenance
+        .as_ref()
+        .and_then(|p| p.created_by.as_deref())
+    else {
+        return String::new();
+    };
+    let step = step.trim();
+    let short: String = step.chars().take(CREATED_BY_LEN).collect();
+    let ellipsis = if short.len() < step.len() { "…" } else { "" };
+    format!("`{}{}`", table_cell(&short), ellipsis)
+}
+
+/// `text` safe inside a Markdown table cell and code span.
+fn table_cell(text: &str) -> String {
+    text.replace('|', "\\|").replace('`', "'")
+}
+
+/// `text` escaped as a workflow command's message.
+fn escape_command(text: &str) -> String {
+    text.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provenance::LayerProvenance;
+
+    #[test]
+    fn test_report_round_trip() {
+        let file = |path: &str, layer_index| FileInfo {
+            path: path.to_string(),
+            size: 42,
+            hash: "abcd".to_string(),
+            layer_index,
+            provenance: None,
+        };
+        let duplicates = vec![DuplicateInfo {
+            original: file("usr/lib/a.so", 0),
+            duplicates: vec![FileInfo {
+                provenance: Some(LayerProvenance {
+                    diff_id: "sha256:bb".to_string(),
+                    blob_digest: None,
+                    created_by: Some("COPY vendor /opt".to_string()),
+                    created: None,
+                }),
+                ..file("opt/a.so", 1)
+            }],
+            total_savings: 42,
+        }];
+        let mut json = Vec::new();
+        Report::new(&duplicates, &Timings::default())
+            .write(ReportFormat::Json, &mut json)
+            .unwrap();
+
+        let report: Report = serde_json::from_slice(&json).unwrap();
+        assert_eq!(report.schema_version, crate::SCHEMA_VERSION);
+        assert_eq!(report.total_savings, 42);
+        assert_eq!(report.duplicates[0].duplicates[0].path, "opt/a.so");
+
+        let mut dot = Vec::new();
+        Report::new(&duplicates, &Timings::default())
+            .write(ReportFormat::Dot, &mut dot)
+            .unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph duplicates {"));
+        assert!(dot.contains("layer0 -> layer1 [label=\"42 B, 1 group\", weight=42"));
+        assert!(dot.contains("layer1 [label=\"layer 1\\n42 B duplicated\"]"));
+
+        let report = Report::new(&duplicates, &Timings::default());
+        assert_eq!(
+            report.annotations(),
+            "::warning title=Duplicate files::usr/lib/a.so (layer 0) is duplicated as \
+             opt/a.so (layer 1): 42 B to save\n"
+        );
+        let markdown = report.markdown();
+        assert!(markdown.contains("**1** duplicate groups, **42 B** to save."));
+        assert!(
+            markdown.contains("| 42 B | 2 | `usr/lib/a.so` (layer 0) | `opt/a.so` (layer 1) |")
+        );
+        assert!(markdown.contains("(layer 1) | `COPY vendor /opt` |"));
+        assert!(markdown.contains("| 1 | 0 | 1 | 42 B | `COPY vendor /opt` |"));
+        assert!(markdown.contains("| 0 | 1 | 0 | 0 B |  |"));
+        let mut md = Vec::new();
+        report.write(ReportFormat::Md, &mut md).unwrap();
+        assert_eq!(String::from_utf8(md).unwrap(), markdown);
+        assert_eq!(escape_command("50%\nmore"), "50%25%0Amore");
+    }
+}
@@ -0,0 +1,230 @@
+//! The filesystem-free part of the analysis: hashing the files of a layer
+//! tar and grouping identical ones. Both [`crate::Analyzer`] and
+//! [`crate::stream`] are built on it.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+
+use flate2::read::GzDecoder;
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+use tar::Archive;
+use tracing::info;
+
+use crate::analyzer::{DuplicateInfo, FileInfo, LayerCompression};
+use crate::cancel;
+use crate::error::{DedupeError, Result};
+use crate::options::AnalyzerOptions;
+use crate::paths::{self, PathMatcher};
+use crate::timings::CountingReader;
+
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Groups of files up to this size are summarized by directory rather
+/// than listed one by one.
+pub const SMALL_FILE_SIZE: u64 = 64 * 1024;
+
+/// Files up to this size are read whole into a buffer the layer reuses and
+/// hashed in one call, saving the hasher's own buffer per file.
+const WHOLE_READ_SIZE: u64 = BUFFER_SIZE as u64;
+
+/// Wraps a layer blob stream in a gzip decoder when it starts with the gzip
+/// magic, looking only at the buffered head of the stream. Other
+/// compressions are errors.
+pub fn decompress<'a, R: Read + 'a>(reader: R) -> Result<Box<dyn Read + 'a>> {
+    let mut reader = BufReader::with_capacity(BUFFER_SIZE, reader);
+    match LayerCompression::detect(reader.fill_buf()?) {
+        LayerCompression::Gzip => Ok(Box::new(GzDecoder::new(reader))),
+        LayerCompression::None => Ok(Box::new(reader)),
+        compression => Err(DedupeError::UnsupportedCompression(format!(
+            "{:?} layers can't be read",
+            compression
+        ))),
+    }
+}
+
+/// Hashes every regular file in the uncompressed layer tar `reader` that
+/// passes `min_size` and `excludes`, calling `on_hashed` with each size.
+/// Empty files, which nothing is saved on, are never hashed. Returns the
+/// files and the number of tar bytes read.
+pub fn scan_tar<R: Read>(
+    reader: R,
+    layer_index: usize,
+    options: &AnalyzerOptions,
+    excludes: &PathMatcher,
+    mut on_hashed: impl FnMut(u64),
+) -> Result<(Vec<FileInfo>, u64)> {
+    // Grouping by size first then only hashing the files with same size was slower
+    //  due to having to re-decompress the layers for a second pass
+    let mut archive = Archive::new(CountingReader::new(reader));
+    let mut files = Vec::new();
+    let mut data = Vec::new();
+    for entry in archive.entries()? {
+        cancel::check()?;
+        let mut entry = entry?;
+
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let size = entry.size();
+
+        if size == 0 || size < options.min_size {
+            continue;
+        }
+
+        // Borrowed from the header unless the name isn't UTF-8, and only
+        // copied for the files kept
+        let path_bytes = entry.path_bytes();
+        let path = String::from_utf8_lossy(&path_bytes);
+
+        if path.contains("/.wh.") || path.ends_with(".wh..wh..opq") {
+            // ignore removed files for now
+            continue;
+        }
+
+        if excludes.is_match(&path) {
+            continue;
+        }
+
+        let path = path.into_owned();
+        drop(path_bytes);
+        let digest = if size <= WHOLE_READ_SIZE {
+            data.clear();
+            entry
+                .read_to_end(&mut data)
+                .map(|_| options.hasher.hash_bytes(&data))
+        } else {
+            options.hasher.hash_reader(&mut entry)
+        };
+        let hash = digest
+            .map(|digest| digest.to_hex())
+            .map_err(|source| DedupeError::Hashing {
+                path: path.clone(),
+                source,
+            })?;
+        on_hashed(size);
+        files.push(FileInfo {
+            path,
+            size,
+            hash,
+            layer_index,
+            provenance: None,
+        });
+    }
+
+    Ok((files, archive.into_inner().count()))
+}
+
+/// Groups files with the same hash. The copy in the lowest layer becomes
+/// the original; groups are sorted by savings, largest first. Sorts rather
+/// than hashes the files, so millions of small ones take no map of their
+/// own.
+pub fn group_duplicates(mut files: Vec<FileInfo>) -> Vec<DuplicateInfo> {
+    files.sort_by(|a, b| a.hash.cmp(&b.hash));
+    let runs: Vec<usize> = files
+        .chunk_by(|a, b| a.hash == b.hash)
+        .map(<[FileInfo]>::len)
+        .collect();
+    let mut files = files.into_iter();
+    let mut duplicates = Vec::new();
+    for len in runs {
+        if len == 1 {
+            files.next();
+            continue;
+        }
+        let mut copies: Vec<FileInfo> = files.by_ref().take(len).collect();
+        copies.sort_by_key(|f| f.layer_index);
+        let target = copies.remove(0);
+        let savings = target.size * copies.len() as u64;
+        duplicates.push(DuplicateInfo {
+            original: target,
+            duplicates: copies,
+            total_savings: savings,
+        });
+    }
+    duplicates.sort_by_key(|d| Reverse(d.total_savings));
+    duplicates
+}
+
+/// What deduplicating the small files under one directory saves, see
+/// [`savings_by_directory`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectorySavings {
+    /// Normalized, `""` for the root
+    pub dir: String,
+    /// Duplicates directly in it
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Savings of the groups of files up to `max_size` bytes, summed by the
+/// directory of each duplicate, largest first.
+pub fn savings_by_directory(duplicates: &[DuplicateInfo], max_size: u64) -> Vec<DirectorySavings> {
+    let mut dirs: HashMap<&str, (usize, u64)> = HashMap::new();
+    for group in duplicates.iter().filter(|g| g.original.size <= max_size) {
+        for file in &group.duplicates {
+            let path = paths::normalize(&file.path);
+            let dir = path.rsplit_once('/').map_or("", |(dir, _)| dir);
+            let totals = dirs.entry(dir).or_default();
+            totals.0 += 1;
+            totals.1 += file.size;
+        }
+    }
+    let mut dirs: Vec<DirectorySavings> = dirs
+        .into_iter()
+        .map(|(dir, (files, bytes))| DirectorySavings {
+            dir: dir.to_string(),
+            files,
+            bytes,
+        })
+        .collect();
+    dirs.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.dir.cmp(&b.dir)));
+    dirs
+}
+
+/// Logs the total and every duplicate group.
+pub fn print_possible_savings(duplicates: &[DuplicateInfo]) {
+    info!("=============================");
+    info!("Total duplicate files: {}", duplicates.len());
+    info!(
+        "Total duplicate size: {}",
+        format_size(
+            duplicates.iter().map(|f| f.total_savings).sum::<u64>(),
+            BINARY
+        )
+    );
+    info!("=============================");
+    info!("Duplicate files:");
+    for dup_info in duplicates
+        .iter()
+        .filter(|d| d.original.size > SMALL_FILE_SIZE)
+    {
+        info!(
+            "\tOriginal: {}, layer: {} size: {}",
+            dup_info.original.path,
+            dup_info.original.layer_index,
+            format_size(dup_info.original.size, BINARY)
+        );
+        for dup in dup_info.duplicates.iter() {
+            info!("\tDuplicate: {}, layer: {}", dup.path, dup.layer_index);
+        }
+    }
+    let small = savings_by_directory(duplicates, SMALL_FILE_SIZE);
+    if !small.is_empty() {
+        info!(
+            "Duplicate files up to {}, by directory:",
+            format_size(SMALL_FILE_SIZE, BINARY)
+        );
+        for dir in &small {
+            info!(
+                "\t/{}: {} files, {}",
+                dir.dir,
+                dir.files,
+                format_size(dir.bytes, BINARY)
+            );
+        }
+    }
+    info!("=============================");
+}
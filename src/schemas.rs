@@ -1,54 +1,87 @@
-use std::collections::HashMap;
-use std::fs;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
 use std::path::Path;
+use std::str::FromStr;
 
-use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
 
+use crate::error::{DedupeError, IoResultExt, Result};
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Manifest {
     pub config: String,
     pub repo_tags: Vec<String>,
     pub layers: Vec<String>,
+    /// Distribution descriptors of layers that can't be described by their
+    /// file alone, by diff_id, as `docker save` keeps foreign layers. Used
+    /// for encrypted and non-tar layers, which are passed through as they
+    /// came.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub layer_sources: BTreeMap<String, Descriptor>,
 }
 
 pub type ManifestFile = Vec<Manifest>;
 
+impl FromStr for Manifest {
+    type Err = DedupeError;
+
+    fn from_str(contents: &str) -> Result<Self> {
+        Self::from_reader(contents.as_bytes())
+    }
+}
+
 impl Manifest {
-    pub fn from_str(contents: &str) -> Result<Self> {
-        let manifests: ManifestFile = serde_json::from_str(contents)?;
-        let manifest = manifests
+    /// The first image entry of the manifest.json `reader` yields, parsed
+    /// as it's read, e.g. straight out of an image tar.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let manifests: ManifestFile = serde_json::from_reader(BufReader::new(reader))
+            .map_err(|e| DedupeError::Manifest(e.to_string()))?;
+        manifests
             .into_iter()
             .next()
-            .ok_or(anyhow!("No manifest.json found"))?;
-        Ok(manifest)
+            .ok_or(DedupeError::Manifest("no image entries".to_string()))
     }
 
     pub fn from_file(path: &Path) -> Result<Self> {
-        let contents = std::fs::read_to_string(path)?;
-        Self::from_str(&contents)
+        let file = File::open(path)
+            .map_err(|e| DedupeError::Manifest(format!("{}: {}", path.display(), e)))?;
+        Self::from_reader(file)
     }
 
     pub fn write_to_file(&self, path: &Path) -> Result<()> {
         let manifests = vec![self];
         let manifest_json = serde_json::to_string_pretty(&manifests)?;
-        fs::write(path, manifest_json)?;
+        fs::write(path, manifest_json)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
         Ok(())
     }
 }
 
+/// Only `architecture`, `os` and `rootfs` are required: minimal configs,
+/// such as those of `crane append` or buildah, may leave out the rest, and
+/// what was left out stays out when the config is written again.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DockerConfig {
     pub architecture: String,
+
+    #[serde(default)]
     pub config: ContainerConfig,
+
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub created: String,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub history: Vec<HistoryEntry>,
+
     pub os: String,
     pub rootfs: RootFs,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Maps are ordered so the config, and with it the image ID, serializes
+/// the same way every time.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ContainerConfig {
     #[serde(rename = "Env")]
     pub env: Option<Vec<String>>,
@@ -60,7 +93,7 @@ pub struct ContainerConfig {
     pub working_dir: Option<String>,
 
     #[serde(rename = "Labels")]
-    pub labels: Option<HashMap<String, String>>,
+    pub labels: Option<BTreeMap<String, String>>,
 
     #[serde(rename = "ArgsEscaped")]
     pub args_escaped: Option<bool>,
@@ -72,15 +105,18 @@ pub struct ContainerConfig {
     pub user: Option<String>,
 
     #[serde(rename = "ExposedPorts")]
-    pub exposed_ports: Option<HashMap<String, serde_json::Value>>,
+    pub exposed_ports: Option<BTreeMap<String, serde_json::Value>>,
 
     #[serde(rename = "Volumes")]
-    pub volumes: Option<HashMap<String, serde_json::Value>>,
+    pub volumes: Option<BTreeMap<String, serde_json::Value>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HistoryEntry {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub created: String,
+
+    #[serde(default)]
     pub created_by: String,
 
     #[serde(default)]
@@ -93,6 +129,12 @@ pub struct HistoryEntry {
     pub author: Option<String>,
 }
 
+/// The history entry that created layer `layer_index`. Entries marked
+/// `empty_layer` don't have a layer and are skipped.
+pub fn layer_history(history: &[HistoryEntry], layer_index: usize) -> Option<&HistoryEntry> {
+    history.iter().filter(|h| !h.empty_layer).nth(layer_index)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RootFs {
     #[serde(rename = "type")]
@@ -102,20 +144,176 @@ pub struct RootFs {
     pub diff_ids: Vec<String>,
 }
 
+impl FromStr for DockerConfig {
+    type Err = DedupeError;
+
+    fn from_str(contents: &str) -> Result<Self> {
+        serde_json::from_str(contents).map_err(|e| DedupeError::Config(e.to_string()))
+    }
+}
+
 impl DockerConfig {
-    pub fn from_str(contents: &str) -> Result<Self> {
-        let config: DockerConfig = serde_json::from_str(contents)?;
-        Ok(config)
+    /// The config `reader` yields, parsed as it's read.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        serde_json::from_reader(BufReader::new(reader))
+            .map_err(|e| DedupeError::Config(e.to_string()))
     }
 
     pub fn from_file(path: &Path) -> Result<Self> {
-        let contents = std::fs::read_to_string(path)?;
-        Self::from_str(&contents)
+        let file = File::open(path)
+            .map_err(|e| DedupeError::Config(format!("{}: {}", path.display(), e)))?;
+        Self::from_reader(file)
     }
 
     pub fn to_json(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string_pretty(self)
     }
+
+    /// Makes the history list one entry not marked `empty_layer` per
+    /// diff_id, as runtimes check, returning how many entries it changed:
+    /// surplus ones are marked empty from the end, missing ones appended.
+    /// An empty history is valid as is.
+    pub fn reconcile_history(&mut self) -> usize {
+        if self.history.is_empty() {
+            return 0;
+        }
+        let layers = self.rootfs.diff_ids.len();
+        let with_layer = self.history.iter().filter(|h| !h.empty_layer).count();
+        if with_layer > layers {
+            let surplus = with_layer - layers;
+            self.history
+                .iter_mut()
+                .rev()
+                .filter(|h| !h.empty_layer)
+                .take(surplus)
+                .for_each(|h| h.empty_layer = true);
+            return surplus;
+        }
+        let missing = layers - with_layer;
+        self.history
+            .extend((with_layer..layers).map(|layer_index| HistoryEntry {
+                created: self.created.clone(),
+                created_by: format!("layer {}", layer_index),
+                comment: "history entry missing from the original config".to_string(),
+                empty_layer: false,
+                author: None,
+            }));
+        missing
+    }
+}
+
+/// `unix_secs` as the RFC 3339 UTC timestamp configs carry, e.g.
+/// `2023-11-14T22:13:20Z`.
+pub fn format_timestamp(unix_secs: u64) -> String {
+    // Days to civil date, from Howard Hinnant's date algorithms
+    let days = (unix_secs / 86_400) as i64 + 719_468;
+    let secs = unix_secs % 86_400;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// A blob reference in an OCI or Docker distribution manifest.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+
+    /// What an artifact manifest holds, e.g. a signature or an SBOM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl Descriptor {
+    /// Whether the blob is encrypted with ocicrypt (a `+encrypted` media
+    /// type) and so can't be read without its keys.
+    pub fn is_encrypted(&self) -> bool {
+        self.media_type.ends_with("+encrypted")
+    }
+
+    /// Whether the blob is a (possibly compressed) tar layer, rather than
+    /// e.g. an artifact, a wasm module or a squashfs image.
+    pub fn is_tar_layer(&self) -> bool {
+        self.media_type.contains(".tar") && !self.is_encrypted()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+/// An OCI image manifest or Docker schema 2 manifest, which share a layout.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageManifest {
+    pub schema_version: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+/// An OCI image index (index.json, multi-platform images) or Docker
+/// manifest list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndex {
+    pub schema_version: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+
+    pub manifests: Vec<Descriptor>,
+}
+
+impl FromStr for ImageManifest {
+    type Err = DedupeError;
+
+    fn from_str(contents: &str) -> Result<Self> {
+        serde_json::from_str(contents).map_err(|e| DedupeError::Manifest(e.to_string()))
+    }
+}
+
+impl FromStr for ImageIndex {
+    type Err = DedupeError;
+
+    fn from_str(contents: &str) -> Result<Self> {
+        serde_json::from_str(contents).map_err(|e| DedupeError::Manifest(e.to_string()))
+    }
 }
 
 #[cfg(test)]
@@ -158,4 +356,74 @@ mod tests {
         assert_eq!(config.os, "linux");
         assert_eq!(config.rootfs.fs_type, "layers");
     }
+
+    #[test]
+    fn test_parse_minimal_config() {
+        let json = r#"{
+            "architecture": "amd64",
+            "os": "linux",
+            "rootfs": {"type": "layers", "diff_ids": ["sha256:a"]}
+        }"#;
+        let config: DockerConfig = json.parse().unwrap();
+        assert!(config.created.is_empty());
+        assert!(config.history.is_empty());
+        assert!(config.config.cmd.is_none());
+        let written = config.to_json().unwrap();
+        assert!(!written.contains("created"));
+        assert!(!written.contains("history"));
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_reconcile_history() {
+        let entry = |created_by: &str, empty_layer| HistoryEntry {
+            created: String::new(),
+            created_by: created_by.to_string(),
+            comment: String::new(),
+            empty_layer,
+            author: None,
+        };
+        let mut config = DockerConfig {
+            architecture: "amd64".to_string(),
+            config: ContainerConfig::default(),
+            created: String::new(),
+            history: vec![
+                entry("ADD /", false),
+                entry("ENV A=1", true),
+                entry("RUN a", false),
+            ],
+            os: "linux".to_string(),
+            rootfs: RootFs {
+                fs_type: "layers".to_string(),
+                diff_ids: vec!["sha256:a".to_string()],
+            },
+        };
+        assert_eq!(config.reconcile_history(), 1);
+        assert!(config.history[2].empty_layer);
+        assert_eq!(
+            layer_history(&config.history, 0).unwrap().created_by,
+            "ADD /"
+        );
+
+        config
+            .rootfs
+            .diff_ids
+            .extend(["sha256:b".to_string(), "sha256:c".to_string()]);
+        assert_eq!(config.reconcile_history(), 2);
+        assert_eq!(config.history.len(), 5);
+        assert_eq!(
+            layer_history(&config.history, 2).unwrap().created_by,
+            "layer 2"
+        );
+        assert_eq!(config.reconcile_history(), 0);
+
+        config.history.clear();
+        assert_eq!(config.reconcile_history(), 0);
+    }
 }
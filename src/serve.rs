@@ -0,0 +1,490 @@
+//! HTTP service mode, behind the `serve` feature and run by the `serve`
+//! subcommand:
+//!
+//! ```text
+//! POST   /images              submit an image: a `docker save` tarball as the body,
+//!                             or {"image": "docker://..."} as application/json
+//! GET    /images/<id>         job status
+//! GET    /images/<id>/report  JSON report, once done
+//! GET    /images/<id>/image   deduplicated `docker save` tarball, once done
+//! DELETE /images/<id>         drop the job and its files
+//! GET    /metrics             Prometheus gauges of the finished jobs
+//! ```
+//!
+//! `POST` takes `min_size=<bytes>` and `dry_run=true` (report only) query
+//! parameters, and uploads over [`ServeOptions::max_upload`] get 413. Jobs
+//! run one at a time per worker with the server's [`AnalyzerOptions`],
+//! minus the work dir, which jobs can't share. There is no authentication,
+//! so bind to localhost or put a proxy in front.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+use tiny_http::{Header, Method, Request, Response, ResponseBox, Server};
+use tracing::{error, info, info_span, warn};
+
+use crate::cancel;
+use crate::error::{DedupeError, IoResultExt, Result};
+use crate::metrics::{self, RunMetrics};
+use crate::options::AnalyzerOptions;
+use crate::report::{Report, ReportFormat};
+use crate::transport::ImageRef;
+
+const REPORT_FILE: &str = "report.json";
+const IMAGE_FILE: &str = "image.tar";
+const UPLOAD_FILE: &str = "upload.tar";
+/// Default [`ServeOptions::max_upload`], 16 GiB
+pub const DEFAULT_MAX_UPLOAD: u64 = 16 << 30;
+/// How often the accept loop checks for Ctrl-C
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub struct ServeOptions {
+    /// `host:port` to listen on
+    pub listen: String,
+    /// Where jobs keep uploads, reports and output images. Defaults to a
+    /// temp dir removed on exit.
+    pub data_dir: Option<PathBuf>,
+    /// Number of images analyzed at the same time
+    pub workers: usize,
+    /// Largest tarball accepted in a `POST`, in bytes; bigger ones get a
+    /// 413 rather than filling the data dir
+    pub max_upload: u64,
+    pub analyzer: AnalyzerOptions,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:8080".to_string(),
+            data_dir: None,
+            workers: 1,
+            max_upload: DEFAULT_MAX_UPLOAD,
+            analyzer: AnalyzerOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done {
+        duplicate_groups: usize,
+        bytes_saved: u64,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+/// Body of `GET /images/<id>` and of the `POST` response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    /// See [`crate::SCHEMA_VERSION`]
+    pub schema_version: u32,
+    pub id: u64,
+    pub image: String,
+    pub dry_run: bool,
+    #[serde(flatten)]
+    pub state: JobState,
+}
+
+#[derive(Deserialize)]
+struct Submission {
+    image: String,
+}
+
+struct Job {
+    id: u64,
+    image: ImageRef,
+    dir: PathBuf,
+    options: AnalyzerOptions,
+    dry_run: bool,
+}
+
+struct State {
+    data_dir: PathBuf,
+    options: AnalyzerOptions,
+    max_upload: u64,
+    next_id: AtomicU64,
+    jobs: Mutex<HashMap<u64, JobStatus>>,
+    /// Of the jobs done, by id
+    metrics: Mutex<HashMap<u64, RunMetrics>>,
+    queue: Mutex<Sender<Job>>,
+}
+
+impl State {
+    fn set_state(&self, id: u64, state: JobState) {
+        if let Some(status) = self.jobs.lock().unwrap().get_mut(&id) {
+            status.state = state;
+        }
+    }
+
+    fn status(&self, id: &str) -> Option<JobStatus> {
+        let id = id.parse().ok()?;
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+}
+
+/// The data directory, or a temp dir standing in for it that lives as long
+/// as the returned guard.
+pub(crate) fn open_data_dir(
+    data_dir: Option<PathBuf>,
+    options: &AnalyzerOptions,
+    prefix: &str,
+) -> Result<(PathBuf, Option<TempDir>)> {
+    let (dir, temp_dir) = match data_dir {
+        Some(dir) => (dir, None),
+        None => {
+            let temp_dir = TempDir::with_prefix_in(prefix, options.temp_dir())?;
+            (temp_dir.path().to_path_buf(), Some(temp_dir))
+        }
+    };
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    Ok((dir, temp_dir))
+}
+
+/// `options` with the work dir dropped: jobs run side by side and one
+/// after another, and would reset or resume each other's checkpoint.
+pub(crate) fn shared_options(mut options: AnalyzerOptions) -> AnalyzerOptions {
+    if options.work_dir.take().is_some() {
+        warn!("Ignoring the work dir, jobs would share one checkpoint");
+    }
+    options
+}
+
+pub(crate) fn bind(listen: &str) -> Result<Server> {
+    Server::http(listen)
+        .map_err(|e| DedupeError::InvalidOption(format!("Can't listen on {}: {}", listen, e)))
+}
+
+/// Hands each request to `handle` on its own thread until Ctrl-C (see
+/// [`crate::cancel`]).
+pub(crate) fn accept<S: Send + Sync + 'static>(
+    server: &Server,
+    state: &Arc<S>,
+    handle: fn(&S, Request) -> io::Result<()>,
+) -> Result<()> {
+    while !cancel::is_cancelled() {
+        let Some(request) = server.recv_timeout(POLL_INTERVAL)? else {
+            continue;
+        };
+        let state = state.clone();
+        thread::spawn(move || {
+            let line = format!("{} {}", request.method(), request.url());
+            if let Err(e) = handle(&state, request) {
+                warn!("{}: {}", line, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Serves until Ctrl-C (see [`crate::cancel`]).
+pub fn serve(options: ServeOptions) -> Result<()> {
+    let (data_dir, _temp_dir) = open_data_dir(options.data_dir, &options.analyzer, "ddf-serve")?;
+    let server = bind(&options.listen)?;
+
+    let (sender, receiver) = mpsc::channel();
+    let state = Arc::new(State {
+        data_dir,
+        options: shared_options(options.analyzer),
+        max_upload: options.max_upload,
+        next_id: AtomicU64::new(1),
+        jobs: Mutex::new(HashMap::new()),
+        metrics: Mutex::new(HashMap::new()),
+        queue: Mutex::new(sender),
+    });
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..options.workers.max(1) {
+        let state = state.clone();
+        let receiver = receiver.clone();
+        thread::spawn(move || worker(&state, &receiver));
+    }
+
+    info!(
+        "Listening on http://{}, data in {}",
+        options.listen,
+        state.data_dir.display()
+    );
+    accept(&server, &state, handle)
+}
+
+fn worker(state: &State, receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let Ok(job) = receiver.lock().unwrap().recv() else {
+            return;
+        };
+        state.set_state(job.id, JobState::Running);
+        info!("Job {}: analyzing {}", job.id, job.image);
+        // Each job is its own trace when exporting to OpenTelemetry
+        let result = info_span!("job", id = job.id).in_scope(|| run(&job));
+        let _ = fs::remove_file(job.dir.join(UPLOAD_FILE));
+        let new_state = match result {
+            Ok((done, run_metrics)) => {
+                state.metrics.lock().unwrap().insert(job.id, run_metrics);
+                done
+            }
+            Err(e) => {
+                error!("Job {}: {}", job.id, e);
+                JobState::Failed {
+                    error: e.to_string(),
+                }
+            }
+        };
+        state.set_state(job.id, new_state);
+    }
+}
+
+fn run(job: &Job) -> Result<(JobState, RunMetrics)> {
+    let analyzer = job.image.load(job.options.clone())?;
+    let files = analyzer.scan_files()?;
+    let files_scanned = files.len();
+    let duplicates = analyzer.group_duplicates(files);
+    let summary = if job.dry_run {
+        None
+    } else {
+        Some(
+            ImageRef::DockerArchive(job.dir.join(IMAGE_FILE))
+                .write(&analyzer, duplicates.clone())?,
+        )
+    };
+    let bytes_saved = match &summary {
+        Some(summary) => summary.bytes_saved,
+        None => duplicates.iter().map(|d| d.total_savings).sum(),
+    };
+    let run_metrics = RunMetrics::new(
+        job.image.to_string(),
+        &analyzer,
+        files_scanned,
+        &duplicates,
+        summary.as_ref(),
+    );
+    #[cfg(feature = "otel")]
+    crate::otel::record_run(&run_metrics);
+    let mut report = Report::new(&duplicates, analyzer.timings());
+    if let Some(summary) = summary {
+        report = report.with_rewrite(summary);
+    }
+    report.write(ReportFormat::Json, File::create(job.dir.join(REPORT_FILE))?)?;
+    let done = JobState::Done {
+        duplicate_groups: duplicates.len(),
+        bytes_saved,
+    };
+    Ok((done, run_metrics))
+}
+
+pub(crate) fn json_header() -> Header {
+    Header::from_bytes("Content-Type", "application/json").unwrap()
+}
+
+pub(crate) fn json<T: Serialize>(status: u16, body: &T) -> ResponseBox {
+    Response::from_data(serde_json::to_vec_pretty(body).unwrap_or_default())
+        .with_status_code(status)
+        .with_header(json_header())
+        .boxed()
+}
+
+fn error_response(status: u16, message: impl Into<String>) -> ResponseBox {
+    json(status, &serde_json::json!({ "error": message.into() }))
+}
+
+fn file_response(path: &Path, content_type: &str) -> ResponseBox {
+    match File::open(path) {
+        Ok(file) => Response::from_file(file)
+            .with_header(Header::from_bytes("Content-Type", content_type).unwrap())
+            .boxed(),
+        Err(_) => error_response(404, "not found"),
+    }
+}
+
+fn handle(state: &State, mut request: Request) -> io::Result<()> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let response = match (request.method(), segments.as_slice()) {
+        (Method::Post, ["images"]) => submit(state, &mut request, query),
+        (Method::Get, ["images", id]) => match state.status(id) {
+            Some(status) => json(200, &status),
+            None => error_response(404, "no such job"),
+        },
+        (Method::Get, ["images", id, file @ ("report" | "image")]) => match state.status(id) {
+            None => error_response(404, "no such job"),
+            Some(
+                status @ JobStatus {
+                    state: JobState::Done { .. },
+                    ..
+                },
+            ) => {
+                let dir = state.data_dir.join(status.id.to_string());
+                match *file {
+                    "report" => file_response(&dir.join(REPORT_FILE), "application/json"),
+                    _ if status.dry_run => error_response(404, "dry run jobs have no image"),
+                    _ => file_response(&dir.join(IMAGE_FILE), "application/x-tar"),
+                }
+            }
+            Some(status) => json(409, &status),
+        },
+        (Method::Delete, ["images", id]) => delete(state, id),
+        (Method::Get, ["metrics"]) => {
+            let mut runs: Vec<RunMetrics> =
+                state.metrics.lock().unwrap().values().cloned().collect();
+            runs.sort_by_key(|run| run.finished);
+            Response::from_string(metrics::render(&runs))
+                .with_header(
+                    Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap(),
+                )
+                .boxed()
+        }
+        _ => error_response(404, "not found"),
+    };
+    request.respond(response)
+}
+
+fn query_params(query: &str) -> HashMap<&str, &str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .collect()
+}
+
+fn submit(state: &State, request: &mut Request, query: &str) -> ResponseBox {
+    let params = query_params(query);
+    let mut options = state.options.clone();
+    if let Some(min_size) = params.get("min_size") {
+        match min_size.parse() {
+            Ok(min_size) => options.min_size = min_size,
+            Err(_) => return error_response(400, "min_size must be a number"),
+        }
+    }
+    let dry_run = params
+        .get("dry_run")
+        .is_some_and(|v| *v == "true" || *v == "1");
+
+    let is_json = request
+        .headers()
+        .iter()
+        .any(|h| h.field.equiv("Content-Type") && h.value.as_str().starts_with("application/json"));
+    let reference = if is_json {
+        match submitted_reference(request) {
+            Ok(image) => Some(image),
+            Err(e) => return error_response(400, e.to_string()),
+        }
+    } else {
+        None
+    };
+
+    let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+    let dir = state.data_dir.join(id.to_string());
+    let image = match reference {
+        Some(image) => image,
+        None => {
+            if request
+                .body_length()
+                .is_some_and(|length| length as u64 > state.max_upload)
+            {
+                return too_large(state.max_upload);
+            }
+            match receive_upload(request.as_reader(), &dir, state.max_upload) {
+                Ok(Some(image)) => image,
+                Ok(None) => {
+                    let _ = fs::remove_dir_all(&dir);
+                    return too_large(state.max_upload);
+                }
+                Err(e) => {
+                    let _ = fs::remove_dir_all(&dir);
+                    return error_response(400, e.to_string());
+                }
+            }
+        }
+    };
+    if let Err(e) = fs::create_dir_all(&dir) {
+        return error_response(500, e.to_string());
+    }
+
+    let status = JobStatus {
+        schema_version: crate::SCHEMA_VERSION,
+        id,
+        image: image.to_string(),
+        dry_run,
+        state: JobState::Queued,
+    };
+    state.jobs.lock().unwrap().insert(id, status.clone());
+    let job = Job {
+        id,
+        image,
+        dir,
+        options,
+        dry_run,
+    };
+    if state.queue.lock().unwrap().send(job).is_err() {
+        return error_response(503, "no workers running");
+    }
+    info!("Job {}: queued {}", id, status.image);
+    json(202, &status)
+}
+
+/// Only registry references are accepted from clients; local paths would
+/// let them read the server's files.
+fn submitted_reference(request: &mut Request) -> Result<ImageRef> {
+    let submission: Submission = serde_json::from_reader(request.as_reader())?;
+    match submission.image.parse::<ImageRef>()? {
+        #[cfg(feature = "network")]
+        image @ ImageRef::Docker(_) => Ok(image),
+        _ => Err(DedupeError::InvalidOption(
+            "only docker:// references can be submitted, upload other images".to_string(),
+        )),
+    }
+}
+
+fn too_large(max_upload: u64) -> ResponseBox {
+    error_response(413, format!("uploads are limited to {} bytes", max_upload))
+}
+
+/// The uploaded image, or `None` if the body is over `max_upload` bytes;
+/// a chunked body has no length to check up front.
+fn receive_upload(body: &mut dyn Read, dir: &Path, max_upload: u64) -> Result<Option<ImageRef>> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(UPLOAD_FILE);
+    let mut file = File::create(&path)?;
+    let bytes = io::copy(&mut body.take(max_upload.saturating_add(1)), &mut file)
+        .with_context(|| "Failed to receive upload".to_string())?;
+    if bytes > max_upload {
+        return Ok(None);
+    }
+    if bytes == 0 {
+        return Err(DedupeError::InvalidOption(
+            "empty body, send a docker save tarball or a JSON reference".to_string(),
+        ));
+    }
+    Ok(Some(ImageRef::DockerArchive(path)))
+}
+
+fn delete(state: &State, id: &str) -> ResponseBox {
+    let removed = match state.status(id) {
+        Some(JobStatus {
+            state: JobState::Queued | JobState::Running,
+            ..
+        }) => return error_response(409, "job is still running"),
+        Some(status) => state.jobs.lock().unwrap().remove(&status.id),
+        None => None,
+    };
+    match removed {
+        Some(status) => {
+            state.metrics.lock().unwrap().remove(&status.id);
+            let _ = fs::remove_dir_all(state.data_dir.join(status.id.to_string()));
+            Response::empty(204).boxed()
+        }
+        None => error_response(404, "no such job"),
+    }
+}
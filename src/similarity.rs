@@ -0,0 +1,285 @@
+//! Near-identical layers across a set of images: layers holding almost the
+//! same files that still got different digests, so registries and nodes
+//! store and pull each of them in full. Usually a build step that isn't
+//! reproducible (timestamps, ownership) or a file or two that change with
+//! every build.
+//!
+//! Layers are compared by a MinHash over the content hashes of the files
+//! [`crate::Analyzer::scan_files`] found in them; clusters are then checked
+//! file by file to say what differs.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use rapidhash::v3::{RapidSecrets, rapidhash_v3_seeded};
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::FileInfo;
+
+/// Hash functions per fingerprint; the similarity estimate is off by about
+/// `1 / sqrt(MINHASH_SIZE)`
+const MINHASH_SIZE: usize = 128;
+/// Differing paths a [`LayerDifference`] lists at most
+const MAX_LISTED: usize = 20;
+
+/// The scanned files of one layer of one image.
+#[derive(Debug, Clone)]
+pub struct LayerFiles {
+    /// How the image was given, e.g. `docker://alpine:3.20`
+    pub image: String,
+    pub layer_index: usize,
+    pub diff_id: String,
+    pub files: Vec<FileInfo>,
+}
+
+impl LayerFiles {
+    /// Splits the files of an image by layer, one entry per element of
+    /// `diff_ids` whether it has files or not.
+    pub fn by_layer(image: &str, diff_ids: &[String], files: Vec<FileInfo>) -> Vec<Self> {
+        let mut layers: Vec<Self> = diff_ids
+            .iter()
+            .enumerate()
+            .map(|(layer_index, diff_id)| Self {
+                image: image.to_string(),
+                layer_index,
+                diff_id: diff_id.clone(),
+                files: Vec::new(),
+            })
+            .collect();
+        for file in files {
+            if let Some(layer) = layers.get_mut(file.layer_index) {
+                layer.files.push(file);
+            }
+        }
+        layers
+    }
+}
+
+/// A layer in a [`LayerCluster`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterMember {
+    pub image: String,
+    pub layer_index: usize,
+    pub diff_id: String,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// How a member differs from the first one of its cluster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerDifference {
+    pub image: String,
+    pub layer_index: usize,
+    /// Estimated share of files both have, from 0 to 1
+    pub similarity: f64,
+    /// Paths of files added or changed relative to the first member, up to
+    /// 20
+    pub changed: Vec<String>,
+    /// Paths of the first member's files this one lacks, up to 20
+    pub missing: Vec<String>,
+    /// What would make the two byte-identical
+    pub suggestion: String,
+}
+
+/// Layers with different digests but nearly the same files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerCluster {
+    /// The largest layer first, then in the order the images were given
+    pub layers: Vec<ClusterMember>,
+    /// One per member after the first
+    pub differences: Vec<LayerDifference>,
+}
+
+/// Minimum of each of [`MINHASH_SIZE`] hash functions over a layer's file
+/// hashes.
+fn minhash(files: &[FileInfo]) -> Vec<u64> {
+    let secrets = RapidSecrets::seed(0);
+    let mut signature = vec![u64::MAX; MINHASH_SIZE];
+    for file in files {
+        let base = rapidhash_v3_seeded(file.hash.as_bytes(), &secrets);
+        for (i, min) in signature.iter_mut().enumerate() {
+            *min = (*min).min(mix(base ^ (i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)));
+        }
+    }
+    signature
+}
+
+/// splitmix64's finalizer, to derive the hash functions from one hash
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn estimate(a: &[u64], b: &[u64]) -> f64 {
+    let same = a.iter().zip(b).filter(|(a, b)| a == b).count();
+    same as f64 / MINHASH_SIZE as f64
+}
+
+fn find(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[i] = root;
+    root
+}
+
+fn difference(anchor: &LayerFiles, layer: &LayerFiles, similarity: f64) -> LayerDifference {
+    let entries = |l: &LayerFiles| -> BTreeSet<(String, String)> {
+        l.files
+            .iter()
+            .map(|f| (f.path.clone(), f.hash.clone()))
+            .collect()
+    };
+    let (theirs, ours) = (entries(anchor), entries(layer));
+    let list = |a: &BTreeSet<(String, String)>, b: &BTreeSet<(String, String)>| -> Vec<String> {
+        a.difference(b)
+            .map(|(path, _)| path.clone())
+            .take(MAX_LISTED)
+            .collect()
+    };
+    let changed = list(&ours, &theirs);
+    let missing = list(&theirs, &ours);
+    let suggestion = if changed.is_empty() && missing.is_empty() {
+        "Same files, yet different bytes: build the layer reproducibly (fixed mtimes \
+         through SOURCE_DATE_EPOCH, same ownership and entry order)"
+            .to_string()
+    } else {
+        let mut paths: Vec<&str> = changed.iter().chain(&missing).map(String::as_str).collect();
+        paths.sort_unstable();
+        paths.dedup();
+        paths.truncate(3);
+        format!(
+            "Files differ ({}): move what changes between builds to a later layer",
+            paths.join(", ")
+        )
+    };
+    LayerDifference {
+        image: layer.image.clone(),
+        layer_index: layer.layer_index,
+        similarity,
+        changed,
+        missing,
+        suggestion,
+    }
+}
+
+/// Clusters layers that share at least `threshold` of their files (0 to 1)
+/// with another layer of the cluster but have a digest of their own.
+/// Layers with the same diff_id are already stored once and count as one;
+/// layers without files are left out. Largest clusters first.
+pub fn cluster_layers(layers: &[LayerFiles], threshold: f64) -> Vec<LayerCluster> {
+    // One representative per diff_id
+    let mut seen = HashSet::new();
+    let layers: Vec<&LayerFiles> = layers
+        .iter()
+        .filter(|l| !l.files.is_empty() && seen.insert(l.diff_id.as_str()))
+        .collect();
+    let signatures: Vec<Vec<u64>> = layers.iter().map(|l| minhash(&l.files)).collect();
+
+    let mut parents: Vec<usize> = (0..layers.len()).collect();
+    for i in 0..layers.len() {
+        for j in i + 1..layers.len() {
+            if estimate(&signatures[i], &signatures[j]) >= threshold {
+                let (a, b) = (find(&mut parents, i), find(&mut parents, j));
+                parents[b] = a;
+            }
+        }
+    }
+    let mut clusters: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..layers.len() {
+        let root = find(&mut parents, i);
+        clusters.entry(root).or_default().push(i);
+    }
+
+    let bytes = |l: &LayerFiles| l.files.iter().map(|f| f.size).sum::<u64>();
+    let mut clusters: Vec<LayerCluster> = clusters
+        .into_values()
+        .filter(|members| members.len() > 1)
+        .map(|mut members| {
+            members.sort_by_key(|&i| (std::cmp::Reverse(bytes(layers[i])), i));
+            let anchor = members[0];
+            LayerCluster {
+                layers: members
+                    .iter()
+                    .map(|&i| ClusterMember {
+                        image: layers[i].image.clone(),
+                        layer_index: layers[i].layer_index,
+                        diff_id: layers[i].diff_id.clone(),
+                        files: layers[i].files.len(),
+                        bytes: bytes(layers[i]),
+                    })
+                    .collect(),
+                differences: members[1..]
+                    .iter()
+                    .map(|&i| {
+                        let similarity = estimate(&signatures[anchor], &signatures[i]);
+                        difference(layers[anchor], layers[i], similarity)
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    clusters.sort_by_key(|c| std::cmp::Reverse(c.layers.iter().map(|l| l.bytes).sum::<u64>()));
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(image: &str, diff_id: &str, hashes: &[&str]) -> LayerFiles {
+        LayerFiles {
+            image: image.to_string(),
+            layer_index: 0,
+            diff_id: diff_id.to_string(),
+            files: hashes
+                .iter()
+                .map(|hash| FileInfo {
+                    path: format!("usr/lib/{}", hash),
+                    size: 100,
+                    hash: hash.to_string(),
+                    layer_index: 0,
+                    provenance: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_near_identical_layers_cluster() {
+        let shared: Vec<String> = (0..40).map(|i| format!("h{}", i)).collect();
+        let mut a: Vec<&str> = shared.iter().map(String::as_str).collect();
+        let mut b = a.clone();
+        a.push("only-a");
+        b.push("only-b");
+        let layers = vec![
+            layer("one", "sha256:1", &a),
+            layer("two", "sha256:2", &b),
+            layer("three", "sha256:1", &a),
+            layer("four", "sha256:4", &["x", "y"]),
+        ];
+
+        let clusters = cluster_layers(&layers, 0.8);
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].layers.len(), 2);
+        let difference = &clusters[0].differences[0];
+        assert_eq!(difference.image, "two");
+        assert_eq!(difference.changed, vec!["usr/lib/only-b"]);
+        assert_eq!(difference.missing, vec!["usr/lib/only-a"]);
+    }
+
+    #[test]
+    fn test_same_files_suggest_reproducible_build() {
+        let layers = vec![
+            layer("one", "sha256:1", &["a", "b"]),
+            layer("two", "sha256:2", &["a", "b"]),
+        ];
+        let clusters = cluster_layers(&layers, 0.9);
+        assert!(
+            clusters[0].differences[0]
+                .suggestion
+                .contains("reproducibly")
+        );
+    }
+}
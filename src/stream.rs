@@ -0,0 +1,192 @@
+//! Finds duplicates in a `docker save` stream in a single pass, without
+//! extracting anything to disk. Rewriting still needs an [`crate::Analyzer`].
+//!
+//! `docker save` writes manifest.json after the layer blobs, so every blob
+//! that looks like a layer is scanned as it streams by and only matched to
+//! its manifest position at the end. The manifest is parsed as it streams
+//! by too, and so is the config when it comes after the manifest. Other
+//! small entries (the config otherwise, empty layers) are kept in memory.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+
+use tar::Archive;
+use tracing::debug;
+
+use crate::analyzer::{DuplicateInfo, FileInfo, LayerCompression};
+use crate::cancel::{self, CancellableReader};
+use crate::error::{DedupeError, Result};
+use crate::options::AnalyzerOptions;
+use crate::paths::{self, PathMatcher};
+use crate::provenance;
+use crate::scan::{self, decompress};
+use crate::schemas::{DockerConfig, Manifest};
+use crate::timings::CountingReader;
+
+/// Entries up to this size are buffered rather than scanned on the fly
+const SMALL_ENTRY: u64 = 8 * 1024 * 1024;
+/// Offset and value of the magic in a ustar/GNU tar header
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// Outcome of [`scan_stream`].
+pub struct StreamScan {
+    pub manifest: Manifest,
+    pub config: DockerConfig,
+    /// Files of all layers, with `layer_index` in manifest order
+    pub files: Vec<FileInfo>,
+    /// Bytes read from the outer stream
+    pub bytes: u64,
+}
+
+impl StreamScan {
+    pub fn find_duplicates(&self) -> Vec<DuplicateInfo> {
+        let mut duplicates = scan::group_duplicates(self.files.clone());
+        provenance::annotate(&mut duplicates, &self.manifest, &self.config);
+        duplicates
+    }
+}
+
+/// Blobs seen so far, by normalized archive path
+enum Blob {
+    Small(Vec<u8>),
+    Scanned(Result<Vec<FileInfo>>),
+    /// Too large to buffer and not a tar or gzip stream
+    Skipped,
+}
+
+fn looks_like_layer(head: &[u8]) -> bool {
+    LayerCompression::detect(head) != LayerCompression::None
+        || head.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()) == Some(TAR_MAGIC)
+}
+
+/// Scans a `docker save` stream, holding at most one layer's worth of
+/// decompression state and the small metadata entries in memory.
+pub fn scan_stream<R: Read>(image_stream: R, options: &AnalyzerOptions) -> Result<StreamScan> {
+    let excludes =
+        PathMatcher::excludes(&options.excludes, &options.allowed_paths)?.within(&options.scope)?;
+    let mut archive = Archive::new(CountingReader::new(CancellableReader::new(image_stream)));
+    let mut blobs: HashMap<String, Blob> = HashMap::new();
+    // Symlinks some saves list layers through, by normalized archive path
+    let mut links: HashMap<String, String> = HashMap::new();
+    let mut manifest: Option<Result<Manifest>> = None;
+    let mut config: Option<Result<DockerConfig>> = None;
+
+    for entry in archive.entries()? {
+        cancel::check()?;
+        let entry = entry?;
+        let path = paths::normalize(&entry.path()?.to_string_lossy()).to_string();
+        if entry.header().entry_type().is_symlink() {
+            if let Some(target) = entry.link_name()? {
+                links.insert(path, target.to_string_lossy().into_owned());
+            }
+            continue;
+        }
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        if path == "manifest.json" {
+            manifest = Some(Manifest::from_reader(entry));
+            continue;
+        }
+        if let Some(Ok(manifest)) = &manifest
+            && path == paths::normalize(&manifest.config)
+        {
+            config = Some(DockerConfig::from_reader(entry));
+            continue;
+        }
+        let size = entry.size();
+
+        let mut reader = BufReader::with_capacity(64 * 1024, entry);
+        let blob = if size <= SMALL_ENTRY || !looks_like_layer(reader.fill_buf()?) {
+            if size > SMALL_ENTRY {
+                debug!("Skipping {} ({} bytes), not a layer", path, size);
+                blobs.insert(path, Blob::Skipped);
+                continue;
+            }
+            let mut data = Vec::with_capacity(size as usize);
+            reader.read_to_end(&mut data)?;
+            Blob::Small(data)
+        } else {
+            debug!("Scanning {} while streaming", path);
+            // Errors only matter if the manifest turns out to reference it
+            let files =
+                decompress(reader).and_then(|layer| scan_layer_blob(layer, options, &excludes));
+            Blob::Scanned(files)
+        };
+        blobs.insert(path, blob);
+    }
+    let bytes = archive.into_inner().count();
+
+    let resolve = |path: &str| -> String {
+        let mut path = paths::normalize(path).to_string();
+        for _ in 0..paths::MAX_LINKS {
+            match links.get(&path).and_then(|t| paths::link_target(&path, t)) {
+                Some(target) => path = target,
+                None => break,
+            }
+        }
+        path
+    };
+
+    let manifest =
+        manifest.ok_or_else(|| DedupeError::NotAnImage("no manifest.json".to_string()))??;
+    let config = match (config, blobs.get(&resolve(&manifest.config))) {
+        (Some(config), _) => config?,
+        (None, Some(Blob::Small(data))) => DockerConfig::from_reader(data.as_slice())?,
+        (None, _) => {
+            return Err(DedupeError::Config(format!(
+                "{} missing from the image",
+                manifest.config
+            )));
+        }
+    };
+
+    let mut files = Vec::new();
+    for (layer_index, layer_path) in manifest.layers.iter().enumerate() {
+        let key = resolve(layer_path);
+        let layer_files = match blobs.get(&key) {
+            Some(Blob::Small(data)) => decompress(data.as_slice())
+                .and_then(|layer| scan_layer_blob(layer, options, &excludes)),
+            Some(Blob::Scanned(Ok(files))) => Ok(files.clone()),
+            Some(Blob::Scanned(Err(_))) => match blobs.remove(&key) {
+                Some(Blob::Scanned(Err(e))) => Err(e),
+                _ => unreachable!(),
+            },
+            Some(Blob::Skipped) => Err(DedupeError::UnsupportedCompression(format!(
+                "{} is neither tar nor gzip",
+                layer_path
+            ))),
+            None => Err(DedupeError::NotAnImage(format!(
+                "layer {} missing from the image",
+                layer_path
+            ))),
+        };
+        let layer_files = layer_files.map_err(|e| DedupeError::Layer {
+            index: layer_index,
+            path: layer_path.into(),
+            source: Box::new(e),
+        })?;
+        files.extend(layer_files.into_iter().map(|file| FileInfo {
+            layer_index,
+            ..file
+        }));
+    }
+
+    Ok(StreamScan {
+        manifest,
+        config,
+        files,
+        bytes,
+    })
+}
+
+/// Layer positions are only known once the manifest has been read, so files
+/// are scanned with a placeholder index that [`scan_stream`] fixes up.
+fn scan_layer_blob<R: Read>(
+    layer: R,
+    options: &AnalyzerOptions,
+    excludes: &PathMatcher,
+) -> Result<Vec<FileInfo>> {
+    scan::scan_tar(layer, usize::MAX, options, excludes, |_| {}).map(|(files, _)| files)
+}
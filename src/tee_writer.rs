@@ -1,31 +1,91 @@
+//! Fanning one stream of writes out to several writers, e.g. a compressor,
+//! a digest of the uncompressed bytes and progress reporting at once.
+
 use std::io::{self, Write};
 
-// TeeWriter that supports into_inner()
-pub struct TeeWriter<W1: Write, W2: Write> {
-    writer1: W1,
-    writer2: W2,
+/// Writes everything to each of its writers in turn, counting the bytes.
+/// The writers are borrowed, so their owners get them back, e.g. to
+/// finish a compressor or read a digest, once this is dropped.
+#[derive(Default)]
+pub struct MultiWriter<'a> {
+    writers: Vec<&'a mut dyn Write>,
+    written: u64,
+}
+
+impl<'a> MultiWriter<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `writer` after the others.
+    pub fn with(mut self, writer: &'a mut dyn Write) -> Self {
+        self.writers.push(writer);
+        self
+    }
+
+    /// Bytes written so far, to each writer.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
 }
 
-impl<W1: Write, W2: Write> TeeWriter<W1, W2> {
-    pub fn new(writer1: W1, writer2: W2) -> Self {
-        Self { writer1, writer2 }
+impl Write for MultiWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for writer in &mut self.writers {
+            writer.write_all(buf)?;
+        }
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for writer in &mut self.writers {
+            writer.flush()?;
+        }
+        Ok(())
     }
+}
 
-    pub fn into_inner(self) -> (W1, W2) {
-        (self.writer1, self.writer2)
+/// Discards what is written, passing the length of each write to `F`,
+/// for progress reporting alongside a [`MultiWriter`]'s other writers.
+pub struct ReportingSink<F: FnMut(u64)>(F);
+
+impl<F: FnMut(u64)> ReportingSink<F> {
+    pub fn new(report: F) -> Self {
+        Self(report)
     }
 }
 
-impl<W1: Write, W2: Write> Write for TeeWriter<W1, W2> {
+impl<F: FnMut(u64)> Write for ReportingSink<F> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.writer1.write_all(buf)?;
-        self.writer2.write_all(buf)?;
+        (self.0)(buf.len() as u64);
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.writer1.flush()?;
-        self.writer2.flush()?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multi_writer_fans_out_and_counts() {
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        let mut reported = 0;
+        let mut report = ReportingSink::new(|bytes| reported += bytes);
+        let mut writer = MultiWriter::new()
+            .with(&mut a)
+            .with(&mut b)
+            .with(&mut report);
+        writer.write_all(b"abc").unwrap();
+        writer.write_all(b"de").unwrap();
+        assert_eq!(writer.written(), 5);
+        drop(writer);
+        assert_eq!(a, b"abcde");
+        assert_eq!(b, b"abcde");
+        assert_eq!(reported, 5);
+    }
+}
@@ -0,0 +1,510 @@
+//! Builds small `docker save` tarballs in memory, so tests don't need
+//! multi-MB fixtures or a Docker daemon. Enabled by the `testing` feature.
+//!
+//! ```
+//! use docker_duplicate_files::testing::{ImageBuilder, LayerBuilder, random_bytes};
+//!
+//! let lib = random_bytes(2_000_000, 1);
+//! let image = ImageBuilder::new()
+//!     .layer(LayerBuilder::new().file("usr/lib/libbig.so", lib.clone()))
+//!     .layer(LayerBuilder::new().file("opt/app/libbig.so", lib))
+//!     .build();
+//! # let _ = image;
+//! ```
+//!
+//! Helpers panic instead of returning errors, as befits test code.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Read, Write};
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use tar::{Archive, Builder, EntryType, Header};
+
+use crate::digest_writer::sha256_digest;
+use crate::entries::{EntryKind, entry_kind};
+use crate::schemas::{ContainerConfig, DockerConfig, HistoryEntry, Manifest, RootFs};
+
+const CREATED: &str = "2025-01-01T00:00:00Z";
+
+/// Deterministic pseudo-random contents, large enough files pass `min_size`
+/// without compressing to nothing.
+pub fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+    // xorshift64*, seeded away from the all-zero state
+    let mut state = seed ^ 0x9e37_79b9_7f4a_7c15;
+    let mut bytes = Vec::with_capacity(len + 8);
+    while bytes.len() < len {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        bytes.extend_from_slice(&state.wrapping_mul(0x2545_f491_4f6c_dd1d).to_le_bytes());
+    }
+    bytes.truncate(len);
+    bytes
+}
+
+/// A little-endian x86-64 ELF shared object with `text` as its only loaded
+/// section, a GNU build ID note and, unless `debug` is empty, a
+/// `.debug_info` section, as a build with and without `-g` or `strip`
+/// would give.
+pub fn elf_file(text: &[u8], build_id: &[u8], debug: &[u8]) -> Vec<u8> {
+    let mut note = Vec::new();
+    note.extend_from_slice(&4u32.to_le_bytes());
+    note.extend_from_slice(&(build_id.len() as u32).to_le_bytes());
+    note.extend_from_slice(&3u32.to_le_bytes());
+    note.extend_from_slice(b"GNU\0");
+    note.extend_from_slice(build_id);
+    // (name, sh_type, sh_flags, contents)
+    let mut sections: Vec<(&str, u32, u64, &[u8])> = vec![
+        (".text", 1, 0x6, text),
+        (".note.gnu.build-id", 7, 0x2, &note),
+    ];
+    if !debug.is_empty() {
+        sections.push((".debug_info", 1, 0, debug));
+    }
+    let mut names = vec![0u8];
+    let mut name_offsets = Vec::new();
+    for name in sections.iter().map(|s| s.0).chain([".shstrtab"]) {
+        name_offsets.push(names.len() as u32);
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+    sections.push((".shstrtab", 3, 0, &names));
+
+    let mut elf = vec![0u8; 64];
+    let mut headers = vec![0u8; 64];
+    for ((_, kind, flags, contents), name) in sections.iter().zip(&name_offsets) {
+        let offset = elf.len() as u64;
+        elf.extend_from_slice(contents);
+        headers.extend_from_slice(&name.to_le_bytes());
+        headers.extend_from_slice(&kind.to_le_bytes());
+        headers.extend_from_slice(&flags.to_le_bytes());
+        headers.extend_from_slice(&0u64.to_le_bytes());
+        headers.extend_from_slice(&offset.to_le_bytes());
+        headers.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+        headers.extend_from_slice(&[0; 24]);
+    }
+    let shoff = elf.len() as u64;
+    elf.extend_from_slice(&headers);
+    elf[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+    elf[0x10..0x12].copy_from_slice(&3u16.to_le_bytes());
+    elf[0x12..0x14].copy_from_slice(&62u16.to_le_bytes());
+    elf[0x14..0x18].copy_from_slice(&1u32.to_le_bytes());
+    elf[0x28..0x30].copy_from_slice(&shoff.to_le_bytes());
+    elf[0x34..0x36].copy_from_slice(&64u16.to_le_bytes());
+    elf[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+    elf[0x3c..0x3e].copy_from_slice(&((sections.len() + 1) as u16).to_le_bytes());
+    elf[0x3e..0x40].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+    elf
+}
+
+/// One tar entry, as written by [`LayerBuilder`] or read back by
+/// [`ImageContents`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestEntry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub link_target: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// Entries of a single layer, in archive order.
+#[derive(Debug, Clone, Default)]
+pub struct LayerBuilder {
+    entries: Vec<TestEntry>,
+    created_by: Option<String>,
+}
+
+impl LayerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(
+        mut self,
+        path: &str,
+        kind: EntryKind,
+        link_target: Option<&str>,
+        data: Vec<u8>,
+    ) -> Self {
+        self.entries.push(TestEntry {
+            path: path.to_string(),
+            kind,
+            link_target: link_target.map(str::to_string),
+            data,
+        });
+        self
+    }
+
+    pub fn file(self, path: &str, contents: impl Into<Vec<u8>>) -> Self {
+        self.push(path, EntryKind::File, None, contents.into())
+    }
+
+    pub fn dir(self, path: &str) -> Self {
+        self.push(path, EntryKind::Dir, None, Vec::new())
+    }
+
+    pub fn symlink(self, path: &str, target: &str) -> Self {
+        self.push(path, EntryKind::Symlink, Some(target), Vec::new())
+    }
+
+    /// `target` is an archive path written earlier in the same layer.
+    pub fn hardlink(self, path: &str, target: &str) -> Self {
+        self.push(path, EntryKind::Hardlink, Some(target), Vec::new())
+    }
+
+    /// Deletes `path` from the lower layers.
+    pub fn whiteout(self, path: &str) -> Self {
+        let whiteout = match path.rsplit_once('/') {
+            Some((dir, name)) => format!("{}/.wh.{}", dir, name),
+            None => format!(".wh.{}", path),
+        };
+        self.push(&whiteout, EntryKind::Whiteout, None, Vec::new())
+    }
+
+    /// Hides the lower contents of directory `dir`.
+    pub fn opaque(self, dir: &str) -> Self {
+        let marker = format!("{}/.wh..wh..opq", dir.trim_end_matches('/'));
+        self.push(&marker, EntryKind::Opaque, None, Vec::new())
+    }
+
+    /// History `created_by` of this layer. Defaults to `layer N`.
+    pub fn created_by(mut self, created_by: &str) -> Self {
+        self.created_by = Some(created_by.to_string());
+        self
+    }
+
+    /// The uncompressed layer tar.
+    pub fn build_tar(&self) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for entry in &self.entries {
+            let mut header = Header::new_gnu();
+            header.set_mtime(0);
+            header.set_uid(0);
+            header.set_gid(0);
+            match entry.kind {
+                EntryKind::Dir => {
+                    header.set_entry_type(EntryType::Directory);
+                    header.set_mode(0o755);
+                    header.set_size(0);
+                    builder
+                        .append_data(&mut header, &entry.path, &[][..])
+                        .unwrap();
+                }
+                EntryKind::Symlink | EntryKind::Hardlink => {
+                    header.set_entry_type(if entry.kind == EntryKind::Symlink {
+                        EntryType::Symlink
+                    } else {
+                        EntryType::Link
+                    });
+                    header.set_mode(0o777);
+                    header.set_size(0);
+                    let target = entry.link_target.as_deref().unwrap_or_default();
+                    builder
+                        .append_link(&mut header, &entry.path, target)
+                        .unwrap();
+                }
+                _ => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_mode(0o644);
+                    header.set_size(entry.data.len() as u64);
+                    builder
+                        .append_data(&mut header, &entry.path, entry.data.as_slice())
+                        .unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+}
+
+enum HistoryItem {
+    Layer(LayerBuilder),
+    Raw(Vec<u8>),
+    Empty(String),
+}
+
+/// A whole image, written in the `docker save` layout with content
+/// addressed blobs under `blobs/sha256/`.
+pub struct ImageBuilder {
+    items: Vec<HistoryItem>,
+    config: ContainerConfig,
+    repo_tags: Vec<String>,
+    gzip: bool,
+    linked_layers: bool,
+    manifest_first: bool,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self {
+            items: Vec::new(),
+            config: ContainerConfig::default(),
+            repo_tags: vec!["test:latest".to_string()],
+            gzip: true,
+            linked_layers: false,
+            manifest_first: false,
+        }
+    }
+}
+
+impl ImageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn layer(mut self, layer: LayerBuilder) -> Self {
+        self.items.push(HistoryItem::Layer(layer));
+        self
+    }
+
+    /// A layer blob used verbatim, e.g. a corrupt or hand-crafted one.
+    pub fn raw_layer(mut self, blob: impl Into<Vec<u8>>) -> Self {
+        self.items.push(HistoryItem::Raw(blob.into()));
+        self
+    }
+
+    /// A history entry without a layer, like `ENV` or `CMD`.
+    pub fn empty_layer(mut self, created_by: &str) -> Self {
+        self.items.push(HistoryItem::Empty(created_by.to_string()));
+        self
+    }
+
+    /// Gzip the layer blobs, on by default.
+    pub fn gzip(mut self, gzip: bool) -> Self {
+        self.gzip = gzip;
+        self
+    }
+
+    /// List the layers as `<n>/layer.tar`, symlinks to their blobs from the
+    /// archive root, like some save formats do.
+    pub fn linked_layers(mut self, linked: bool) -> Self {
+        self.linked_layers = linked;
+        self
+    }
+
+    /// Write manifest.json and the config ahead of the layers rather than
+    /// after them, as `docker save` does.
+    pub fn manifest_first(mut self, first: bool) -> Self {
+        self.manifest_first = first;
+        self
+    }
+
+    pub fn repo_tag(mut self, tag: &str) -> Self {
+        self.repo_tags = vec![tag.to_string()];
+        self
+    }
+
+    pub fn volume(mut self, path: &str) -> Self {
+        self.config
+            .volumes
+            .get_or_insert_with(BTreeMap::new)
+            .insert(path.to_string(), serde_json::json!({}));
+        self
+    }
+
+    pub fn label(mut self, key: &str, value: &str) -> Self {
+        self.config
+            .labels
+            .get_or_insert_with(BTreeMap::new)
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Replaces the whole container config, for fields without a setter.
+    pub fn container_config(mut self, config: ContainerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The image as a `docker save` tarball.
+    pub fn build(&self) -> Vec<u8> {
+        let mut blobs = Vec::new();
+        let mut history = Vec::new();
+        let mut diff_ids = Vec::new();
+        for item in &self.items {
+            match item {
+                HistoryItem::Layer(layer) => {
+                    let tar = layer.build_tar();
+                    diff_ids.push(sha256_digest(&tar));
+                    let blob = if self.gzip {
+                        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+                        encoder.write_all(&tar).unwrap();
+                        encoder.finish().unwrap()
+                    } else {
+                        tar
+                    };
+                    history.push(history_entry(
+                        layer
+                            .created_by
+                            .clone()
+                            .unwrap_or_else(|| format!("layer {}", diff_ids.len() - 1)),
+                        false,
+                    ));
+                    blobs.push(blob);
+                }
+                HistoryItem::Raw(blob) => {
+                    diff_ids.push(sha256_digest(blob));
+                    history.push(history_entry(
+                        format!("layer {}", diff_ids.len() - 1),
+                        false,
+                    ));
+                    blobs.push(blob.clone());
+                }
+                HistoryItem::Empty(created_by) => {
+                    history.push(history_entry(created_by.clone(), true));
+                }
+            }
+        }
+
+        let config = DockerConfig {
+            architecture: "amd64".to_string(),
+            config: self.config.clone(),
+            created: CREATED.to_string(),
+            history,
+            os: "linux".to_string(),
+            rootfs: RootFs {
+                fs_type: "layers".to_string(),
+                diff_ids,
+            },
+        };
+        let config_json = config.to_json().unwrap().into_bytes();
+        let config_path = format!("blobs/{}", sha256_digest(&config_json).replace(':', "/"));
+        let layer_paths: Vec<String> = blobs
+            .iter()
+            .map(|blob| format!("blobs/{}", sha256_digest(blob).replace(':', "/")))
+            .collect();
+        let listed_paths: Vec<String> = if self.linked_layers {
+            (0..blobs.len())
+                .map(|i| format!("{}/layer.tar", i))
+                .collect()
+        } else {
+            layer_paths.clone()
+        };
+        let manifest = vec![Manifest {
+            config: config_path.clone(),
+            repo_tags: self.repo_tags.clone(),
+            layers: listed_paths.clone(),
+            layer_sources: BTreeMap::new(),
+        }];
+
+        let mut builder = Builder::new(Vec::new());
+        let mut add = |path: &str, data: &[u8]| {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_mtime(0);
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        let manifest_json = serde_json::to_vec_pretty(&manifest).unwrap();
+        if self.manifest_first {
+            add("manifest.json", &manifest_json);
+            add(&config_path, &config_json);
+        }
+        for (path, blob) in layer_paths.iter().zip(&blobs) {
+            add(path, blob);
+        }
+        if !self.manifest_first {
+            add(&config_path, &config_json);
+            add("manifest.json", &manifest_json);
+        }
+        if self.linked_layers {
+            for (link, target) in listed_paths.iter().zip(&layer_paths) {
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Symlink);
+                header.set_mode(0o777);
+                header.set_mtime(0);
+                header.set_size(0);
+                builder
+                    .append_link(&mut header, link, format!("/{}", target))
+                    .unwrap();
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+}
+
+fn history_entry(created_by: String, empty_layer: bool) -> HistoryEntry {
+    HistoryEntry {
+        created: CREATED.to_string(),
+        created_by,
+        comment: String::new(),
+        empty_layer,
+        author: None,
+    }
+}
+
+/// A `docker save` tarball read back into memory, to assert on the output of
+/// a rewrite.
+pub struct ImageContents {
+    pub manifest: Manifest,
+    pub config: DockerConfig,
+    pub layers: Vec<Vec<TestEntry>>,
+}
+
+impl ImageContents {
+    pub fn read(image: &[u8]) -> Self {
+        let mut files = HashMap::new();
+        for entry in Archive::new(image).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.header().entry_type() != EntryType::Regular {
+                continue;
+            }
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            files.insert(path.trim_start_matches("./").to_string(), data);
+        }
+
+        let manifest_json = String::from_utf8(files["manifest.json"].clone()).unwrap();
+        let manifest: Manifest = manifest_json.parse().unwrap();
+        let config_json = String::from_utf8(files[&manifest.config].clone()).unwrap();
+        let config: DockerConfig = config_json.parse().unwrap();
+        let layers = manifest
+            .layers
+            .iter()
+            .map(|path| read_layer(&files[path]))
+            .collect();
+        Self {
+            manifest,
+            config,
+            layers,
+        }
+    }
+
+    /// The entry at `path` in layer `layer_index`, if any.
+    pub fn entry(&self, layer_index: usize, path: &str) -> Option<&TestEntry> {
+        self.layers[layer_index].iter().find(|e| e.path == path)
+    }
+}
+
+fn read_layer(blob: &[u8]) -> Vec<TestEntry> {
+    let reader: Box<dyn Read> = if blob.starts_with(&[0x1f, 0x8b]) {
+        Box::new(GzDecoder::new(Cursor::new(blob)))
+    } else {
+        Box::new(Cursor::new(blob))
+    };
+    let mut archive = Archive::new(reader);
+    archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let kind = entry_kind(&path, entry.header().entry_type());
+            let link_target = entry
+                .link_name()
+                .unwrap()
+                .map(|target| target.to_string_lossy().to_string());
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            TestEntry {
+                path,
+                kind,
+                link_target,
+                data,
+            }
+        })
+        .collect()
+}
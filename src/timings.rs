@@ -0,0 +1,141 @@
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Extract,
+    Scan,
+    Plan,
+    Rewrite,
+    Compress,
+    Pack,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhaseTiming {
+    pub phase: Phase,
+    pub seconds: f64,
+    pub bytes: u64,
+}
+
+/// Wall time and bytes processed per pipeline phase.
+///
+/// Compress is measured inside each layer's encoder and summed over all
+/// layers, so with parallel rewrites it is CPU time and may exceed the
+/// rewrite wall time it is part of.
+#[derive(Debug, Default)]
+pub struct Timings {
+    phases: Mutex<Vec<PhaseTiming>>,
+}
+
+impl Timings {
+    pub fn record(&self, phase: Phase, elapsed: Duration, bytes: u64) {
+        let mut phases = self.phases.lock().unwrap();
+        match phases.iter_mut().find(|p| p.phase == phase) {
+            Some(timing) => {
+                timing.seconds += elapsed.as_secs_f64();
+                timing.bytes += bytes;
+            }
+            None => phases.push(PhaseTiming {
+                phase,
+                seconds: elapsed.as_secs_f64(),
+                bytes,
+            }),
+        }
+    }
+
+    pub fn summary(&self) -> Vec<PhaseTiming> {
+        self.phases.lock().unwrap().clone()
+    }
+
+    pub fn print_summary(&self) {
+        info!("=============================");
+        info!("Phase timings:");
+        for timing in self.summary() {
+            let throughput = if timing.seconds > 0.0 && timing.bytes > 0 {
+                format!(
+                    " ({}/s)",
+                    format_size((timing.bytes as f64 / timing.seconds) as u64, BINARY)
+                )
+            } else {
+                String::new()
+            };
+            info!(
+                "\t{:?}: {:.2}s, {}{}",
+                timing.phase,
+                timing.seconds,
+                format_size(timing.bytes, BINARY),
+                throughput
+            );
+        }
+        info!("=============================");
+    }
+}
+
+/// Counts the bytes read through it.
+pub struct CountingReader<R: Read> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Counts the bytes written through it and the time spent in the inner writer.
+pub struct TimedWriter<W: Write> {
+    inner: W,
+    bytes: u64,
+    elapsed: Duration,
+}
+
+impl<W: Write> TimedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub fn into_inner(self) -> (W, Duration, u64) {
+        (self.inner, self.elapsed, self.bytes)
+    }
+}
+
+impl<W: Write> Write for TimedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let n = self.inner.write(buf)?;
+        self.elapsed += start.elapsed();
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let start = Instant::now();
+        self.inner.flush()?;
+        self.elapsed += start.elapsed();
+        Ok(())
+    }
+}
@@ -0,0 +1,415 @@
+//! skopeo-style image references, so `--image` and `--output` take the same
+//! syntax as `skopeo copy`:
+//!
+//! ```text
+//! docker-archive:/path/image.tar   `docker save` tarball, also any bare path
+//! oci:/path/layout[:tag]           OCI image layout
+//! dir:/path                        skopeo dir layout: manifest.json plus blobs named by digest
+//! docker://registry/repo[:tag]     a registry, needs the `network` feature
+//! s3://bucket/key                  a `docker save` tarball in S3, needs the `network` feature
+//! https://host/path/image.tar[.gz] a `docker save` tarball to download, read only, needs the
+//!                                  `network` feature
+//! ```
+//!
+//! Everything but archives is converted to and from the unpacked
+//! `docker save` layout the [`Analyzer`] works on, hard linking blobs
+//! instead of copying them where the filesystem allows.
+
+use std::fmt;
+#[cfg(feature = "rewrite")]
+use std::fs::{self, File};
+#[cfg(feature = "rewrite")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+#[cfg(feature = "rewrite")]
+use std::time::Instant;
+
+#[cfg(feature = "network")]
+use std::io::{BufRead, BufReader, Read};
+
+#[cfg(feature = "network")]
+use flate2::read::GzDecoder;
+
+use crate::analyzer::Analyzer;
+#[cfg(feature = "network")]
+use crate::analyzer::LayerCompression;
+#[cfg(feature = "rewrite")]
+use crate::analyzer::rewrite::StagedImage;
+#[cfg(feature = "rewrite")]
+use crate::analyzer::{DuplicateInfo, ModificationPlan, SplitBy};
+#[cfg(feature = "rewrite")]
+use crate::error::IoResultExt;
+use crate::error::{DedupeError, Result};
+use crate::options::AnalyzerOptions;
+#[cfg(feature = "rewrite")]
+use crate::pipeline::DedupeSummary;
+#[cfg(feature = "rewrite")]
+use crate::timings::Phase;
+
+#[cfg(feature = "network")]
+mod http;
+pub(crate) mod layout;
+#[cfg(feature = "rewrite")]
+mod referrers;
+#[cfg(feature = "network")]
+pub mod registry;
+#[cfg(feature = "network")]
+pub mod s3;
+
+/// Where an image is read from or written to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageRef {
+    DockerArchive(PathBuf),
+    /// An OCI image layout. Without a tag the layout must hold one image.
+    Oci {
+        path: PathBuf,
+        tag: Option<String>,
+    },
+    Dir(PathBuf),
+    /// `[registry/]repository[:tag][@digest]`, as after `docker://`
+    #[cfg(feature = "network")]
+    Docker(String),
+    /// `bucket/key`, as after `s3://`, of a `docker save` tarball
+    #[cfg(feature = "network")]
+    S3(String),
+    /// An `https://` or `http://` URL of a `docker save` tarball, gzipped
+    /// or not. Can only be read.
+    #[cfg(feature = "network")]
+    Url(String),
+}
+
+fn non_empty(path: &str, reference: &str) -> Result<PathBuf> {
+    if path.is_empty() {
+        return Err(DedupeError::InvalidOption(format!(
+            "{} is missing a path",
+            reference
+        )));
+    }
+    Ok(PathBuf::from(path))
+}
+
+impl FromStr for ImageRef {
+    type Err = DedupeError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        #[cfg(feature = "network")]
+        if let Some(reference) = s.strip_prefix("docker://") {
+            reference.parse::<registry::Reference>()?;
+            return Ok(ImageRef::Docker(reference.to_string()));
+        }
+        #[cfg(feature = "network")]
+        if let Some(location) = s.strip_prefix("s3://") {
+            location.parse::<s3::Location>()?;
+            return Ok(ImageRef::S3(location.to_string()));
+        }
+        #[cfg(feature = "network")]
+        if s.starts_with("https://") || s.starts_with("http://") {
+            return Ok(ImageRef::Url(s.to_string()));
+        }
+        #[cfg(not(feature = "network"))]
+        if ["docker://", "s3://", "https://", "http://"]
+            .iter()
+            .any(|scheme| s.starts_with(scheme))
+        {
+            return Err(network_disabled());
+        }
+        if let Some(rest) = s.strip_prefix("oci:") {
+            // A tag never contains a slash, so `oci:dir/x:y/z` is all path
+            let (path, tag) = match rest.rsplit_once(':') {
+                Some((path, tag)) if !path.is_empty() && !tag.is_empty() && !tag.contains('/') => {
+                    (path, Some(tag.to_string()))
+                }
+                _ => (rest, None),
+            };
+            return Ok(ImageRef::Oci {
+                path: non_empty(path, s)?,
+                tag,
+            });
+        }
+        if let Some(path) = s.strip_prefix("dir:") {
+            return Ok(ImageRef::Dir(non_empty(path, s)?));
+        }
+        let path = s.strip_prefix("docker-archive:").unwrap_or(s);
+        Ok(ImageRef::DockerArchive(non_empty(path, s)?))
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageRef::DockerArchive(path) => write!(f, "docker-archive:{}", path.display()),
+            ImageRef::Oci { path, tag: None } => write!(f, "oci:{}", path.display()),
+            ImageRef::Oci {
+                path,
+                tag: Some(tag),
+            } => write!(f, "oci:{}:{}", path.display(), tag),
+            ImageRef::Dir(path) => write!(f, "dir:{}", path.display()),
+            #[cfg(feature = "network")]
+            ImageRef::Docker(reference) => write!(f, "docker://{}", reference),
+            #[cfg(feature = "network")]
+            ImageRef::S3(location) => write!(f, "s3://{}", location),
+            #[cfg(feature = "network")]
+            ImageRef::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+#[cfg(not(feature = "network"))]
+fn network_disabled() -> DedupeError {
+    DedupeError::InvalidOption(
+        "docker://, s3:// and https:// references need the `network` feature of \
+         docker_duplicate_files"
+            .to_string(),
+    )
+}
+
+impl ImageRef {
+    /// Loads the image for analysis. Archives behave like
+    /// [`Analyzer::from_path`]; with a `work_dir` option the other
+    /// transports resume too.
+    pub fn load(&self, options: AnalyzerOptions) -> Result<Analyzer> {
+        match self {
+            ImageRef::DockerArchive(path) => Analyzer::from_path(path, options),
+            ImageRef::Oci { path, tag } => layout::load_oci(path, tag.as_deref(), options),
+            ImageRef::Dir(path) => layout::load_dir(path, options),
+            #[cfg(feature = "network")]
+            ImageRef::Docker(reference) => registry::pull(&reference.parse()?, options),
+            #[cfg(feature = "network")]
+            ImageRef::S3(location) => {
+                Analyzer::from_reader(decompressed(s3::open(&location.parse()?)?)?, options)
+            }
+            #[cfg(feature = "network")]
+            ImageRef::Url(url) => {
+                let download = http::open_url(url, &options.http_headers)?;
+                Analyzer::from_reader(decompressed(download)?, options)
+            }
+        }
+    }
+
+    /// What to hand signing tools for the image written here with manifest
+    /// `digest`: `registry/repo@digest` for registries, the reference itself
+    /// otherwise.
+    #[cfg_attr(not(feature = "network"), allow(unused_variables))]
+    pub fn pinned(&self, digest: &str) -> String {
+        match self {
+            #[cfg(feature = "network")]
+            ImageRef::Docker(reference) => match reference.parse::<registry::Reference>() {
+                Ok(reference) => registry::Reference {
+                    tag: None,
+                    digest: Some(digest.to_string()),
+                    ..reference
+                }
+                .to_string(),
+                Err(_) => self.to_string(),
+            },
+            _ => self.to_string(),
+        }
+    }
+
+    #[cfg(feature = "rewrite")]
+    /// Writes the deduplicated image of `analyzer` here. Archives are written
+    /// under a `.partial` name and renamed once complete, so a failed run
+    /// never leaves a truncated tar behind.
+    pub fn write(
+        &self,
+        analyzer: &Analyzer,
+        duplicates: Vec<DuplicateInfo>,
+    ) -> Result<DedupeSummary> {
+        self.write_with(analyzer, || analyzer.stage_deduplicated_image(duplicates))
+    }
+
+    #[cfg(feature = "rewrite")]
+    /// Writes the image a saved `plan` describes here, see
+    /// [`Analyzer::create_image_from_plan`].
+    pub fn write_plan(
+        &self,
+        analyzer: &Analyzer,
+        plan: &ModificationPlan,
+    ) -> Result<DedupeSummary> {
+        self.write_with(analyzer, || analyzer.stage_saved_plan(plan))
+    }
+
+    #[cfg(feature = "rewrite")]
+    /// Writes the image of `analyzer` here with one copy of each of
+    /// `duplicates` moved into a new bottom layer, see
+    /// [`Analyzer::create_shared_layer_image`].
+    pub fn write_shared_layer(
+        &self,
+        analyzer: &Analyzer,
+        duplicates: Vec<DuplicateInfo>,
+    ) -> Result<DedupeSummary> {
+        self.write_with(analyzer, || analyzer.stage_shared_layer_image(duplicates))
+    }
+
+    #[cfg(feature = "rewrite")]
+    /// Writes the image of `analyzer` here with layers `from..` squashed
+    /// into one, see [`Analyzer::create_squashed_image`].
+    pub fn write_squashed(&self, analyzer: &Analyzer, from: usize) -> Result<DedupeSummary> {
+        self.write_with(analyzer, || analyzer.stage_squashed_image(from))
+    }
+
+    #[cfg(feature = "rewrite")]
+    /// Writes the image of `analyzer` here with layer `index` split, see
+    /// [`Analyzer::create_split_image`].
+    pub fn write_split(
+        &self,
+        analyzer: &Analyzer,
+        index: usize,
+        split_by: SplitBy,
+    ) -> Result<DedupeSummary> {
+        self.write_with(analyzer, || analyzer.stage_split_image(index, split_by))
+    }
+
+    #[cfg(feature = "rewrite")]
+    fn write_with(
+        &self,
+        analyzer: &Analyzer,
+        stage: impl FnOnce() -> Result<StagedImage>,
+    ) -> Result<DedupeSummary> {
+        match self {
+            ImageRef::DockerArchive(path) => write_archive(analyzer, stage, path),
+            ImageRef::Oci { path, tag } => write_staged(analyzer, stage, |staged| {
+                layout::write_oci(staged, path, tag.as_deref())
+            }),
+            ImageRef::Dir(path) => {
+                write_staged(analyzer, stage, |staged| layout::write_dir(staged, path))
+            }
+            #[cfg(feature = "network")]
+            ImageRef::Docker(reference) => {
+                let reference = reference.parse()?;
+                write_staged(analyzer, stage, |staged| registry::push(staged, &reference))
+            }
+            #[cfg(feature = "network")]
+            ImageRef::S3(location) => write_upload(analyzer, stage, &location.parse()?),
+            #[cfg(feature = "network")]
+            ImageRef::Url(_) => Err(read_only(self)),
+        }
+    }
+}
+
+#[cfg(feature = "rewrite")]
+/// Stages the rewritten image and hands it to `convert`, which returns the
+/// bytes it wrote and the manifest digest.
+fn write_staged(
+    analyzer: &Analyzer,
+    stage: impl FnOnce() -> Result<StagedImage>,
+    convert: impl FnOnce(&Path) -> Result<(u64, String)>,
+) -> Result<DedupeSummary> {
+    let mut staged = stage()?;
+    let start = Instant::now();
+    let (bytes, manifest_digest) = convert(&staged.dir)?;
+    analyzer
+        .timings()
+        .record(Phase::Pack, start.elapsed(), bytes);
+    staged.summary.manifest_digest = Some(manifest_digest);
+    Ok(staged.summary)
+}
+
+#[cfg(feature = "rewrite")]
+fn write_archive(
+    analyzer: &Analyzer,
+    stage: impl FnOnce() -> Result<StagedImage>,
+    path: &Path,
+) -> Result<DedupeSummary> {
+    let partial_path = PathBuf::from(format!("{}.partial", path.display()));
+    let output_file = File::create(&partial_path)
+        .with_context(|| format!("Failed to create output file: {}", path.display()))?;
+    let result = stage()
+        .and_then(|staged| analyzer.pack_staged(staged, output_file))
+        .and_then(|summary| {
+            fs::rename(&partial_path, path)
+                .with_context(|| format!("Failed to move output into place: {}", path.display()))?;
+            Ok(summary)
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&partial_path);
+    }
+    result
+}
+
+#[cfg(all(feature = "rewrite", feature = "network"))]
+fn read_only(image: &ImageRef) -> DedupeError {
+    DedupeError::InvalidOption(format!("{} can only be read, not written", image))
+}
+
+#[cfg(feature = "network")]
+/// A downloaded archive, decompressed if it's gzipped.
+fn decompressed(reader: impl Read + 'static) -> Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(reader);
+    match LayerCompression::detect(reader.fill_buf()?) {
+        LayerCompression::None => Ok(Box::new(reader)),
+        LayerCompression::Gzip => Ok(Box::new(GzDecoder::new(reader))),
+        compression => Err(DedupeError::UnsupportedCompression(format!(
+            "the image archive is {:?}-compressed",
+            compression
+        ))),
+    }
+}
+
+#[cfg(feature = "network")]
+/// Streams the archive into an S3 upload, which only completes, making
+/// the object visible, once the archive is whole.
+fn write_upload(
+    analyzer: &Analyzer,
+    stage: impl FnOnce() -> Result<StagedImage>,
+    location: &s3::Location,
+) -> Result<DedupeSummary> {
+    let mut upload = s3::Upload::new(location);
+    let summary = analyzer.pack_staged(stage()?, &mut upload)?;
+    upload.finish()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_transports() {
+        let parse = |s: &str| s.parse::<ImageRef>().unwrap();
+        assert_eq!(
+            parse("image.tar"),
+            ImageRef::DockerArchive("image.tar".into())
+        );
+        assert_eq!(
+            parse("docker-archive:/tmp/image.tar"),
+            ImageRef::DockerArchive("/tmp/image.tar".into())
+        );
+        assert_eq!(
+            parse("oci:/tmp/layout:v1"),
+            ImageRef::Oci {
+                path: "/tmp/layout".into(),
+                tag: Some("v1".to_string())
+            }
+        );
+        assert_eq!(
+            parse("oci:/tmp/lay:out/x"),
+            ImageRef::Oci {
+                path: "/tmp/lay:out/x".into(),
+                tag: None
+            }
+        );
+        assert_eq!(parse("dir:/tmp/d"), ImageRef::Dir("/tmp/d".into()));
+        #[cfg(feature = "network")]
+        {
+            assert_eq!(
+                parse("docker://alpine:3"),
+                ImageRef::Docker("alpine:3".to_string())
+            );
+            assert_eq!(
+                parse("s3://ci-artifacts/app.tar"),
+                ImageRef::S3("ci-artifacts/app.tar".to_string())
+            );
+            assert_eq!(
+                parse("https://ci.example.com/app.tar.gz"),
+                ImageRef::Url("https://ci.example.com/app.tar.gz".to_string())
+            );
+        }
+        assert_eq!(
+            parse("oci:/tmp/layout:v1").to_string(),
+            "oci:/tmp/layout:v1"
+        );
+        assert!("dir:".parse::<ImageRef>().is_err());
+    }
+}
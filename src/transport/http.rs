@@ -0,0 +1,124 @@
+//! Downloads read as streams, picking up where they broke off with a range
+//! request when the connection drops, so a long transfer doesn't start
+//! over for one reset. Backs `s3://` inputs and `https://` ones, archives
+//! CI systems publish.
+//!
+//! Requests go through the proxy `HTTPS_PROXY`, `HTTP_PROXY` or
+//! `ALL_PROXY` name, minus `NO_PROXY`. Redirects are followed, keeping
+//! an `Authorization` header only when they stay on the same host.
+
+use std::io::{self, Read};
+
+use tracing::warn;
+use ureq::config::RedirectAuthHeaders;
+use ureq::http::Response;
+use ureq::{Agent, BodyReader};
+
+use crate::error::{DedupeError, Result};
+
+/// Times a download is resumed before its read error is returned
+const MAX_RESUMES: usize = 3;
+
+/// Opens `url` for reading, sending `headers` with every request.
+pub(crate) fn open_url(url: &str, headers: &[(String, String)]) -> Result<impl Read + use<>> {
+    let agent: Agent = Agent::config_builder()
+        .http_status_as_error(false)
+        .redirect_auth_headers(RedirectAuthHeaders::SameHost)
+        .build()
+        .into();
+    let (url, headers) = (url.to_string(), headers.to_vec());
+    RangeReader::new(url.clone(), move |offset| {
+        let mut request = agent.get(&url);
+        for (name, value) in &headers {
+            request = request.header(name, value);
+        }
+        if offset > 0 {
+            request = request.header("Range", format!("bytes={}-", offset));
+        }
+        request
+            .call()
+            .map_err(|e| DedupeError::Http(format!("{}: {}", url, e)))
+    })
+}
+
+/// A GET of `url`, resumed from the bytes read so far after errors.
+/// `open` sends the request for the given offset, with a `Range` header
+/// unless it's 0.
+pub(crate) struct RangeReader<F> {
+    url: String,
+    open: F,
+    body: Option<BodyReader<'static>>,
+    offset: u64,
+    resumes: usize,
+}
+
+impl<F> RangeReader<F>
+where
+    F: FnMut(u64) -> Result<Response<ureq::Body>>,
+{
+    /// Sends the first request right away, so a missing object or refused
+    /// credentials fail here rather than on the first read.
+    pub(crate) fn new(url: impl Into<String>, open: F) -> Result<Self> {
+        let mut reader = Self {
+            url: url.into(),
+            open,
+            body: None,
+            offset: 0,
+            resumes: 0,
+        };
+        reader.connect()?;
+        Ok(reader)
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        let mut response = (self.open)(self.offset)?;
+        let status = response.status();
+        if self.offset > 0 && status != 206 {
+            return Err(DedupeError::Http(format!(
+                "{} answered {} to a range request, can't resume at byte {}",
+                self.url, status, self.offset
+            )));
+        }
+        if !status.is_success() {
+            let body = response.body_mut().read_to_string().unwrap_or_default();
+            return Err(DedupeError::Http(format!(
+                "{} answered {}: {}",
+                self.url,
+                status,
+                body.trim()
+            )));
+        }
+        self.body = Some(response.into_body().into_reader());
+        Ok(())
+    }
+}
+
+impl<F> Read for RangeReader<F>
+where
+    F: FnMut(u64) -> Result<Response<ureq::Body>>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.body.is_none() {
+                self.connect().map_err(io::Error::other)?;
+            }
+            let body = self.body.as_mut().expect("connected");
+            match body.read(buf) {
+                Ok(read) => {
+                    self.offset += read as u64;
+                    return Ok(read);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if self.resumes < MAX_RESUMES => {
+                    self.resumes += 1;
+                    warn!(
+                        "Reading {} failed at byte {}, resuming: {}",
+                        self.url, self.offset, e
+                    );
+                    self.body = None;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
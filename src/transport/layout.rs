@@ -0,0 +1,587 @@
+//! Conversion between distribution manifests (OCI layouts, skopeo dirs,
+//! registries) and the unpacked `docker save` layout.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "rewrite")]
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+#[cfg(feature = "rewrite")]
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use tracing::debug;
+
+#[cfg(feature = "rewrite")]
+use crate::analyzer::LayerCompression;
+use crate::analyzer::{Analyzer, link_or_copy};
+use crate::cancel;
+#[cfg(feature = "rewrite")]
+use crate::digest_writer::{sha256_digest, sha256_file};
+use crate::error::{DedupeError, Result};
+use crate::options::AnalyzerOptions;
+#[cfg(feature = "rewrite")]
+use crate::schemas::Platform;
+use crate::schemas::{Descriptor, DockerConfig, ImageIndex, ImageManifest, Manifest};
+
+#[cfg(feature = "rewrite")]
+pub(crate) const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+#[cfg(feature = "rewrite")]
+pub(crate) const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+#[cfg(feature = "network")]
+pub(crate) const DOCKER_MANIFEST_LIST: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+#[cfg(feature = "rewrite")]
+pub(crate) const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+#[cfg(feature = "rewrite")]
+const OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+#[cfg(feature = "rewrite")]
+const OCI_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+#[cfg(feature = "rewrite")]
+const OCI_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+#[cfg(feature = "rewrite")]
+const DOCKER_CONFIG: &str = "application/vnd.docker.container.image.v1+json";
+#[cfg(feature = "rewrite")]
+const DOCKER_LAYER: &str = "application/vnd.docker.image.rootfs.diff.tar";
+#[cfg(feature = "rewrite")]
+const DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+/// Indexes nested deeper than this are rejected rather than followed
+pub(crate) const MAX_INDEX_DEPTH: usize = 2;
+/// Annotation naming an image in an OCI index
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+/// Annotation containerd, `ctr images import` and `nerdctl load` name an
+/// imported image by
+#[cfg(feature = "rewrite")]
+const CONTAINERD_NAME: &str = "io.containerd.image.name";
+#[cfg(feature = "rewrite")]
+const OCI_LAYOUT: &str = r#"{"imageLayoutVersion":"1.0.0"}"#;
+#[cfg(feature = "rewrite")]
+const DIR_VERSION: &str = "Directory Transport Version: 1.1\n";
+
+/// The hex part of a `sha256:` digest. Digests come from untrusted
+/// manifests and become file names, so anything else is rejected.
+pub(crate) fn digest_hex(digest: &str) -> Result<&str> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => Ok(hex),
+        _ => Err(DedupeError::Manifest(format!(
+            "unsupported digest {}",
+            digest
+        ))),
+    }
+}
+
+/// Whether a manifest document is an index or manifest list rather than a
+/// single image.
+pub(crate) fn is_index(document: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(document)
+        .is_ok_and(|value| value.get("manifests").is_some())
+}
+
+/// Docker platform name of the architecture we run on
+fn host_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        other => other,
+    }
+}
+
+/// The linux image for this machine's architecture, or the only entry.
+pub(crate) fn select_platform(index: &ImageIndex) -> Result<&Descriptor> {
+    let architecture = host_architecture();
+    index
+        .manifests
+        .iter()
+        .find(|d| {
+            d.platform
+                .as_ref()
+                .is_some_and(|p| p.os == "linux" && p.architecture == architecture)
+        })
+        .or(match index.manifests.as_slice() {
+            [only] => Some(only),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            DedupeError::NotAnImage(format!("no linux/{} image in the index", architecture))
+        })
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| DedupeError::Manifest(format!("{}: {}", path.display(), e)))?;
+    serde_json::from_str(&contents)
+        .map_err(|e| DedupeError::Manifest(format!("{}: {}", path.display(), e)))
+}
+
+/// Lays out the blobs of `manifest` as an unpacked `docker save` image in
+/// `dst`. `fetch` puts one blob at the path it is given and returns its
+/// size; the total is returned.
+pub(crate) fn import(
+    manifest: &ImageManifest,
+    repo_tag: Option<String>,
+    dst: &Path,
+    mut fetch: impl FnMut(&Descriptor, &Path) -> Result<u64>,
+) -> Result<u64> {
+    fs::create_dir_all(dst.join("blobs/sha256"))?;
+    let mut bytes = 0;
+    let mut blob = |descriptor: &Descriptor| -> Result<String> {
+        cancel::check()?;
+        let path = format!("blobs/sha256/{}", digest_hex(&descriptor.digest)?);
+        // An image may list the same layer twice
+        if !dst.join(&path).exists() {
+            bytes += fetch(descriptor, &dst.join(&path))?;
+        }
+        Ok(path)
+    };
+
+    let config = blob(&manifest.config)?;
+    let mut layers = Vec::new();
+    let mut layer_sources = BTreeMap::new();
+    for (index, layer) in manifest.layers.iter().enumerate() {
+        if !layer.is_tar_layer() {
+            // Keyed by diff_id, which only the config knows
+            let diff_ids = &DockerConfig::from_file(&dst.join(&config))?.rootfs.diff_ids;
+            let diff_id = diff_ids.get(index).ok_or_else(|| {
+                DedupeError::Config(format!(
+                    "no diff_id for {} layer {}",
+                    layer.media_type, index
+                ))
+            })?;
+            layer_sources.insert(diff_id.clone(), layer.clone());
+        } else if layer.media_type.ends_with("zstd") {
+            return Err(DedupeError::UnsupportedCompression(format!(
+                "{} is {}",
+                layer.digest, layer.media_type
+            )));
+        }
+        layers.push(blob(layer)?);
+    }
+    Manifest {
+        config,
+        repo_tags: repo_tag.into_iter().collect(),
+        layers,
+        layer_sources,
+    }
+    .write_to_file(&dst.join("manifest.json"))?;
+    Ok(bytes)
+}
+
+fn link_blob(src: &Path, dst: &Path) -> Result<u64> {
+    link_or_copy(src, dst)?;
+    Ok(fs::metadata(dst)?.len())
+}
+
+fn ref_name(descriptor: &Descriptor) -> Option<&str> {
+    descriptor
+        .annotations
+        .as_ref()?
+        .get(REF_NAME)
+        .map(String::as_str)
+}
+
+/// Whether `descriptor` in the OCI layout at `root` is an artifact
+/// attached to another manifest.
+fn is_referrer(root: &Path, descriptor: &Descriptor) -> bool {
+    if descriptor.artifact_type.is_some() {
+        return true;
+    }
+    let Ok(path) = digest_hex(&descriptor.digest).map(|hex| root.join("blobs/sha256").join(hex))
+    else {
+        return false;
+    };
+    fs::read(path)
+        .ok()
+        .and_then(|document| serde_json::from_slice::<serde_json::Value>(&document).ok())
+        .is_some_and(|document| document.get("subject").is_some())
+}
+
+/// Finds the image named `tag` in an OCI layout, or its only image.
+fn resolve_oci(root: &Path, tag: Option<&str>) -> Result<(String, ImageManifest)> {
+    let index: ImageIndex = read_json(&root.join("index.json"))?;
+    // Artifacts attached to the image, like signatures, aren't images
+    let images: Vec<&Descriptor> = index
+        .manifests
+        .iter()
+        .filter(|d| !is_referrer(root, d))
+        .collect();
+    let descriptor = match (tag, images.as_slice()) {
+        (Some(tag), manifests) => manifests
+            .iter()
+            .find(|d| ref_name(d) == Some(tag))
+            .ok_or_else(|| {
+                DedupeError::NotAnImage(format!("no image tagged {} in {}", tag, root.display()))
+            })?,
+        (None, [only]) => only,
+        (None, manifests) => {
+            return Err(DedupeError::NotAnImage(format!(
+                "{} holds {} images, pick one with oci:{}:<tag>",
+                root.display(),
+                manifests.len(),
+                root.display()
+            )));
+        }
+    };
+
+    let mut digest = descriptor.digest.clone();
+    // A multi-platform image points at a nested index
+    for _ in 0..=MAX_INDEX_DEPTH {
+        let document = fs::read_to_string(root.join("blobs/sha256").join(digest_hex(&digest)?))
+            .map_err(|e| DedupeError::Manifest(format!("{}: {}", digest, e)))?;
+        if !is_index(&document) {
+            return Ok((digest, document.parse()?));
+        }
+        let index: ImageIndex = document.parse()?;
+        digest = select_platform(&index)?.digest.clone();
+    }
+    Err(DedupeError::Manifest(format!(
+        "{} nests indexes too deep",
+        root.display()
+    )))
+}
+
+/// Digest of the image named `tag` in the OCI layout at `root`.
+#[cfg(feature = "rewrite")]
+pub(crate) fn oci_digest(root: &Path, tag: Option<&str>) -> Result<String> {
+    Ok(resolve_oci(root, tag)?.0)
+}
+
+/// A blob of the OCI layout at `root`, e.g. a manifest.
+#[cfg(feature = "rewrite")]
+pub(crate) fn oci_blob(root: &Path, digest: &str) -> Result<Vec<u8>> {
+    let path = root.join("blobs/sha256").join(digest_hex(digest)?);
+    fs::read(&path).map_err(|e| DedupeError::Manifest(format!("{}: {}", path.display(), e)))
+}
+
+/// The manifests index.json lists whose subject is `digest`, with the
+/// artifact type filled in as the referrers API would.
+#[cfg(feature = "rewrite")]
+pub(crate) fn oci_referrers(root: &Path, digest: &str) -> Result<Vec<Descriptor>> {
+    let index: ImageIndex = read_json(&root.join("index.json"))?;
+    let mut referrers = Vec::new();
+    for mut descriptor in index.manifests {
+        let document: serde_json::Value =
+            serde_json::from_slice(&oci_blob(root, &descriptor.digest)?)?;
+        if document["subject"]["digest"].as_str() != Some(digest) {
+            continue;
+        }
+        if descriptor.artifact_type.is_none() {
+            descriptor.artifact_type = document["artifactType"]
+                .as_str()
+                .or(document["config"]["mediaType"].as_str())
+                .map(str::to_string);
+        }
+        referrers.push(descriptor);
+    }
+    Ok(referrers)
+}
+
+/// Adds an artifact manifest and its blobs to the OCI layout at `root`,
+/// listed in index.json without a tag.
+#[cfg(feature = "rewrite")]
+pub(crate) fn add_oci_artifact(
+    root: &Path,
+    descriptor: &Descriptor,
+    manifest: &[u8],
+    blobs: &[(Descriptor, PathBuf)],
+) -> Result<()> {
+    let blobs_dir = root.join("blobs/sha256");
+    for (blob, path) in blobs {
+        let dst = blobs_dir.join(digest_hex(&blob.digest)?);
+        if !dst.exists() {
+            link_or_copy(path, &dst)?;
+        }
+    }
+    fs::write(blobs_dir.join(digest_hex(&descriptor.digest)?), manifest)?;
+    let index_path = root.join("index.json");
+    let mut index: ImageIndex = read_json(&index_path)?;
+    index.manifests.retain(|d| d.digest != descriptor.digest);
+    index.manifests.push(descriptor.clone());
+    write_atomic(&index_path, &serde_json::to_vec_pretty(&index)?)
+}
+
+/// Loads an image from an OCI layout.
+pub(crate) fn load_oci(
+    root: &Path,
+    tag: Option<&str>,
+    options: AnalyzerOptions,
+) -> Result<Analyzer> {
+    let (digest, manifest) = resolve_oci(root, tag)?;
+    debug!("Loading {} from {}", digest, root.display());
+    let blobs = root.join("blobs/sha256");
+    let source = format!("oci:{}@{}", root.display(), digest);
+    Analyzer::load_with(&source, options, |dst| {
+        import(&manifest, None, dst, |descriptor, path| {
+            link_blob(&blobs.join(digest_hex(&descriptor.digest)?), path)
+        })
+    })
+}
+
+/// Loads an image from a skopeo `dir:` layout.
+pub(crate) fn load_dir(root: &Path, options: AnalyzerOptions) -> Result<Analyzer> {
+    let manifest_path = root.join("manifest.json");
+    let manifest: ImageManifest = read_json(&manifest_path)?;
+    let metadata = fs::metadata(&manifest_path)?;
+    let source = format!(
+        "dir:{}:{}:{:?}",
+        root.display(),
+        metadata.len(),
+        metadata.modified().ok()
+    );
+    Analyzer::load_with(&source, options, |dst| {
+        import(&manifest, None, dst, |descriptor, path| {
+            link_blob(&root.join(digest_hex(&descriptor.digest)?), path)
+        })
+    })
+}
+
+/// Which family of media types an exported manifest uses
+#[cfg(feature = "rewrite")]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Flavor {
+    Oci,
+    Docker,
+}
+
+/// An unpacked `docker save` image described as a distribution manifest.
+#[cfg(feature = "rewrite")]
+pub(crate) struct Exported {
+    pub manifest_json: Vec<u8>,
+    pub platform: Platform,
+    /// Every blob, config first, with the file holding it
+    pub blobs: Vec<(Descriptor, PathBuf)>,
+}
+
+/// The digest of a staged blob, named by it in `blobs/sha256` when the
+/// image was staged, which spares hashing it again.
+#[cfg(feature = "rewrite")]
+fn staged_digest(path: &Path) -> Option<String> {
+    let hex = path.file_name()?.to_str()?;
+    let in_blobs = path.parent()?.ends_with("blobs/sha256");
+    let is_hex = hex.len() == 64 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    (in_blobs && is_hex).then(|| format!("sha256:{}", hex))
+}
+
+#[cfg(feature = "rewrite")]
+fn describe(media_type: &str, path: &Path) -> Result<Descriptor> {
+    let (digest, size) = match staged_digest(path) {
+        Some(digest) => (digest, fs::metadata(path)?.len()),
+        None => sha256_file(path)?,
+    };
+    Ok(Descriptor {
+        media_type: media_type.to_string(),
+        digest,
+        size,
+        artifact_type: None,
+        platform: None,
+        annotations: None,
+    })
+}
+
+#[cfg(feature = "rewrite")]
+pub(crate) fn export(staged: &Path, flavor: Flavor) -> Result<Exported> {
+    let manifest = Manifest::from_file(&staged.join("manifest.json"))?;
+    let config_path = staged.join(&manifest.config);
+    let config = DockerConfig::from_file(&config_path)?;
+    let (manifest_type, config_type, layer_type, gzip_layer_type) = match flavor {
+        Flavor::Oci => (OCI_MANIFEST, OCI_CONFIG, OCI_LAYER, OCI_LAYER_GZIP),
+        Flavor::Docker => (
+            DOCKER_MANIFEST,
+            DOCKER_CONFIG,
+            DOCKER_LAYER,
+            DOCKER_LAYER_GZIP,
+        ),
+    };
+
+    let config_descriptor = describe(config_type, &config_path)?;
+    let mut blobs = vec![(config_descriptor.clone(), config_path)];
+    let mut layers = Vec::new();
+    for (index, layer) in manifest.layers.iter().enumerate() {
+        cancel::check()?;
+        let path = staged.join(layer);
+        let media_type = if LayerCompression::of_file(&path)? == LayerCompression::Gzip {
+            gzip_layer_type
+        } else {
+            layer_type
+        };
+        let mut descriptor = describe(media_type, &path)?;
+        // Passed through untouched, so keep what the keys need
+        let source =
+            (config.rootfs.diff_ids.get(index)).and_then(|d| manifest.layer_sources.get(d));
+        if let Some(source) = source {
+            descriptor.media_type = source.media_type.clone();
+            descriptor.annotations = source.annotations.clone();
+        }
+        layers.push(descriptor.clone());
+        blobs.push((descriptor, path));
+    }
+    let image_manifest = ImageManifest {
+        schema_version: 2,
+        media_type: Some(manifest_type.to_string()),
+        config: config_descriptor,
+        layers,
+    };
+    Ok(Exported {
+        manifest_json: serde_json::to_vec_pretty(&image_manifest)?,
+        platform: Platform {
+            architecture: config.architecture,
+            os: config.os,
+            variant: None,
+        },
+        blobs,
+    })
+}
+
+/// Writes `contents` next to `path` and renames it into place.
+#[cfg(feature = "rewrite")]
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let partial = path.with_extension("partial");
+    fs::write(&partial, contents)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// Adds the staged image to the OCI layout at `root`, creating it if
+/// needed. Other images in the layout are kept; one with the same tag is
+/// replaced. Returns the bytes written and the manifest digest.
+#[cfg(feature = "rewrite")]
+pub(crate) fn write_oci(staged: &Path, root: &Path, tag: Option<&str>) -> Result<(u64, String)> {
+    let exported = export(staged, Flavor::Oci)?;
+    let blobs_dir = root.join("blobs/sha256");
+    fs::create_dir_all(&blobs_dir)?;
+
+    let mut bytes = 0;
+    for (descriptor, path) in &exported.blobs {
+        let dst = blobs_dir.join(digest_hex(&descriptor.digest)?);
+        if !dst.exists() {
+            link_or_copy(path, &dst)?;
+            bytes += descriptor.size;
+        }
+    }
+    let digest = sha256_digest(&exported.manifest_json);
+    fs::write(
+        blobs_dir.join(digest_hex(&digest)?),
+        &exported.manifest_json,
+    )?;
+    fs::write(root.join("oci-layout"), OCI_LAYOUT)?;
+
+    let index_path = root.join("index.json");
+    let mut index = if index_path.exists() {
+        read_json(&index_path)?
+    } else {
+        ImageIndex {
+            schema_version: 2,
+            media_type: Some(OCI_INDEX.to_string()),
+            manifests: Vec::new(),
+        }
+    };
+    index.manifests.retain(|d| ref_name(d) != tag);
+    index.manifests.push(Descriptor {
+        media_type: OCI_MANIFEST.to_string(),
+        digest: digest.clone(),
+        size: exported.manifest_json.len() as u64,
+        artifact_type: None,
+        platform: Some(exported.platform),
+        annotations: tag.map(|tag| HashMap::from([(REF_NAME.to_string(), tag.to_string())])),
+    });
+    write_atomic(&index_path, &serde_json::to_vec_pretty(&index)?)?;
+    Ok((bytes + exported.manifest_json.len() as u64, digest))
+}
+
+/// `name` as containerd stores it: `app:1` is `docker.io/library/app:1`,
+/// and a name without a tag is `:latest`.
+#[cfg(feature = "rewrite")]
+fn containerd_name(name: &str) -> String {
+    let mut full = match name.split_once('/') {
+        Some((host, _)) if host.contains(['.', ':']) || host == "localhost" => name.to_string(),
+        Some(_) => format!("docker.io/{}", name),
+        None => format!("docker.io/library/{}", name),
+    };
+    let last = full.rsplit('/').next().unwrap_or_default();
+    if !last.contains([':', '@']) {
+        full.push_str(":latest");
+    }
+    full
+}
+
+/// Makes the staged `docker save` image an OCI layout as well, as newer
+/// `docker save` versions do, so the packed archive imports with
+/// `ctr images import` and `nerdctl load` as an OCI image. The blobs are
+/// already where a layout wants them, bar a config named `<hex>.json`;
+/// the index names the image by each of the manifest's `RepoTags`.
+#[cfg(feature = "rewrite")]
+pub(crate) fn add_oci_index(staged: &Path) -> Result<()> {
+    let repo_tags = Manifest::from_file(&staged.join("manifest.json"))?.repo_tags;
+    let exported = export(staged, Flavor::Oci)?;
+    let blobs_dir = staged.join("blobs/sha256");
+    fs::create_dir_all(&blobs_dir)?;
+    for (descriptor, path) in &exported.blobs {
+        let dst = blobs_dir.join(digest_hex(&descriptor.digest)?);
+        if !dst.exists() {
+            link_or_copy(path, &dst)?;
+        }
+    }
+    let digest = sha256_digest(&exported.manifest_json);
+    fs::write(
+        blobs_dir.join(digest_hex(&digest)?),
+        &exported.manifest_json,
+    )?;
+    fs::write(staged.join("oci-layout"), OCI_LAYOUT)?;
+
+    let descriptor = |annotations| Descriptor {
+        media_type: OCI_MANIFEST.to_string(),
+        digest: digest.clone(),
+        size: exported.manifest_json.len() as u64,
+        artifact_type: None,
+        platform: Some(exported.platform.clone()),
+        annotations,
+    };
+    let mut manifests: Vec<Descriptor> = repo_tags
+        .iter()
+        .map(|name| {
+            let full = containerd_name(name);
+            let tag = full.rsplit_once(':').map_or("latest", |(_, tag)| tag);
+            descriptor(Some(HashMap::from([
+                (CONTAINERD_NAME.to_string(), full.clone()),
+                (REF_NAME.to_string(), tag.to_string()),
+            ])))
+        })
+        .collect();
+    if manifests.is_empty() {
+        manifests.push(descriptor(None));
+    }
+    let index = ImageIndex {
+        schema_version: 2,
+        media_type: Some(OCI_INDEX.to_string()),
+        manifests,
+    };
+    fs::write(
+        staged.join("index.json"),
+        serde_json::to_vec_pretty(&index)?,
+    )?;
+    Ok(())
+}
+
+/// Writes the staged image as a skopeo `dir:` layout. Returns the bytes
+/// written and the manifest digest.
+#[cfg(feature = "rewrite")]
+pub(crate) fn write_dir(staged: &Path, root: &Path) -> Result<(u64, String)> {
+    let exported = export(staged, Flavor::Docker)?;
+    fs::create_dir_all(root)?;
+
+    let mut bytes = 0;
+    for (descriptor, path) in &exported.blobs {
+        let dst = root.join(digest_hex(&descriptor.digest)?);
+        if !dst.exists() {
+            link_or_copy(path, &dst)?;
+            bytes += descriptor.size;
+        }
+    }
+    write_atomic(&root.join("manifest.json"), &exported.manifest_json)?;
+    fs::write(root.join("version"), DIR_VERSION)?;
+    Ok((
+        bytes + exported.manifest_json.len() as u64,
+        sha256_digest(&exported.manifest_json),
+    ))
+}
@@ -0,0 +1,192 @@
+//! Artifacts attached to an image through the OCI `subject` field, such as
+//! signatures, SBOMs and attestations. They name one manifest digest, so a
+//! rewritten image is left without them: they can be listed for
+//! regenerating, or copied over marked stale.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, info};
+
+#[cfg(feature = "network")]
+use super::registry::Client;
+use super::{ImageRef, layout};
+use crate::analyzer::rewrite::LABEL_PREFIX;
+use crate::digest_writer::sha256_digest;
+use crate::error::{DedupeError, Result};
+use crate::schemas::{Descriptor, ImageManifest};
+
+/// Where referrers are read from and written to.
+enum Store<'a> {
+    Oci(&'a Path),
+    #[cfg(feature = "network")]
+    Registry(Client),
+}
+
+impl Store<'_> {
+    fn referrers(&self, digest: &str) -> Result<Vec<Descriptor>> {
+        match self {
+            Store::Oci(root) => layout::oci_referrers(root, digest),
+            #[cfg(feature = "network")]
+            Store::Registry(client) => client.referrers(digest),
+        }
+    }
+
+    fn manifest(&self, digest: &str) -> Result<Vec<u8>> {
+        match self {
+            Store::Oci(root) => layout::oci_blob(root, digest),
+            #[cfg(feature = "network")]
+            Store::Registry(client) => Ok(client.manifest_document(digest)?.1),
+        }
+    }
+
+    fn fetch_blob(&self, descriptor: &Descriptor, path: &Path) -> Result<()> {
+        match self {
+            Store::Oci(root) => {
+                fs::write(path, layout::oci_blob(root, &descriptor.digest)?)?;
+                Ok(())
+            }
+            #[cfg(feature = "network")]
+            Store::Registry(client) => client.download(descriptor, path).map(|_| ()),
+        }
+    }
+
+    #[cfg_attr(not(feature = "network"), allow(unused_variables))]
+    fn put(
+        &self,
+        subject: &str,
+        descriptor: &Descriptor,
+        manifest: &[u8],
+        blobs: &[(Descriptor, PathBuf)],
+    ) -> Result<()> {
+        match self {
+            Store::Oci(root) => layout::add_oci_artifact(root, descriptor, manifest, blobs),
+            #[cfg(feature = "network")]
+            Store::Registry(client) => {
+                for (blob, path) in blobs {
+                    client.put_blob(blob, path)?;
+                }
+                client.put_referrer(subject, descriptor, manifest)
+            }
+        }
+    }
+}
+
+impl ImageRef {
+    /// Opens the store behind this reference, with `actions` on registries,
+    /// and resolves the digest of the image it names. None for archives and
+    /// `dir:` layouts, which can't hold referrers.
+    #[cfg_attr(not(feature = "network"), allow(unused_variables))]
+    fn referrer_store(&self, actions: &str) -> Result<Option<(Store<'_>, String)>> {
+        match self {
+            ImageRef::Oci { path, tag } => Ok(Some((
+                Store::Oci(path),
+                layout::oci_digest(path, tag.as_deref())?,
+            ))),
+            #[cfg(feature = "network")]
+            ImageRef::Docker(reference) => {
+                let client = Client::connect(&reference.parse()?, actions)?;
+                let (digest, _) = client.manifest()?;
+                Ok(Some((Store::Registry(client), digest)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The artifacts attached to the image this names, found through the
+    /// referrers API on registries and index.json in OCI layouts. Other
+    /// transports have none.
+    pub fn referrers(&self) -> Result<Vec<Descriptor>> {
+        let Some((store, digest)) = self.referrer_store("pull")? else {
+            return Ok(Vec::new());
+        };
+        let referrers = store.referrers(&digest)?;
+        debug!("{} has {} referrers", self, referrers.len());
+        Ok(referrers)
+    }
+
+    /// Copies the artifacts attached to this image to `output`, attached to
+    /// the manifest `digest` written there instead. Signatures and
+    /// attestations there don't hold for the new image, so each copy is
+    /// annotated `org.dedupe.stale` and `org.dedupe.original-subject`.
+    /// Returns the referrers copied.
+    pub fn copy_referrers(&self, output: &ImageRef, digest: &str) -> Result<Vec<Descriptor>> {
+        let Some((source, original)) = self.referrer_store("pull")? else {
+            return Ok(Vec::new());
+        };
+        let referrers = source.referrers(&original)?;
+        if referrers.is_empty() {
+            return Ok(referrers);
+        }
+        let Some((target, _)) = output.referrer_store("pull,push")? else {
+            return Err(DedupeError::InvalidOption(format!(
+                "{} can't hold referrers, list them instead",
+                output
+            )));
+        };
+        let subject_manifest = target.manifest(digest)?;
+        let subject_type =
+            serde_json::from_slice::<serde_json::Value>(&subject_manifest)?["mediaType"]
+                .as_str()
+                .unwrap_or(layout::OCI_MANIFEST)
+                .to_string();
+        let subject = Descriptor {
+            media_type: subject_type,
+            digest: digest.to_string(),
+            size: subject_manifest.len() as u64,
+            artifact_type: None,
+            platform: None,
+            annotations: None,
+        };
+        let stale = HashMap::from([
+            (format!("{}.stale", LABEL_PREFIX), "true".to_string()),
+            (format!("{}.original-subject", LABEL_PREFIX), original),
+        ]);
+
+        let tmp_dir = tempfile::tempdir()?;
+        for referrer in &referrers {
+            let document = source.manifest(&referrer.digest)?;
+            let manifest: ImageManifest = serde_json::from_slice(&document)?;
+            let mut blobs = Vec::new();
+            for blob in std::iter::once(&manifest.config).chain(&manifest.layers) {
+                let path = tmp_dir.path().join(layout::digest_hex(&blob.digest)?);
+                source.fetch_blob(blob, &path)?;
+                blobs.push((blob.clone(), path));
+            }
+            // Edit the document itself, so fields we don't model survive
+            let mut document: serde_json::Value = serde_json::from_slice(&document)?;
+            document["subject"] = serde_json::to_value(&subject)?;
+            let annotations = document
+                .as_object_mut()
+                .and_then(|document| {
+                    document
+                        .entry("annotations")
+                        .or_insert_with(|| serde_json::json!({}))
+                        .as_object_mut()
+                })
+                .ok_or_else(|| {
+                    DedupeError::Manifest(format!("{} isn't a manifest", referrer.digest))
+                })?;
+            for (key, value) in &stale {
+                annotations.insert(key.clone(), value.clone().into());
+            }
+            let copy = serde_json::to_vec_pretty(&document)?;
+            let mut descriptor = Descriptor {
+                digest: sha256_digest(&copy),
+                size: copy.len() as u64,
+                ..referrer.clone()
+            };
+            descriptor
+                .annotations
+                .get_or_insert_with(HashMap::new)
+                .extend(stale.clone());
+            target.put(digest, &descriptor, &copy, &blobs)?;
+            info!(
+                "Copied referrer {} to {} as {}, marked stale",
+                referrer.digest, output, descriptor.digest
+            );
+        }
+        Ok(referrers)
+    }
+}
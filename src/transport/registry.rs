@@ -0,0 +1,683 @@
+//! `docker://` references: pulling from and pushing to registries that speak
+//! the distribution API, behind the `network` feature.
+//!
+//! Requests are anonymous unless `~/.docker/config.json` (or
+//! `$DOCKER_CONFIG/config.json`) has an `auth` entry for the registry;
+//! credential helpers aren't consulted. Bearer tokens are negotiated from
+//! the registry's `WWW-Authenticate` challenge. `localhost` registries are
+//! spoken to over plain HTTP, everything else over HTTPS.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use tracing::{debug, info};
+use ureq::Agent;
+use ureq::http::Response;
+
+use super::layout::{
+    self, DOCKER_MANIFEST, DOCKER_MANIFEST_LIST, Flavor, MAX_INDEX_DEPTH, OCI_INDEX, OCI_MANIFEST,
+    digest_hex,
+};
+use crate::analyzer::Analyzer;
+use crate::cancel::{self, CancellableReader};
+use crate::checkpoint::Checkpoint;
+use crate::digest_writer::{DigestWriter, sha256_digest};
+use crate::disk_space;
+use crate::error::{DedupeError, IoResultExt, Result};
+use crate::options::AnalyzerOptions;
+use crate::schemas::{Descriptor, ImageIndex, ImageManifest};
+
+const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_API: &str = "registry-1.docker.io";
+
+/// A parsed `docker://` reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    /// Registry host, with port. Docker Hub is `docker.io`.
+    pub registry: String,
+    /// Repository path, with Docker Hub's implicit `library/` filled in
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+fn invalid(reference: &str, why: &str) -> DedupeError {
+    DedupeError::InvalidOption(format!("docker://{}: {}", reference, why))
+}
+
+impl FromStr for Reference {
+    type Err = DedupeError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, digest) = match s.split_once('@') {
+            Some((name, digest)) => {
+                digest_hex(digest).map_err(|_| invalid(s, "unsupported digest"))?;
+                (name, Some(digest.to_string()))
+            }
+            None => (s, None),
+        };
+        // A tag comes after the last slash, a registry port before it
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, Some(tag.to_string())),
+            _ => (name, None),
+        };
+        // Like docker, the first component is a registry only if it looks
+        // like a host name
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, path)) if host.contains(['.', ':']) || host == "localhost" => {
+                (host.to_string(), path.to_string())
+            }
+            _ => (DOCKER_HUB.to_string(), name.to_string()),
+        };
+        let repository = if registry == DOCKER_HUB && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository
+        };
+
+        let valid_repository = !repository.is_empty()
+            && repository.split('/').all(|part| {
+                !part.is_empty()
+                    && part.bytes().all(|b| {
+                        b.is_ascii_lowercase() || b.is_ascii_digit() || b"._-".contains(&b)
+                    })
+            });
+        if !valid_repository {
+            return Err(invalid(s, "invalid repository name"));
+        }
+        if let Some(tag) = &tag {
+            let valid_tag = tag.len() <= 128
+                && tag
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b));
+            if !valid_tag {
+                return Err(invalid(s, "invalid tag"));
+            }
+        }
+        Ok(Self {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+impl Reference {
+    /// What goes after `/manifests/`: the digest, else the tag, else `latest`
+    pub fn manifest_reference(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or(self.tag.as_deref())
+            .unwrap_or("latest")
+    }
+
+    fn origin(&self) -> String {
+        let host = if self.registry == DOCKER_HUB {
+            DOCKER_HUB_API
+        } else {
+            &self.registry
+        };
+        let local = ["localhost", "127.0.0.1", "[::1]"]
+            .iter()
+            .any(|local| host == *local || host.starts_with(&format!("{}:", local)));
+        let scheme = if local { "http" } else { "https" };
+        format!("{}://{}", scheme, host)
+    }
+}
+
+/// The tag listing the referrers of `digest` on registries without the
+/// referrers API.
+fn referrers_tag(digest: &str) -> Result<String> {
+    Ok(format!("sha256-{}", digest_hex(digest)?))
+}
+
+/// The base64 `user:password` docker stores for `registry`, if any.
+fn stored_credentials(registry: &str) -> Option<String> {
+    let dir = env::var_os("DOCKER_CONFIG")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".docker")))?;
+    let config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(dir.join("config.json")).ok()?).ok()?;
+    let auths = config.get("auths")?;
+    let keys = if registry == DOCKER_HUB {
+        vec![
+            "https://index.docker.io/v1/".to_string(),
+            DOCKER_HUB.to_string(),
+        ]
+    } else {
+        vec![registry.to_string(), format!("https://{}", registry)]
+    };
+    keys.iter()
+        .find_map(|key| auths.get(key)?.get("auth")?.as_str())
+        .map(str::to_string)
+}
+
+/// Splits `Bearer realm="...",service="..."` into the scheme and its
+/// parameters.
+fn parse_challenge(header: &str) -> (String, HashMap<String, String>) {
+    let (scheme, mut rest) = header.trim().split_once(' ').unwrap_or((header, ""));
+    let mut params = HashMap::new();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key
+            .trim()
+            .trim_start_matches(',')
+            .trim()
+            .to_ascii_lowercase();
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        params.insert(key, value.to_string());
+        rest = after;
+    }
+    (scheme.to_ascii_lowercase(), params)
+}
+
+pub(crate) struct Client {
+    agent: Agent,
+    reference: Reference,
+    origin: String,
+    /// `<origin>/v2/<repository>`
+    base: String,
+    authorization: Option<String>,
+}
+
+fn request_error(url: &str, e: ureq::Error) -> DedupeError {
+    DedupeError::Registry(format!("{}: {}", url, e))
+}
+
+fn status_error(url: &str, mut response: Response<ureq::Body>) -> DedupeError {
+    let body = response.body_mut().read_to_string().unwrap_or_default();
+    DedupeError::Registry(format!(
+        "{} answered {}: {}",
+        url,
+        response.status(),
+        body.trim()
+    ))
+}
+
+impl Client {
+    /// Connects to the registry of `reference` and negotiates credentials
+    /// for `actions` (`pull` or `pull,push`) on its repository.
+    pub(crate) fn connect(reference: &Reference, actions: &str) -> Result<Self> {
+        let agent: Agent = Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .into();
+        let origin = reference.origin();
+        let mut client = Self {
+            agent,
+            base: format!("{}/v2/{}", origin, reference.repository),
+            origin,
+            reference: reference.clone(),
+            authorization: None,
+        };
+        client.authenticate(actions)?;
+        Ok(client)
+    }
+
+    fn authenticate(&mut self, actions: &str) -> Result<()> {
+        let url = format!("{}/v2/", self.origin);
+        let response = self
+            .agent
+            .get(&url)
+            .call()
+            .map_err(|e| request_error(&url, e))?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        if response.status() != 401 {
+            return Err(status_error(&url, response));
+        }
+        let challenge = response
+            .headers()
+            .get("www-authenticate")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let (scheme, params) = parse_challenge(challenge);
+        let credentials = stored_credentials(&self.reference.registry);
+        match scheme.as_str() {
+            "basic" => {
+                let credentials = credentials.ok_or_else(|| {
+                    DedupeError::Registry(format!(
+                        "{} needs credentials, run docker login {}",
+                        self.reference.registry, self.reference.registry
+                    ))
+                })?;
+                self.authorization = Some(format!("Basic {}", credentials));
+            }
+            "bearer" => {
+                let realm = params.get("realm").ok_or_else(|| {
+                    DedupeError::Registry(format!("no token realm in {:?}", challenge))
+                })?;
+                let scope = format!("repository:{}:{}", self.reference.repository, actions);
+                let mut request = self.agent.get(realm).query("scope", &scope);
+                if let Some(service) = params.get("service") {
+                    request = request.query("service", service);
+                }
+                if let Some(credentials) = &credentials {
+                    request = request.header("Authorization", format!("Basic {}", credentials));
+                }
+                let mut response = request.call().map_err(|e| request_error(realm, e))?;
+                if !response.status().is_success() {
+                    return Err(status_error(realm, response));
+                }
+                let body: serde_json::Value = serde_json::from_str(
+                    &response
+                        .body_mut()
+                        .read_to_string()
+                        .map_err(|e| request_error(realm, e))?,
+                )?;
+                let token = body
+                    .get("token")
+                    .or_else(|| body.get("access_token"))
+                    .and_then(|token| token.as_str())
+                    .ok_or_else(|| DedupeError::Registry(format!("{} sent no token", realm)))?;
+                self.authorization = Some(format!("Bearer {}", token));
+            }
+            _ => {
+                return Err(DedupeError::Registry(format!(
+                    "unsupported authentication {:?}",
+                    challenge
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn with_auth<B>(&self, request: ureq::RequestBuilder<B>) -> ureq::RequestBuilder<B> {
+        match &self.authorization {
+            Some(authorization) => request.header("Authorization", authorization),
+            None => request,
+        }
+    }
+
+    fn get(&self, url: &str, accept: &str) -> Result<Response<ureq::Body>> {
+        let response = self
+            .with_auth(self.agent.get(url).header("Accept", accept))
+            .call()
+            .map_err(|e| request_error(url, e))?;
+        if !response.status().is_success() {
+            return Err(status_error(url, response));
+        }
+        Ok(response)
+    }
+
+    /// Sends a GET (or HEAD) for `path` under the repository and returns
+    /// the response whatever its status.
+    #[cfg(feature = "proxy")]
+    pub(crate) fn forward(
+        &self,
+        head: bool,
+        path: &str,
+        accept: &str,
+    ) -> Result<Response<ureq::Body>> {
+        let url = format!("{}/{}", self.base, path);
+        let request = if head {
+            self.agent.head(&url)
+        } else {
+            self.agent.get(&url)
+        };
+        self.with_auth(request.header("Accept", accept))
+            .call()
+            .map_err(|e| request_error(&url, e))
+    }
+
+    /// Like [`Client::get`], with None for a 404.
+    fn get_optional(&self, url: &str, accept: &str) -> Result<Option<Response<ureq::Body>>> {
+        let response = self
+            .with_auth(self.agent.get(url).header("Accept", accept))
+            .call()
+            .map_err(|e| request_error(url, e))?;
+        match response.status().as_u16() {
+            404 => Ok(None),
+            _ if response.status().is_success() => Ok(Some(response)),
+            _ => Err(status_error(url, response)),
+        }
+    }
+
+    /// Fetches the image manifest of the reference, picking this
+    /// platform's image from an index. Returns it with its digest.
+    pub(crate) fn manifest(&self) -> Result<(String, ImageManifest)> {
+        self.resolve(self.reference.manifest_reference())
+    }
+
+    /// Like [`Client::manifest`], starting from `reference` in the same
+    /// repository.
+    pub(crate) fn resolve(&self, reference: &str) -> Result<(String, ImageManifest)> {
+        let accept = [
+            OCI_INDEX,
+            OCI_MANIFEST,
+            DOCKER_MANIFEST_LIST,
+            DOCKER_MANIFEST,
+        ]
+        .join(", ");
+        let mut reference = reference.to_string();
+        for _ in 0..=MAX_INDEX_DEPTH {
+            let url = format!("{}/manifests/{}", self.base, reference);
+            let document = self
+                .get(&url, &accept)?
+                .body_mut()
+                .read_to_string()
+                .map_err(|e| request_error(&url, e))?;
+            let digest = sha256_digest(document.as_bytes());
+            if reference.starts_with("sha256:") && reference != digest {
+                return Err(DedupeError::Registry(format!(
+                    "{} has digest {}",
+                    url, digest
+                )));
+            }
+            if layout::is_index(&document) {
+                let index: ImageIndex = document.parse()?;
+                reference = layout::select_platform(&index)?.digest.clone();
+                continue;
+            }
+            let manifest: ImageManifest = document.parse()?;
+            if manifest.schema_version != 2 {
+                return Err(DedupeError::Manifest(format!(
+                    "schema {} manifests aren't supported",
+                    manifest.schema_version
+                )));
+            }
+            return Ok((digest, manifest));
+        }
+        Err(DedupeError::Manifest(format!(
+            "{} nests indexes too deep",
+            self.reference
+        )))
+    }
+
+    /// Downloads a blob to `path`, checking its digest and size.
+    pub(crate) fn download(&self, descriptor: &Descriptor, path: &Path) -> Result<u64> {
+        let url = format!("{}/blobs/{}", self.base, descriptor.digest);
+        debug!("Downloading {}", url);
+        let body = self.get(&url, "*/*")?.into_body();
+        let partial = path.with_extension("partial");
+        let file = File::create(&partial)
+            .with_context(|| format!("Failed to create {}", partial.display()))?;
+        let mut writer = DigestWriter::new(BufWriter::new(file));
+        let size = io::copy(&mut CancellableReader::new(body.into_reader()), &mut writer)
+            .with_context(|| format!("Failed to download {}", url))?;
+        let (mut file, digest) = writer.finish();
+        file.flush()?;
+        if digest != descriptor.digest || size != descriptor.size {
+            let _ = fs::remove_file(&partial);
+            return Err(DedupeError::Registry(format!(
+                "{} sent {} bytes with digest {}, expected {} bytes",
+                url, size, digest, descriptor.size
+            )));
+        }
+        fs::rename(&partial, path)?;
+        Ok(size)
+    }
+
+    /// The manifest document `reference` names, as stored, with its media
+    /// type.
+    pub(crate) fn manifest_document(&self, reference: &str) -> Result<(String, Vec<u8>)> {
+        let url = format!("{}/manifests/{}", self.base, reference);
+        let accept = [OCI_MANIFEST, DOCKER_MANIFEST, OCI_INDEX].join(", ");
+        let mut response = self.get(&url, &accept)?;
+        let media_type = response
+            .headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(OCI_MANIFEST)
+            .to_string();
+        let document = response
+            .body_mut()
+            .read_to_vec()
+            .map_err(|e| request_error(&url, e))?;
+        Ok((media_type, document))
+    }
+
+    /// Manifests whose subject is `digest`, from the referrers API or, on
+    /// registries without one, the `sha256-<hex>` tag that stands in for it.
+    pub(crate) fn referrers(&self, digest: &str) -> Result<Vec<Descriptor>> {
+        let url = format!("{}/referrers/{}", self.base, digest);
+        let response = match self.get_optional(&url, OCI_INDEX)? {
+            Some(response) => Some(response),
+            None => {
+                let url = format!("{}/manifests/{}", self.base, referrers_tag(digest)?);
+                self.get_optional(&url, OCI_INDEX)?
+            }
+        };
+        let Some(mut response) = response else {
+            return Ok(Vec::new());
+        };
+        let document = response
+            .body_mut()
+            .read_to_string()
+            .map_err(|e| request_error(&url, e))?;
+        Ok(document.parse::<ImageIndex>()?.manifests)
+    }
+
+    /// Uploads a blob unless the registry has it. Returns the bytes sent.
+    pub(crate) fn put_blob(&self, descriptor: &Descriptor, path: &Path) -> Result<u64> {
+        if self.has_blob(&descriptor.digest)? {
+            debug!(
+                "{} already in {}",
+                descriptor.digest, self.reference.registry
+            );
+            return Ok(0);
+        }
+        self.upload(descriptor, path)?;
+        Ok(descriptor.size)
+    }
+
+    /// Pushes an artifact manifest by digest, listing it under the
+    /// `sha256-<hex>` tag of `subject` too when the registry didn't take
+    /// note of the subject itself.
+    pub(crate) fn put_referrer(
+        &self,
+        subject: &str,
+        descriptor: &Descriptor,
+        manifest: &[u8],
+    ) -> Result<()> {
+        if self.put_manifest(&descriptor.digest, &descriptor.media_type, manifest)? {
+            return Ok(());
+        }
+        let tag = referrers_tag(subject)?;
+        let url = format!("{}/manifests/{}", self.base, tag);
+        let mut index = match self.get_optional(&url, OCI_INDEX)? {
+            Some(mut response) => response
+                .body_mut()
+                .read_to_string()
+                .map_err(|e| request_error(&url, e))?
+                .parse()?,
+            None => ImageIndex {
+                schema_version: 2,
+                media_type: Some(OCI_INDEX.to_string()),
+                manifests: Vec::new(),
+            },
+        };
+        index.manifests.retain(|d| d.digest != descriptor.digest);
+        index.manifests.push(descriptor.clone());
+        self.put_manifest(&tag, OCI_INDEX, &serde_json::to_vec_pretty(&index)?)?;
+        Ok(())
+    }
+
+    fn has_blob(&self, digest: &str) -> Result<bool> {
+        let url = format!("{}/blobs/{}", self.base, digest);
+        let response = self
+            .with_auth(self.agent.head(&url))
+            .call()
+            .map_err(|e| request_error(&url, e))?;
+        match response.status().as_u16() {
+            200 => Ok(true),
+            404 => Ok(false),
+            _ => Err(status_error(&url, response)),
+        }
+    }
+
+    /// Monolithic upload: open an upload session, then PUT the whole blob.
+    fn upload(&self, descriptor: &Descriptor, path: &Path) -> Result<()> {
+        let url = format!("{}/blobs/uploads/", self.base);
+        let response = self
+            .with_auth(self.agent.post(&url))
+            .send_empty()
+            .map_err(|e| request_error(&url, e))?;
+        if response.status() != 202 {
+            return Err(status_error(&url, response));
+        }
+        let location = response
+            .headers()
+            .get("location")
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| DedupeError::Registry(format!("{} sent no upload location", url)))?;
+        let location = if location.starts_with('/') {
+            format!("{}{}", self.origin, location)
+        } else {
+            location.to_string()
+        };
+        let separator = if location.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}digest={}", location, separator, descriptor.digest);
+
+        let file = File::open(path)?;
+        let response = self
+            .with_auth(
+                self.agent
+                    .put(&url)
+                    .header("Content-Type", "application/octet-stream"),
+            )
+            .send(file)
+            .map_err(|e| request_error(&url, e))?;
+        if !response.status().is_success() {
+            return Err(status_error(&url, response));
+        }
+        Ok(())
+    }
+
+    /// Puts a manifest under `reference`, returning whether the registry
+    /// processed its subject, as registries with the referrers API say.
+    fn put_manifest(&self, reference: &str, media_type: &str, manifest: &[u8]) -> Result<bool> {
+        let url = format!("{}/manifests/{}", self.base, reference);
+        let response = self
+            .with_auth(self.agent.put(&url).header("Content-Type", media_type))
+            .send(manifest)
+            .map_err(|e| request_error(&url, e))?;
+        if !response.status().is_success() {
+            return Err(status_error(&url, response));
+        }
+        Ok(response.headers().contains_key("oci-subject"))
+    }
+}
+
+/// Pulls an image for analysis. Blobs are verified against their digests
+/// as they download; with a `work_dir` a finished pull of the same
+/// manifest is reused.
+pub fn pull(reference: &Reference, options: AnalyzerOptions) -> Result<Analyzer> {
+    let client = Client::connect(reference, "pull")?;
+    let (digest, manifest) = client.manifest()?;
+    info!("Pulling {} ({})", reference, digest);
+
+    let source = format!("docker://{}@{}", reference, digest);
+    let resuming = match &options.work_dir {
+        Some(work_dir) => Checkpoint::open(work_dir)?.is_extracted(&source)?,
+        None => false,
+    };
+    if !resuming {
+        let required = manifest.config.size + manifest.layers.iter().map(|l| l.size).sum::<u64>();
+        let space_dir = options
+            .work_dir
+            .clone()
+            .unwrap_or_else(|| options.temp_dir());
+        disk_space::ensure_available(&space_dir, required, "pulling the image")?;
+    }
+    let repo_tag = reference
+        .tag
+        .as_ref()
+        .map(|tag| format!("{}/{}:{}", reference.registry, reference.repository, tag));
+    Analyzer::load_with(&source, options, |dst| {
+        layout::import(&manifest, repo_tag, dst, |descriptor, path| {
+            client.download(descriptor, path)
+        })
+    })
+}
+
+/// Pushes the staged image to `reference`, skipping blobs the registry
+/// already has. Returns the bytes uploaded and the manifest digest.
+pub(crate) fn push(staged: &Path, reference: &Reference) -> Result<(u64, String)> {
+    if reference.digest.is_some() {
+        return Err(DedupeError::InvalidOption(format!(
+            "can't push to docker://{}, it names a digest",
+            reference
+        )));
+    }
+    let exported = layout::export(staged, Flavor::Docker)?;
+    let client = Client::connect(reference, "pull,push")?;
+    info!("Pushing {}", reference);
+
+    let mut bytes = 0;
+    for (descriptor, path) in &exported.blobs {
+        cancel::check()?;
+        bytes += client.put_blob(descriptor, path)?;
+    }
+    client.put_manifest(
+        reference.manifest_reference(),
+        DOCKER_MANIFEST,
+        &exported.manifest_json,
+    )?;
+    Ok((
+        bytes + exported.manifest_json.len() as u64,
+        sha256_digest(&exported.manifest_json),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_references() {
+        let parse = |s: &str| s.parse::<Reference>().unwrap();
+        let alpine = parse("alpine");
+        assert_eq!(alpine.registry, "docker.io");
+        assert_eq!(alpine.repository, "library/alpine");
+        assert_eq!(alpine.manifest_reference(), "latest");
+        assert_eq!(alpine.origin(), "https://registry-1.docker.io");
+
+        let local = parse("localhost:5000/team/app:v1.2");
+        assert_eq!(local.registry, "localhost:5000");
+        assert_eq!(local.repository, "team/app");
+        assert_eq!(local.tag.as_deref(), Some("v1.2"));
+        assert_eq!(local.origin(), "http://localhost:5000");
+
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        let pinned = parse(&format!("ghcr.io/o/app:v1@{}", digest));
+        assert_eq!(pinned.manifest_reference(), digest);
+        assert_eq!(pinned.to_string(), format!("ghcr.io/o/app:v1@{}", digest));
+
+        assert!("Alpine".parse::<Reference>().is_err());
+        assert!("alpine@sha256:xyz".parse::<Reference>().is_err());
+    }
+
+    #[test]
+    fn test_parse_challenges() {
+        let (scheme, params) = parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull""#,
+        );
+        assert_eq!(scheme, "bearer");
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/alpine:pull");
+    }
+}
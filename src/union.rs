@@ -0,0 +1,284 @@
+//! The merged filesystem a container sees: layers applied in order, each
+//! one's whiteouts and opaque directories hiding what lower layers put
+//! there.
+
+use std::collections::btree_map::Values;
+use std::collections::{BTreeMap, BTreeSet};
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::entries::{EntryInfo, EntryKind};
+use crate::error::{DedupeError, Result};
+use crate::paths;
+
+/// Space taken by the files below one directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirUsage {
+    /// Absolute, `/` for the root
+    pub path: String,
+    pub size: u64,
+    pub files: usize,
+    /// Layers the files come from, ascending
+    pub layers: Vec<usize>,
+}
+
+/// Rolls up file sizes into every directory at most `max_depth` levels
+/// below the root, in path order. Pass [`UnionView::iter`] for what a
+/// container sees, or one layer's entries for that layer alone.
+pub fn disk_usage<'a>(
+    entries: impl IntoIterator<Item = &'a EntryInfo>,
+    max_depth: usize,
+) -> Vec<DirUsage> {
+    let mut dirs: BTreeMap<String, DirUsage> = BTreeMap::new();
+    for entry in entries {
+        if entry.kind != EntryKind::File {
+            continue;
+        }
+        let path = key(&entry.path);
+        let parents = path.match_indices('/').map(|(i, _)| &path[..i]);
+        for dir in std::iter::once("").chain(parents).take(max_depth + 1) {
+            let usage = dirs.entry(dir.to_string()).or_insert_with(|| DirUsage {
+                path: format!("/{}", dir),
+                size: 0,
+                files: 0,
+                layers: Vec::new(),
+            });
+            usage.size += entry.size;
+            usage.files += 1;
+            if let Err(i) = usage.layers.binary_search(&entry.layer_index) {
+                usage.layers.insert(i, entry.layer_index);
+            }
+        }
+    }
+    dirs.into_values().collect()
+}
+
+/// Every visible path of the image with the entry that provides it.
+#[derive(Debug, Clone, Default)]
+pub struct UnionView {
+    /// By normalized path, without a trailing `/`
+    entries: BTreeMap<String, EntryInfo>,
+    /// Whiteouts and opaque directories that still hide something lower
+    /// than the first layer applied, which the view can't know about
+    whiteouts: BTreeSet<String>,
+    opaque_dirs: BTreeSet<String>,
+}
+
+/// Hops [`UnionView::resolve`] follows before giving up, like Linux's
+/// `MAXSYMLINKS`
+const MAX_SYMLINKS: usize = 40;
+
+/// Why [`UnionView::follow`] found no entry
+enum Unresolved {
+    TooDeep,
+    Missing,
+}
+
+fn key(path: &str) -> &str {
+    paths::normalize(path).trim_end_matches('/')
+}
+
+impl UnionView {
+    /// Merges entries listed in layer order, as [`crate::Analyzer::entries`]
+    /// yields them.
+    pub fn build(entries: impl IntoIterator<Item = Result<EntryInfo>>) -> Result<Self> {
+        let entries: Vec<EntryInfo> = entries.into_iter().collect::<Result<_>>()?;
+        let mut view = Self::default();
+        for (_, layer) in &entries.into_iter().chunk_by(|e| e.layer_index) {
+            view.apply_layer(layer.collect());
+        }
+        Ok(view)
+    }
+
+    fn remove_below(&mut self, dir: &str) {
+        let prefix = format!("{}/", dir);
+        let below = |set: &BTreeSet<String>| -> Vec<String> {
+            set.range(prefix.clone()..)
+                .take_while(|path| path.starts_with(&prefix))
+                .cloned()
+                .collect()
+        };
+        let hidden: Vec<String> = self
+            .entries
+            .range(prefix.clone()..)
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for path in hidden {
+            self.entries.remove(&path);
+        }
+        for path in below(&self.whiteouts) {
+            self.whiteouts.remove(&path);
+        }
+        for path in below(&self.opaque_dirs) {
+            self.opaque_dirs.remove(&path);
+        }
+    }
+
+    fn apply_layer(&mut self, layer: Vec<EntryInfo>) {
+        // Markers only hide lower layers, so apply them before the layer's
+        // own entries
+        for entry in &layer {
+            let path = key(&entry.path);
+            let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+            match entry.kind {
+                EntryKind::Opaque if dir.is_empty() => {
+                    self.entries.clear();
+                    self.whiteouts.clear();
+                    self.opaque_dirs.clear();
+                    self.opaque_dirs.insert(String::new());
+                }
+                EntryKind::Opaque => {
+                    self.remove_below(dir);
+                    self.opaque_dirs.insert(dir.to_string());
+                }
+                EntryKind::Whiteout => {
+                    let hidden = name.strip_prefix(".wh.").unwrap_or(name);
+                    let hidden = if dir.is_empty() {
+                        hidden.to_string()
+                    } else {
+                        format!("{}/{}", dir, hidden)
+                    };
+                    self.remove_below(&hidden);
+                    self.entries.remove(&hidden);
+                    self.opaque_dirs.remove(&hidden);
+                    self.whiteouts.insert(hidden);
+                }
+                _ => {}
+            }
+        }
+        for entry in layer {
+            if matches!(entry.kind, EntryKind::Whiteout | EntryKind::Opaque) {
+                continue;
+            }
+            let path = key(&entry.path).to_string();
+            if path.is_empty() {
+                continue;
+            }
+            // Anything but a directory replaces a lower directory whole
+            if entry.kind != EntryKind::Dir {
+                self.remove_below(&path);
+                self.opaque_dirs.remove(&path);
+                self.whiteouts.remove(&path);
+            } else if self.whiteouts.remove(&path) {
+                // A deleted directory created anew starts out empty
+                self.opaque_dirs.insert(path.clone());
+            }
+            self.entries.insert(path, entry);
+        }
+    }
+
+    /// The entry visible at `path`, if any. Links aren't followed.
+    pub fn get(&self, path: &str) -> Option<&EntryInfo> {
+        self.entries.get(key(path))
+    }
+
+    /// The entry a container opening `path` gets: symlinks are followed,
+    /// in the last component and in the directories leading to it, as are
+    /// hardlinks.
+    pub fn resolve(&self, path: &str) -> Result<&EntryInfo> {
+        self.follow(path).map_err(|unresolved| {
+            let why = match unresolved {
+                Unresolved::TooDeep => "too many levels of links",
+                Unresolved::Missing => "no such file in the image",
+            };
+            DedupeError::InvalidOption(format!("{}: {}", path, why))
+        })
+    }
+
+    /// Whether opening `path` would fail with `ELOOP`: its links form a
+    /// cycle or a chain longer than the kernel follows.
+    pub fn loops(&self, path: &str) -> bool {
+        matches!(self.follow(path), Err(Unresolved::TooDeep))
+    }
+
+    /// Puts `entry` at its path, replacing what the view had there, to see
+    /// the image as a planned rewrite will leave it.
+    pub(crate) fn replace(&mut self, entry: EntryInfo) {
+        self.entries.insert(key(&entry.path).to_string(), entry);
+    }
+
+    fn follow(&self, path: &str) -> Result<&EntryInfo, Unresolved> {
+        let mut pending = key(path).to_string();
+        let mut hops = 0;
+        'restart: loop {
+            let components: Vec<String> = pending
+                .split('/')
+                .filter(|c| !c.is_empty() && *c != ".")
+                .map(str::to_string)
+                .collect();
+            let mut resolved = String::new();
+            for (i, component) in components.iter().enumerate() {
+                if component == ".." {
+                    resolved.truncate(resolved.rfind('/').unwrap_or(0));
+                    continue;
+                }
+                let candidate = if resolved.is_empty() {
+                    component.clone()
+                } else {
+                    format!("{}/{}", resolved, component)
+                };
+                let link = self
+                    .entries
+                    .get(&candidate)
+                    .filter(|e| matches!(e.kind, EntryKind::Symlink | EntryKind::Hardlink));
+                if let Some(link) = link {
+                    hops += 1;
+                    if hops > MAX_SYMLINKS {
+                        return Err(Unresolved::TooDeep);
+                    }
+                    let target = link.link_target.as_deref().unwrap_or_default();
+                    // Hardlink targets are archive paths, symlinks relative
+                    // to their directory unless absolute
+                    let base = if link.kind == EntryKind::Hardlink || target.starts_with('/') {
+                        ""
+                    } else {
+                        &resolved
+                    };
+                    let rest = components[i + 1..].join("/");
+                    pending = [base, target, &rest].join("/");
+                    continue 'restart;
+                }
+                resolved = candidate;
+            }
+            return self.entries.get(&resolved).ok_or(Unresolved::Missing);
+        }
+    }
+
+    /// Whether the view's markers hide `path` in the layers below the ones
+    /// it was built from.
+    pub fn hides(&self, path: &str) -> bool {
+        let path = key(path);
+        let mut ancestors = path.match_indices('/').map(|(i, _)| &path[..i]);
+        self.opaque_dirs.contains("")
+            || self.whiteouts.contains(path)
+            || ancestors.any(|dir| self.whiteouts.contains(dir) || self.opaque_dirs.contains(dir))
+    }
+
+    /// Visible entries in path order
+    pub fn iter(&self) -> Values<'_, String, EntryInfo> {
+        self.entries.values()
+    }
+
+    /// Paths deleted from below the first layer applied, e.g. to carry
+    /// over when layers are squashed
+    pub fn whiteouts(&self) -> impl Iterator<Item = &str> {
+        self.whiteouts.iter().map(String::as_str)
+    }
+
+    /// Directories whose contents below the first layer applied are hidden,
+    /// `""` for the root
+    pub fn opaque_dirs(&self) -> impl Iterator<Item = &str> {
+        self.opaque_dirs.iter().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
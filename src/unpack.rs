@@ -0,0 +1,278 @@
+//! Unpacking the outer image tar. Images are untrusted, so every entry is
+//! checked before anything is written rather than leaving it to the tar
+//! crate's defaults: paths stay inside the destination, links can't lead
+//! out of it, and [`UnpackLimits`] bound what one image may unpack.
+//!
+//! Only the header values of ownership and special files ever matter, so
+//! ownership is never applied. A rootless unpack also skips device nodes
+//! rather than refusing the image, leaves out xattrs, and keeps what it
+//! writes owner-writable for an unprivileged user to clean up.
+
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path};
+
+use tar::{Archive, EntryType};
+use tracing::warn;
+
+use crate::cancel;
+use crate::error::{DedupeError, Result};
+use crate::paths;
+use crate::timings::CountingReader;
+
+/// Bounds on what [`unpack_image`] writes to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnpackLimits {
+    /// Entries of the outer tar
+    pub max_entries: u64,
+    /// Bytes of file contents, no limit when None
+    pub max_bytes: Option<u64>,
+}
+
+impl Default for UnpackLimits {
+    fn default() -> Self {
+        // A `docker save` has a handful of entries per layer
+        Self {
+            max_entries: 100_000,
+            max_bytes: None,
+        }
+    }
+}
+
+fn refuse(path: &str, reason: &str) -> DedupeError {
+    DedupeError::Unpack(format!("{} {}", path, reason))
+}
+
+/// Errors unless `path` is relative and never climbs with `..`.
+fn check_path(path: &str) -> Result<()> {
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            Component::ParentDir => return Err(refuse(path, "climbs out with `..`")),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(refuse(path, "is absolute"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Errors when `path`, or a dir on the way to it, is already a symlink in
+/// `dst`: writing there would follow it instead of staying put.
+fn check_not_through_link(dst: &Path, path: &str) -> Result<()> {
+    let mut current = dst.to_path_buf();
+    for component in Path::new(path).components() {
+        current.push(component);
+        if fs::symlink_metadata(&current).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(refuse(path, "would be written through a symlink"));
+        }
+    }
+    Ok(())
+}
+
+/// Adds the owner bits an unprivileged user needs to write into and
+/// delete what was unpacked at `path`.
+fn make_owner_writable(path: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    let owner = if metadata.is_dir() { 0o700 } else { 0o600 };
+    let mode = metadata.permissions().mode();
+    if mode & owner != owner {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode | owner))?;
+    }
+    Ok(())
+}
+
+/// Unpacks the outer image tar into `dst`, returning the number of bytes
+/// read. Symlinks are recreated relative to their own dir, so absolute
+/// targets (meant from the archive root) don't point into the host.
+/// `rootless` skips device nodes and FIFOs with a warning instead of
+/// refusing the image, see the module docs.
+pub fn unpack_image<R: Read>(
+    image_stream: R,
+    dst: &Path,
+    limits: UnpackLimits,
+    rootless: bool,
+) -> Result<u64> {
+    let mut archive = Archive::new(CountingReader::new(image_stream));
+    archive.set_preserve_ownerships(false);
+    if rootless {
+        archive.set_preserve_permissions(false);
+        archive.set_unpack_xattrs(false);
+    }
+    let mut entries = 0;
+    let mut bytes = 0;
+    for entry in archive.entries()? {
+        cancel::check()?;
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        entries += 1;
+        if entries > limits.max_entries {
+            return Err(DedupeError::Unpack(format!(
+                "more than {} entries",
+                limits.max_entries
+            )));
+        }
+        check_path(&path)?;
+        check_not_through_link(dst, &path)?;
+
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                bytes += entry.size();
+                if let Some(max) = limits.max_bytes.filter(|max| bytes > *max) {
+                    return Err(DedupeError::Unpack(format!(
+                        "more than {} bytes of contents",
+                        max
+                    )));
+                }
+            }
+            EntryType::Directory => {}
+            EntryType::Link => {
+                let target = entry.link_name()?.unwrap_or_default();
+                let target = target.to_string_lossy();
+                check_path(&target)?;
+                check_not_through_link(dst, &target)?;
+            }
+            EntryType::Symlink => {
+                let target = entry.link_name()?.unwrap_or_default();
+                let target = paths::link_target(&path, &target.to_string_lossy())
+                    .ok_or_else(|| refuse(&path, "links outside the archive"))?;
+                let link = dst.join(&path);
+                if let Some(parent) = link.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let depth = Path::new(&path)
+                    .components()
+                    .filter(|c| matches!(c, Component::Normal(_)))
+                    .count()
+                    .saturating_sub(1);
+                let relative = format!("{}{}", "../".repeat(depth), target);
+                std::os::unix::fs::symlink(relative, link)?;
+                continue;
+            }
+            EntryType::Char | EntryType::Block | EntryType::Fifo if rootless => {
+                warn!("Skipping {}, a special file", path);
+                continue;
+            }
+            other => return Err(refuse(&path, &format!("is a {:?} entry", other))),
+        }
+        if !entry.unpack_in(dst)? {
+            return Err(refuse(&path, "is outside the destination"));
+        }
+        if rootless {
+            make_owner_writable(&dst.join(&path))?;
+        }
+    }
+    Ok(archive.into_inner().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use tar::{Builder, Header};
+
+    use super::*;
+
+    /// A tar of `(path, type, link target)` entries, written raw so unsafe
+    /// paths aren't refused on the way in. Dirs are read-only.
+    fn tar(entries: &[(&str, EntryType, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for (path, entry_type, target) in entries {
+            let mut header = Header::new_gnu();
+            let gnu = header.as_gnu_mut().unwrap();
+            gnu.name[..path.len()].copy_from_slice(path.as_bytes());
+            gnu.linkname[..target.len()].copy_from_slice(target.as_bytes());
+            let data: &[u8] = if entry_type.is_file() { b"data" } else { b"" };
+            header.set_entry_type(*entry_type);
+            let mode = if *entry_type == EntryType::Directory {
+                0o555
+            } else {
+                0o644
+            };
+            header.set_mode(mode);
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn unpack(
+        entries: &[(&str, EntryType, &str)],
+        limits: UnpackLimits,
+    ) -> Result<tempfile::TempDir> {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("dst")).unwrap();
+        unpack_image(
+            tar(entries).as_slice(),
+            &root.path().join("dst"),
+            limits,
+            false,
+        )?;
+        Ok(root)
+    }
+
+    #[test]
+    fn test_unsafe_entries_are_refused() {
+        let limits = UnpackLimits::default();
+        for entries in [
+            vec![("../evil", EntryType::Regular, "")],
+            vec![("/evil", EntryType::Regular, "")],
+            vec![("a", EntryType::Symlink, "../..")],
+            vec![("a", EntryType::Link, "../evil")],
+            vec![
+                ("a", EntryType::Symlink, "b"),
+                ("a/evil", EntryType::Regular, ""),
+            ],
+            vec![("dev", EntryType::Char, "")],
+        ] {
+            assert!(
+                matches!(unpack(&entries, limits), Err(DedupeError::Unpack(_))),
+                "{:?}",
+                entries
+            );
+        }
+
+        let entries = [("a", EntryType::Regular, ""), ("b", EntryType::Regular, "")];
+        let tight = |max_entries, max_bytes| UnpackLimits {
+            max_entries,
+            max_bytes,
+        };
+        assert!(unpack(&entries, tight(2, Some(8))).is_ok());
+        assert!(unpack(&entries, tight(1, None)).is_err());
+        assert!(unpack(&entries, tight(2, Some(7))).is_err());
+    }
+
+    #[test]
+    fn test_absolute_symlinks_stay_in_the_archive() {
+        let root = unpack(
+            &[
+                ("blobs/abc", EntryType::Regular, ""),
+                ("1/layer.tar", EntryType::Symlink, "/blobs/abc"),
+            ],
+            UnpackLimits::default(),
+        )
+        .unwrap();
+        let link = root.path().join("dst/1/layer.tar");
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("../blobs/abc"));
+        assert_eq!(fs::read(link).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_rootless_unpack() {
+        let image = tar(&[
+            ("dev/null", EntryType::Char, ""),
+            ("ro", EntryType::Directory, ""),
+        ]);
+        let dst = tempfile::tempdir().unwrap();
+        let limits = UnpackLimits::default();
+        assert!(unpack_image(image.as_slice(), dst.path(), limits, false).is_err());
+        let dst = tempfile::tempdir().unwrap();
+        unpack_image(image.as_slice(), dst.path(), limits, true).unwrap();
+        assert!(!dst.path().join("dev/null").exists());
+        let mode = fs::metadata(dst.path().join("ro"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+}
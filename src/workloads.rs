@@ -0,0 +1,556 @@
+//! The images Kubernetes workloads run, and the duplicate waste in them
+//! per workload and per namespace. Workloads come from manifests, YAML or
+//! JSON, or from `kubectl get pods -o json`.
+//!
+//! YAML is read line by line rather than parsed: each document is one
+//! resource, its top-level `kind` and `metadata` name it and every
+//! `image:` key under it counts. That covers manifests as written and as
+//! `helm template` renders them, but not `kind: List` documents. Aliases,
+//! flow-style containers and the like are errors naming the line;
+//! `kubectl get -o json` output has none of that problem.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+use humansize::{BINARY, format_size};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::cancel;
+use crate::error::{DedupeError, Result};
+use crate::yaml;
+
+const DEFAULT_NAMESPACE: &str = "default";
+
+/// A Deployment, a bare Pod or anything else with containers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Workload {
+    pub namespace: String,
+    pub kind: String,
+    pub name: String,
+    /// As the manifest spells them, e.g. `nginx:1.27`
+    pub images: BTreeSet<String>,
+}
+
+/// What analyzing one image found.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageWaste {
+    pub image: String,
+    pub duplicate_groups: usize,
+    pub duplicate_bytes: u64,
+    /// Why the image couldn't be analyzed, e.g. a pull failure; it then
+    /// counts for nothing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Waste in the images of one workload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkloadWaste {
+    pub namespace: String,
+    pub kind: String,
+    pub name: String,
+    pub images: Vec<String>,
+    pub duplicate_bytes: u64,
+}
+
+/// Waste in the images a namespace's workloads run, each image counted
+/// once however many workloads run it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NamespaceWaste {
+    pub namespace: String,
+    pub workloads: usize,
+    pub images: usize,
+    pub duplicate_bytes: u64,
+}
+
+/// Outcome of [`fleet_report`], largest waste first throughout.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FleetReport {
+    /// See [`crate::SCHEMA_VERSION`]
+    pub schema_version: u32,
+    pub images: Vec<ImageWaste>,
+    pub workloads: Vec<WorkloadWaste>,
+    pub namespaces: Vec<NamespaceWaste>,
+}
+
+fn yaml_resource(document: &[yaml::Line]) -> Option<Workload> {
+    let mut kind = None;
+    let mut name = None;
+    let mut namespace = None;
+    let mut images = BTreeSet::new();
+    let mut in_metadata = false;
+    let mut metadata_indent = None;
+    for line in document {
+        if line.indent == 0 {
+            in_metadata = line.key == "metadata";
+            if line.key == "kind" {
+                kind = Some(line.value.to_string());
+            }
+        } else if in_metadata && *metadata_indent.get_or_insert(line.indent) == line.indent {
+            match line.key {
+                "name" => name = Some(line.value.to_string()),
+                "namespace" => namespace = Some(line.value.to_string()),
+                _ => {}
+            }
+        }
+        if line.key == "image" && !line.value.is_empty() {
+            images.insert(line.value.to_string());
+        }
+    }
+    if images.is_empty() {
+        return None;
+    }
+    Some(Workload {
+        namespace: namespace.unwrap_or_else(|| DEFAULT_NAMESPACE.to_string()),
+        kind: kind?,
+        name: name?,
+        images,
+    })
+}
+
+/// The workloads of a multi-document YAML stream. YAML the line-based
+/// reader can't follow, e.g. a flow-style container list, is an error.
+pub fn parse_yaml(contents: &str) -> Result<Vec<Workload>> {
+    Ok(yaml::documents(contents)?
+        .iter()
+        .filter_map(|d| yaml_resource(d))
+        .collect())
+}
+
+/// Every `image` string anywhere under `value`.
+fn json_images(value: &Value, images: &mut BTreeSet<String>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                match value {
+                    Value::String(image) if key == "image" && !image.is_empty() => {
+                        images.insert(image.clone());
+                    }
+                    _ => json_images(value, images),
+                }
+            }
+        }
+        Value::Array(values) => values.iter().for_each(|v| json_images(v, images)),
+        _ => {}
+    }
+}
+
+/// The workload a resource belongs to: a pod's controller where it has
+/// one, the Deployment rather than its ReplicaSet, or the resource itself.
+fn owner(resource: &Value) -> Option<(String, String)> {
+    let metadata = resource.get("metadata")?;
+    let Some(controller) = metadata
+        .get("ownerReferences")
+        .and_then(Value::as_array)
+        .and_then(|owners| owners.first())
+    else {
+        let kind = resource.get("kind")?.as_str()?;
+        return Some((
+            kind.to_string(),
+            metadata.get("name")?.as_str()?.to_string(),
+        ));
+    };
+    let kind = controller.get("kind")?.as_str()?;
+    let name = controller.get("name")?.as_str()?;
+    // A Deployment's ReplicaSets are named after it plus the pod template
+    // hash, which the pods carry as a label
+    let hash = metadata
+        .get("labels")
+        .and_then(|labels| labels.get("pod-template-hash"))
+        .and_then(Value::as_str);
+    if kind == "ReplicaSet"
+        && let Some(deployment) = hash.and_then(|h| name.strip_suffix(&format!("-{}", h)))
+    {
+        return Some(("Deployment".to_string(), deployment.to_string()));
+    }
+    Some((kind.to_string(), name.to_string()))
+}
+
+fn json_resources(value: &Value, workloads: &mut Vec<Workload>) {
+    if let Some(items) = value.get("items").and_then(Value::as_array) {
+        items
+            .iter()
+            .for_each(|item| json_resources(item, workloads));
+        return;
+    }
+    let mut images = BTreeSet::new();
+    // Not status, whose containerStatuses name the images resolved
+    if let Some(spec) = value.get("spec") {
+        json_images(spec, &mut images);
+    }
+    let Some((kind, name)) = owner(value) else {
+        return;
+    };
+    if images.is_empty() {
+        return;
+    }
+    let namespace = value
+        .pointer("/metadata/namespace")
+        .and_then(Value::as_str)
+        .unwrap_or(DEFAULT_NAMESPACE);
+    workloads.push(Workload {
+        namespace: namespace.to_string(),
+        kind,
+        name,
+        images,
+    });
+}
+
+/// The workloads of one JSON resource or list, as `kubectl get -o json`
+/// prints them. Pods are counted for their controller.
+pub fn parse_json(contents: &str) -> Result<Vec<Workload>> {
+    let value: Value = serde_json::from_str(contents)
+        .map_err(|e| DedupeError::Manifest(format!("not a Kubernetes resource: {}", e)))?;
+    let mut workloads = Vec::new();
+    json_resources(&value, &mut workloads);
+    Ok(workloads)
+}
+
+fn read_file(path: &Path, workloads: &mut Vec<Workload>) -> Result<()> {
+    let contents = fs::read_to_string(path)?;
+    if contents.trim_start().starts_with('{') {
+        workloads.extend(
+            parse_json(&contents)
+                .map_err(|e| DedupeError::Manifest(format!("{}: {}", path.display(), e)))?,
+        );
+    } else {
+        workloads.extend(parse_yaml(&contents).map_err(yaml::in_file(path))?);
+    }
+    Ok(())
+}
+
+/// The workloads in the manifest file at `path`, or in the `.yaml`,
+/// `.yml` and `.json` files anywhere under the directory at `path`. The
+/// replicas of a workload, and the same workload in several files, come
+/// out as one.
+pub fn read_workloads(path: &Path) -> Result<Vec<Workload>> {
+    let mut workloads = Vec::new();
+    if path.is_dir() {
+        let mut pending = vec![path.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let mut entries = fs::read_dir(&dir)?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<Vec<_>>>()?;
+            entries.sort();
+            for entry in entries {
+                let extension = entry.extension().and_then(|e| e.to_str());
+                if entry.is_dir() {
+                    pending.push(entry);
+                } else if matches!(extension, Some("yaml" | "yml" | "json")) {
+                    debug!("Reading {}", entry.display());
+                    read_file(&entry, &mut workloads)?;
+                }
+            }
+        }
+    } else {
+        read_file(path, &mut workloads)?;
+    }
+
+    let mut merged: BTreeMap<(String, String, String), BTreeSet<String>> = BTreeMap::new();
+    for workload in workloads {
+        merged
+            .entry((workload.namespace, workload.kind, workload.name))
+            .or_default()
+            .extend(workload.images);
+    }
+    Ok(merged
+        .into_iter()
+        .map(|((namespace, kind, name), images)| Workload {
+            namespace,
+            kind,
+            name,
+            images,
+        })
+        .collect())
+}
+
+/// Runs `analyze` once on each image `workloads` reference, which returns
+/// its duplicate groups and the bytes they waste, and adds the waste up
+/// per workload and namespace. Images that fail are reported as such
+/// rather than ending the run, unless it was cancelled.
+pub fn fleet_report<E: Display>(
+    workloads: &[Workload],
+    mut analyze: impl FnMut(&str) -> Result<(usize, u64), E>,
+) -> Result<FleetReport> {
+    let images: BTreeSet<&String> = workloads.iter().flat_map(|w| &w.images).collect();
+    let mut by_image: BTreeMap<&str, ImageWaste> = BTreeMap::new();
+    for image in images {
+        cancel::check()?;
+        let waste = match analyze(image) {
+            Ok((duplicate_groups, duplicate_bytes)) => ImageWaste {
+                image: image.clone(),
+                duplicate_groups,
+                duplicate_bytes,
+                error: None,
+            },
+            Err(e) => {
+                cancel::check()?;
+                warn!("Skipping {}: {}", image, e);
+                ImageWaste {
+                    image: image.clone(),
+                    duplicate_groups: 0,
+                    duplicate_bytes: 0,
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+        by_image.insert(image, waste);
+    }
+    let waste = |images: &mut dyn Iterator<Item = &String>| -> u64 {
+        images.map(|i| by_image[i.as_str()].duplicate_bytes).sum()
+    };
+
+    let mut workload_waste: Vec<WorkloadWaste> = workloads
+        .iter()
+        .map(|w| WorkloadWaste {
+            namespace: w.namespace.clone(),
+            kind: w.kind.clone(),
+            name: w.name.clone(),
+            images: w.images.iter().cloned().collect(),
+            duplicate_bytes: waste(&mut w.images.iter()),
+        })
+        .collect();
+    workload_waste.sort_by_key(|w| Reverse(w.duplicate_bytes));
+
+    let mut namespaces: BTreeMap<&str, (usize, BTreeSet<&String>)> = BTreeMap::new();
+    for workload in workloads {
+        let (count, images) = namespaces.entry(&workload.namespace).or_default();
+        *count += 1;
+        images.extend(&workload.images);
+    }
+    let mut namespace_waste: Vec<NamespaceWaste> = namespaces
+        .into_iter()
+        .map(|(namespace, (workloads, images))| NamespaceWaste {
+            namespace: namespace.to_string(),
+            workloads,
+            images: images.len(),
+            duplicate_bytes: waste(&mut images.into_iter()),
+        })
+        .collect();
+    namespace_waste.sort_by_key(|n| Reverse(n.duplicate_bytes));
+
+    let mut images: Vec<ImageWaste> = by_image.into_values().collect();
+    images.sort_by_key(|i| Reverse(i.duplicate_bytes));
+    Ok(FleetReport {
+        schema_version: crate::SCHEMA_VERSION,
+        images,
+        workloads: workload_waste,
+        namespaces: namespace_waste,
+    })
+}
+
+impl FleetReport {
+    /// Namespaces, then workloads, then images, one per line.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec!["Namespaces:".to_string()];
+        lines.extend(self.namespaces.iter().map(|n| {
+            format!(
+                "  {:>10}  {} ({} workloads, {} images)",
+                format_size(n.duplicate_bytes, BINARY),
+                n.namespace,
+                n.workloads,
+                n.images
+            )
+        }));
+        lines.push("Workloads:".to_string());
+        lines.extend(self.workloads.iter().map(|w| {
+            format!(
+                "  {:>10}  {}/{}/{}",
+                format_size(w.duplicate_bytes, BINARY),
+                w.namespace,
+                w.kind,
+                w.name
+            )
+        }));
+        lines.push("Images:".to_string());
+        lines.extend(self.images.iter().map(|i| match &i.error {
+            Some(error) => format!("  {:>10}  {} ({})", "-", i.image, error),
+            None => format!(
+                "  {:>10}  {} ({} groups)",
+                format_size(i.duplicate_bytes, BINARY),
+                i.image,
+                i.duplicate_groups
+            ),
+        }));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEPLOYMENT: &str = r#"
+# The web tier
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: web
+  namespace: shop
+  labels:
+    name: not-the-name
+spec:
+  template:
+    metadata:
+      name: also-not-the-name
+    spec:
+      initContainers:
+        - name: migrate
+          image: "registry.io/shop/web:1.4"  # same image
+      containers:
+        - name: web
+          image: registry.io/shop/web:1.4
+        - image: 'envoyproxy/envoy:v1.31'
+          name: proxy
+---
+apiVersion: v1
+kind: Service
+metadata:
+  name: web
+---
+kind: CronJob
+metadata:
+  name: report
+spec:
+  jobTemplate:
+    spec:
+      template:
+        spec:
+          containers:
+          - name: report
+            image: python:3.12
+"#;
+
+    #[test]
+    fn test_parse_yaml() {
+        let workloads = parse_yaml(DEPLOYMENT).unwrap();
+        assert_eq!(workloads.len(), 2);
+        assert_eq!(workloads[0].namespace, "shop");
+        assert_eq!(workloads[0].kind, "Deployment");
+        assert_eq!(workloads[0].name, "web");
+        assert_eq!(
+            workloads[0].images.iter().collect::<Vec<_>>(),
+            ["envoyproxy/envoy:v1.31", "registry.io/shop/web:1.4"]
+        );
+        assert_eq!(workloads[1].namespace, "default");
+        assert_eq!(workloads[1].name, "report");
+    }
+
+    #[test]
+    fn test_parse_yaml_block_scalar() {
+        let manifest = r#"
+kind: Pod
+metadata:
+  name: banner
+spec:
+  containers:
+    - name: banner
+      args:
+        - |
+          ---
+          image: not-an-image
+      image: busybox:1.36 # after the script
+---
+kind: Pod
+metadata:
+  name: 'web # not a comment'
+spec:
+  containers:
+    - image: "alpine:3.20"
+      command: ["sh", "-c", "true"]
+      resources: {}
+"#;
+        let workloads = parse_yaml(manifest).unwrap();
+        assert_eq!(workloads.len(), 2);
+        assert_eq!(workloads[0].name, "banner");
+        assert_eq!(
+            workloads[0].images.iter().collect::<Vec<_>>(),
+            ["busybox:1.36"]
+        );
+        assert_eq!(workloads[1].name, "web # not a comment");
+        assert_eq!(
+            workloads[1].images.iter().collect::<Vec<_>>(),
+            ["alpine:3.20"]
+        );
+    }
+
+    #[test]
+    fn test_parse_yaml_unsupported() {
+        let unsupported = |manifest: &str| match parse_yaml(manifest) {
+            Err(DedupeError::Yaml(message)) => message,
+            other => panic!("expected a YAML error, got {:?}", other),
+        };
+        let flow = "kind: Pod\nmetadata:\n  name: web\nspec:\n  containers: [{image: web:1.4}]\n";
+        assert_eq!(unsupported(flow), "line 5: flow mappings aren't supported");
+        let alias = "kind: Pod\nspec:\n  containers:\n    - <<: *defaults\n";
+        assert_eq!(unsupported(alias), "line 4: merge keys aren't supported");
+        let folded = "kind: Pod\nspec:\n  containers:\n    - image: registry.io/a\n        /very/long/name\n";
+        assert_eq!(
+            unsupported(folded),
+            "line 5: multi-line scalars aren't supported"
+        );
+    }
+
+    #[test]
+    fn test_parse_pods_json() {
+        let pods = r#"{"kind": "List", "items": [
+            {"kind": "Pod",
+             "metadata": {"name": "web-7d4b9c8f5-x2x9z", "namespace": "shop",
+                          "labels": {"pod-template-hash": "7d4b9c8f5"},
+                          "ownerReferences": [{"kind": "ReplicaSet", "name": "web-7d4b9c8f5"}]},
+             "spec": {"containers": [{"name": "web", "image": "web:1.4"}]},
+             "status": {"containerStatuses": [{"image": "docker.io/library/web:1.4"}]}},
+            {"kind": "Pod",
+             "metadata": {"name": "debug"},
+             "spec": {"containers": [{"name": "sh", "image": "busybox"}]}}
+        ]}"#;
+        let workloads = parse_json(pods).unwrap();
+        assert_eq!(workloads.len(), 2);
+        assert_eq!(
+            (workloads[0].kind.as_str(), workloads[0].name.as_str()),
+            ("Deployment", "web")
+        );
+        assert_eq!(workloads[0].images.len(), 1);
+        assert_eq!(
+            (workloads[1].kind.as_str(), workloads[1].name.as_str()),
+            ("Pod", "debug")
+        );
+    }
+
+    #[test]
+    fn test_fleet_report() {
+        let workload = |namespace: &str, name: &str, images: &[&str]| Workload {
+            namespace: namespace.to_string(),
+            kind: "Deployment".to_string(),
+            name: name.to_string(),
+            images: images.iter().map(|i| i.to_string()).collect(),
+        };
+        let workloads = [
+            workload("shop", "web", &["web:1", "envoy:1"]),
+            workload("shop", "api", &["api:1", "envoy:1"]),
+            workload("ops", "agent", &["private:1"]),
+        ];
+        let mut analyzed = Vec::new();
+        let report = fleet_report(&workloads, |image| {
+            analyzed.push(image.to_string());
+            match image {
+                "private:1" => Err(DedupeError::Registry("unauthorized".to_string())),
+                "envoy:1" => Ok((2, 100)),
+                _ => Ok((1, 10)),
+            }
+        })
+        .unwrap();
+        // Once each, whoever runs it
+        assert_eq!(analyzed.len(), 4);
+        assert_eq!(report.workloads[0].duplicate_bytes, 110);
+        assert_eq!(report.namespaces[0].namespace, "shop");
+        assert_eq!(report.namespaces[0].duplicate_bytes, 120);
+        assert_eq!(report.namespaces[1].duplicate_bytes, 0);
+        assert!(report.images.iter().any(|i| i.error.is_some()));
+    }
+}
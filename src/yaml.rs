@@ -0,0 +1,196 @@
+//! Just enough YAML to find image references in Kubernetes manifests and
+//! Compose files, read line by line: block mappings and sequences of plain
+//! or quoted scalars. Block scalars are skipped. Flow mappings, aliases,
+//! merge keys and multi-line plain or quoted scalars could hide an image,
+//! so they're errors naming the line rather than being misread.
+
+use crate::error::{DedupeError, Result};
+
+/// A `key: value` line. `value` is empty for a key opening a nested block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Line<'a> {
+    /// 1-based line number in the whole stream
+    pub number: usize,
+    /// Columns before the key, or before the `- ` of a sequence item
+    pub indent: usize,
+    pub key: &'a str,
+    pub value: &'a str,
+}
+
+fn unsupported(number: usize, what: &str) -> DedupeError {
+    DedupeError::Yaml(format!("line {}: {} aren't supported", number, what))
+}
+
+/// Puts `path` in front of the line number of a [`DedupeError::Yaml`].
+pub(crate) fn in_file(path: &std::path::Path) -> impl FnOnce(DedupeError) -> DedupeError {
+    move |e| match e {
+        DedupeError::Yaml(message) => DedupeError::Yaml(format!("{}, {}", path.display(), message)),
+        e => e,
+    }
+}
+
+/// `value` without the quotes YAML may put around it.
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
+
+/// `item` up to its comment, and where the `:` ending its key is if it
+/// has one. Quotes only count at the start of a scalar, so `don't` is
+/// plain text.
+fn scan(item: &str, number: usize) -> Result<(&str, Option<usize>)> {
+    let mut quote = None;
+    let mut key_end = None;
+    let mut previous = ' ';
+    let mut chars = item.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match quote {
+            Some('"') if c == '\\' => {
+                chars.next();
+            }
+            Some('\'') if c == '\'' && chars.peek().is_some_and(|&(_, next)| next == '\'') => {
+                chars.next();
+            }
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if matches!(c, '"' | '\'') && previous.is_whitespace() => quote = Some(c),
+            None if c == '#' && previous.is_whitespace() => {
+                return Ok((item[..i].trim_end(), key_end));
+            }
+            None if c == ':'
+                && key_end.is_none()
+                && chars.peek().is_none_or(|(_, next)| next.is_whitespace()) =>
+            {
+                key_end = Some(i);
+            }
+            None => {}
+        }
+        previous = c;
+    }
+    if quote.is_some() {
+        return Err(unsupported(number, "multi-line quoted scalars"));
+    }
+    Ok((item.trim_end(), key_end))
+}
+
+/// `node` without its anchor and tag, refusing what this reader can't
+/// follow. Empty flow collections and flow sequences of scalars, as in
+/// `command: ["sh", "-c"]`, can't hold an image and are let through.
+fn node(node: &str, number: usize) -> Result<&str> {
+    let mut node = node.trim();
+    while node.starts_with(['&', '!']) {
+        node = node
+            .split_once(char::is_whitespace)
+            .map_or("", |(_, rest)| rest.trim_start());
+    }
+    if node.starts_with('*') {
+        return Err(unsupported(number, "aliases"));
+    }
+    if node.starts_with('{') {
+        let inner = node.strip_prefix('{').and_then(|n| n.strip_suffix('}'));
+        if inner.is_none_or(|inner| !inner.trim().is_empty()) {
+            return Err(unsupported(number, "flow mappings"));
+        }
+    } else if node.starts_with('[') {
+        if node.contains('{') {
+            return Err(unsupported(number, "flow mappings"));
+        }
+        if !node.ends_with(']') {
+            return Err(unsupported(number, "multi-line flow collections"));
+        }
+    }
+    Ok(node)
+}
+
+/// Whether `line` starts `marker` as a line of its own, e.g. `---` but
+/// not `----`.
+fn is_marker(line: &str, marker: &str) -> bool {
+    line.strip_prefix(marker)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+}
+
+/// The `key: value` lines of each document of a multi-document stream,
+/// split at `---`, with list dashes and comments dropped. Blank lines,
+/// comments, bare sequence items and block scalars are skipped, so a `---`
+/// inside a block scalar doesn't end the document.
+pub(crate) fn documents(contents: &str) -> Result<Vec<Vec<Line<'_>>>> {
+    let mut documents = vec![Vec::new()];
+    // Lines indented past this column belong to a block scalar
+    let mut block_scalar = None;
+    for (index, line) in contents.lines().enumerate() {
+        let number = index + 1;
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+        if let Some(column) = block_scalar {
+            if trimmed.is_empty() || indent > column {
+                continue;
+            }
+            block_scalar = None;
+        }
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if indent == 0 {
+            if is_marker(trimmed, "---") {
+                documents.push(Vec::new());
+                continue;
+            }
+            if is_marker(trimmed, "...") || trimmed.starts_with('%') {
+                continue;
+            }
+        }
+        let mut item = trimmed;
+        let mut column = indent;
+        let mut dash = None;
+        while let Some(rest) = item
+            .strip_prefix('-')
+            .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+        {
+            dash = Some(column);
+            let rest = rest.trim_start();
+            column += item.len() - rest.len();
+            item = rest;
+        }
+        let (item, key_end) = scan(item, number)?;
+        let Some(key_end) = key_end else {
+            let Some(dash) = dash else {
+                return Err(unsupported(number, "multi-line scalars"));
+            };
+            if node(item, number)?.starts_with(['|', '>']) {
+                block_scalar = Some(dash);
+            }
+            continue;
+        };
+        let key = unquote(&item[..key_end]);
+        if key == "<<" {
+            return Err(unsupported(number, "merge keys"));
+        }
+        let mut value = node(&item[key_end + 1..], number)?;
+        if value.starts_with(['|', '>']) {
+            block_scalar = Some(column);
+            value = "";
+        }
+        if let Some(document) = documents.last_mut() {
+            document.push(Line {
+                number,
+                indent,
+                key,
+                value: unquote(value),
+            });
+        }
+    }
+    Ok(documents)
+}
+
+/// The `key: value` lines of a single-document stream, see [`documents`].
+pub(crate) fn lines(contents: &str) -> Result<Vec<Line<'_>>> {
+    Ok(documents(contents)?.into_iter().flatten().collect())
+}
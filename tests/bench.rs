@@ -0,0 +1,47 @@
+#![cfg(feature = "rewrite")]
+
+use docker_duplicate_files::Analyzer;
+use docker_duplicate_files::bench::{bench, gzip_bench, synthetic_image};
+use docker_duplicate_files::timings::Phase;
+
+#[test]
+fn test_bench_reports_every_phase() {
+    let image = synthetic_image(2, 4, 10_000);
+    let options = Analyzer::builder().min_size(1).options();
+    let report = bench(2, true, || {
+        Analyzer::from_reader(image.as_slice(), options.clone())
+    })
+    .unwrap();
+    assert_eq!(report.runs, 2);
+    assert_eq!(report.run_seconds.len(), 2);
+    let phases: Vec<Phase> = report.phases.iter().map(|p| p.phase).collect();
+    for phase in [Phase::Extract, Phase::Scan, Phase::Rewrite, Phase::Pack] {
+        assert!(
+            phases.contains(&phase),
+            "{:?} missing from {:?}",
+            phase,
+            phases
+        );
+    }
+    let scan = report
+        .phases
+        .iter()
+        .find(|p| p.phase == Phase::Scan)
+        .unwrap();
+    // Two layers of four 10 kB files, plus their tar headers
+    assert!(scan.bytes > 80_000, "{:?}", scan);
+    assert!(report.lines().last().unwrap().contains("over 2 runs"));
+}
+
+#[test]
+fn test_gzip_bench_round_trips_the_largest_layer() {
+    let image = synthetic_image(2, 2, 10_000);
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    let report = gzip_bench(2, &analyzer).unwrap();
+    assert_eq!(report.runs, 2);
+    // Two 10 kB files and their tar headers
+    assert!(report.bytes > 20_000, "{:?}", report);
+    assert!(report.compressed_bytes > 0);
+    assert!(report.gzip_median_seconds > 0.0);
+    assert!(report.lines()[2].contains("over 2 runs"));
+}
@@ -0,0 +1,50 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use common::image_with_duplicate;
+
+/// Runs the command line tool, returning its log
+fn run(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_docker_duplicate_files"))
+        .args(args)
+        .env("RUST_LOG", "info")
+        .output()
+        .unwrap();
+    let log = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(output.status.success(), "{}", log);
+    log
+}
+
+fn arg(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+fn test_verify_output_keeps_the_work_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("in.tar");
+    fs::write(&input, image_with_duplicate()).unwrap();
+    let output = dir.path().join("out.tar");
+    let work_dir = dir.path().join("work");
+    let args = [
+        "-i",
+        arg(&input),
+        "-o",
+        arg(&output),
+        "--work-dir",
+        arg(&work_dir),
+        "--verify-output",
+    ];
+
+    let log = run(&args);
+    assert!(!log.contains("discarding"), "{}", log);
+    fs::remove_file(&output).unwrap();
+    let log = run(&args);
+    assert!(log.contains("Resuming from image extracted"), "{}", log);
+    assert!(output.exists());
+}
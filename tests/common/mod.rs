@@ -0,0 +1,152 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use docker_duplicate_files::testing::{ImageBuilder, ImageContents, LayerBuilder, random_bytes};
+use docker_duplicate_files::{Analyzer, DedupeSummary, ImageRef};
+
+pub fn image_with_duplicate() -> Vec<u8> {
+    let lib = random_bytes(1_200_000, 10);
+    ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a.so", lib.clone()))
+        .layer(LayerBuilder::new().file("b.so", lib))
+        .build()
+}
+
+/// Loads `image` and repacks it as a `docker save` tarball for inspection
+pub fn load_contents(image: &ImageRef) -> ImageContents {
+    let analyzer = image.load(Analyzer::builder().options()).unwrap();
+    let mut output = Vec::new();
+    analyzer
+        .create_deduplicated_image(Vec::new(), &mut output)
+        .unwrap();
+    ImageContents::read(&output)
+}
+
+pub fn dedupe_to(image: &[u8], output: &ImageRef) -> DedupeSummary {
+    let analyzer = Analyzer::builder().load(image).unwrap();
+    let duplicates = analyzer.find_duplicates().unwrap();
+    let summary = output.write(&analyzer, duplicates).unwrap();
+    assert_eq!(summary.duplicate_files, 1);
+    summary
+}
+
+/// The paths a container of the image sees, in union view order
+pub fn visible_paths(analyzer: &Analyzer) -> Vec<String> {
+    let view = analyzer.union_view().unwrap();
+    view.iter().map(|e| e.path.clone()).collect()
+}
+
+/// A minimal in-process registry serving `test/app`: one request per
+/// connection, blobs and manifests kept in memory.
+#[cfg(feature = "network")]
+pub mod registry {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[derive(Default)]
+    struct Store {
+        blobs: HashMap<String, Vec<u8>>,
+        manifests: HashMap<String, (String, Vec<u8>)>,
+    }
+
+    fn respond(stream: &mut TcpStream, status: &str, headers: &[(&str, String)], body: &[u8]) {
+        let mut response = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            status,
+            body.len()
+        );
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str("\r\n");
+        stream.write_all(response.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+    }
+
+    fn handle(mut stream: TcpStream, store: &Mutex<Store>) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let Some((name, value)) = line.trim_end().split_once(": ") else {
+                break;
+            };
+            headers.insert(name.to_ascii_lowercase(), value.to_string());
+        }
+        let length = headers
+            .get("content-length")
+            .map_or(0, |l| l.parse().unwrap());
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next().unwrap(), parts.next().unwrap());
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut store = store.lock().unwrap();
+        let digest = docker_duplicate_files::digest_writer::sha256_digest;
+
+        if path == "/v2/" {
+            return respond(&mut stream, "200 OK", &[], b"{}");
+        }
+        if let Some(id) = path.strip_prefix("/upload/") {
+            let expected = query.strip_prefix("digest=").unwrap().replace("%3A", ":");
+            assert_eq!(digest(&body), expected, "upload {}", id);
+            store.blobs.insert(expected, body);
+            return respond(&mut stream, "201 Created", &[], b"");
+        }
+        let rest = path.strip_prefix("/v2/test/app/").unwrap();
+        match (method, rest.split_once('/').unwrap()) {
+            ("POST", ("blobs", "uploads/")) => {
+                let location = format!("/upload/{}", store.blobs.len());
+                respond(&mut stream, "202 Accepted", &[("Location", location)], b"")
+            }
+            ("HEAD" | "GET", ("blobs", blob)) => match store.blobs.get(blob) {
+                Some(data) if method == "GET" => respond(&mut stream, "200 OK", &[], data),
+                Some(_) => respond(&mut stream, "200 OK", &[], b""),
+                None => respond(&mut stream, "404 Not Found", &[], b""),
+            },
+            ("PUT", ("manifests", reference)) => {
+                let media_type = headers["content-type"].clone();
+                store
+                    .manifests
+                    .insert(digest(&body), (media_type.clone(), body.clone()));
+                store
+                    .manifests
+                    .insert(reference.to_string(), (media_type, body));
+                respond(&mut stream, "201 Created", &[], b"")
+            }
+            ("GET", ("manifests", reference)) => match store.manifests.get(reference) {
+                Some((media_type, data)) => respond(
+                    &mut stream,
+                    "200 OK",
+                    &[("Content-Type", media_type.clone())],
+                    data,
+                ),
+                None => respond(&mut stream, "404 Not Found", &[], b""),
+            },
+            // No referrers API, like many registries
+            ("GET", ("referrers", _)) => respond(&mut stream, "404 Not Found", &[], b""),
+            _ => respond(&mut stream, "405 Method Not Allowed", &[], b""),
+        }
+    }
+
+    pub fn start_registry() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let store = Arc::new(Mutex::new(Store::default()));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let store = store.clone();
+                thread::spawn(move || handle(stream.unwrap(), &store));
+            }
+        });
+        address
+    }
+}
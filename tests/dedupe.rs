@@ -0,0 +1,825 @@
+#![cfg(feature = "rewrite")]
+
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::sync::Arc;
+
+use common::image_with_duplicate;
+use docker_duplicate_files::analyzer::{LayerCompression, LayerFormat};
+use docker_duplicate_files::entries::EntryKind;
+use docker_duplicate_files::options::Created;
+use docker_duplicate_files::schemas::{ImageIndex, ImageManifest};
+use docker_duplicate_files::testing::{ImageBuilder, ImageContents, LayerBuilder, random_bytes};
+use docker_duplicate_files::{
+    Analyzer, CacheFriendlyPolicy, CanonicalPolicy, Strategy, dedupe_image,
+};
+use tar::Archive;
+
+fn dedupe(image: &[u8]) -> (ImageContents, docker_duplicate_files::DedupeSummary) {
+    let options = Analyzer::builder().compression(false).options();
+    let mut output = Vec::new();
+    let summary = dedupe_image(image, &mut output, options).unwrap();
+    (ImageContents::read(&output), summary)
+}
+
+#[test]
+fn test_cross_layer_duplicate_becomes_symlink() {
+    let lib = random_bytes(2_000_000, 1);
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .dir("usr/lib")
+                .file("usr/lib/libbig.so", lib.clone()),
+        )
+        .layer(LayerBuilder::new().dir("opt").file("opt/libbig.so", lib))
+        .build();
+
+    let (output, summary) = dedupe(&image);
+    assert_eq!(summary.duplicate_files, 1);
+    assert_eq!(summary.bytes_saved, 2_000_000);
+
+    let link = output.entry(1, "opt/libbig.so").unwrap();
+    assert_eq!(link.kind, EntryKind::Symlink);
+    assert_eq!(link.link_target.as_deref(), Some("/usr/lib/libbig.so"));
+    assert_eq!(
+        output.entry(0, "usr/lib/libbig.so").unwrap().kind,
+        EntryKind::File
+    );
+    assert_eq!(output.config.rootfs.diff_ids.len(), 2);
+    assert!(!summary.layers[0].rewritten);
+    assert_eq!(
+        summary.layers[1].new_diff_id,
+        output.config.rootfs.diff_ids[1]
+    );
+    assert_ne!(summary.old_config_digest, summary.new_config_digest);
+}
+
+#[test]
+fn test_same_layer_duplicate_becomes_hardlink() {
+    let lib = random_bytes(1_500_000, 2);
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("a.bin", lib.clone())
+                .file("b.bin", lib),
+        )
+        .build();
+
+    let (output, _) = dedupe(&image);
+    let link = output.entry(0, "b.bin").unwrap();
+    assert_eq!(link.kind, EntryKind::Hardlink);
+    assert_eq!(link.link_target.as_deref(), Some("a.bin"));
+}
+
+#[test]
+fn test_history_entry_is_opt_in() {
+    let image = image_with_duplicate();
+    let (output, _) = dedupe(&image);
+    assert_eq!(output.config.history.len(), 2);
+
+    let options = Analyzer::builder().history_entry(true).options();
+    let mut written = Vec::new();
+    dedupe_image(image.as_slice(), &mut written, options).unwrap();
+    let history = ImageContents::read(&written).config.history;
+    assert_eq!(history.len(), 3);
+    assert!(history[2].empty_layer);
+    assert!(history[2].created_by.ends_with("saved 1200000 bytes"));
+}
+
+#[test]
+fn test_label_savings() {
+    let lib = random_bytes(1_500_000, 5);
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a.bin", lib.clone()))
+        .layer(LayerBuilder::new().file("b.bin", lib))
+        .label("maintainer", "ops")
+        .build();
+    let options = Analyzer::builder().label_savings(true).options();
+    let mut written = Vec::new();
+    let summary = dedupe_image(image.as_slice(), &mut written, options).unwrap();
+    let labels = ImageContents::read(&written).config.config.labels.unwrap();
+    assert_eq!(labels["maintainer"], "ops");
+    assert_eq!(labels["org.dedupe.bytes-saved"], "1500000");
+    assert_eq!(
+        labels["org.dedupe.original-digest"],
+        summary.old_config_digest
+    );
+    assert!(labels.contains_key("org.dedupe.version"));
+}
+
+#[test]
+fn test_repo_tags() {
+    let image = image_with_duplicate();
+    let options = Analyzer::builder()
+        .compression(false)
+        .repo_tag("app:1.0")
+        .repo_tag("app:latest")
+        .options();
+    let mut written = Vec::new();
+    dedupe_image(image.as_slice(), &mut written, options).unwrap();
+    assert_eq!(
+        ImageContents::read(&written).manifest.repo_tags,
+        ["app:1.0", "app:latest"]
+    );
+}
+
+#[test]
+fn test_archives_are_oci_layouts() {
+    let image = image_with_duplicate();
+    let options = Analyzer::builder().repo_tag("app:1").options();
+    let mut written = Vec::new();
+    dedupe_image(image.as_slice(), &mut written, options).unwrap();
+
+    let mut files = HashMap::new();
+    for entry in Archive::new(written.as_slice()).entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).unwrap();
+        files.insert(path.trim_start_matches("./").to_string(), data);
+    }
+    assert!(files.contains_key("oci-layout"));
+    let index: ImageIndex = String::from_utf8(files["index.json"].clone())
+        .unwrap()
+        .parse()
+        .unwrap();
+    let annotations = index.manifests[0].annotations.as_ref().unwrap();
+    assert_eq!(
+        annotations["io.containerd.image.name"],
+        "docker.io/library/app:1"
+    );
+    assert_eq!(annotations["org.opencontainers.image.ref.name"], "1");
+
+    // The OCI manifest lists the same blobs as manifest.json
+    let blob = |digest: &str| &files[&format!("blobs/{}", digest.replace(':', "/"))];
+    let manifest: ImageManifest = serde_json::from_slice(blob(&index.manifests[0].digest)).unwrap();
+    let contents = ImageContents::read(&written);
+    assert_eq!(
+        format!("blobs/{}", manifest.config.digest.replace(':', "/")),
+        contents.manifest.config
+    );
+    for (layer, path) in manifest.layers.iter().zip(&contents.manifest.layers) {
+        assert_eq!(&format!("blobs/{}", layer.digest.replace(':', "/")), path);
+        assert_eq!(blob(&layer.digest).len() as u64, layer.size);
+    }
+}
+
+#[test]
+fn test_created_epoch_applies_to_added_history() {
+    let image = image_with_duplicate();
+    let options = Analyzer::builder()
+        .history_entry(true)
+        .created(Created::Epoch)
+        .options();
+    let mut written = Vec::new();
+    dedupe_image(image.as_slice(), &mut written, options).unwrap();
+    let config = ImageContents::read(&written).config;
+    assert_eq!(config.created, "1970-01-01T00:00:00Z");
+    assert_eq!(config.history[2].created, "1970-01-01T00:00:00Z");
+    assert_ne!(config.history[0].created, "1970-01-01T00:00:00Z");
+}
+
+#[test]
+fn test_non_tar_layers_pass_through() {
+    let lib = random_bytes(1_500_000, 7);
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a.bin", lib.clone()))
+        .raw_layer(b"\0asm\x01\0\0\0".repeat(100))
+        .layer(LayerBuilder::new().file("b.bin", lib))
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    assert_eq!(analyzer.layers[1].format, LayerFormat::Unsupported);
+    assert_eq!(analyzer.entries().count(), 2);
+
+    let duplicates = analyzer.find_duplicates().unwrap();
+    assert_eq!(duplicates.len(), 1);
+    let summary = analyzer
+        .create_deduplicated_image(duplicates, Vec::new())
+        .unwrap();
+    assert!(!summary.layers[1].rewritten);
+    assert_eq!(summary.layers[1].old_diff_id, summary.layers[1].new_diff_id);
+    assert!(summary.layers[2].rewritten);
+}
+
+#[test]
+fn test_symlinked_layer_paths() {
+    let lib = random_bytes(1_500_000, 8);
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a.bin", lib.clone()))
+        .layer(LayerBuilder::new().file("b.bin", lib))
+        .linked_layers(true)
+        .build();
+    let options = Analyzer::builder().options();
+    let scan = docker_duplicate_files::scan_stream(image.as_slice(), &options).unwrap();
+    assert_eq!(scan.find_duplicates().len(), 1);
+
+    let analyzer = Analyzer::from_reader(image.as_slice(), options).unwrap();
+    assert!(
+        analyzer.layers[1]
+            .path
+            .parent()
+            .unwrap()
+            .ends_with("blobs/sha256")
+    );
+    let duplicates = analyzer.find_duplicates().unwrap();
+    assert_eq!(duplicates.len(), 1);
+    let mut output = Vec::new();
+    analyzer
+        .create_deduplicated_image(duplicates, &mut output)
+        .unwrap();
+    let contents = ImageContents::read(&output);
+    assert_eq!(contents.entry(1, "b.bin").unwrap().kind, EntryKind::Symlink);
+}
+
+#[test]
+fn test_rewritten_config_is_named_by_digest() {
+    let image = image_with_duplicate();
+    let mut output = Vec::new();
+    let summary =
+        dedupe_image(image.as_slice(), &mut output, Analyzer::builder().options()).unwrap();
+    let hex = summary.new_config_digest.trim_start_matches("sha256:");
+    let contents = ImageContents::read(&output);
+    assert_eq!(contents.manifest.config, format!("blobs/sha256/{}", hex));
+
+    let verification = Analyzer::builder()
+        .load(output.as_slice())
+        .unwrap()
+        .verify()
+        .unwrap();
+    assert!(verification.is_ok(), "{:?}", verification.problems);
+}
+
+#[test]
+fn test_links_that_would_loop_are_dropped() {
+    let lib = random_bytes(1_500_000, 10);
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a", lib.clone()))
+        .layer(LayerBuilder::new().file("b", lib))
+        .layer(LayerBuilder::new().symlink("a", "b"))
+        .build();
+    let (output, summary) = dedupe(&image);
+    assert_eq!(summary.duplicate_files, 0);
+    assert_eq!(output.entry(1, "b").unwrap().kind, EntryKind::File);
+}
+
+#[test]
+fn test_hardlinked_duplicates_are_kept() {
+    let lib = random_bytes(1_500_000, 11);
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a", lib.clone()))
+        .layer(
+            LayerBuilder::new()
+                .file("b", lib.clone())
+                .hardlink("b-alias", "b")
+                .file("c", lib),
+        )
+        .build();
+    let (output, summary) = dedupe(&image);
+    assert_eq!(summary.duplicate_files, 1);
+    assert_eq!(output.entry(1, "b").unwrap().kind, EntryKind::File);
+    assert_eq!(output.entry(1, "c").unwrap().kind, EntryKind::Symlink);
+}
+
+#[test]
+fn test_layer_compression_by_magic() {
+    for (head, expected) in [
+        (&b""[..], LayerCompression::None),
+        (b"\x1f", LayerCompression::None),
+        (b"\x1f\x8b\x08", LayerCompression::Gzip),
+        (b"\x28\xb5\x2f\xfd\x00", LayerCompression::Zstd),
+        (b"\xfd7zXZ\x00\x00", LayerCompression::Xz),
+        (b"etc/\0\0\0", LayerCompression::None),
+    ] {
+        assert_eq!(LayerCompression::detect(head), expected, "{:?}", head);
+    }
+
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a", "a"))
+        .raw_layer(b"\x28\xb5\x2f\xfd".repeat(200))
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    assert_eq!(analyzer.layers[1].format, LayerFormat::Tar);
+    let error = analyzer.find_duplicates().unwrap_err();
+    assert!(error.to_string().contains("Zstd"), "{}", error);
+}
+
+#[test]
+fn test_empty_layers_are_passed_through() {
+    let mut empty_gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    std::io::Write::write_all(&mut empty_gzip, &[0; 1024]).unwrap();
+    let lib = random_bytes(1_000_000, 6);
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("usr/lib/libbig.so", lib.clone()))
+        .raw_layer(Vec::new())
+        .raw_layer(empty_gzip.finish().unwrap())
+        .layer(LayerBuilder::new().dir("opt").dir("opt/app"))
+        .layer(LayerBuilder::new().file("opt/app/libbig.so", lib))
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    let formats: Vec<LayerFormat> = analyzer.layers.iter().map(|l| l.format).collect();
+    assert_eq!(
+        formats,
+        [
+            LayerFormat::Tar,
+            LayerFormat::Empty,
+            LayerFormat::Empty,
+            LayerFormat::Tar,
+            LayerFormat::Tar
+        ]
+    );
+
+    let (output, summary) = dedupe(&image);
+    assert_eq!(summary.duplicate_files, 1);
+    for layer in &summary.layers[..4] {
+        assert!(!layer.rewritten);
+        assert_eq!(layer.old_diff_id, layer.new_diff_id);
+    }
+    assert!(output.layers[1].is_empty() && output.layers[2].is_empty());
+    let dirs: Vec<&str> = output.layers[3].iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(dirs, ["opt", "opt/app"]);
+}
+
+/// An uncompressed layer of ustar entries, their paths in PAX headers.
+fn pax_layer(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, data) in files {
+        let record = format!(" path={}\n", path);
+        let record = format!("{}{}", record.len() + 3, record);
+        let mut pax = tar::Header::new_ustar();
+        pax.set_entry_type(tar::EntryType::XHeader);
+        pax.set_size(record.len() as u64);
+        pax.set_cksum();
+        builder.append(&pax, record.as_bytes()).unwrap();
+        let mut header = tar::Header::new_ustar();
+        header.set_path("truncated").unwrap();
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        builder.append(&header, *data).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+#[test]
+fn test_long_paths_round_trip() {
+    let deep = |name: &str| format!("app/{}{}", "node_modules/@scope/package/".repeat(10), name);
+    let lib = random_bytes(1_000_000, 7);
+    let other = random_bytes(1_000_000, 8);
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file(&deep("a.js"), lib.clone()))
+        .layer(
+            LayerBuilder::new()
+                .file(&deep("b.js"), lib.clone())
+                .file(&deep("c.js"), other.clone())
+                .hardlink(&deep("c-alias.js"), &deep("c.js"))
+                .symlink(&deep("d.js"), &deep("c.js")),
+        )
+        .raw_layer(pax_layer(&[
+            (&deep("e.js"), &lib),
+            (&deep("f.js"), b"kept"),
+        ]))
+        .build();
+    assert!(deep("a.js").len() > 255);
+
+    let (output, summary) = dedupe(&image);
+    assert_eq!(summary.duplicate_files, 2);
+    let link = |layer, name: &str| {
+        let entry = output.entry(layer, &deep(name)).unwrap();
+        (entry.kind, entry.link_target.clone())
+    };
+    let deduplicated = (EntryKind::Symlink, Some(format!("/{}", deep("a.js"))));
+    assert_eq!(link(1, "b.js"), deduplicated);
+    assert_eq!(link(2, "e.js"), deduplicated);
+    assert_eq!(
+        link(1, "c-alias.js"),
+        (EntryKind::Hardlink, Some(deep("c.js")))
+    );
+    assert_eq!(link(1, "d.js"), (EntryKind::Symlink, Some(deep("c.js"))));
+    assert_eq!(output.entry(1, &deep("c.js")).unwrap().data, other);
+    assert_eq!(output.entry(2, &deep("f.js")).unwrap().data, b"kept");
+}
+
+#[test]
+fn test_small_files_and_whiteouts_are_ignored() {
+    let small = random_bytes(1000, 3);
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a.txt", small.clone()))
+        .layer(LayerBuilder::new().file("b.txt", small).whiteout("a.txt"))
+        .empty_layer("CMD [\"/bin/sh\"]")
+        .build();
+
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    assert!(analyzer.find_duplicates().unwrap().is_empty());
+}
+
+#[test]
+fn test_stream_scan_matches_analyzer() {
+    // Above the streaming buffer limit, so the layer is scanned on the fly
+    let lib = random_bytes(9_000_000, 4);
+    let other = random_bytes(1_100_000, 5);
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("a", lib.clone())
+                .file("b", other.clone()),
+        )
+        .empty_layer("ENV A=1")
+        .layer(LayerBuilder::new().file("c", lib))
+        .layer(
+            LayerBuilder::new()
+                .file("d", other.clone())
+                .file("e", other),
+        )
+        .build();
+
+    let options = Analyzer::builder().options();
+    let scan = docker_duplicate_files::scan_stream(image.as_slice(), &options).unwrap();
+    let mut streamed = scan.find_duplicates();
+    let mut extracted = Analyzer::from_reader(image.as_slice(), options)
+        .unwrap()
+        .find_duplicates()
+        .unwrap();
+    for duplicates in [&mut streamed, &mut extracted] {
+        duplicates.sort_by(|a, b| a.original.path.cmp(&b.original.path));
+    }
+
+    assert_eq!(streamed.len(), 2);
+    for (s, e) in streamed.iter().zip(&extracted) {
+        assert_eq!(s.original.path, e.original.path);
+        assert_eq!(s.original.layer_index, e.original.layer_index);
+        let paths = |d: &docker_duplicate_files::analyzer::DuplicateInfo| {
+            d.duplicates
+                .iter()
+                .map(|f| (f.path.clone(), f.layer_index))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(s), paths(e));
+    }
+}
+
+#[test]
+fn test_strategies() {
+    let lib = random_bytes(1_100_000, 40);
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("usr/lib/a.so", lib.clone())
+                .file("usr/lib/b.so", lib.clone()),
+        )
+        .layer(LayerBuilder::new().file("opt/c.so", lib.clone()))
+        .build();
+    // A later layer adding the same file again at the same path
+    let readded = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("usr/lib/a.so", lib.clone()))
+        .layer(
+            LayerBuilder::new()
+                .file("usr/lib/a.so", lib.clone())
+                .file("opt/c.so", lib),
+        )
+        .build();
+    let run = |image: &[u8], strategy| {
+        let options = Analyzer::builder()
+            .compression(false)
+            .strategy(strategy)
+            .options();
+        let mut output = Vec::new();
+        let summary = dedupe_image(image, &mut output, options).unwrap();
+        (ImageContents::read(&output), summary)
+    };
+
+    let (output, summary) = run(&image, Strategy::Symlink);
+    assert_eq!(summary.duplicate_files, 2);
+    assert_eq!(
+        output.entry(0, "usr/lib/b.so").unwrap().kind,
+        EntryKind::Symlink
+    );
+
+    let (output, summary) = run(&image, Strategy::Hardlink);
+    assert_eq!(summary.duplicate_files, 1);
+    assert_eq!(
+        output.entry(0, "usr/lib/b.so").unwrap().kind,
+        EntryKind::Hardlink
+    );
+    assert_eq!(output.entry(1, "opt/c.so").unwrap().kind, EntryKind::File);
+
+    let (output, summary) = run(&readded, Strategy::Omit);
+    assert_eq!(summary.duplicate_files, 1);
+    assert!(output.entry(1, "usr/lib/a.so").is_none());
+    assert_eq!(output.entry(1, "opt/c.so").unwrap().kind, EntryKind::File);
+
+    let (output, summary) = run(&image, Strategy::SharedLayer);
+    assert_eq!(summary.duplicate_files, 2);
+    assert_eq!(output.layers.len(), 3);
+
+    let (output, summary) = run(&image, Strategy::ReportOnly);
+    assert_eq!(summary.duplicate_files, 0);
+    assert!(summary.layers.iter().all(|l| !l.rewritten));
+    assert_eq!(output.entry(1, "opt/c.so").unwrap().kind, EntryKind::File);
+}
+
+#[test]
+fn test_canonical_originals() {
+    let lib = random_bytes(1_100_000, 41);
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("opt/app/vendor/libssl.so.3", lib.clone())
+                .file("opt/app/libcrypto.so.3", lib.clone())
+                .symlink("opt/app/current.so", "libcrypto.so.3"),
+        )
+        .layer(LayerBuilder::new().file("usr/lib/libssl.so.3", lib.clone()))
+        .build();
+    let run = |image: &[u8], policy: CanonicalPolicy| {
+        let options = Analyzer::builder()
+            .compression(false)
+            .policy(Arc::new(policy))
+            .options();
+        let mut output = Vec::new();
+        dedupe_image(image, &mut output, options).unwrap();
+        ImageContents::read(&output)
+    };
+    let target = |output: &ImageContents, layer, path| {
+        output
+            .entry(layer, path)
+            .and_then(|e| e.link_target.clone())
+    };
+
+    // The copy a symlink points to wins, even from the vendor dir's layer
+    let output = run(&image, CanonicalPolicy::new::<&str>(&[], 0).unwrap());
+    assert_eq!(
+        output.entry(0, "opt/app/libcrypto.so.3").unwrap().kind,
+        EntryKind::File
+    );
+    assert_eq!(
+        target(&output, 1, "usr/lib/libssl.so.3").as_deref(),
+        Some("/opt/app/libcrypto.so.3")
+    );
+
+    // Patterns come first, then the canonical location over the vendor dir
+    let output = run(
+        &image,
+        CanonicalPolicy::new(&["usr/lib/libssl.so.3", "opt/app/vendor"], 0).unwrap(),
+    );
+    assert_eq!(
+        output.entry(1, "usr/lib/libssl.so.3").unwrap().kind,
+        EntryKind::File
+    );
+    assert_eq!(
+        target(&output, 0, "opt/app/vendor/libssl.so.3").as_deref(),
+        Some("/usr/lib/libssl.so.3")
+    );
+
+    // A protected layer's copy wins over the canonical location
+    let vendored = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("opt/app/vendor/libssl.so.3", lib.clone()))
+        .layer(LayerBuilder::new().file("usr/lib/libssl.so.3", lib))
+        .build();
+    let output = run(&vendored, CanonicalPolicy::new::<&str>(&[], 0).unwrap());
+    assert_eq!(
+        output.entry(1, "usr/lib/libssl.so.3").unwrap().kind,
+        EntryKind::File
+    );
+    let output = run(&vendored, CanonicalPolicy::new::<&str>(&[], 1).unwrap());
+    assert_eq!(
+        output.entry(0, "opt/app/vendor/libssl.so.3").unwrap().kind,
+        EntryKind::File
+    );
+    assert_eq!(
+        target(&output, 1, "usr/lib/libssl.so.3").as_deref(),
+        Some("/opt/app/vendor/libssl.so.3")
+    );
+}
+
+#[test]
+fn test_cache_friendly_originals() {
+    let lib = random_bytes(1_100_000, 42);
+    let data = random_bytes(1_100_000, 43);
+    // Layer 0 changes anyway, for the second copy of lib; keeping the
+    // original of data in layer 1 leaves that layer intact
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("usr/lib/a.so", lib.clone())
+                .file("usr/lib/b.so", lib)
+                .file("usr/share/data", data.clone()),
+        )
+        .layer(LayerBuilder::new().file("opt/data", data))
+        .build();
+    let options = Analyzer::builder()
+        .compression(false)
+        .policy(Arc::new(CacheFriendlyPolicy))
+        .options();
+    let mut output = Vec::new();
+    let summary = dedupe_image(image.as_slice(), &mut output, options).unwrap();
+    let output = ImageContents::read(&output);
+
+    assert_eq!(summary.duplicate_files, 2);
+    assert!(summary.layers[0].rewritten);
+    assert!(!summary.layers[1].rewritten);
+    assert_eq!(summary.layers[1].old_diff_id, summary.layers[1].new_diff_id);
+    assert_eq!(output.entry(1, "opt/data").unwrap().kind, EntryKind::File);
+    assert_eq!(
+        output
+            .entry(0, "usr/share/data")
+            .unwrap()
+            .link_target
+            .as_deref(),
+        Some("/opt/data")
+    );
+}
+
+#[test]
+fn test_scope_limits_rewrite() {
+    let lib = random_bytes(1_100_000, 44);
+    let jar = random_bytes(1_100_000, 45);
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("usr/lib/a.so", lib.clone())
+                .file("srv/a.jar", jar.clone()),
+        )
+        .layer(
+            LayerBuilder::new()
+                .file("usr/lib64/a.so", lib)
+                .file("srv/b.jar", jar),
+        )
+        .build();
+    let options = Analyzer::builder()
+        .compression(false)
+        .scope("/usr")
+        .options();
+    let mut output = Vec::new();
+    let summary = dedupe_image(image.as_slice(), &mut output, options).unwrap();
+    let output = ImageContents::read(&output);
+
+    assert_eq!(summary.duplicate_groups, 1);
+    assert_eq!(
+        output.entry(1, "usr/lib64/a.so").unwrap().kind,
+        EntryKind::Symlink
+    );
+    assert_eq!(output.entry(1, "srv/b.jar").unwrap().kind, EntryKind::File);
+}
+
+#[test]
+fn test_volumes_left_alone() {
+    let lib = random_bytes(1_100_000, 46);
+    let seed = random_bytes(1_100_000, 47);
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("usr/lib/a.so", lib.clone())
+                .file("usr/share/seed.db", seed.clone()),
+        )
+        .layer(
+            LayerBuilder::new()
+                .file("opt/a.so", lib)
+                .file("var/lib/app/seed.db", seed),
+        )
+        .volume("/var/lib/app")
+        .build();
+    let run = |dedupe_volumes| {
+        let options = Analyzer::builder()
+            .compression(false)
+            .dedupe_volumes(dedupe_volumes)
+            .options();
+        let mut output = Vec::new();
+        let summary = dedupe_image(image.as_slice(), &mut output, options).unwrap();
+        (ImageContents::read(&output), summary)
+    };
+
+    let (output, summary) = run(false);
+    assert_eq!(summary.duplicate_files, 1);
+    assert_eq!(
+        output.entry(1, "opt/a.so").unwrap().kind,
+        EntryKind::Symlink
+    );
+    assert_eq!(
+        output.entry(1, "var/lib/app/seed.db").unwrap().kind,
+        EntryKind::File
+    );
+
+    let (output, summary) = run(true);
+    assert_eq!(summary.duplicate_files, 2);
+    assert_eq!(
+        output.entry(1, "var/lib/app/seed.db").unwrap().kind,
+        EntryKind::Symlink
+    );
+}
+
+#[test]
+fn test_ignore_file_opts_paths_out() {
+    let lib = random_bytes(1_100_000, 48);
+    let jar = random_bytes(1_100_000, 49);
+    let build = |ignore: &str| {
+        ImageBuilder::new()
+            .layer(
+                LayerBuilder::new()
+                    .file(".dedupeignore", "# signed\n/opt/vendor\n")
+                    .file("usr/lib/a.so", lib.clone())
+                    .file("srv/a.jar", jar.clone()),
+            )
+            .layer(
+                LayerBuilder::new()
+                    .file("opt/vendor/a.so", lib.clone())
+                    .file("srv/b.jar", jar.clone())
+                    .file(".dedupeignore", ignore),
+            )
+            .build()
+    };
+    let run = |image: &[u8], ignore_file| {
+        let options = Analyzer::builder()
+            .compression(false)
+            .ignore_file(ignore_file)
+            .options();
+        let mut output = Vec::new();
+        dedupe_image(image, &mut output, options).unwrap();
+        ImageContents::read(&output)
+    };
+
+    // The file of the upper layer is the one that counts
+    let output = run(&build("*.jar\n"), true);
+    assert_eq!(output.entry(1, "srv/b.jar").unwrap().kind, EntryKind::File);
+    assert_eq!(
+        output.entry(1, "opt/vendor/a.so").unwrap().kind,
+        EntryKind::Symlink
+    );
+
+    let output = run(&build("/opt/vendor/\n"), true);
+    assert_eq!(
+        output.entry(1, "opt/vendor/a.so").unwrap().kind,
+        EntryKind::File
+    );
+    assert_eq!(
+        output.entry(1, "srv/b.jar").unwrap().kind,
+        EntryKind::Symlink
+    );
+
+    let output = run(&build("*.jar\n"), false);
+    assert_eq!(
+        output.entry(1, "srv/b.jar").unwrap().kind,
+        EntryKind::Symlink
+    );
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_single_pipeline_matches_unbounded() {
+    let mut builder = ImageBuilder::new();
+    for layer in 0..6 {
+        builder = builder.layer(
+            LayerBuilder::new()
+                .file(&format!("opt/{}/a.bin", layer), random_bytes(1_100_000, 48))
+                .file(
+                    &format!("opt/{}/b.bin", layer),
+                    random_bytes(1_100_000, layer),
+                ),
+        );
+    }
+    let image = builder.build();
+    let rewrite = |options| {
+        let mut output = Vec::new();
+        let summary = dedupe_image(image.as_slice(), &mut output, options).unwrap();
+        (summary.new_config_digest, summary.bytes_saved)
+    };
+    let unbounded = rewrite(Analyzer::builder().compression(false).threads(4).options());
+    let bounded = rewrite(
+        Analyzer::builder()
+            .compression(false)
+            .threads(4)
+            .max_pipelines(1)
+            .options(),
+    );
+    assert_eq!(bounded.1, 5 * 1_100_000);
+    assert_eq!(bounded, unbounded);
+
+    let options = Analyzer::builder().max_pipelines(0).options();
+    assert!(dedupe_image(image.as_slice(), &mut Vec::new(), options).is_err());
+}
+
+#[test]
+fn test_work_dir_tells_streamed_images_apart() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("notes.txt"), "mine").unwrap();
+    let duplicates = |image: &[u8]| {
+        let analyzer = Analyzer::builder()
+            .work_dir(dir.path())
+            .load(image)
+            .unwrap();
+        analyzer.find_duplicates().unwrap().len()
+    };
+    let first = image_with_duplicate();
+    let second = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("c.so", random_bytes(1_200_000, 31)))
+        .build();
+
+    assert_eq!(duplicates(&first), 1);
+    assert_eq!(duplicates(&second), 0);
+    assert_eq!(duplicates(&first), 1);
+    assert!(dir.path().join("notes.txt").exists());
+}
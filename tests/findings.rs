@@ -0,0 +1,304 @@
+//! Analyses that look past individual duplicate files.
+
+#[cfg(feature = "reports")]
+use docker_duplicate_files::database;
+use docker_duplicate_files::junk::JunkKind;
+use docker_duplicate_files::packages::Ecosystem;
+use docker_duplicate_files::scan::{SMALL_FILE_SIZE, savings_by_directory};
+use docker_duplicate_files::testing::{ImageBuilder, LayerBuilder, elf_file, random_bytes};
+use docker_duplicate_files::{Analyzer, scan_stream};
+
+#[test]
+fn test_packages_installed_twice() {
+    let record = "requests/__init__.py,sha256=abc,5000\nrequests/api.py,sha256=def,3000\n\
+                  requests-2.31.0.dist-info/RECORD,,\n";
+    let package_json = r#"{"name": "left-pad", "version": "1.3.0"}"#;
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file(
+                    "usr/lib/python3/site-packages/requests-2.31.0.dist-info/RECORD",
+                    record,
+                )
+                .file("app/node_modules/left-pad/package.json", package_json)
+                .file("app/node_modules/left-pad/index.js", random_bytes(700, 1))
+                .file(
+                    "app/node_modules/left-pad/node_modules/x/index.js",
+                    random_bytes(900, 2),
+                ),
+        )
+        .layer(
+            LayerBuilder::new()
+                .file(
+                    "opt/venv/lib/python3/site-packages/requests-2.31.0.dist-info/RECORD",
+                    record,
+                )
+                .file(
+                    "opt/venv/lib/python3/site-packages/Requests-2.30.0.dist-info/RECORD",
+                    record,
+                )
+                .file("srv/node_modules/left-pad/package.json", package_json)
+                .file("srv/node_modules/left-pad/index.js", random_bytes(700, 1)),
+        )
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+
+    let packages = analyzer.find_duplicate_packages().unwrap();
+    assert_eq!(packages.len(), 2);
+    let requests = &packages[0];
+    assert_eq!(requests.ecosystem, Ecosystem::Python);
+    assert_eq!(
+        (requests.name.as_str(), requests.version.as_str()),
+        ("requests", "2.31.0")
+    );
+    assert_eq!(requests.installs.len(), 2);
+    assert_eq!(requests.duplicate_bytes, 8000);
+
+    let left_pad = &packages[1];
+    assert_eq!(left_pad.ecosystem, Ecosystem::Node);
+    assert_eq!(left_pad.installs[0].path, "app/node_modules/left-pad");
+    assert_eq!(left_pad.installs[0].size, (package_json.len() + 700) as u64);
+    assert!(
+        left_pad
+            .to_string()
+            .starts_with("left-pad 1.3.0 (node) installed 2× in 2 layers")
+    );
+}
+
+#[test]
+fn test_waste_attributed_to_history_entries() {
+    let lib = random_bytes(2_000_000, 3);
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .created_by("COPY lib /usr/lib")
+                .file("usr/lib/libbig.so", lib.clone())
+                .file("tmp/cache", random_bytes(1000, 4)),
+        )
+        .empty_layer("ENV A=b")
+        .layer(
+            LayerBuilder::new()
+                .created_by("RUN cp")
+                .file("opt/libbig.so", lib)
+                .whiteout("tmp/cache"),
+        )
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    let duplicates = analyzer.find_duplicates().unwrap();
+
+    let ledger = analyzer.attribute_waste(&duplicates).unwrap();
+    assert_eq!(ledger.len(), 3);
+    assert_eq!(ledger[0].created_by, "COPY lib /usr/lib");
+    assert_eq!(ledger[0].added, 2_001_000);
+    assert_eq!(ledger[0].overwritten, 1000);
+    assert_eq!(ledger[0].net, 2_000_000);
+    assert_eq!(ledger[1].layer_index, None);
+    assert_eq!(ledger[1].added, 0);
+    assert_eq!(ledger[2].layer_index, Some(1));
+    assert_eq!(ledger[2].duplicated, 2_000_000);
+    assert_eq!(ledger[2].net, 0);
+}
+
+#[test]
+fn test_junk_files_found_hidden_or_not() {
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("var/log/dpkg.log", random_bytes(300, 5))
+                .file("tmp/src.tar", random_bytes(5000, 6))
+                .file("var/log/empty.log", Vec::new())
+                .file("etc/app.conf", random_bytes(10, 7)),
+        )
+        .layer(LayerBuilder::new().whiteout("tmp/src.tar"))
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+
+    let junk = analyzer.find_junk_files().unwrap();
+    assert_eq!(junk.len(), 2);
+    assert_eq!(junk[0].entry.path, "tmp/src.tar");
+    assert_eq!(junk[0].kind, JunkKind::Temp);
+    assert!(!junk[0].visible);
+    assert_eq!(junk[1].kind, JunkKind::Log);
+    assert!(junk[1].visible);
+}
+
+#[cfg(feature = "reports")]
+#[test]
+fn test_compressibility_of_largest_files() {
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("data/zeros.bin", vec![0u8; 200_000]))
+        .layer(
+            LayerBuilder::new()
+                .file("data/noise.bin", random_bytes(100_000, 8))
+                .file("etc/small", random_bytes(10, 9)),
+        )
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+
+    let mut files = analyzer.largest_files(2).unwrap();
+    assert!(files.iter().all(|f| f.compression_ratio.is_none()));
+    analyzer.measure_compressibility(&mut files).unwrap();
+    assert_eq!(files[0].entry.path, "data/zeros.bin");
+    assert!(files[0].compression_ratio.unwrap() < 0.05);
+    assert_eq!(files[1].entry.path, "data/noise.bin");
+    assert!(files[1].compression_ratio.unwrap() > 0.95);
+}
+
+#[cfg(feature = "reports")]
+#[test]
+fn test_inventory_as_sql() {
+    let shared = random_bytes(2000, 10);
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("usr/lib/it's.so", shared.clone())
+                .created_by("COPY lib /usr/lib"),
+        )
+        .layer(LayerBuilder::new().file("opt/copy.so", shared))
+        .build();
+    let analyzer = Analyzer::builder()
+        .min_size(1)
+        .load(image.as_slice())
+        .unwrap();
+    let files = analyzer.scan_files().unwrap();
+    let duplicates = analyzer.group_duplicates(files.clone());
+    let steps = analyzer.attribute_waste(&duplicates).unwrap();
+
+    let mut sql = Vec::new();
+    database::write_sql(&mut sql, &analyzer, &files, &duplicates, &steps).unwrap();
+    let sql = String::from_utf8(sql).unwrap();
+    assert!(sql.contains("CREATE TABLE duplicate_groups"));
+    assert!(sql.contains("'usr/lib/it''s.so'"));
+    assert!(sql.contains("INSERT INTO duplicates VALUES (0, 1, 'opt/copy.so');"));
+    assert!(sql.contains("'COPY lib /usr/lib'"));
+    assert!(sql.trim_end().ends_with("COMMIT;"));
+}
+
+#[test]
+fn test_duplicates_carry_layer_provenance() {
+    let lib = random_bytes(1_200_000, 7);
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("usr/lib/a.so", lib.clone())
+                .created_by("ADD rootfs /"),
+        )
+        .empty_layer("ENV A=1")
+        .layer(
+            LayerBuilder::new()
+                .file("opt/a.so", lib)
+                .created_by("COPY vendor /opt"),
+        )
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    let duplicates = analyzer.find_duplicates().unwrap();
+    let copy = duplicates[0].duplicates[0].provenance.clone().unwrap();
+    assert_eq!(copy.diff_id, analyzer.config().rootfs.diff_ids[1]);
+    assert_eq!(copy.created_by.as_deref(), Some("COPY vendor /opt"));
+    let blob = &analyzer.manifest().layers[1];
+    assert_eq!(
+        copy.blob_digest,
+        Some(blob.replace("blobs/sha256/", "sha256:"))
+    );
+
+    let stream = scan_stream(image.as_slice(), analyzer.options()).unwrap();
+    let streamed = stream.find_duplicates();
+    assert_eq!(
+        streamed[0].original.provenance,
+        duplicates[0].original.provenance
+    );
+}
+
+#[test]
+fn test_small_files_summarized_by_directory() {
+    let mut base = LayerBuilder::new().file("etc/empty", "");
+    let mut app = LayerBuilder::new().file("app/empty", "");
+    for i in 0..50 {
+        let contents = random_bytes(100 + i, i as u64);
+        base = base.file(&format!("usr/share/doc/{}.txt", i), contents.clone());
+        app = app.file(&format!("app/docs/{}.txt", i), contents.clone());
+        if i < 10 {
+            app = app.file(&format!("app/assets/{}.txt", i), contents);
+        }
+    }
+    let image = ImageBuilder::new().layer(base).layer(app).build();
+    let analyzer = Analyzer::builder()
+        .min_size(0)
+        .load(image.as_slice())
+        .unwrap();
+    let duplicates = analyzer.find_duplicates().unwrap();
+    // Empty files save nothing and aren't grouped
+    assert_eq!(duplicates.len(), 50);
+    assert!(
+        duplicates
+            .iter()
+            .all(|d| d.original.path.starts_with("usr/"))
+    );
+
+    let dirs = savings_by_directory(&duplicates, SMALL_FILE_SIZE);
+    assert_eq!(dirs.len(), 2);
+    assert_eq!(dirs[0].dir, "app/docs");
+    assert_eq!(dirs[0].files, 50);
+    assert_eq!(dirs[0].bytes, (100..150).sum::<u64>());
+    assert_eq!(dirs[1].dir, "app/assets");
+    assert_eq!(dirs[1].files, 10);
+    assert!(savings_by_directory(&duplicates, 99).is_empty());
+}
+
+#[test]
+fn test_binaries_differing_in_debug_info() {
+    let text = random_bytes(20_000, 11);
+    let debug = random_bytes(50_000, 12);
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("usr/bin/tool", elf_file(&text, b"\x01\x02", &debug)))
+        .layer(
+            LayerBuilder::new()
+                .file("opt/app/bin/tool", elf_file(&text, b"\x03\x04", &[]))
+                .file(
+                    "opt/app/bin/other",
+                    elf_file(&random_bytes(20_000, 13), b"\x05", &[]),
+                )
+                .file("opt/app/bin/copy", elf_file(&text, b"\x01\x02", &debug)),
+        )
+        .build();
+    let analyzer = Analyzer::builder()
+        .min_size(0)
+        .load(image.as_slice())
+        .unwrap();
+    let groups = analyzer.find_elf_near_duplicates().unwrap();
+    assert_eq!(groups.len(), 1);
+    let group = &groups[0];
+    let paths: Vec<&str> = group.files.iter().map(|f| f.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        ["usr/bin/tool", "opt/app/bin/tool", "opt/app/bin/copy"]
+    );
+    assert_eq!(group.files[0].build_id.as_deref(), Some("0102"));
+    assert_eq!(group.files[1].build_id.as_deref(), Some("0304"));
+    assert_eq!(group.files[0].debug_bytes, 50_000);
+    assert_eq!(group.files[1].debug_bytes, 0);
+    assert_eq!(group.strip_savings, 100_000);
+    assert_eq!(group.dedupe_savings, 2 * group.files[1].size);
+}
+
+#[test]
+fn test_stream_parses_metadata_in_either_order() {
+    let lib = random_bytes(1_100_000, 60);
+    for manifest_first in [false, true] {
+        let image = ImageBuilder::new()
+            .layer(LayerBuilder::new().file("usr/lib/a.so", lib.clone()))
+            .layer(LayerBuilder::new().file("opt/a.so", lib.clone()))
+            .label("stage", "final")
+            .manifest_first(manifest_first)
+            .build();
+        let options = Analyzer::builder().options();
+        let scan = scan_stream(image.as_slice(), &options).unwrap();
+        assert_eq!(scan.manifest.layers.len(), 2);
+        assert_eq!(scan.config.rootfs.diff_ids.len(), 2);
+        assert_eq!(
+            scan.config.config.labels.as_ref().unwrap()["stage"],
+            "final"
+        );
+        assert_eq!(scan.find_duplicates().len(), 1);
+    }
+}
@@ -0,0 +1,84 @@
+//! Malformed manifests, configs and layers must produce errors, not panics.
+
+use docker_duplicate_files::schemas::DockerConfig;
+use docker_duplicate_files::testing::{ImageBuilder, LayerBuilder, random_bytes};
+use docker_duplicate_files::{Analyzer, Manifest};
+use proptest::prelude::*;
+
+/// Loads `image` and runs every read-only pass over it.
+fn analyze(image: &[u8]) {
+    let Ok(analyzer) = Analyzer::builder().min_size(0).load(image) else {
+        return;
+    };
+    let _ = analyzer.find_duplicates();
+    let _ = analyzer.entries().collect::<Vec<_>>();
+}
+
+fn valid_layer() -> Vec<u8> {
+    let data = random_bytes(3000, 7);
+    LayerBuilder::new()
+        .dir("usr/lib")
+        .file("usr/lib/a", data.clone())
+        .file("usr/lib/b", data)
+        .symlink("usr/lib/c", "a")
+        .whiteout("etc/passwd")
+        .build_tar()
+}
+
+proptest! {
+    #[test]
+    fn manifest_parse_never_panics(json in ".{0,256}") {
+        let _ = json.parse::<Manifest>();
+    }
+
+    #[test]
+    fn config_parse_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+        let _ = String::from_utf8_lossy(&bytes).parse::<DockerConfig>();
+    }
+
+    #[test]
+    fn manifest_round_trips(
+        config in "[a-z0-9/]{1,40}",
+        repo_tags in prop::collection::vec("[a-z:]{1,20}", 0..3),
+        layers in prop::collection::vec("[a-z0-9/.]{1,40}", 0..5),
+    ) {
+        let manifest = Manifest { config, repo_tags, layers, layer_sources: Default::default() };
+        let json = serde_json::to_string(&vec![&manifest]).unwrap();
+        let parsed: Manifest = json.parse().unwrap();
+        prop_assert_eq!(parsed.config, manifest.config);
+        prop_assert_eq!(parsed.repo_tags, manifest.repo_tags);
+        prop_assert_eq!(parsed.layers, manifest.layers);
+    }
+}
+
+proptest! {
+    // Every case extracts an image to disk
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn random_image_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..4096)) {
+        analyze(&bytes);
+    }
+
+    #[test]
+    fn random_layer_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..4096)) {
+        analyze(&ImageBuilder::new().raw_layer(bytes).build());
+    }
+
+    #[test]
+    fn corrupted_layer_never_panics(
+        flips in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..16),
+        truncate in any::<prop::sample::Index>(),
+    ) {
+        let mut layer = valid_layer();
+        for (index, byte) in flips {
+            let i = index.index(layer.len());
+            layer[i] = byte;
+        }
+        layer.truncate(truncate.index(layer.len() + 1));
+        analyze(&ImageBuilder::new()
+            .layer(LayerBuilder::new().file("base", random_bytes(100, 1)))
+            .raw_layer(layer)
+            .build());
+    }
+}
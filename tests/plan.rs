@@ -0,0 +1,57 @@
+#![cfg(feature = "rewrite")]
+
+use docker_duplicate_files::analyzer::ModificationPlan;
+use docker_duplicate_files::entries::EntryKind;
+use docker_duplicate_files::testing::{ImageBuilder, ImageContents, LayerBuilder, random_bytes};
+use docker_duplicate_files::{Analyzer, DedupeError};
+
+fn image(top: &str) -> Vec<u8> {
+    let lib = random_bytes(1_200_000, 20);
+    ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a.so", lib.clone()))
+        .layer(LayerBuilder::new().file("b.so", lib).file("c", top))
+        .build()
+}
+
+fn saved_plan(image: &[u8]) -> ModificationPlan {
+    let analyzer = Analyzer::builder().load(image).unwrap();
+    let duplicates = analyzer.find_duplicates().unwrap();
+    let plan = analyzer.plan(duplicates).unwrap();
+    serde_json::from_str(&serde_json::to_string(&plan).unwrap()).unwrap()
+}
+
+#[test]
+fn test_saved_plan_applies() {
+    let image = image("one");
+    let plan = saved_plan(&image);
+    assert_eq!(plan.diff_ids.len(), 2);
+    assert_eq!(plan.layers.len(), 1);
+    assert_eq!(plan.layers[0].changes[0].original_layer, 0);
+
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    let mut output = Vec::new();
+    let summary = analyzer.create_image_from_plan(&plan, &mut output).unwrap();
+    assert_eq!(summary.duplicate_files, 1);
+    let contents = ImageContents::read(&output);
+    assert_eq!(contents.entry(1, "b.so").unwrap().kind, EntryKind::Symlink);
+}
+
+#[test]
+fn test_plan_for_changed_image_is_refused() {
+    let plan = saved_plan(&image("one"));
+    let analyzer = Analyzer::builder().load(image("two").as_slice()).unwrap();
+    let err = analyzer.validate_plan(&plan).unwrap_err();
+    assert!(matches!(err, DedupeError::Plan(_)), "{err}");
+}
+
+#[test]
+fn test_edited_plan_is_refused() {
+    let image = image("one");
+    let mut plan = saved_plan(&image);
+    let change = &mut plan.layers[0].changes[0];
+    change.original_path = "c".to_string();
+    change.original_layer = 1;
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    let err = analyzer.validate_plan(&plan).unwrap_err();
+    assert!(err.to_string().contains("other contents"), "{err}");
+}
@@ -0,0 +1,103 @@
+#![cfg(feature = "proxy")]
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::registry::start_registry;
+use common::{image_with_duplicate, load_contents};
+use docker_duplicate_files::ImageRef;
+use docker_duplicate_files::analyzer::Analyzer;
+use docker_duplicate_files::entries::EntryKind;
+use docker_duplicate_files::proxy::{ImageStats, ProxyOptions, proxy};
+use docker_duplicate_files::serve::JobState;
+
+/// Starts a proxy whose analyzer options name `work_dir`, which jobs and
+/// variant builds must not share.
+fn start_proxy(upstream: String, work_dir: &Path) -> String {
+    let listen = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let options = ProxyOptions {
+        listen: listen.clone(),
+        upstream,
+        dedupe_suffix: Some("-dedup".to_string()),
+        workers: 2,
+        analyzer: Analyzer::builder().work_dir(work_dir).options(),
+        ..Default::default()
+    };
+    thread::spawn(move || proxy(options).unwrap());
+    let start = Instant::now();
+    while TcpStream::connect(&listen).is_err() {
+        assert!(start.elapsed() < Duration::from_secs(10), "proxy not up");
+        thread::sleep(Duration::from_millis(20));
+    }
+    listen
+}
+
+fn stats(proxy: &str) -> Vec<ImageStats> {
+    let mut stream = TcpStream::connect(proxy).unwrap();
+    stream
+        .write_all(b"GET /stats HTTP/1.0\r\nHost: proxy\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    serde_json::from_str(body).unwrap()
+}
+
+#[test]
+fn test_pull_through_proxy() {
+    let upstream = start_registry();
+    let original: ImageRef = format!("docker://{}/test/app:v1", upstream)
+        .parse()
+        .unwrap();
+    let analyzer = Analyzer::builder()
+        .load(image_with_duplicate().as_slice())
+        .unwrap();
+    original.write(&analyzer, Vec::new()).unwrap();
+
+    let work_dir = tempfile::tempdir().unwrap();
+    let proxy = start_proxy(upstream, work_dir.path());
+    let proxied: ImageRef = format!("docker://{}/test/app:v1", proxy).parse().unwrap();
+    let contents = load_contents(&proxied);
+    assert_eq!(contents.entry(1, "b.so").unwrap().kind, EntryKind::File);
+
+    let variant: ImageRef = format!("docker://{}/test/app:v1-dedup", proxy)
+        .parse()
+        .unwrap();
+    let contents = load_contents(&variant);
+    assert_eq!(contents.entry(1, "b.so").unwrap().kind, EntryKind::Symlink);
+
+    let start = Instant::now();
+    let stats = loop {
+        let stats = stats(&proxy);
+        if matches!(
+            stats[0].state,
+            JobState::Done { .. } | JobState::Failed { .. }
+        ) {
+            break stats;
+        }
+        assert!(start.elapsed() < Duration::from_secs(30), "analysis hung");
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].image, "test/app:v1");
+    assert_eq!(stats[0].pulls, 1);
+    match stats[0].state {
+        JobState::Done {
+            duplicate_groups,
+            bytes_saved,
+        } => {
+            assert_eq!(duplicate_groups, 1);
+            assert!(bytes_saved >= 1_200_000);
+        }
+        ref state => panic!("analysis didn't finish: {:?}", state),
+    }
+}
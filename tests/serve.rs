@@ -0,0 +1,152 @@
+#![cfg(feature = "serve")]
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::image_with_duplicate;
+use docker_duplicate_files::analyzer::Analyzer;
+use docker_duplicate_files::report::Report;
+use docker_duplicate_files::serve::{JobState, JobStatus, ServeOptions, serve};
+use docker_duplicate_files::testing::{ImageBuilder, ImageContents, LayerBuilder, random_bytes};
+
+fn start_server(options: ServeOptions) -> String {
+    let listen = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let options = ServeOptions {
+        listen: listen.clone(),
+        ..options
+    };
+    thread::spawn(move || serve(options).unwrap());
+    let start = Instant::now();
+    while TcpStream::connect(&listen).is_err() {
+        assert!(start.elapsed() < Duration::from_secs(10), "server not up");
+        thread::sleep(Duration::from_millis(20));
+    }
+    listen
+}
+
+/// Status code and body of one request
+fn request(server: &str, method: &str, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+    let mut stream = TcpStream::connect(server).unwrap();
+    let head = format!(
+        "{} {} HTTP/1.0\r\nHost: server\r\nContent-Length: {}\r\n\r\n",
+        method,
+        path,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let status = String::from_utf8_lossy(&response[9..12]).parse().unwrap();
+    (status, response[end + 4..].to_vec())
+}
+
+fn wait_for(server: &str, id: u64) -> JobStatus {
+    let start = Instant::now();
+    loop {
+        let (_, body) = request(server, "GET", &format!("/images/{}", id), b"");
+        let status: JobStatus = serde_json::from_slice(&body).unwrap();
+        if matches!(
+            status.state,
+            JobState::Done { .. } | JobState::Failed { .. }
+        ) {
+            return status;
+        }
+        assert!(start.elapsed() < Duration::from_secs(30), "job not done");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn submit(server: &str, image: &[u8]) -> u64 {
+    let (code, body) = request(server, "POST", "/images", image);
+    assert_eq!(code, 202);
+    serde_json::from_slice::<JobStatus>(&body).unwrap().id
+}
+
+#[test]
+fn test_job_round_trip() {
+    let server = start_server(ServeOptions::default());
+    let id = submit(&server, &image_with_duplicate());
+    let status = wait_for(&server, id);
+    assert!(matches!(
+        status.state,
+        JobState::Done {
+            duplicate_groups: 1,
+            ..
+        }
+    ));
+
+    let (code, body) = request(&server, "GET", &format!("/images/{}/report", id), b"");
+    assert_eq!(code, 200);
+    let report: Report = serde_json::from_slice(&body).unwrap();
+    assert_eq!(report.duplicate_groups, 1);
+
+    let (code, body) = request(&server, "GET", &format!("/images/{}/image", id), b"");
+    assert_eq!(code, 200);
+    assert!(ImageContents::read(&body).entry(1, "b.so").is_some());
+
+    let (code, body) = request(&server, "GET", "/metrics", b"");
+    assert_eq!(code, 200);
+    assert!(
+        String::from_utf8(body)
+            .unwrap()
+            .contains("dedupe_image_total_bytes")
+    );
+
+    assert_eq!(
+        request(&server, "DELETE", &format!("/images/{}", id), b"").0,
+        204
+    );
+    assert_eq!(
+        request(&server, "GET", &format!("/images/{}", id), b"").0,
+        404
+    );
+}
+
+#[test]
+fn test_jobs_dont_share_the_work_dir() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let server = start_server(ServeOptions {
+        workers: 2,
+        analyzer: Analyzer::builder().work_dir(work_dir.path()).options(),
+        ..Default::default()
+    });
+    let unique = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("c.so", random_bytes(1_200_000, 40)))
+        .build();
+    let first = submit(&server, &image_with_duplicate());
+    let second = submit(&server, &unique);
+
+    let groups = |id| match wait_for(&server, id).state {
+        JobState::Done {
+            duplicate_groups, ..
+        } => duplicate_groups,
+        state => panic!("job {} ended {:?}", id, state),
+    };
+    assert_eq!(groups(first), 1);
+    assert_eq!(groups(second), 0);
+}
+
+#[test]
+fn test_upload_over_the_limit() {
+    let image = image_with_duplicate();
+    let server = start_server(ServeOptions {
+        max_upload: 1000,
+        ..Default::default()
+    });
+    let (code, body) = request(&server, "POST", "/images", &image[..2000]);
+    assert_eq!(code, 413);
+    assert!(String::from_utf8_lossy(&body).contains("1000 bytes"));
+
+    let (code, _) = request(&server, "POST", "/images", &image[..1000]);
+    assert_eq!(code, 202);
+}
@@ -0,0 +1,61 @@
+#![cfg(feature = "rewrite")]
+
+use docker_duplicate_files::Analyzer;
+use docker_duplicate_files::entries::EntryKind;
+use docker_duplicate_files::testing::{ImageBuilder, ImageContents, LayerBuilder, random_bytes};
+
+#[test]
+fn test_shared_layer_holds_one_copy() {
+    let lib = random_bytes(1_500_000, 30);
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("usr/lib/libbig.so", lib.clone())
+                .file("etc/hostname", "box")
+                .created_by("ADD rootfs /"),
+        )
+        .layer(
+            LayerBuilder::new()
+                .file("opt/libbig.so", lib.clone())
+                .file("srv/libbig.so", lib.clone())
+                .created_by("COPY vendor /"),
+        )
+        .build();
+    let analyzer = Analyzer::builder()
+        .compression(false)
+        .load(image.as_slice())
+        .unwrap();
+    let duplicates = analyzer.find_duplicates().unwrap();
+    let hash = duplicates[0].original.hash.clone();
+    let mut output = Vec::new();
+    let summary = analyzer
+        .create_shared_layer_image(duplicates, &mut output)
+        .unwrap();
+    assert_eq!(summary.duplicate_files, 2);
+    assert_eq!(summary.bytes_saved, 3_000_000);
+    assert_eq!(summary.layers.len(), 2);
+    assert!(summary.layers.iter().all(|l| l.rewritten));
+
+    let contents = ImageContents::read(&output);
+    assert_eq!(contents.layers.len(), 3);
+    assert_eq!(contents.config.rootfs.diff_ids.len(), 3);
+    assert_eq!(
+        contents.config.history[0].created_by,
+        "shared copies of 1 duplicated files"
+    );
+    let shared = format!(".dedupe/{}/libbig.so", hash);
+    assert_eq!(contents.entry(0, &shared).unwrap().data, lib);
+    for (layer, path) in [
+        (1, "usr/lib/libbig.so"),
+        (2, "opt/libbig.so"),
+        (2, "srv/libbig.so"),
+    ] {
+        let link = contents.entry(layer, path).unwrap();
+        assert_eq!(link.kind, EntryKind::Symlink, "{}", path);
+        assert_eq!(
+            link.link_target.as_deref(),
+            Some(format!("/{}", shared).as_str())
+        );
+    }
+    assert_eq!(contents.entry(1, "etc/hostname").unwrap().data, b"box");
+}
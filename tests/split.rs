@@ -0,0 +1,68 @@
+#![cfg(feature = "rewrite")]
+
+mod common;
+
+use common::visible_paths;
+use docker_duplicate_files::Analyzer;
+use docker_duplicate_files::analyzer::SplitBy;
+use docker_duplicate_files::testing::{ImageBuilder, ImageContents, LayerBuilder};
+
+#[test]
+fn test_split_keeps_merged_rootfs() {
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("etc/hosts", "localhost"))
+        .layer(
+            LayerBuilder::new()
+                .whiteout("etc/hosts")
+                .file("usr/bin/tool", vec![1; 600])
+                .hardlink("usr/bin/tool-alias", "usr/bin/tool")
+                .file("usr/lib/libc.so", vec![2; 600])
+                .file("opt/data", vec![3; 600])
+                .created_by("RUN install"),
+        )
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+
+    let mut output = Vec::new();
+    analyzer
+        .create_split_image(1, SplitBy::Dir { depth: 1 }, &mut output)
+        .unwrap();
+    let contents = ImageContents::read(&output);
+    let paths: Vec<Vec<&str>> = contents
+        .layers
+        .iter()
+        .map(|layer| layer.iter().map(|e| e.path.as_str()).collect())
+        .collect();
+    assert_eq!(
+        paths,
+        [
+            vec!["etc/hosts"],
+            vec![
+                "etc/.wh.hosts",
+                "usr/bin/tool",
+                "usr/bin/tool-alias",
+                "usr/lib/libc.so"
+            ],
+            vec!["opt/data"],
+        ]
+    );
+    assert_eq!(contents.config.rootfs.diff_ids.len(), 3);
+    let comments: Vec<&str> = contents
+        .config
+        .history
+        .iter()
+        .map(|h| h.comment.as_str())
+        .collect();
+    assert_eq!(comments, ["", "part 1 of 2", "part 2 of 2"]);
+    let split = Analyzer::builder().load(output.as_slice()).unwrap();
+    assert_eq!(visible_paths(&split), visible_paths(&analyzer));
+
+    let mut output = Vec::new();
+    let summary = analyzer
+        .create_split_image(1, SplitBy::Size(1000), &mut output)
+        .unwrap();
+    assert_eq!(summary.layers.len(), 4);
+    let contents = ImageContents::read(&output);
+    let sizes: Vec<usize> = contents.layers.iter().map(Vec::len).collect();
+    assert_eq!(sizes, [1, 3, 1, 1]);
+}
@@ -0,0 +1,65 @@
+#![cfg(feature = "rewrite")]
+
+mod common;
+
+use common::visible_paths;
+use docker_duplicate_files::Analyzer;
+use docker_duplicate_files::entries::EntryKind;
+use docker_duplicate_files::testing::{ImageBuilder, ImageContents, LayerBuilder};
+
+#[test]
+fn test_squash_merges_top_layers() {
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("etc/passwd", "root")
+                .dir("var/cache")
+                .file("var/cache/old", "stale")
+                .created_by("ADD rootfs /"),
+        )
+        .layer(
+            LayerBuilder::new()
+                .file("tmp/build.tar", vec![0; 1000])
+                .file("etc/motd", "hello")
+                .created_by("RUN build"),
+        )
+        .empty_layer("ENV A=1")
+        .layer(
+            LayerBuilder::new()
+                .whiteout("tmp/build.tar")
+                .whiteout("var/cache")
+                .dir("var/cache")
+                .file("var/cache/new", "fresh")
+                .file("etc/motd", "bye")
+                .created_by("RUN cleanup"),
+        )
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    let mut output = Vec::new();
+    let summary = analyzer.create_squashed_image(1, &mut output).unwrap();
+    assert_eq!(summary.layers.len(), 3);
+    assert_eq!(summary.layers[1].new_blob, summary.layers[2].new_blob);
+
+    let contents = ImageContents::read(&output);
+    assert_eq!(contents.layers.len(), 2);
+    assert_eq!(contents.config.rootfs.diff_ids.len(), 2);
+    let layer_history: Vec<&str> = contents
+        .config
+        .history
+        .iter()
+        .filter(|h| !h.empty_layer)
+        .map(|h| h.created_by.as_str())
+        .collect();
+    assert_eq!(layer_history, ["ADD rootfs /", "squash layers 1-2"]);
+
+    assert!(contents.entry(1, "tmp/build.tar").is_none());
+    assert_eq!(contents.entry(1, "etc/motd").unwrap().data, b"bye");
+    assert_eq!(
+        contents.entry(1, "var/cache/.wh..wh..opq").unwrap().kind,
+        EntryKind::Opaque
+    );
+
+    let squashed = Analyzer::builder().load(output.as_slice()).unwrap();
+    assert_eq!(visible_paths(&squashed), visible_paths(&analyzer));
+    assert!(analyzer.create_squashed_image(3, Vec::new()).is_err());
+}
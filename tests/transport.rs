@@ -0,0 +1,225 @@
+#![cfg(feature = "rewrite")]
+
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use common::{dedupe_to, image_with_duplicate, load_contents};
+use docker_duplicate_files::ImageRef;
+use docker_duplicate_files::analyzer::{Analyzer, LayerFormat};
+use docker_duplicate_files::digest_writer::sha256_digest as sha256;
+use docker_duplicate_files::entries::EntryKind;
+use docker_duplicate_files::schemas::{Descriptor, ImageIndex, ImageManifest};
+use docker_duplicate_files::testing::{ImageBuilder, LayerBuilder, random_bytes};
+
+#[test]
+fn test_oci_and_dir_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let oci: ImageRef = format!("oci:{}:v1", dir.path().join("oci").display())
+        .parse()
+        .unwrap();
+    dedupe_to(&image_with_duplicate(), &oci);
+
+    let contents = load_contents(&oci);
+    assert_eq!(contents.entry(1, "b.so").unwrap().kind, EntryKind::Symlink);
+
+    // Re-export through dir: and read it back
+    let copy: ImageRef = format!("dir:{}", dir.path().join("copy").display())
+        .parse()
+        .unwrap();
+    let analyzer = oci.load(Analyzer::builder().options()).unwrap();
+    copy.write(&analyzer, Vec::new()).unwrap();
+    let contents = load_contents(&copy);
+    assert_eq!(contents.entry(0, "a.so").unwrap().kind, EntryKind::File);
+    assert_eq!(contents.entry(1, "b.so").unwrap().kind, EntryKind::Symlink);
+}
+
+/// Writes `image` to a new OCI layout at `root` with a signature-like
+/// artifact attached, returning the reference and the image's digest.
+fn oci_with_referrer(image: &[u8], root: &Path) -> (ImageRef, String) {
+    let input: ImageRef = format!("oci:{}", root.display()).parse().unwrap();
+    let analyzer = Analyzer::builder().load(image).unwrap();
+    input.write(&analyzer, Vec::new()).unwrap();
+
+    let write_blob = |data: &[u8]| {
+        let digest = sha256(data);
+        fs::write(root.join("blobs/sha256").join(&digest[7..]), data).unwrap();
+        digest
+    };
+    let index_path = root.join("index.json");
+    let mut index: ImageIndex = fs::read_to_string(&index_path).unwrap().parse().unwrap();
+    let subject = index.manifests[0].clone();
+    let artifact = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "artifactType": "application/vnd.example.signature",
+        "config": {
+            "mediaType": "application/vnd.oci.empty.v1+json",
+            "digest": write_blob(b"{}"),
+            "size": 2
+        },
+        "layers": [{
+            "mediaType": "application/octet-stream",
+            "digest": write_blob(b"signature"),
+            "size": 9
+        }],
+        "subject": {
+            "mediaType": subject.media_type,
+            "digest": subject.digest,
+            "size": subject.size
+        }
+    });
+    let json = serde_json::to_vec(&artifact).unwrap();
+    index.manifests.push(Descriptor {
+        media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+        digest: write_blob(&json),
+        size: json.len() as u64,
+        artifact_type: None,
+        platform: None,
+        annotations: None,
+    });
+    fs::write(&index_path, serde_json::to_vec(&index).unwrap()).unwrap();
+    (input, subject.digest)
+}
+
+#[test]
+fn test_copy_referrers_marks_them_stale() {
+    let dir = tempfile::tempdir().unwrap();
+    let (input, original) = oci_with_referrer(&image_with_duplicate(), &dir.path().join("in"));
+    let referrers = input.referrers().unwrap();
+    assert_eq!(referrers.len(), 1);
+    assert_eq!(
+        referrers[0].artifact_type.as_deref(),
+        Some("application/vnd.example.signature")
+    );
+
+    let output: ImageRef = format!("oci:{}", dir.path().join("out").display())
+        .parse()
+        .unwrap();
+    let analyzer = input.load(Analyzer::builder().options()).unwrap();
+    let summary = output
+        .write(&analyzer, analyzer.find_duplicates().unwrap())
+        .unwrap();
+    let copied = input
+        .copy_referrers(&output, &summary.manifest_digest.unwrap())
+        .unwrap();
+    assert_eq!(copied, referrers);
+
+    let attached = output.referrers().unwrap();
+    assert_eq!(attached.len(), 1);
+    let annotations = attached[0].annotations.as_ref().unwrap();
+    assert_eq!(annotations["org.dedupe.stale"], "true");
+    assert_eq!(annotations["org.dedupe.original-subject"], original);
+    assert_eq!(load_contents(&output).layers.len(), 2);
+}
+
+/// The manifest of the only image in the OCI layout at `root`.
+fn oci_manifest(root: &Path) -> ImageManifest {
+    let index: ImageIndex = fs::read_to_string(root.join("index.json"))
+        .unwrap()
+        .parse()
+        .unwrap();
+    let hex = &index.manifests[0].digest["sha256:".len()..];
+    let blob = root.join("blobs/sha256").join(hex);
+    fs::read_to_string(blob).unwrap().parse().unwrap()
+}
+
+#[test]
+fn test_encrypted_layers_pass_through() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib = random_bytes(1_200_000, 11);
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a.so", lib.clone()))
+        .layer(LayerBuilder::new().file("b.so", lib))
+        .raw_layer(random_bytes(5000, 12))
+        .build();
+    let input: ImageRef = format!("oci:{}", dir.path().join("in").display())
+        .parse()
+        .unwrap();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    input.write(&analyzer, Vec::new()).unwrap();
+
+    // Mark the random layer as ocicrypt would have written it
+    let root = dir.path().join("in");
+    let mut manifest = oci_manifest(&root);
+    let encrypted = &mut manifest.layers[2];
+    encrypted.media_type = "application/vnd.oci.image.layer.v1.tar+encrypted".to_string();
+    encrypted.annotations = Some(HashMap::from([(
+        "org.opencontainers.image.enc.keys.jwe".to_string(),
+        "a2V5".to_string(),
+    )]));
+    let json = serde_json::to_vec(&manifest).unwrap();
+    let digest = sha256(&json);
+    fs::write(root.join("blobs/sha256").join(&digest[7..]), &json).unwrap();
+    let mut index: ImageIndex = fs::read_to_string(root.join("index.json"))
+        .unwrap()
+        .parse()
+        .unwrap();
+    index.manifests[0].digest = digest;
+    index.manifests[0].size = json.len() as u64;
+    fs::write(root.join("index.json"), serde_json::to_vec(&index).unwrap()).unwrap();
+
+    let output: ImageRef = format!("oci:{}", dir.path().join("out").display())
+        .parse()
+        .unwrap();
+    let analyzer = input.load(Analyzer::builder().options()).unwrap();
+    assert_eq!(analyzer.layers[2].format, LayerFormat::Encrypted);
+    let duplicates = analyzer.find_duplicates().unwrap();
+    assert_eq!(duplicates.len(), 1);
+    output.write(&analyzer, duplicates).unwrap();
+    assert!(analyzer.flatten(Vec::new()).is_err());
+
+    let written = oci_manifest(&dir.path().join("out"));
+    assert_eq!(written.layers[2], manifest.layers[2]);
+    assert_ne!(written.layers[1].digest, manifest.layers[1].digest);
+}
+
+#[cfg(feature = "network")]
+mod registry {
+    use super::*;
+    use crate::common::registry::start_registry;
+
+    #[test]
+    fn test_push_and_pull() {
+        let registry = start_registry();
+        let image: ImageRef = format!("docker://{}/test/app:v1", registry)
+            .parse()
+            .unwrap();
+        let summary = dedupe_to(&image_with_duplicate(), &image);
+
+        let contents = load_contents(&image);
+        assert_eq!(contents.entry(0, "a.so").unwrap().kind, EntryKind::File);
+        assert_eq!(contents.entry(1, "b.so").unwrap().kind, EntryKind::Symlink);
+
+        let pinned = image.pinned(&summary.manifest_digest.unwrap());
+        assert!(pinned.starts_with(&format!("{}/test/app@sha256:", registry)));
+        let pinned: ImageRef = format!("docker://{}", pinned).parse().unwrap();
+        assert_eq!(load_contents(&pinned).layers.len(), 2);
+    }
+
+    #[test]
+    fn test_copy_referrers_to_registry() {
+        let registry = start_registry();
+        let dir = tempfile::tempdir().unwrap();
+        let (input, _) = oci_with_referrer(&image_with_duplicate(), dir.path());
+        let output: ImageRef = format!("docker://{}/test/app:v2", registry)
+            .parse()
+            .unwrap();
+        let analyzer = input.load(Analyzer::builder().options()).unwrap();
+        let summary = output
+            .write(&analyzer, analyzer.find_duplicates().unwrap())
+            .unwrap();
+        input
+            .copy_referrers(&output, &summary.manifest_digest.unwrap())
+            .unwrap();
+        // Found through the sha256-<hex> tag, the registry has no API
+        let attached = output.referrers().unwrap();
+        assert_eq!(attached.len(), 1);
+        assert_eq!(
+            attached[0].annotations.as_ref().unwrap()["org.dedupe.stale"],
+            "true"
+        );
+    }
+}
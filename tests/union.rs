@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use docker_duplicate_files::Analyzer;
+use docker_duplicate_files::entries::EntryKind;
+use docker_duplicate_files::testing::{ImageBuilder, LayerBuilder};
+use docker_duplicate_files::union::disk_usage;
+
+fn layered_image() -> Vec<u8> {
+    ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .dir("etc")
+                .file("etc/motd", "hello")
+                .file("etc/passwd", "root")
+                .dir("var/cache")
+                .file("var/cache/a", "cached")
+                .file("opt", "a file"),
+        )
+        .layer(
+            LayerBuilder::new()
+                .whiteout("etc/passwd")
+                .opaque("var/cache")
+                .file("var/cache/b", "fresh")
+                .dir("opt")
+                .file("opt/tool", "tool")
+                .hardlink("opt/tool-link", "opt/tool"),
+        )
+        .layer(LayerBuilder::new().file("etc/motd", "bye"))
+        .build()
+}
+
+#[test]
+fn test_union_view_applies_whiteouts() {
+    let analyzer = Analyzer::builder()
+        .load(layered_image().as_slice())
+        .unwrap();
+    let view = analyzer.union_view().unwrap();
+    let paths: Vec<&str> = view.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        [
+            "etc",
+            "etc/motd",
+            "opt",
+            "opt/tool",
+            "opt/tool-link",
+            "var/cache",
+            "var/cache/b"
+        ]
+    );
+    assert_eq!(view.get("/etc/motd").unwrap().layer_index, 2);
+    assert_eq!(view.get("opt").unwrap().kind, EntryKind::Dir);
+    assert!(view.get("etc/passwd").is_none());
+}
+
+#[test]
+fn test_flatten_writes_merged_rootfs() {
+    let analyzer = Analyzer::builder()
+        .load(layered_image().as_slice())
+        .unwrap();
+    let mut output = Vec::new();
+    assert_eq!(analyzer.flatten(&mut output).unwrap(), 7);
+
+    let mut contents = BTreeMap::new();
+    let mut archive = tar::Archive::new(output.as_slice());
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().to_string_lossy().to_string();
+        let mut data = String::new();
+        entry.read_to_string(&mut data).unwrap();
+        contents.insert(path, (entry.header().entry_type(), data));
+    }
+    assert_eq!(contents["etc/motd"].1, "bye");
+    assert_eq!(contents["var/cache/b"].1, "fresh");
+    assert!(contents["opt/tool-link"].0.is_hard_link());
+    assert!(!contents.contains_key("etc/passwd"));
+    assert!(!contents.contains_key("var/cache/a"));
+    assert!(!contents.keys().any(|path| path.contains(".wh.")));
+}
+
+#[test]
+fn test_disk_usage_rolls_up_visible_files() {
+    let analyzer = Analyzer::builder()
+        .load(layered_image().as_slice())
+        .unwrap();
+    let view = analyzer.union_view().unwrap();
+    let usage = disk_usage(view.iter(), 1);
+    let by_path: BTreeMap<&str, _> = usage.iter().map(|d| (d.path.as_str(), d)).collect();
+    assert_eq!(
+        by_path.keys().copied().collect::<Vec<_>>(),
+        ["/", "/etc", "/opt", "/var"]
+    );
+    assert_eq!(by_path["/"].size, 12);
+    assert_eq!(by_path["/"].files, 3);
+    assert_eq!(by_path["/"].layers, [1, 2]);
+    assert_eq!(by_path["/etc"].size, 3);
+}
+
+#[test]
+fn test_largest_files_flags_hidden_ones() {
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .file("big", vec![0; 300])
+                .file("small", vec![0; 10])
+                .created_by("ADD rootfs /"),
+        )
+        .layer(
+            LayerBuilder::new()
+                .whiteout("big")
+                .file("medium", vec![0; 100]),
+        )
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    let files = analyzer.largest_files(2).unwrap();
+    let summary: Vec<_> = files
+        .iter()
+        .map(|f| (f.entry.path.as_str(), f.visible, f.created_by.as_deref()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("big", false, Some("ADD rootfs /")),
+            ("medium", true, Some("layer 1"))
+        ]
+    );
+}
+
+#[test]
+fn test_extract_file_follows_links() {
+    let image = ImageBuilder::new()
+        .layer(
+            LayerBuilder::new()
+                .dir("usr/lib")
+                .file("usr/lib/libc.so.6", "libc")
+                .symlink("lib", "usr/lib")
+                .symlink("usr/lib/libc.so", "libc.so.6")
+                .symlink("loop", "/loop"),
+        )
+        .layer(LayerBuilder::new().file("usr/lib/libc.so.6", "patched libc"))
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    let mut contents = Vec::new();
+    analyzer
+        .extract_file("/lib/libc.so", &mut contents)
+        .unwrap();
+    assert_eq!(contents, b"patched libc");
+
+    let view = analyzer.union_view().unwrap();
+    assert!(view.resolve("loop").is_err());
+    assert!(analyzer.extract_file("usr/lib", Vec::new()).is_err());
+    assert!(analyzer.extract_layer(2, Vec::new()).is_err());
+}
@@ -0,0 +1,74 @@
+#![cfg(feature = "sha256")]
+
+use std::fs;
+
+use docker_duplicate_files::Analyzer;
+use docker_duplicate_files::testing::{ImageBuilder, LayerBuilder};
+
+#[test]
+fn test_verify_detects_corruption() {
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a", "first"))
+        .layer(LayerBuilder::new().file("b", "second"))
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    let verification = analyzer.verify().unwrap();
+    assert!(verification.is_ok(), "{:?}", verification.problems);
+    assert_eq!(verification.files_checked, 3);
+
+    fs::write(&analyzer.layers[1].path, b"not a layer").unwrap();
+    let verification = analyzer.verify().unwrap();
+    assert_eq!(verification.problems.len(), 1);
+    assert!(
+        verification.problems[0]
+            .message
+            .starts_with("contents have digest"),
+        "{:?}",
+        verification.problems
+    );
+}
+
+#[cfg(feature = "rewrite")]
+#[test]
+fn test_verify_written_checks_the_summary() {
+    let lib = docker_duplicate_files::testing::random_bytes(1_500_000, 1);
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a", lib.clone()))
+        .layer(LayerBuilder::new().file("b", lib))
+        .build();
+    let analyzer = Analyzer::builder().load(image.as_slice()).unwrap();
+    let mut output = Vec::new();
+    let mut summary = analyzer
+        .create_deduplicated_image(analyzer.find_duplicates().unwrap(), &mut output)
+        .unwrap();
+    let written = Analyzer::builder().load(output.as_slice()).unwrap();
+    let verification = written.verify_written(&summary).unwrap();
+    assert!(verification.is_ok(), "{:?}", verification.problems);
+
+    summary.layers[1].new_diff_id = summary.layers[1].old_diff_id.clone();
+    summary.new_config_digest = summary.old_config_digest.clone();
+    assert_eq!(written.verify_written(&summary).unwrap().problems.len(), 2);
+}
+
+#[cfg(feature = "rewrite")]
+#[test]
+fn test_written_blobs_are_named_by_digest() {
+    let lib = docker_duplicate_files::testing::random_bytes(1_500_000, 2);
+    let image = ImageBuilder::new()
+        .layer(LayerBuilder::new().file("a", lib.clone()))
+        .layer(LayerBuilder::new().file("b", lib).file("c", "other"))
+        .build();
+    for compression in [true, false] {
+        let analyzer = Analyzer::builder()
+            .compression(compression)
+            .load(image.as_slice())
+            .unwrap();
+        let mut output = Vec::new();
+        analyzer
+            .create_deduplicated_image(analyzer.find_duplicates().unwrap(), &mut output)
+            .unwrap();
+        let written = Analyzer::builder().load(output.as_slice()).unwrap();
+        let verification = written.verify().unwrap();
+        assert!(verification.is_ok(), "{:?}", verification.problems);
+    }
+}